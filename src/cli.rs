@@ -0,0 +1,205 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::youtube::import::Format;
+
+/// ytb-dlp helper to download music from youtube directly into VLC app on iOS
+#[derive(Debug, Parser)]
+#[command(name = "monsieur_dlp", version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    /// Stage to run, the whole pipeline (`sync`) when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// Config file to load on top of the XDG and project config files
+    #[arg(short, long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Print more details, repeat for even more (-vv)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Print what would be done without downloading or writing anything, the differential
+    /// sync only reads the devices
+    #[arg(short = 'n', long, global = true)]
+    pub dry_run: bool,
+
+    /// Only talk to this device, by UDID or name (every connected device by default)
+    #[arg(short, long, global = true, value_name = "UDID|NAME")]
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Download the songs of the songs file and record them in the library
+    Download {
+        /// Download the songs already in the library again
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Download, then pair, mount, move the songs to the device and unmount
+    Sync {
+        /// Download the songs already in the library again
+        #[arg(short, long)]
+        force: bool,
+
+        /// Compare the device with the library and rename, delete or upload what differs,
+        /// preview the plan with --dry-run
+        #[arg(long)]
+        differential: bool,
+
+        /// With --differential, also delete the device files the library does not know
+        #[arg(long, requires = "differential")]
+        prune: bool,
+    },
+    /// Measure the loudness of the downloaded songs not measured yet, then tag or normalize
+    /// them following `download.loudness`
+    Loudness,
+    /// Pair and validate the devices
+    Pair,
+    /// Mount the app Documents container of each device under the mounting path
+    Mount,
+    /// Unmount the devices mounted under the mounting path
+    Unmount,
+    /// List the connected devices with their UDID, name, iOS version and pairing state
+    Devices,
+    /// Show the usbmuxd status, the configured paths and the pending songs
+    Status,
+    /// Print the already downloaded songs
+    History {
+        /// Only print the last N songs
+        #[arg(short = 'l', long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Add a song to the songs file
+    Add {
+        /// Url of the video
+        url: String,
+        /// Artist tag of the song
+        #[arg(short, long, default_value = "")]
+        artist: String,
+        /// Title tag of the song, also used in the file name (`download.template`)
+        #[arg(short = 't', long = "name", default_value = "")]
+        name: String,
+    },
+    /// Add the songs of a CSV, JSON or M3U file to the songs file
+    Import {
+        /// The file to import, its format is guessed from the extension
+        file: PathBuf,
+        /// Format of the file: csv, json or m3u
+        #[arg(long)]
+        format: Option<Format>,
+        /// CSV column holding a song field, the column named after the field by default
+        #[arg(long = "column", value_name = "FIELD=HEADER")]
+        columns: Vec<String>,
+        /// Also add the songs already in the songs file or in the library
+        #[arg(short, long)]
+        force: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_definition_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_without_subcommand_runs_default() {
+        let cli = Cli::parse_from(["monsieur_dlp"]);
+
+        assert!(cli.command.is_none());
+        assert!(!cli.global.dry_run);
+        assert_eq!(cli.global.verbose, 0);
+    }
+
+    #[test]
+    fn parse_add_with_global_flags_after_subcommand() {
+        let cli = Cli::parse_from([
+            "monsieur_dlp",
+            "add",
+            "https://youtu.be/abc",
+            "--artist",
+            "Rust",
+            "--name",
+            "Lang",
+            "--dry-run",
+            "-vv",
+            "--config",
+            "/tmp/config.toml",
+        ]);
+
+        assert!(cli.global.dry_run);
+        assert_eq!(cli.global.verbose, 2);
+        assert_eq!(cli.global.config, Some(PathBuf::from("/tmp/config.toml")));
+        match cli.command {
+            Some(Command::Add { url, artist, name }) => {
+                assert_eq!(url, "https://youtu.be/abc");
+                assert_eq!(artist, "Rust");
+                assert_eq!(name, "Lang");
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_device_selector() {
+        let cli = Cli::parse_from(["monsieur_dlp", "sync", "--device", "Work iPhone"]);
+
+        assert_eq!(cli.global.device.as_deref(), Some("Work iPhone"));
+        assert!(matches!(cli.command, Some(Command::Sync { force: false, .. })));
+    }
+
+    #[test]
+    fn parse_differential_sync() {
+        let cli = Cli::parse_from(["monsieur_dlp", "sync", "--differential", "--prune", "-n"]);
+
+        assert!(cli.global.dry_run);
+        assert!(matches!(cli.command, Some(Command::Sync { differential: true, prune: true, .. })));
+        assert!(Cli::try_parse_from(["monsieur_dlp", "sync", "--prune"]).is_err());
+    }
+
+    #[test]
+    fn parse_import_with_columns() {
+        let cli = Cli::parse_from([
+            "monsieur_dlp", "import", "songs.txt", "--format", "csv", "--column", "url=Link", "--column", "title=Song",
+        ]);
+
+        match cli.command {
+            Some(Command::Import { file, format, columns, force }) => {
+                assert_eq!(file, PathBuf::from("songs.txt"));
+                assert_eq!(format, Some(Format::Csv));
+                assert_eq!(columns, vec!["url=Link", "title=Song"]);
+                assert!(!force);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_download_force() {
+        let cli = Cli::parse_from(["monsieur_dlp", "download", "--force"]);
+
+        assert!(matches!(cli.command, Some(Command::Download { force: true })));
+    }
+
+    #[test]
+    fn parse_verbose_and_quiet_conflict() {
+        assert!(Cli::try_parse_from(["monsieur_dlp", "-v", "-q", "status"]).is_err());
+    }
+}
@@ -0,0 +1,613 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use indicatif::HumanBytes;
+use tokio::task::JoinSet;
+
+use super::{CommandError, Context};
+use crate::common::config::Transport;
+use crate::common::constants;
+use crate::ios::devices::DeviceDetails;
+use crate::ios::filesystem::{DeviceStorage, FileSystemError, MountedDir};
+use crate::ios::plan::{Action, Applied, Wanted};
+use crate::ios::{self, service::UsbmuxdStatus};
+use crate::library::{self, Library, TransferState};
+use crate::youtube;
+use crate::youtube::naming::Template;
+
+/// Check if usbmuxd service is running before talking to the device
+async fn check_usbmuxd(ctx: &Context) -> Result<(), CommandError> {
+    match ios::service::check_usbmuxd_service_status().await? {
+        UsbmuxdStatus::Running => ctx.debug("usbmuxd is running"),
+        UsbmuxdStatus::Stopped => eprintln!("Warning: usbmuxd service is not running"),
+    }
+    Ok(())
+}
+
+/// The devices picked by --device, every connected device by default
+async fn selected_devices(ctx: &Context) -> Result<Vec<DeviceDetails>, CommandError> {
+    let devices = ios::devices::connected_devices().await?;
+    Ok(ios::devices::select(devices, ctx.device.as_deref())?)
+}
+
+/// What --device designates, for the dry-run messages
+fn devices_description(ctx: &Context) -> String {
+    match &ctx.device {
+        Some(selector) => format!("device '{}'", selector),
+        None => "every connected device".to_string(),
+    }
+}
+
+/// List the connected devices
+pub async fn devices(ctx: &Context) -> Result<(), CommandError> {
+    check_usbmuxd(ctx).await?;
+    let devices = ios::devices::connected_devices().await?;
+    if devices.is_empty() {
+        ctx.info("No device connected");
+    }
+    for device in devices {
+        println!("{}", device);
+    }
+    Ok(())
+}
+
+/// Pair then validate the selected devices, one after the other as each may ask
+/// the user to trust the computer
+pub async fn pair(ctx: &Context) -> Result<(), CommandError> {
+    if ctx.dry_run {
+        ctx.would(format!("pair and validate {}", devices_description(ctx)));
+        return Ok(());
+    }
+
+    check_usbmuxd(ctx).await?;
+
+    for details in selected_devices(ctx).await? {
+        if !details.paired {
+            ctx.info(format!("Waiting for {} to trust this computer...", details.udid()));
+        }
+        let output = ios::pairing::pair_device(&details.device).await?;
+        ctx.info(format!("Pairing successful ✅\n{}", output));
+
+        let output = ios::pairing::validate_device(&details.device).await?;
+        ctx.info(format!("Device validation successful ✅\n{}", output));
+    }
+    Ok(())
+}
+
+/// Mount the app Documents of every selected device on its own mountpoint
+pub async fn mount(ctx: &Context) -> Result<(), CommandError> {
+    let bundle_id = constants::app_bundle_id();
+    let mounting_path = constants::mounting_path();
+
+    if ctx.dry_run {
+        ctx.would(format!(
+            "mount {} of {} on {}/<UDID>",
+            bundle_id,
+            devices_description(ctx),
+            mounting_path.display()
+        ));
+        return Ok(());
+    }
+
+    check_usbmuxd(ctx).await?;
+
+    for details in selected_devices(ctx).await? {
+        let mountpoint = ios::devices::mountpoint(&mounting_path, &details.device);
+        fs::create_dir_all(&mountpoint)?;
+        if ios::mounting::is_mounted(&mountpoint)? {
+            ctx.info(format!("{} is already mounted", mountpoint.display()));
+            continue;
+        }
+
+        let output = ios::mounting::mount_app(bundle_id, details.udid(), &mountpoint).await?;
+        ctx.info(output);
+    }
+    Ok(())
+}
+
+/// Unmount the mountpoints of the selected devices, even the unplugged ones: a name is
+/// resolved through the connected devices, an unplugged device is only found by UDID
+pub async fn unmount(ctx: &Context) -> Result<(), CommandError> {
+    let mounting_path = constants::mounting_path();
+
+    if ctx.dry_run {
+        ctx.would(format!("unmount the mountpoints of {} in {}", devices_description(ctx), mounting_path.display()));
+        return Ok(());
+    }
+
+    if !mounting_path.is_dir() {
+        return Ok(());
+    }
+    let udids = match ctx.device.as_deref() {
+        Some(selector) => {
+            let devices = ios::devices::connected_devices().await.unwrap_or_default();
+            Some(ios::devices::selected_udids(&devices, selector))
+        }
+        None => None,
+    };
+    // A mountpoint failing to unmount does not keep the others mounted
+    let mut failure = None;
+    for entry in fs::read_dir(&mounting_path)? {
+        let mountpoint = entry?.path();
+        let udid = mountpoint.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let selected =
+            udids.as_ref().is_none_or(|udids| udids.iter().any(|selected| selected.eq_ignore_ascii_case(&udid)));
+
+        if selected && ios::mounting::is_mounted(&mountpoint)? {
+            match ios::mounting::unmount(&mountpoint).await {
+                Ok(output) => ctx.info(output),
+                Err(e) => {
+                    eprintln!("{}: {}", udid, e);
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+    }
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// The app Documents of the device, through its ifuse mount or AFC
+async fn open_storage(details: &DeviceDetails, ifuse: bool) -> Result<Box<dyn DeviceStorage>, CommandError> {
+    if ifuse {
+        let mountpoint = ios::devices::mountpoint(constants::mounting_path(), &details.device);
+        ios::mounting::require_mounted(&mountpoint)?;
+        return Ok(Box::new(MountedDir::new(mountpoint)));
+    }
+    let paired = ios::pairing::validate_device(&details.device).await?;
+    Ok(Box::new(ios::house_arrest::open_documents(&paired).await?))
+}
+
+/// In archive mode, move the downloaded songs from the download path to the archive,
+/// returns where every moved file went
+pub fn archive_downloads(ctx: &Context, library: &Library) -> Result<HashMap<PathBuf, PathBuf>, CommandError> {
+    let config = crate::common::config::get();
+    if !config.download.archive {
+        return Ok(HashMap::new());
+    }
+    // Validated with the config
+    let template = Template::parse(&config.download.archive_template).unwrap_or_default();
+    let download_path = constants::download_path();
+    let moves = library::archive::destinations(library, &download_path, &constants::archive_path(), &template)?;
+    for (from, to) in &moves {
+        if ctx.dry_run {
+            ctx.would(format!("archive {} to {}", from.display(), to.display()));
+            continue;
+        }
+        library::archive::move_file(from, to)?;
+        library.move_path(from, to)?;
+        ctx.debug(format!("Archived {}", to.display()));
+    }
+    if !ctx.dry_run && !moves.is_empty() {
+        youtube::staging::remove_finished(&download_path)?;
+    }
+    Ok(moves.into_iter().collect())
+}
+
+/// The folder `path` is relative to on the device: the archive for the archived songs
+fn device_root<'a>(path: &Path, download_path: &'a Path, archive_path: &'a Path) -> &'a Path {
+    if path.starts_with(archive_path) { archive_path } else { download_path }
+}
+
+/// The files that fit on the device with `device.free_space_margin_mb` to spare and the
+/// ones left out, see [`ios::filesystem::preflight`]
+async fn preflight(
+    files: &[(PathBuf, String)],
+    storage: &mut dyn DeviceStorage,
+) -> Result<(Vec<(PathBuf, String)>, Vec<(PathBuf, String)>), CommandError> {
+    let device = &crate::common::config::get().device;
+    let margin = device.free_space_margin_mb * 1024 * 1024;
+    Ok(ios::filesystem::preflight(files, storage, margin, device.when_full).await?)
+}
+
+/// Copy the downloaded songs to the app Documents of every selected device in
+/// parallel, through AFC or the ifuse mounts. Only the files the library recorded
+/// are copied, each is deleted locally once every device has it back verified. In
+/// archive mode they are moved to the archive first, and every device gets the
+/// archived songs it does not have yet.
+pub async fn move_songs(ctx: &Context) -> Result<(), CommandError> {
+    let download_path = constants::download_path();
+    let archive_path = constants::archive_path();
+    let archive = crate::common::config::get().download.archive;
+    let ifuse = crate::common::config::get().device.transport == Transport::Ifuse;
+
+    // The songs go to the folder set in the songs file
+    let library = super::open_library(ctx)?;
+    let archived = archive_downloads(ctx, &library)?;
+    let mut songs = HashMap::new();
+    let mut priorities = HashMap::new();
+    let mut named_files = vec![];
+    for song in library.waiting()? {
+        let Some(path) = song.path.as_ref().map(|path| archived.get(path).unwrap_or(path).clone()) else {
+            continue;
+        };
+        let in_archive = archive && path.starts_with(&archive_path);
+        if !in_archive && !path.starts_with(&download_path) {
+            continue;
+        }
+        // An older download of the same file goes with it
+        if songs.insert(path.clone(), song.id).is_none() {
+            let root = device_root(&path, &download_path, &archive_path);
+            named_files.push((path.clone(), library::device_name(song.folder.as_deref(), root, &path)));
+        }
+        priorities.insert(path, song.priority);
+    }
+    // The songs of highest priority first, they go first to a device short of room
+    named_files.sort_by_key(|(path, _)| (Reverse(priorities[path]), path.clone()));
+
+    if ctx.dry_run {
+        for (path, name) in &named_files {
+            ctx.would(format!(
+                "copy {} to {} in the {} Documents of {}",
+                path.display(),
+                name,
+                constants::app_bundle_id(),
+                devices_description(ctx)
+            ));
+        }
+        return Ok(());
+    }
+
+    if named_files.is_empty() {
+        ctx.info("No song to move");
+        return Ok(());
+    }
+    check_usbmuxd(ctx).await?;
+
+    let mut transferred = HashSet::new();
+    for id in songs.values() {
+        for transfer in library.transfers(*id)? {
+            if transfer.state == TransferState::Transferred {
+                transferred.insert((*id, transfer.device_udid));
+            }
+        }
+    }
+
+    let mut transfers = JoinSet::new();
+    for details in selected_devices(ctx).await? {
+        // Every device only gets the songs it does not have
+        let files: Vec<(PathBuf, String)> = named_files
+            .iter()
+            .filter(|(path, _)| !transferred.contains(&(songs[path], details.udid().to_string())))
+            .cloned()
+            .collect();
+        if files.is_empty() {
+            ctx.info(format!("{}: every song is already there", details.udid()));
+            continue;
+        }
+
+        transfers.spawn(async move {
+            let result = async {
+                let mut storage = open_storage(&details, ifuse).await?;
+                let (fitting, left) = preflight(&files, storage.as_mut()).await?;
+                Ok::<_, CommandError>((ios::filesystem::copy_files_to_device(&fitting, storage.as_mut()).await, left))
+            }
+            .await;
+            (details, files, result)
+        });
+    }
+
+    // A file stays local until every device has it
+    let mut missed = HashSet::new();
+    let mut left_out = HashSet::new();
+    let mut failure = None;
+    while let Some(transfer) = transfers.join_next().await {
+        let (details, files, result) = transfer.map_err(io::Error::other)?;
+        let (report, left) = match result {
+            Ok(done) => done,
+            Err(e) => {
+                eprintln!("{}: {}", details.udid(), e);
+                for (path, _) in &files {
+                    library.set_transfer(songs[path], details.udid(), TransferState::Failed, Some(&e.to_string()))?;
+                }
+                missed.extend(files.into_iter().map(|(path, _)| path));
+                failure.get_or_insert(e);
+                continue;
+            }
+        };
+
+        for outcome in &report.outcomes {
+            let Some(id) = songs.get(&outcome.path) else {
+                continue;
+            };
+            match &outcome.result {
+                Ok(_) => library.set_transfer(*id, details.udid(), TransferState::Transferred, None)?,
+                Err(e) => {
+                    eprintln!("{}: {} ❌ {}", details.udid(), outcome.name, e);
+                    library.set_transfer(*id, details.udid(), TransferState::Failed, Some(&e.to_string()))?;
+                    missed.insert(outcome.path.clone());
+                }
+            }
+        }
+        ctx.info(format!("{}: {}", details.udid(), report.summary()));
+        if !left.is_empty() {
+            ctx.info(format!("{}: ⏭ {} songs do not fit, they wait for the next sync", details.udid(), left.len()));
+            for (_, name) in &left {
+                ctx.debug(format!("  {}", name));
+            }
+        }
+        left_out.extend(left.into_iter().map(|(path, _)| path));
+    }
+
+    // The archived files stay
+    for (path, _) in &named_files {
+        if !missed.contains(path) && !left_out.contains(path) && path.starts_with(&download_path) {
+            fs::remove_file(path)?;
+        }
+    }
+    youtube::staging::remove_empty_dirs(&download_path)?;
+    youtube::staging::remove_finished(&download_path)?;
+
+    match failure {
+        Some(e) => Err(e),
+        None if !missed.is_empty() => Err(FileSystemError::Incomplete(missed.len()).into()),
+        None => Ok(()),
+    }
+}
+
+/// Bring the app Documents of every selected device to the library: the songs found
+/// under another name are renamed, the duplicates and interrupted copies deleted (with
+/// `prune` every file the library does not know too), and the songs the device misses
+/// uploaded from the computer. With --dry-run the plan of every device is only printed.
+pub async fn sync_songs(ctx: &Context, prune: bool) -> Result<(), CommandError> {
+    let download_path = constants::download_path();
+    let ifuse = crate::common::config::get().device.transport == Transport::Ifuse;
+
+    // A song downloaded again replaces the older file of its name
+    let library = super::open_library(ctx)?;
+    let archived = archive_downloads(ctx, &library)?;
+    let archive_path = constants::archive_path();
+    let mut wanted = BTreeMap::new();
+    for song in library.songs()? {
+        let (Some(path), Some(checksum), Some(size)) = (&song.path, &song.checksum, song.size) else {
+            continue;
+        };
+        let local = path.is_file().then(|| archived.get(path).unwrap_or(path).clone());
+        let path = archived.get(path).unwrap_or(path);
+        let name = library::device_name(song.folder.as_deref(), device_root(path, &download_path, &archive_path), path);
+        let (checksum, priority) = (checksum.clone(), song.priority);
+        wanted.insert(name.clone(), Wanted { song_id: song.id, name, size, checksum, local, priority });
+    }
+    let wanted: Vec<Wanted> = wanted.into_values().collect();
+    let waiting: HashMap<i64, PathBuf> = wanted
+        .iter()
+        .filter_map(|song| Some((song.song_id, song.local.clone().filter(|path| path.starts_with(&download_path))?)))
+        .collect();
+
+    check_usbmuxd(ctx).await?;
+    let mut syncs = JoinSet::new();
+    for details in selected_devices(ctx).await? {
+        let mountpoint = ios::devices::mountpoint(constants::mounting_path(), &details.device);
+        if ctx.dry_run && ifuse && !ios::mounting::is_mounted(&mountpoint)? {
+            ctx.would(format!("compare {} with the library once mounted", details.udid()));
+            continue;
+        }
+        let wanted = wanted.clone();
+        let dry_run = ctx.dry_run;
+
+        syncs.spawn(async move {
+            let result = async {
+                let mut storage = open_storage(&details, ifuse).await?;
+                let mut plan = ios::plan::plan(&wanted, storage.as_mut(), prune).await?;
+                let (_, left) = preflight(&plan.uploads(), storage.as_mut()).await?;
+                plan.leave_out(&left);
+                let applied = if dry_run { vec![] } else { ios::plan::apply(plan.actions.clone(), storage.as_mut()).await };
+                Ok::<_, CommandError>((plan, applied))
+            }
+            .await;
+            (details, result)
+        });
+    }
+
+    // A file stays local until every device has it
+    let mut missed = HashSet::new();
+    let mut left_out = HashSet::new();
+    let mut failure = None;
+    while let Some(sync) = syncs.join_next().await {
+        let (details, result) = sync.map_err(io::Error::other)?;
+        let udid = details.udid();
+        let (plan, applied) = match result {
+            Ok(done) => done,
+            Err(e) => {
+                eprintln!("{}: {}", udid, e);
+                missed.extend(waiting.keys().copied());
+                failure.get_or_insert(e);
+                continue;
+            }
+        };
+        for name in &plan.missing {
+            eprintln!("{}: {} is neither on the device nor on the computer anymore", udid, name);
+        }
+        if !plan.left_out.is_empty() {
+            ctx.info(format!("{}: ⏭ {} songs do not fit, they wait for the next sync", udid, plan.left_out.len()));
+        }
+        for action in &plan.left_out {
+            ctx.debug(format!("  {}", action));
+            if let Action::Upload { song_id, .. } = action {
+                left_out.insert(*song_id);
+            }
+        }
+        if ctx.dry_run {
+            ctx.info(format!("{}: {} songs up to date, {} changes", udid, plan.unchanged.len(), plan.actions.len()));
+            for action in &plan.actions {
+                ctx.would(format!("{} on {}", action, udid));
+            }
+            continue;
+        }
+
+        let mut placed: HashSet<i64> = plan.unchanged.iter().copied().collect();
+        for id in &plan.unchanged {
+            library.set_transfer(*id, udid, TransferState::Transferred, None)?;
+        }
+        let mut failed = 0;
+        for Applied { action, result } in &applied {
+            let song_id = match action {
+                Action::Rename { song_id, .. } | Action::Upload { song_id, .. } => Some(*song_id),
+                Action::Delete { .. } => None,
+            };
+            match result {
+                Ok(()) => {
+                    ctx.debug(format!("{}: {} ✅", udid, action));
+                    if let Some(id) = song_id {
+                        library.set_transfer(id, udid, TransferState::Transferred, None)?;
+                        placed.insert(id);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}: {} ❌ {}", udid, action, e);
+                    failed += 1;
+                    if let Some(id) = song_id {
+                        library.set_transfer(id, udid, TransferState::Failed, Some(&e.to_string()))?;
+                    }
+                }
+            }
+        }
+        missed.extend(waiting.keys().filter(|id| !placed.contains(id) && !left_out.contains(id)));
+        ctx.info(format!(
+            "{}: {} songs up to date, {} of {} changes applied {}",
+            udid,
+            plan.unchanged.len(),
+            applied.len() - failed,
+            applied.len(),
+            if failed == 0 { "✅" } else { "⚠️" }
+        ));
+    }
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    for (id, path) in &waiting {
+        if !missed.contains(id) && !left_out.contains(id) {
+            fs::remove_file(path)?;
+        }
+    }
+    if download_path.is_dir() {
+        youtube::staging::remove_empty_dirs(&download_path)?;
+        youtube::staging::remove_finished(&download_path)?;
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None if !missed.is_empty() => Err(FileSystemError::Incomplete(missed.len()).into()),
+        None => Ok(()),
+    }
+}
+
+/// The songs and free space of a paired device, read through AFC
+async fn device_documents_status(details: &DeviceDetails) -> Result<String, CommandError> {
+    let paired = ios::pairing::validate_device(&details.device).await?;
+    let mut documents = ios::house_arrest::open_documents(&paired).await?;
+    let files = documents.list("").await.map_err(FileSystemError::from)?;
+    let info = documents.afc().device_info().await.map_err(FileSystemError::from)?;
+
+    Ok(format!(
+        "{} files in the app Documents, {} free of {}",
+        files.len(),
+        HumanBytes(info.free_bytes),
+        HumanBytes(info.total_bytes)
+    ))
+}
+
+/// Print the usbmuxd status, the configured paths and the pending songs
+pub async fn status(ctx: &Context) -> Result<(), CommandError> {
+    let usbmuxd = ios::service::check_usbmuxd_service_status().await?;
+    println!("usbmuxd: {:?}", usbmuxd);
+
+    let songs_file = constants::youtube_songs_file();
+    let list = if songs_file.exists() {
+        youtube::list::parse(&youtube::filesystem::read_songs(&songs_file)?)
+    } else {
+        Default::default()
+    };
+    println!(
+        "Songs file: {} ({} pending, {} lines in error)",
+        songs_file.display(),
+        list.songs.len(),
+        list.diagnostics.len()
+    );
+    for diagnostic in &list.diagnostics {
+        ctx.debug(format!("  {}", diagnostic));
+    }
+
+    let library_path = constants::library_path();
+    let downloaded = if library_path.exists() {
+        library::open()?.songs()?.len()
+    } else {
+        0
+    };
+    println!("Library: {} ({} songs)", library_path.display(), downloaded);
+
+    let download_path = constants::download_path();
+    let waiting = if library_path.exists() {
+        let waiting = library::open()?.waiting()?;
+        waiting.iter().filter(|song| song.path.as_ref().is_some_and(|path| path.starts_with(&download_path))).count()
+    } else {
+        0
+    };
+    let leftovers = if download_path.is_dir() {
+        youtube::staging::leftovers(&download_path)?.len()
+    } else {
+        0
+    };
+    println!(
+        "Download path: {} ({} songs waiting for transfer, {} leftover batches)",
+        download_path.display(),
+        waiting,
+        leftovers
+    );
+
+    if crate::common::config::get().download.archive {
+        let archive_path = constants::archive_path();
+        let archived = if library_path.exists() {
+            let waiting = library::open()?.waiting()?;
+            waiting.iter().filter(|song| song.path.as_ref().is_some_and(|path| path.starts_with(&archive_path))).count()
+        } else {
+            0
+        };
+        println!("Archive: {} ({} songs)", archive_path.display(), archived);
+    }
+
+    let mounting_path = constants::mounting_path();
+    let mut mounted = 0;
+    if mounting_path.is_dir() {
+        for entry in fs::read_dir(&mounting_path)? {
+            if ios::mounting::is_mounted(entry?.path())? {
+                mounted += 1;
+            }
+        }
+    }
+    println!(
+        "Mounting path: {} ({} devices mounted)",
+        mounting_path.display(),
+        mounted
+    );
+    println!("App: {}", constants::app_bundle_id());
+    let afc = crate::common::config::get().device.transport == Transport::Afc;
+    println!("Transport: {}", if afc { "afc" } else { "ifuse" });
+    match ios::devices::connected_devices().await {
+        Ok(devices) if devices.is_empty() => println!("Devices: none connected"),
+        Ok(devices) => {
+            for details in devices {
+                println!("Device: {}", details);
+                if afc && details.paired {
+                    match device_documents_status(&details).await {
+                        Ok(summary) => println!("  {}", summary),
+                        Err(e) => println!("  not available ({})", e),
+                    }
+                }
+            }
+        }
+        Err(e) => println!("Devices: not available ({})", e),
+    }
+
+    ctx.debug(format!(
+        "Config files looked up: {:?}",
+        crate::common::config::config_file_candidates()
+    ));
+    Ok(())
+}
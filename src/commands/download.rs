@@ -0,0 +1,552 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+
+use super::{CommandError, Context, progress};
+use crate::common::config::{self, Leftovers, LoudnessMode};
+use crate::common::constants;
+use crate::library::{self, Library, LibraryError};
+use crate::youtube::downloader::{Download, DownloadError, Downloaders, FailureKind};
+use crate::youtube::loudness::{self, Loudness, LoudnessError};
+use crate::youtube::naming::Template;
+use crate::youtube::retry::RetryPolicy;
+use crate::youtube::scheduler::{Limits, Scheduler};
+use crate::youtube::staging::{self, Batch, Leftover};
+use crate::youtube::tagging::{self, ReplayGain};
+use crate::youtube::{self, Song};
+
+/// Songs of one download run
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    pub success: Vec<Song>,
+    /// Songs kept in the songs file for next time
+    pub fails: Vec<Song>,
+    /// Songs moved to the dead letters file, with the reason
+    pub dead: Vec<(Song, String)>,
+    /// Songs already in the library or twice in the songs file, dropped from it
+    pub skipped: Vec<Song>,
+}
+
+/// Split the songs to download from the ones already in the library or earlier in the list,
+/// comparing YouTube urls by video id. `force` keeps every song.
+pub fn skip_duplicates(
+    ctx: &Context,
+    library: &Library,
+    songs: Vec<Song>,
+    force: bool,
+) -> Result<(Vec<Song>, Vec<Song>), CommandError> {
+    if force {
+        return Ok((songs, vec![]));
+    }
+
+    let mut seen = HashSet::new();
+    let (mut kept, mut skipped) = (vec![], vec![]);
+    for song in songs {
+        if !seen.insert(youtube::url::canonical_url(&song.url)) {
+            ctx.info(format!("⏭ Skipping {}: twice in the songs file", song));
+            skipped.push(song);
+        } else if let Some(known) = library.find_by_url(&song.url)? {
+            let when = known
+                .downloaded_at
+                .map_or("before the library".to_string(), |at| at.format("%Y-%m-%d").to_string());
+            ctx.info(format!(
+                "⏭ Skipping {}: already downloaded as {} - {} ({})",
+                song, known.artist, known.title, when
+            ));
+            skipped.push(song);
+        } else {
+            kept.push(song);
+        }
+    }
+    Ok((kept, skipped))
+}
+
+/// Replace the playlists, channels and albums by the songs they list, with each of them.
+/// The ones that cannot be listed are returned with the error.
+async fn expand_playlists(
+    ctx: &Context,
+    downloaders: &Downloaders,
+    songs: Vec<Song>,
+) -> (Vec<Song>, Vec<(Song, Vec<Song>)>, Vec<(Song, DownloadError)>) {
+    let (mut expanded, mut playlists, mut fails) = (vec![], vec![], vec![]);
+    for song in songs {
+        match downloaders.expand(&song).await {
+            Ok(None) => expanded.push(song),
+            Ok(Some(entries)) => {
+                ctx.info(format!("📃 {} lists {} songs", song.url, entries.len()));
+                expanded.extend(entries.iter().cloned());
+                playlists.push((song, entries));
+            }
+            Err(e) => {
+                eprintln!("❌ Failed to list the songs of {}: {}", song.url, e);
+                fails.push((song, e));
+            }
+        }
+    }
+    (expanded, playlists, fails)
+}
+
+/// Ask the backend of the songs missing their artist or title for them. The songs come back
+/// with the tags found, along with the songs as listed before and after the change.
+async fn fill_missing_tags(
+    ctx: &Context,
+    downloaders: &Downloaders,
+    songs: Vec<Song>,
+) -> (Vec<Song>, Vec<(Song, Song)>) {
+    let (mut kept, mut filled) = (vec![], vec![]);
+    for song in songs {
+        if !youtube::metadata::is_incomplete(&song) {
+            kept.push(song);
+            continue;
+        }
+        match downloaders.metadata(&song).await {
+            Ok(Some(metadata)) => {
+                let mut tagged = song.clone();
+                youtube::metadata::fill(&mut tagged, &metadata);
+                if tagged != song {
+                    ctx.info(format!("🏷 {} is {} - {}", song.url, tagged.artist, tagged.name));
+                    filled.push((song, tagged.clone()));
+                }
+                kept.push(tagged);
+            }
+            Ok(None) => kept.push(song),
+            Err(e) => {
+                eprintln!("Warning: no tags found for {}: {}", song.url, e);
+                kept.push(song);
+            }
+        }
+    }
+    (kept, filled)
+}
+
+/// The cover of the downloaded song: its `cover` image, else the thumbnail downloaded with it.
+/// The thumbnails are removed, they are not songs to transfer.
+fn song_cover(song: &Song, download: &Download, songs_file: &Path) -> Option<Vec<u8>> {
+    let config = &config::get().download;
+    let thumbnails: Vec<_> = download.files.iter().filter_map(youtube::cover::thumbnail_of).collect();
+    let image = match &song.cover {
+        Some(cover) => Some(youtube::cover::local_image(cover, songs_file)),
+        None => thumbnails.first().cloned(),
+    };
+
+    let cover = image.filter(|_| config.covers).and_then(|image| {
+        youtube::cover::prepare(&image, config.cover_size)
+            .inspect_err(|e| eprintln!("Warning: no cover for {} from {}: {}", song.name, image.display(), e))
+            .ok()
+    });
+    for thumbnail in thumbnails {
+        if let Err(e) = fs::remove_file(&thumbnail) {
+            eprintln!("Warning: failed to remove {}: {}", thumbnail.display(), e);
+        }
+    }
+    cover
+}
+
+/// Measure the loudness of the file, then encode it again at the target loudness with
+/// `download.loudness = "normalize"`, or give the ReplayGain to tag it with for "replaygain"
+async fn adjust_loudness(path: &Path) -> Result<(Loudness, Option<ReplayGain>), LoudnessError> {
+    let config = &config::get().download;
+    let measured = loudness::measure(&config.ffmpeg_path, path, config.loudness_target).await?;
+    match config.loudness {
+        LoudnessMode::Normalize => {
+            loudness::normalize(&config.ffmpeg_path, path, &measured, config.loudness_target).await?;
+            Ok((measured, None))
+        }
+        LoudnessMode::Replaygain => Ok((measured, Some(measured.replay_gain(config.loudness_target)))),
+        LoudnessMode::Off => Ok((measured, None)),
+    }
+}
+
+/// Measure the loudness of the downloaded songs the library has no measure for, then tag or
+/// normalize them following `download.loudness`. The songs already moved to the device are left.
+pub async fn loudness(ctx: &Context) -> Result<(), CommandError> {
+    let library = super::open_library(ctx)?;
+    let pending: Vec<_> = library
+        .songs()?
+        .into_iter()
+        .filter(|song| song.loudness.is_none())
+        .filter_map(|song| Some((song.id, song.path.filter(|path| path.is_file())?)))
+        .collect();
+    if pending.is_empty() {
+        ctx.info("🔊 No song to measure");
+        return Ok(());
+    }
+
+    let mode = config::get().download.loudness;
+    let mut measured = 0;
+    for (id, path) in pending {
+        if ctx.dry_run {
+            ctx.would(format!("measure the loudness of {} ({:?})", path.display(), mode));
+            continue;
+        }
+
+        // Encoding again drops the cover, the tags read before are written back
+        let mut tags = match tagging::read(&path) {
+            Ok(tags) => tags,
+            Err(e) => {
+                eprintln!("Warning: failed to read the tags of {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let (loudness, replay_gain) = match adjust_loudness(&path).await {
+            Ok(adjusted) => adjusted,
+            Err(e) => {
+                eprintln!("Warning: failed to measure the loudness of {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if mode == LoudnessMode::Normalize || replay_gain.is_some() {
+            tags.replay_gain = replay_gain;
+            if let Err(e) = tagging::write(&path, &tags) {
+                eprintln!("Warning: failed to tag {}: {}", path.display(), e);
+            }
+        }
+
+        ctx.debug(format!(
+            "🔊 {}: {:.1} LUFS, true peak {:.1} dBTP",
+            path.display(),
+            loudness.integrated,
+            loudness.true_peak
+        ));
+        library.record_loudness(id, &loudness)?;
+        library.update_file(id, &path)?;
+        measured += 1;
+    }
+    if !ctx.dry_run {
+        ctx.info(format!("🔊 Loudness of {} songs measured", measured));
+    }
+    Ok(())
+}
+
+/// Remove the files the leftover batches of the past runs do not need anymore, and pick the one
+/// to resume: with `download.leftovers = "resume"`, the newest one holding some of the songs.
+/// The files of the library, waiting for the devices, are always kept.
+fn clean_leftovers(
+    ctx: &Context,
+    library: &Library,
+    songs: &[Song],
+    download_path: &Path,
+) -> Result<Option<Leftover>, CommandError> {
+    let waiting: HashSet<PathBuf> = library.waiting()?.into_iter().filter_map(|song| song.path).collect();
+    let urls: HashSet<&str> = songs.iter().map(|song| song.url.as_str()).collect();
+
+    let mut leftovers = staging::leftovers(download_path)?;
+    let resumed = match config::get().download.leftovers {
+        Leftovers::Resume => leftovers.iter().rposition(|leftover| urls.iter().any(|url| leftover.has_song(url))),
+        Leftovers::Clean => None,
+    };
+    for (index, leftover) in leftovers.iter().enumerate() {
+        let resumed_urls = if Some(index) == resumed { urls.clone() } else { HashSet::new() };
+        let garbage = leftover.garbage(&waiting, &resumed_urls)?;
+        if garbage.is_empty() {
+            continue;
+        }
+        if ctx.dry_run {
+            ctx.would(format!("remove {} leftover files of {}", garbage.len(), leftover.dir.display()));
+            continue;
+        }
+        for file in &garbage {
+            ctx.debug(format!("🧹 {}", file.display()));
+            fs::remove_file(file)?;
+        }
+        ctx.info(format!("🧹 {} leftover files of {} removed", garbage.len(), leftover.dir.display()));
+    }
+
+    let resumed = resumed.map(|index| leftovers.swap_remove(index));
+    match &resumed {
+        Some(leftover) if ctx.dry_run => ctx.would(format!("resume the downloads of {}", leftover.dir.display())),
+        Some(leftover) => ctx.info(format!("♻ Resuming the downloads of {}", leftover.dir.display())),
+        None => {}
+    }
+    Ok(resumed)
+}
+
+/// Record the downloaded songs with their file and loudness in the library. It comes before
+/// the songs file is rewritten: a song neither listed nor recorded would have its file cleaned
+/// up as a leftover by the next run
+fn record_downloads(
+    library: &Library,
+    downloads: &[(Song, Download)],
+    measures: &[Option<Loudness>],
+) -> Result<(), LibraryError> {
+    let downloaded_at = Utc::now();
+    for ((song, download), measured) in downloads.iter().zip(measures) {
+        let id = library.record_download(song, download, downloaded_at)?;
+        if let Some(measured) = measured {
+            library.record_loudness(id, measured)?;
+        }
+    }
+    Ok(())
+}
+
+/// What a download would do, told without running the backends nor writing anything: the
+/// playlists are not listed and the missing tags not looked up
+fn preview(ctx: &Context, songs: Vec<Song>, force: bool) -> Result<DownloadReport, CommandError> {
+    let config = config::get();
+    let songs_file = constants::youtube_songs_file();
+    let library = super::open_library(ctx)?;
+    let (songs, _) = skip_duplicates(ctx, &library, songs, force)?;
+    let download_path = constants::download_path();
+    clean_leftovers(ctx, &library, &songs, &download_path)?;
+
+    for song in &songs {
+        if youtube::url::is_collection(&song.url) {
+            let written = if config.download.persist_playlists {
+                format!(", writing them in {}", songs_file.display())
+            } else {
+                String::new()
+            };
+            ctx.would(format!("expand {} into the songs it lists{}", song.url, written));
+            ctx.would(format!("download them to {}", download_path.display()));
+            continue;
+        }
+        if youtube::metadata::is_incomplete(song) {
+            ctx.would(format!("look up the artist and title of {}", song.url));
+        }
+        ctx.would(format!("download {} to {}", song, download_path.display()));
+    }
+    Ok(DownloadReport::default())
+}
+
+/// Download every song of the songs file that is not in the library yet (all of them with
+/// `force`), remove the done ones from it and record the downloaded ones in the library
+pub async fn download(ctx: &Context, force: bool) -> Result<DownloadReport, CommandError> {
+    let songs_file = constants::youtube_songs_file();
+    // Reading a missing songs file creates it
+    let lines = if ctx.dry_run && !songs_file.is_file() {
+        vec![]
+    } else {
+        youtube::filesystem::read_songs(&songs_file)?
+    };
+    let list = youtube::list::parse(&lines);
+    for diagnostic in &list.diagnostics {
+        eprintln!("Warning: {} {}, the line is skipped", songs_file.display(), diagnostic);
+    }
+    if ctx.dry_run {
+        return preview(ctx, list.into_songs(), force);
+    }
+    let config = config::get();
+    let downloaders = Downloaders::from_config(config)?;
+    let (songs, mut playlists, unlisted) = expand_playlists(ctx, &downloaders, list.into_songs()).await;
+
+    // The listed songs take the place of their playlist, or it stays until they are all done
+    let persist = config.download.persist_playlists && !playlists.is_empty();
+    if persist {
+        youtube::filesystem::replace_songs(&playlists, &songs_file)?;
+        ctx.info(format!("📃 Songs of {} playlists written in {}", playlists.len(), songs_file.display()));
+    }
+
+    let library = library::open()?;
+    let (songs, skipped) = skip_duplicates(ctx, &library, songs, force)?;
+
+    // The tags found are written in the songs file, the song lines must match to be removed
+    let (songs, filled) = fill_missing_tags(ctx, &downloaders, songs).await;
+    if !filled.is_empty() {
+        let replacements: Vec<(Song, Vec<Song>)> =
+            filled.iter().map(|(listed, tagged)| (listed.clone(), vec![tagged.clone()])).collect();
+        youtube::filesystem::replace_songs(&replacements, &songs_file)?;
+    }
+    for entry in playlists.iter_mut().flat_map(|(_, entries)| entries) {
+        if let Some((_, tagged)) = filled.iter().find(|(listed, _)| listed == entry) {
+            *entry = tagged.clone();
+        }
+    }
+
+    let download_path = constants::download_path();
+    let resumed = clean_leftovers(ctx, &library, &songs, &download_path)?;
+
+    let limits = Limits::from_config(&config.download);
+    ctx.debug(format!(
+        "Downloading {} songs with {} workers ({} per host)",
+        songs.len(),
+        limits.workers,
+        limits.per_host
+    ));
+
+    // Every run downloads in its own batch, named before starting so that the names do not
+    // depend on which download ends first
+    fs::create_dir_all(&download_path)?;
+    let mut batch = match resumed {
+        Some(leftover) => Batch::resume(leftover)?,
+        None => Batch::create(&download_path)?,
+    };
+    staging::remove_finished(&download_path)?;
+    ctx.debug(format!("Staging the songs in {}", batch.dir().display()));
+    let template = Template::parse(&config.download.template).map_err(DownloadError::from)?;
+    let mut namer = batch.namer();
+    for song in library.waiting()? {
+        if let Some(path) = song.path.filter(|path| path.starts_with(&download_path)) {
+            namer.reserve(&staging::relative(&download_path, &path).with_extension(""));
+        }
+    }
+    let songs: Vec<(Song, PathBuf)> = songs
+        .into_iter()
+        .map(|song| {
+            let output = batch.output(&mut namer, &template, &song);
+            (song, output)
+        })
+        .collect();
+    batch.save()?;
+
+    let mut scheduler =
+        Scheduler::new(downloaders, limits).with_retry(RetryPolicy::from_config(&config.download));
+
+    // Progress bars unless --quiet, then only the failures are printed
+    let mut renderer = None;
+    if ctx.verbosity >= 0 {
+        let (sender, handle) = progress::spawn(ctx.verbosity >= 1);
+        scheduler = scheduler.with_progress(sender);
+        renderer = Some(handle);
+    }
+
+    // Ctrl-C stops the queue, the unfinished songs go back to the songs file
+    let cancel = async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Cancelling, unfinished songs are kept for next time...");
+        } else {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    let schedule = scheduler
+        .run(songs, cancel, |song, result| {
+            if let (None, Err(e)) = (&renderer, result) {
+                eprintln!("❌ Failed to download {}: {}", song.name, e);
+            }
+        })
+        .await;
+
+    // Dropping the scheduler closes the progress channel
+    drop(scheduler);
+    if let Some(renderer) = renderer {
+        _ = renderer.await;
+    }
+
+    // An untagged or unmeasured song is still a downloaded song
+    let mut measures = vec![];
+    for (song, download) in &schedule.success {
+        let (measured, replay_gain) = match download.files.first() {
+            Some(file) if config.download.loudness != LoudnessMode::Off => match adjust_loudness(file).await {
+                Ok((measured, replay_gain)) => (Some(measured), replay_gain),
+                Err(e) => {
+                    eprintln!("Warning: failed to adjust the loudness of {}: {}", song.name, e);
+                    (None, None)
+                }
+            },
+            _ => (None, None),
+        };
+        measures.push(measured);
+
+        let cover = song_cover(song, download, &songs_file);
+        if let Err(e) = tagging::tag_download(song, download, cover, replay_gain) {
+            eprintln!("Warning: failed to tag {}: {}", song.name, e);
+        }
+    }
+
+    let mut report = DownloadReport {
+        success: schedule.success.iter().map(|(song, _)| song.clone()).collect(),
+        skipped,
+        ..Default::default()
+    };
+    for (song, e) in schedule.fails.into_iter().chain(unlisted) {
+        match e.kind() {
+            FailureKind::Permanent => report.dead.push((song, e.to_string())),
+            FailureKind::Transient | FailureKind::Environment => report.fails.push(song),
+        }
+    }
+    if !schedule.cancelled.is_empty() {
+        ctx.info(format!("⏹ {} songs not downloaded", schedule.cancelled.len()));
+        report.fails.extend(schedule.cancelled);
+    }
+
+    record_downloads(&library, &schedule.success, &measures)?;
+    ctx.info(format!(
+        "📚 {} songs added to {}",
+        report.success.len(),
+        constants::library_path().display()
+    ));
+
+    // Remove the done songs from the songs file, the failed ones stay for next time
+    let mut done: Vec<Song> = report
+        .success
+        .iter()
+        .chain(report.dead.iter().map(|(song, _)| song))
+        .chain(&report.skipped)
+        .cloned()
+        .collect();
+    if !persist {
+        for (playlist, entries) in playlists {
+            if !entries.iter().any(|entry| report.fails.contains(entry)) {
+                done.push(playlist);
+            }
+        }
+    }
+    youtube::filesystem::remove_songs(&done, &songs_file)?;
+    ctx.info("✅ Files saved!");
+
+    // Songs that will never download are kept aside with the reason
+    if !report.dead.is_empty() {
+        let dead_letters_path = constants::dead_letters_path();
+        youtube::filesystem::add_dead_letters(&report.dead, &dead_letters_path)?;
+        ctx.info(format!(
+            "🪦 {} songs moved to {}",
+            report.dead.len(),
+            dead_letters_path.display()
+        ));
+    }
+
+    if !report.skipped.is_empty() {
+        ctx.info(format!(
+            "⏭ {} duplicate songs skipped, --force downloads them again",
+            report.skipped.len()
+        ));
+    }
+
+    super::device::archive_downloads(ctx, &library)?;
+    // The files of the failed songs stay for the next run to resume
+    batch.close()?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::downloader::SongMetadata;
+    use std::env;
+
+    #[test]
+    fn a_song_failing_to_record_keeps_the_downloaded_files() {
+        let download_path = env::temp_dir().join("monsieur_dlp_record_downloads");
+        _ = fs::remove_dir_all(&download_path);
+        let mut batch = Batch::create(&download_path).unwrap();
+        let mut namer = batch.namer();
+        let songs = [
+            Song::new("https://example.com/a.mp3".into(), "Band".into(), "A".into()),
+            Song::new("https://example.com/b.mp3".into(), "Band".into(), "B".into()),
+        ];
+        let downloads: Vec<(Song, Download)> = songs
+            .iter()
+            .map(|song| {
+                let file = batch.output(&mut namer, &Template::default(), song).with_extension("mp3");
+                let download = Download { files: vec![file], metadata: SongMetadata::default(), profile: None };
+                (song.clone(), download)
+            })
+            .collect();
+        batch.save().unwrap();
+        // B vanished before its checksum was taken
+        fs::write(&downloads[0].1.files[0], b"A").unwrap();
+        let library = Library::open_in_memory().unwrap();
+
+        assert!(record_downloads(&library, &downloads, &[None, None]).is_err());
+        drop(batch);
+
+        // The songs file was not rewritten, the next run finds both songs in it
+        let resumed = clean_leftovers(&Context::default(), &library, &songs, &download_path).unwrap();
+        assert!(resumed.is_some());
+        assert!(downloads[0].1.files[0].is_file());
+        fs::remove_dir_all(download_path).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::cli::{Cli, Command, GlobalArgs};
+use crate::common::config::{Config, ConfigError, Transport};
+use crate::ios::filesystem::FileSystemError;
+use crate::ios::house_arrest::HouseArrestError;
+use crate::ios::mounting::MountingError;
+use crate::ios::pairing::PairingError;
+use crate::ios::service::UsbMuxdError;
+use crate::library::{self, archive, Library, LibraryError};
+use crate::youtube::downloader::DownloadError;
+use crate::youtube::import::ImportError;
+use crate::youtube::{naming, profile};
+
+pub mod device;
+pub mod download;
+pub mod progress;
+pub mod songs;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("Files writting failed unexpectedly: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Download failed ❌: {0}")]
+    Download(#[from] DownloadError),
+
+    #[error("Failed to check usbmuxd status: {0}")]
+    UsbMuxd(#[from] UsbMuxdError),
+
+    #[error("Pairing failed ❌: {0}")]
+    Pairing(#[from] PairingError),
+
+    #[error("Mounting failed ❌: {0}")]
+    Mounting(#[from] MountingError),
+
+    #[error("Opening the app Documents failed ❌: {0}")]
+    HouseArrest(#[from] HouseArrestError),
+
+    #[error("Moving songs failed ❌: {0}")]
+    FileSystem(#[from] FileSystemError),
+
+    #[error("Library update failed ❌: {0}")]
+    Library(#[from] LibraryError),
+
+    #[error("Import failed ❌: {0}")]
+    Import(#[from] ImportError),
+}
+
+/// Options shared by every command
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// -1 when quiet, 0 by default, then one more per -v
+    pub verbosity: i8,
+    pub dry_run: bool,
+    /// The `--device` selector, every connected device when None
+    pub device: Option<String>,
+}
+
+impl Context {
+    pub fn new(args: &GlobalArgs) -> Self {
+        let verbosity = if args.quiet {
+            -1
+        } else {
+            args.verbose.min(i8::MAX as u8) as i8
+        };
+
+        Self {
+            verbosity,
+            dry_run: args.dry_run,
+            device: args.device.clone(),
+        }
+    }
+
+    /// Print a progress message unless --quiet
+    pub fn info(&self, message: impl AsRef<str>) {
+        if self.verbosity >= 0 {
+            println!("{}", message.as_ref());
+        }
+    }
+
+    /// Print a detail only shown with -v
+    pub fn debug(&self, message: impl AsRef<str>) {
+        if self.verbosity >= 1 {
+            println!("{}", message.as_ref());
+        }
+    }
+
+    /// Print what a dry-run would have done
+    pub fn would(&self, message: impl AsRef<str>) {
+        println!("[dry-run] Would {}", message.as_ref());
+    }
+}
+
+/// The library, only copied in memory with --dry-run so that nothing is written
+pub fn open_library(ctx: &Context) -> Result<Library, LibraryError> {
+    if ctx.dry_run { library::open_copy() } else { library::open() }
+}
+
+/// Check the config values only the modules using them know, after `config::init`
+pub fn validate_config(config: &Config) -> Result<(), ConfigError> {
+    profile::validate_config(config)?;
+    naming::validate_config(config)?;
+    archive::validate_config(config)
+}
+
+/// Run the command line, the whole pipeline when no subcommand is given
+pub async fn run(cli: Cli) -> Result<(), CommandError> {
+    let ctx = Context::new(&cli.global);
+
+    let pipeline = Command::Sync {
+        force: false,
+        differential: false,
+        prune: false,
+    };
+    match cli.command.unwrap_or(pipeline) {
+        Command::Download { force } => download::download(&ctx, force).await.map(|_| ()),
+        Command::Sync { force, differential, prune } => sync(&ctx, force, differential, prune).await,
+        Command::Loudness => download::loudness(&ctx).await,
+        Command::Pair => device::pair(&ctx).await,
+        Command::Mount => device::mount(&ctx).await,
+        Command::Unmount => device::unmount(&ctx).await,
+        Command::Devices => device::devices(&ctx).await,
+        Command::Status => device::status(&ctx).await,
+        Command::History { limit } => songs::history(&ctx, limit),
+        Command::Add { url, artist, name } => songs::add(&ctx, url, artist, name),
+        Command::Import { file, format, columns, force } => songs::import(&ctx, &file, format, &columns, force),
+    }
+}
+
+/// download → history → pair → validate → move, mounting around the move with ifuse.
+/// The differential sync compares the devices with the library in place of the move.
+pub async fn sync(ctx: &Context, force: bool, differential: bool, prune: bool) -> Result<(), CommandError> {
+    download::download(ctx, force).await?;
+    device::pair(ctx).await?;
+    let ifuse = crate::common::config::get().device.transport == Transport::Ifuse;
+    let mounted = if ifuse { device::mount(ctx).await } else { Ok(()) };
+    let transfer = match mounted {
+        Ok(()) if differential => device::sync_songs(ctx, prune).await,
+        Ok(()) => device::move_songs(ctx).await,
+        Err(e) => Err(e),
+    };
+
+    // Even a failed mount may have mounted some devices. The error of the transfer is
+    // the one returned, a failed unmount is only told along with it.
+    if ifuse && let Err(e) = device::unmount(ctx).await {
+        if transfer.is_ok() {
+            return Err(e);
+        }
+        eprintln!("Warning: the devices may still be mounted, {}", e);
+    }
+    transfer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::ConfigSource;
+
+    #[test]
+    fn validate_config_points_at_the_values_that_do_not_parse() {
+        assert!(validate_config(&Config::default()).is_ok());
+
+        for (name, value, expected) in [
+            ("MONSIEUR_DLP_DOWNLOAD__PROFILE", "wav", "download.profile"),
+            ("MONSIEUR_DLP_DOWNLOAD__TEMPLATE", "{artist}/{ext}", "download.template"),
+            ("MONSIEUR_DLP_DOWNLOAD__ARCHIVE_TEMPLATE", "{album}/{ext}", "download.archive_template"),
+        ] {
+            let config = Config::load_from(&[], vec![(name.to_string(), value.to_string())]).unwrap();
+            match validate_config(&config) {
+                Err(ConfigError::Invalid { key, origin, .. }) => {
+                    assert_eq!(key, expected);
+                    assert_eq!(origin, ConfigSource::Env(name.to_string()));
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::youtube::progress::{DownloadProgress, ProgressEvent, ProgressKind};
+
+const SONG_TEMPLATE: &str = "{prefix:30!} {bar:25.cyan/blue} {percent:>3}% {msg}";
+const TOTAL_TEMPLATE: &str = "{prefix:30!} {bar:25.green/white} {pos}/{len} songs {msg}";
+
+/// One progress bar per running song plus the overall total, drawn on stderr
+struct Renderer {
+    multi: MultiProgress,
+    total: ProgressBar,
+    bars: HashMap<usize, ProgressBar>,
+    names: HashMap<usize, String>,
+    downloaded: HashMap<usize, u64>,
+    verbose: bool,
+}
+
+/// Start rendering the events sent on the returned channel, the task ends
+/// once every sender is dropped
+pub fn spawn(verbose: bool) -> (UnboundedSender<ProgressEvent>, JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let handle = tokio::spawn(async move {
+        let mut renderer = Renderer::new(verbose);
+        while let Some(event) = receiver.recv().await {
+            renderer.handle(event);
+        }
+        renderer.finish();
+    });
+
+    (sender, handle)
+}
+
+impl Renderer {
+    fn new(verbose: bool) -> Self {
+        let multi = MultiProgress::new();
+        let total = multi.add(ProgressBar::new(0));
+        total.set_style(style(TOTAL_TEMPLATE));
+        total.set_prefix("Total");
+
+        Self {
+            multi,
+            total,
+            bars: HashMap::new(),
+            names: HashMap::new(),
+            downloaded: HashMap::new(),
+            verbose,
+        }
+    }
+
+    fn handle(&mut self, event: ProgressEvent) {
+        let id = event.id;
+
+        match event.kind {
+            ProgressKind::Queued { name } => {
+                self.names.insert(id, name);
+                self.total.inc_length(1);
+            }
+            ProgressKind::Started => {
+                let bar = self.multi.insert_before(&self.total, ProgressBar::new(0));
+                bar.set_style(style(SONG_TEMPLATE));
+                bar.set_prefix(self.name(id).to_string());
+                bar.set_message("starting");
+                self.bars.insert(id, bar);
+            }
+            ProgressKind::Downloading(progress) => {
+                self.downloaded.insert(id, progress.downloaded_bytes);
+                if let Some(bar) = self.bars.get(&id) {
+                    bar.set_length(progress.total_bytes.unwrap_or(0));
+                    bar.set_position(progress.downloaded_bytes);
+                    bar.set_message(describe(&progress));
+                }
+                let downloaded: u64 = self.downloaded.values().sum();
+                self.total.set_message(format!("{} downloaded", HumanBytes(downloaded)));
+            }
+            ProgressKind::PostProcessing { stage } => {
+                if let Some(bar) = self.bars.get(&id) {
+                    bar.set_message(stage);
+                }
+            }
+            ProgressKind::Retrying {
+                attempt,
+                delay,
+                reason,
+            } => {
+                self.println(format!(
+                    "↻ Retrying {} in {} (attempt {}): {}",
+                    self.name(id),
+                    HumanDuration(delay),
+                    attempt,
+                    reason
+                ));
+                if let Some(bar) = self.bars.get(&id) {
+                    bar.set_message(format!("retrying in {}", HumanDuration(delay)));
+                }
+            }
+            ProgressKind::Message(line) => {
+                if self.verbose {
+                    self.println(format!("{}: {}", self.name(id), line));
+                }
+            }
+            ProgressKind::Finished => {
+                self.println(format!("✅ Downloaded: {}", self.name(id)));
+                self.end(id);
+            }
+            ProgressKind::Failed { reason } => {
+                self.println(format!("❌ Failed to download {}: {}", self.name(id), reason));
+                self.end(id);
+            }
+        }
+    }
+
+    fn name(&self, id: usize) -> &str {
+        self.names.get(&id).map(String::as_str).unwrap_or_default()
+    }
+
+    fn end(&mut self, id: usize) {
+        if let Some(bar) = self.bars.remove(&id) {
+            bar.finish_and_clear();
+            self.multi.remove(&bar);
+        }
+        self.total.inc(1);
+    }
+
+    /// Print above the bars, or straight to stdout when they are hidden (not a terminal)
+    fn println(&self, line: String) {
+        if self.multi.is_hidden() {
+            println!("{}", line);
+        } else {
+            _ = self.multi.println(line);
+        }
+    }
+
+    fn finish(self) {
+        for bar in self.bars.values() {
+            bar.finish_and_clear();
+        }
+        self.total.finish();
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+}
+
+/// "3.00 MiB/4.00 MiB at 512.00 KiB/s, 6 seconds left"
+fn describe(progress: &DownloadProgress) -> String {
+    let mut description = HumanBytes(progress.downloaded_bytes).to_string();
+    if let Some(total) = progress.total_bytes {
+        description.push_str(&format!("/{}", HumanBytes(total)));
+    }
+    if let Some(speed) = progress.speed {
+        description.push_str(&format!(" at {}/s", HumanBytes(speed as u64)));
+    }
+    if let Some(eta) = progress.eta {
+        description.push_str(&format!(", {} left", HumanDuration(eta)));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn describe_skips_unknown_values() {
+        let progress = DownloadProgress::new(3 * 1024 * 1024, None, None, None);
+        assert_eq!(describe(&progress), "3.00 MiB");
+
+        let progress = DownloadProgress::new(
+            3 * 1024 * 1024,
+            Some(4 * 1024 * 1024),
+            Some(512.0 * 1024.0),
+            Some(Duration::from_secs(6)),
+        );
+        assert_eq!(
+            describe(&progress),
+            "3.00 MiB/4.00 MiB at 512.00 KiB/s, 6 seconds left"
+        );
+    }
+}
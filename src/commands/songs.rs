@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use super::{CommandError, Context};
+use crate::common::constants;
+use crate::youtube::import::{Columns, Format};
+use crate::youtube::{self, Song};
+
+/// Print the songs of the library with the devices they are on, the last `limit` ones if given
+pub fn history(ctx: &Context, limit: Option<usize>) -> Result<(), CommandError> {
+    let library = super::open_library(ctx)?;
+    let songs = library.songs()?;
+    if songs.is_empty() {
+        ctx.info(format!("No history yet ({})", constants::library_path().display()));
+        return Ok(());
+    }
+
+    let skip = limit.map_or(0, |limit| songs.len().saturating_sub(limit));
+    for song in songs.iter().skip(skip) {
+        let downloaded_at = song
+            .downloaded_at
+            .map_or("imported".to_string(), |at| at.format("%Y-%m-%d %H:%M").to_string());
+        println!("{} - {} ({}) [{}]", song.artist, song.title, song.url, downloaded_at);
+
+        if ctx.verbosity >= 1 {
+            if let Some(path) = &song.path {
+                println!("  file: {}", path.display());
+            }
+            if let Some(loudness) = &song.loudness {
+                println!("  loudness: {:.1} LUFS, true peak {:.1} dBTP", loudness.integrated, loudness.true_peak);
+            }
+            for transfer in library.transfers(song.id)? {
+                println!("  {}: {}", transfer.device_udid, transfer.state);
+            }
+        }
+    }
+    ctx.debug(format!("{} songs in {}", songs.len(), constants::library_path().display()));
+    Ok(())
+}
+
+/// Append a song to the songs file
+pub fn add(ctx: &Context, url: String, artist: String, name: String) -> Result<(), CommandError> {
+    let song = Song::new(url, artist, name);
+    let songs_file = constants::youtube_songs_file();
+
+    if ctx.dry_run {
+        ctx.would(format!("add {} to {}", song, songs_file.display()));
+        return Ok(());
+    }
+
+    youtube::filesystem::append_songs(std::slice::from_ref(&song), &songs_file)?;
+    ctx.info(format!("✅ Added {} to {}", song, songs_file.display()));
+    Ok(())
+}
+
+/// Append the songs of a CSV, JSON or M3U file to the songs file. The songs already in the
+/// songs file, in the library or earlier in the import are left out unless `force`.
+pub fn import(
+    ctx: &Context,
+    file: &Path,
+    format: Option<Format>,
+    columns: &[String],
+    force: bool,
+) -> Result<(), CommandError> {
+    let imported = youtube::import::import(file, format, &Columns::parse(columns)?)?;
+    for message in &imported.skipped {
+        eprintln!("Warning: {} {}, the entry is skipped", file.display(), message);
+    }
+
+    let songs_file = constants::youtube_songs_file();
+    let mut seen = HashSet::new();
+    if !force && songs_file.exists() {
+        let list = youtube::list::parse(&youtube::filesystem::read_songs(&songs_file)?);
+        seen.extend(list.into_songs().iter().map(|song| youtube::url::canonical_url(&song.url)));
+    }
+    let library = super::open_library(ctx)?;
+
+    let mut songs = vec![];
+    let mut duplicates = 0;
+    for song in imported.songs {
+        if !force {
+            if !seen.insert(youtube::url::canonical_url(&song.url)) {
+                ctx.info(format!("⏭ Skipping {}: already in the songs file or the import", song));
+                duplicates += 1;
+                continue;
+            }
+            if let Some(known) = library.find_by_url(&song.url)? {
+                ctx.info(format!(
+                    "⏭ Skipping {}: already downloaded as {} - {}",
+                    song, known.artist, known.title
+                ));
+                duplicates += 1;
+                continue;
+            }
+        }
+        songs.push(song);
+    }
+
+    if ctx.dry_run {
+        for song in &songs {
+            ctx.would(format!("add {} to {}", song, songs_file.display()));
+        }
+        return Ok(());
+    }
+
+    youtube::filesystem::append_songs(&songs, &songs_file)?;
+    ctx.info(format!("✅ Imported {} songs to {}", songs.len(), songs_file.display()));
+    if duplicates > 0 {
+        ctx.info(format!("⏭ {} duplicate songs skipped, --force imports them anyway", duplicates));
+    }
+    Ok(())
+}
@@ -0,0 +1,904 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::constants::{self, convert_path_string_to_pathbuf};
+
+/// Directory name used under the XDG config directories
+pub const CONFIG_DIR_NAME: &str = "monsieur_dlp";
+/// File name looked up in every XDG config directory
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+/// Per-project override, looked up in the current working directory
+pub const PROJECT_CONFIG_FILE_NAME: &str = "monsieur_dlp.toml";
+/// Prefix of the environment variables overriding config keys,
+/// e.g. `MONSIEUR_DLP_PATHS__DOWNLOAD_PATH` overrides `paths.download_path`.
+/// The keys of a table such as `download.hosts` cannot be named by a variable, the whole
+/// table is set as an inline TOML table: `MONSIEUR_DLP_DOWNLOAD__HOSTS='{ "youtube.com" = 1 }'`
+pub const ENV_PREFIX: &str = "MONSIEUR_DLP_";
+/// Environment variable pointing at an explicit config file
+pub const ENV_CONFIG_PATH: &str = "MONSIEUR_DLP_CONFIG";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file '{path}': {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Invalid config file '{path}': {source}")]
+    Parse {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+
+    #[error("Invalid value for '{key}' (set by {origin}): {message}")]
+    Invalid {
+        key: String,
+        origin: ConfigSource,
+        message: String,
+    },
+}
+
+/// Where a config value comes from, used to point validation errors at the right place
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(String),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "built-in defaults"),
+            ConfigSource::File(path) => write!(f, "file '{}'", path.display()),
+            ConfigSource::Env(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub paths: PathsConfig,
+    pub device: DeviceConfig,
+    pub download: DownloadConfig,
+    /// Which layer set every dotted key, the others come from the defaults
+    #[serde(skip)]
+    origins: BTreeMap<String, ConfigSource>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    /// The songs waiting to be downloaded
+    pub youtube_songs_file: PathBuf,
+    /// The songs downloaded before the library existed, imported into it once
+    pub youtube_songs_historic_path: PathBuf,
+    /// The SQLite database of the downloaded songs and their transfers
+    pub library_path: PathBuf,
+    /// The songs that can never be downloaded, with the reason
+    pub dead_letters_path: PathBuf,
+    /// Where yt-dlp downloads the songs
+    pub download_path: PathBuf,
+    /// The mounting path for the ios device
+    pub mounting_path: PathBuf,
+    /// The library of artist and album folders keeping the songs in archive mode
+    pub archive_path: PathBuf,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        Self {
+            youtube_songs_file: PathBuf::from(constants::DEFAULT_YOUTUBE_SONGS_FILE),
+            youtube_songs_historic_path: PathBuf::from(constants::DEFAULT_YOUTUBE_SONGS_HISTORIC_PATH),
+            dead_letters_path: PathBuf::from(constants::DEFAULT_DEAD_LETTERS_PATH),
+            library_path: PathBuf::from(constants::DEFAULT_LIBRARY_PATH),
+            download_path: PathBuf::from(constants::DEFAULT_DOWNLOAD_PATH),
+            mounting_path: PathBuf::from(constants::DEFAULT_MOUNTING_PATH),
+            archive_path: PathBuf::from(constants::DEFAULT_ARCHIVE_PATH),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Bundle id of the app whose Documents container receives the songs
+    pub app_bundle_id: String,
+    /// The usbmuxd daemon socket
+    pub usbmuxd_socket: PathBuf,
+    /// How long to wait for the user to trust the computer on the device
+    pub pair_timeout_secs: u64,
+    /// How the songs reach the device
+    pub transport: Transport,
+    /// Room left free on the device after a transfer, in megabytes
+    pub free_space_margin_mb: u64,
+    /// What happens when the songs do not fit on a device
+    pub when_full: WhenFull,
+}
+
+/// Values of `device.transport`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    /// Talk to the device with AFC through usbmuxd
+    #[default]
+    Afc,
+    /// Copy to the app Documents mounted by ifuse
+    Ifuse,
+}
+
+/// Values of `device.when_full`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhenFull {
+    /// Skip the device when its songs do not fit
+    #[default]
+    Abort,
+    /// Send the songs of highest priority that fit
+    Fit,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            app_bundle_id: constants::DEFAULT_APP_BUNDLE_ID.to_string(),
+            usbmuxd_socket: PathBuf::from(constants::DEFAULT_USBMUXD_SOCKET),
+            pair_timeout_secs: 60,
+            transport: Transport::default(),
+            free_space_margin_mb: 200,
+            when_full: WhenFull::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    /// Backend used for the songs it supports, the others fall back to any backend supporting them
+    pub backend: Backend,
+    /// The yt-dlp executable name or path
+    pub yt_dlp_path: String,
+    /// Downloads running at the same time
+    pub workers: usize,
+    /// Downloads running at the same time on one host
+    pub per_host: usize,
+    /// Per-host overrides of `per_host`, e.g. `"youtube.com" = 1`
+    pub hosts: BTreeMap<String, usize>,
+    /// Retries of a download failing with a network error
+    pub retries: u32,
+    /// Wait before the first retry in milliseconds, doubled for every next one
+    pub backoff_ms: u64,
+    /// Upper bound of the wait between two retries in milliseconds
+    pub backoff_max_ms: u64,
+    /// Write the songs of the playlists, channels and albums in the songs file in place of
+    /// their url, rather than listing them again on every run
+    pub persist_playlists: bool,
+    /// Output profile of the songs without a `profile` option, see `youtube::profile`
+    pub profile: String,
+    /// Path of the song files in the download path and on the device, see `youtube::naming`
+    pub template: String,
+    /// What happens to the partial downloads of a crashed or cancelled run
+    pub leftovers: Leftovers,
+    /// Embed the video thumbnail, or the `cover` image of the song, as the cover of the file
+    pub covers: bool,
+    /// Side in pixels of the square covers
+    pub cover_size: u32,
+    /// What is done with the loudness of the songs
+    pub loudness: LoudnessMode,
+    /// Loudness the songs are brought to, in LUFS
+    pub loudness_target: f64,
+    /// The ffmpeg executable name or path, measuring the loudness
+    pub ffmpeg_path: String,
+    /// Keep the songs in `paths.archive_path` and only copy them to the devices, rather than
+    /// deleting them once every device has them
+    pub archive: bool,
+    /// Path of the song files in the archive, see `youtube::naming`
+    pub archive_template: String,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            yt_dlp_path: "yt-dlp".to_string(),
+            workers: 4,
+            per_host: 2,
+            hosts: BTreeMap::new(),
+            retries: 3,
+            backoff_ms: 2_000,
+            backoff_max_ms: 60_000,
+            persist_playlists: false,
+            profile: constants::DEFAULT_PROFILE.to_string(),
+            template: constants::DEFAULT_TEMPLATE.to_string(),
+            leftovers: Leftovers::default(),
+            covers: true,
+            cover_size: 600,
+            loudness: LoudnessMode::default(),
+            // The ReplayGain 2.0 reference
+            loudness_target: -18.0,
+            ffmpeg_path: "ffmpeg".to_string(),
+            archive: false,
+            archive_template: constants::DEFAULT_ARCHIVE_TEMPLATE.to_string(),
+        }
+    }
+}
+
+/// Values of `download.backend`, see `youtube::downloader`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    #[default]
+    YtDlp,
+    /// Import `file://` urls and plain paths
+    Local,
+}
+
+/// Values of `download.leftovers`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Leftovers {
+    /// Go on with the partial downloads when their songs are downloaded again
+    #[default]
+    Resume,
+    /// Remove them
+    Clean,
+}
+
+/// Values of `download.loudness`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoudnessMode {
+    #[default]
+    Off,
+    /// Tag the songs with the gain reaching `download.loudness_target`
+    Replaygain,
+    /// Encode the songs again at `download.loudness_target`
+    Normalize,
+}
+
+/// One parsed config layer (a file or an environment variable)
+struct Layer {
+    source: ConfigSource,
+    table: toml::Table,
+}
+
+impl Config {
+    /// Load the config from every layer, lowest priority first:
+    /// XDG_CONFIG_DIRS, XDG_CONFIG_HOME, ./monsieur_dlp.toml, the explicit file
+    /// (`explicit` or $MONSIEUR_DLP_CONFIG) and finally the MONSIEUR_DLP_* variables.
+    pub fn load(explicit: Option<&Path>) -> Result<Self, ConfigError> {
+        let explicit = explicit
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os(ENV_CONFIG_PATH).map(PathBuf::from));
+
+        let mut files: Vec<PathBuf> = config_file_candidates()
+            .into_iter()
+            .filter(|path| path.is_file())
+            .collect();
+
+        if let Some(path) = explicit {
+            // An explicitly requested file has to exist
+            if !path.is_file() {
+                return Err(ConfigError::Io {
+                    path,
+                    source: io::Error::new(io::ErrorKind::NotFound, "config file not found"),
+                });
+            }
+            files.push(path);
+        }
+
+        Self::load_from(&files, env::vars())
+    }
+
+    /// Load the config from the given files (lowest priority first) and environment variables
+    pub fn load_from<I>(files: &[PathBuf], vars: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut layers = Vec::new();
+
+        for path in files {
+            layers.push(read_file_layer(path)?);
+        }
+
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != ENV_CONFIG_PATH)
+            .collect();
+        vars.sort();
+
+        for (name, value) in vars {
+            layers.push(env_layer(&name, &value)?);
+        }
+
+        Self::from_layers(layers)
+    }
+
+    fn from_layers(layers: Vec<Layer>) -> Result<Self, ConfigError> {
+        let mut merged = toml::Table::new();
+        let mut origins = BTreeMap::new();
+
+        for layer in layers {
+            merge_tables(&mut merged, layer.table, "", &layer.source, &mut origins);
+        }
+
+        let config: Config = merged.try_into().map_err(|e: toml::de::Error| ConfigError::Invalid {
+            key: String::new(),
+            origin: ConfigSource::Default,
+            message: e.message().to_string(),
+        })?;
+
+        let mut config = config.expand_home();
+        config.origins = origins;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The layer that set `key`
+    pub fn origin(&self, key: &str) -> ConfigSource {
+        self.origins.get(key).cloned().unwrap_or(ConfigSource::Default)
+    }
+
+    /// The error of an invalid `key`, pointing at the layer that set it. The modules using
+    /// a value check it themselves, see `commands::validate_config`
+    pub fn invalid(&self, key: &str, message: &str) -> ConfigError {
+        ConfigError::Invalid {
+            key: key.to_string(),
+            origin: self.origin(key),
+            message: message.to_string(),
+        }
+    }
+
+    /// Convert the home character (~) of every path to the actual emplacement
+    fn expand_home(mut self) -> Self {
+        let paths = &mut self.paths;
+        for path in [
+            &mut paths.youtube_songs_file,
+            &mut paths.youtube_songs_historic_path,
+            &mut paths.dead_letters_path,
+            &mut paths.library_path,
+            &mut paths.download_path,
+            &mut paths.mounting_path,
+            &mut paths.archive_path,
+        ] {
+            if let Some(s) = path.to_str() {
+                *path = convert_path_string_to_pathbuf(s);
+            }
+        }
+        self
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| self.invalid(key, message);
+
+        let paths = &self.paths;
+        for (key, path) in [
+            ("paths.youtube_songs_file", &paths.youtube_songs_file),
+            ("paths.youtube_songs_historic_path", &paths.youtube_songs_historic_path),
+            ("paths.dead_letters_path", &paths.dead_letters_path),
+            ("paths.library_path", &paths.library_path),
+            ("paths.download_path", &paths.download_path),
+            ("paths.mounting_path", &paths.mounting_path),
+            ("paths.archive_path", &paths.archive_path),
+            ("device.usbmuxd_socket", &self.device.usbmuxd_socket),
+        ] {
+            if path.as_os_str().is_empty() {
+                return Err(invalid(key, "path must not be empty"));
+            }
+        }
+
+        if paths.youtube_songs_file == paths.youtube_songs_historic_path {
+            return Err(invalid(
+                "paths.youtube_songs_historic_path",
+                "must differ from paths.youtube_songs_file",
+            ));
+        }
+
+        if paths.dead_letters_path == paths.youtube_songs_file
+            || paths.dead_letters_path == paths.youtube_songs_historic_path
+        {
+            return Err(invalid(
+                "paths.dead_letters_path",
+                "must differ from the songs and historic files",
+            ));
+        }
+
+        if [&paths.youtube_songs_file, &paths.youtube_songs_historic_path, &paths.dead_letters_path]
+            .contains(&&paths.library_path)
+        {
+            return Err(invalid(
+                "paths.library_path",
+                "must differ from the songs, historic and dead letters files",
+            ));
+        }
+
+        if paths.download_path == paths.mounting_path {
+            return Err(invalid(
+                "paths.mounting_path",
+                "must differ from paths.download_path",
+            ));
+        }
+
+        // The transfers delete the files they find in the download path
+        if paths.archive_path.starts_with(&paths.download_path) || paths.download_path.starts_with(&paths.archive_path) {
+            return Err(invalid(
+                "paths.archive_path",
+                "must not be inside paths.download_path nor contain it",
+            ));
+        }
+
+        if !is_bundle_id(&self.device.app_bundle_id) {
+            return Err(invalid(
+                "device.app_bundle_id",
+                "expected a reverse-DNS bundle identifier such as 'org.videolan.vlc-ios'",
+            ));
+        }
+
+        if self.download.yt_dlp_path.is_empty() {
+            return Err(invalid("download.yt_dlp_path", "must not be empty"));
+        }
+
+        if self.download.workers == 0 {
+            return Err(invalid("download.workers", "must be at least 1"));
+        }
+
+        if self.download.per_host == 0 {
+            return Err(invalid("download.per_host", "must be at least 1"));
+        }
+
+        for (host, limit) in &self.download.hosts {
+            if *limit == 0 {
+                let key = format!("download.hosts.{}", host);
+                return Err(invalid(&key, "must be at least 1"));
+            }
+        }
+
+        if self.download.cover_size == 0 {
+            return Err(invalid("download.cover_size", "must be at least 1"));
+        }
+
+        if !(-70.0..=0.0).contains(&self.download.loudness_target) {
+            return Err(invalid("download.loudness_target", "must be between -70 and 0 LUFS"));
+        }
+
+        if self.download.ffmpeg_path.is_empty() {
+            return Err(invalid("download.ffmpeg_path", "must not be empty"));
+        }
+
+        if self.download.backoff_max_ms < self.download.backoff_ms {
+            return Err(invalid(
+                "download.backoff_max_ms",
+                "must be greater than or equal to download.backoff_ms",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Load the config once for the whole process, `explicit` is the file given on the command line
+pub fn init(explicit: Option<&Path>) -> Result<&'static Config, ConfigError> {
+    let config = Config::load(explicit)?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The process config, loaded from the default locations if `init` was not called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        Config::load(None).unwrap_or_else(|e| {
+            eprintln!("Warning: {}, using the default config", e);
+            Config::default()
+        })
+    })
+}
+
+/// Every config file location, lowest priority first
+pub fn config_file_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+
+    // XDG_CONFIG_DIRS is ordered by preference, so the first entry has to come last
+    let config_dirs = env::var("XDG_CONFIG_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".to_string());
+    for dir in config_dirs.split(':').filter(|dir| !dir.is_empty()).rev() {
+        candidates.push(Path::new(dir).join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME));
+    }
+
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(dir) = config_home {
+        candidates.push(dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME));
+    }
+
+    candidates.push(PathBuf::from(PROJECT_CONFIG_FILE_NAME));
+    candidates
+}
+
+fn read_file_layer(path: &Path) -> Result<Layer, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let parse_error = |source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    };
+
+    // Deserialize the layer on its own first so that type errors and unknown
+    // keys are reported with the file position
+    toml::from_str::<Config>(&content).map_err(parse_error)?;
+    let table = toml::from_str::<toml::Table>(&content).map_err(parse_error)?;
+
+    Ok(Layer {
+        source: ConfigSource::File(path.to_path_buf()),
+        table,
+    })
+}
+
+/// Turn `MONSIEUR_DLP_SECTION__KEY=value` into a `section.key = value` layer. The variable
+/// names are upper case, so the keys of a table (hosts with dots) are only set as a whole
+fn env_layer(name: &str, value: &str) -> Result<Layer, ConfigError> {
+    let source = ConfigSource::Env(name.to_string());
+    let key: Vec<String> = name[ENV_PREFIX.len()..]
+        .split("__")
+        .map(str::to_lowercase)
+        .collect();
+    let dotted_key = key.join(".");
+
+    if key.iter().any(String::is_empty) {
+        return Err(ConfigError::Invalid {
+            key: dotted_key,
+            origin: source,
+            message: "expected MONSIEUR_DLP_<SECTION>__<KEY>".to_string(),
+        });
+    }
+
+    if key.len() > 2 {
+        return Err(ConfigError::Invalid {
+            message: format!(
+                "expected MONSIEUR_DLP_<SECTION>__<KEY>, set the whole table as {}{}='{{ \"key\" = value }}'",
+                ENV_PREFIX,
+                key[..2].join("__").to_uppercase()
+            ),
+            key: dotted_key,
+            origin: source,
+        });
+    }
+
+    let mut table = toml::Table::new();
+    table.insert(key[key.len() - 1].clone(), parse_env_value(value));
+    for part in key[..key.len() - 1].iter().rev() {
+        let mut parent = toml::Table::new();
+        parent.insert(part.clone(), toml::Value::Table(table));
+        table = parent;
+    }
+
+    table
+        .clone()
+        .try_into::<Config>()
+        .map_err(|e| ConfigError::Invalid {
+            key: dotted_key,
+            origin: source.clone(),
+            message: e.message().to_string(),
+        })?;
+
+    Ok(Layer { source, table })
+}
+
+/// Environment values are read as TOML literals when possible (numbers, booleans, arrays)
+/// and as plain strings otherwise
+fn parse_env_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Deep merge `layer` into `base`, remembering which source set every leaf key
+fn merge_tables(
+    base: &mut toml::Table,
+    layer: toml::Table,
+    prefix: &str,
+    source: &ConfigSource,
+    origins: &mut BTreeMap<String, ConfigSource>,
+) {
+    for (key, value) in layer {
+        let dotted_key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        match value {
+            toml::Value::Table(layer_table) => {
+                let base_table = base
+                    .entry(key)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                if !base_table.is_table() {
+                    *base_table = toml::Value::Table(toml::Table::new());
+                }
+                if let toml::Value::Table(base_table) = base_table {
+                    merge_tables(base_table, layer_table, &dotted_key, source, origins);
+                }
+            }
+            value => {
+                origins.insert(dotted_key, source.clone());
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn is_bundle_id(bundle_id: &str) -> bool {
+    let parts: Vec<&str> = bundle_id.split('.').collect();
+    parts.len() >= 2
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("monsieur_dlp_{}.toml", name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_from_without_layers_uses_defaults() {
+        let config = Config::load_from(&[], Vec::new()).unwrap();
+
+        assert_eq!(config.device.app_bundle_id, "org.videolan.vlc-ios");
+        assert_eq!(
+            config.paths.youtube_songs_file,
+            Path::new("youtube_files").join("ytb-songs.txt")
+        );
+        assert_eq!(
+            config.paths.mounting_path,
+            convert_path_string_to_pathbuf("~/VLC")
+        );
+    }
+
+    #[test]
+    fn load_from_later_files_override_earlier_ones() {
+        let user = write_config(
+            "override_user",
+            "[paths]\ndownload_path = \"/tmp/user\"\nmounting_path = \"/tmp/user-vlc\"\n",
+        );
+        let project = write_config("override_project", "[paths]\ndownload_path = \"/tmp/project\"\n");
+
+        let config = Config::load_from(&[user.clone(), project.clone()], Vec::new()).unwrap();
+
+        assert_eq!(config.paths.download_path, PathBuf::from("/tmp/project"));
+        assert_eq!(config.paths.mounting_path, PathBuf::from("/tmp/user-vlc"));
+
+        fs::remove_file(user).unwrap();
+        fs::remove_file(project).unwrap();
+    }
+
+    #[test]
+    fn load_from_env_overrides_files() {
+        let file = write_config("env_override", "[device]\napp_bundle_id = \"com.example.file\"\n");
+        let vars = vec![
+            (
+                "MONSIEUR_DLP_DEVICE__APP_BUNDLE_ID".to_string(),
+                "com.example.env".to_string(),
+            ),
+            ("UNRELATED".to_string(), "value".to_string()),
+        ];
+
+        let config = Config::load_from(std::slice::from_ref(&file), vars).unwrap();
+
+        assert_eq!(config.device.app_bundle_id, "com.example.env");
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn load_from_env_expands_home() {
+        let vars = vec![(
+            "MONSIEUR_DLP_PATHS__DOWNLOAD_PATH".to_string(),
+            "~/Downloads/DLP".to_string(),
+        )];
+
+        let config = Config::load_from(&[], vars).unwrap();
+
+        assert_eq!(
+            config.paths.download_path,
+            convert_path_string_to_pathbuf("~/Downloads/DLP")
+        );
+    }
+
+    #[test]
+    fn load_from_unknown_key_reports_file() {
+        let file = write_config("unknown_key", "[paths]\ndownload_dir = \"/tmp\"\n");
+
+        let err = Config::load_from(std::slice::from_ref(&file), Vec::new()).unwrap_err();
+
+        match &err {
+            ConfigError::Parse { path, .. } => assert_eq!(path, &file),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(err.to_string().contains("download_dir"));
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn load_from_invalid_bundle_id_points_at_key_and_file() {
+        let file = write_config("invalid_bundle", "[device]\napp_bundle_id = \"vlc\"\n");
+
+        let err = Config::load_from(std::slice::from_ref(&file), Vec::new()).unwrap_err();
+
+        match err {
+            ConfigError::Invalid { key, origin, .. } => {
+                assert_eq!(key, "device.app_bundle_id");
+                assert_eq!(origin, ConfigSource::File(file.clone()));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn load_from_invalid_env_type_points_at_variable() {
+        let vars = vec![("MONSIEUR_DLP_PATHS__DOWNLOAD_PATH".to_string(), "42".to_string())];
+
+        let err = Config::load_from(&[], vars).unwrap_err();
+
+        match err {
+            ConfigError::Invalid { key, origin, .. } => {
+                assert_eq!(key, "paths.download_path");
+                assert_eq!(
+                    origin,
+                    ConfigSource::Env("MONSIEUR_DLP_PATHS__DOWNLOAD_PATH".to_string())
+                );
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_from_same_songs_and_historic_file_is_invalid() {
+        let vars = vec![(
+            "MONSIEUR_DLP_PATHS__YOUTUBE_SONGS_HISTORIC_PATH".to_string(),
+            "youtube_files/ytb-songs.txt".to_string(),
+        )];
+
+        let err = Config::load_from(&[], vars).unwrap_err();
+
+        match err {
+            ConfigError::Invalid { key, .. } => {
+                assert_eq!(key, "paths.youtube_songs_historic_path")
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_from_archive_in_download_path_is_invalid() {
+        let vars = vec![
+            ("MONSIEUR_DLP_PATHS__DOWNLOAD_PATH".to_string(), "/tmp/music".to_string()),
+            ("MONSIEUR_DLP_PATHS__ARCHIVE_PATH".to_string(), "/tmp/music/library".to_string()),
+        ];
+
+        let err = Config::load_from(&[], vars).unwrap_err();
+
+        match err {
+            ConfigError::Invalid { key, .. } => assert_eq!(key, "paths.archive_path"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_from_unknown_mode_points_at_key_and_origin() {
+        let name = "MONSIEUR_DLP_DEVICE__TRANSPORT".to_string();
+        let err = Config::load_from(&[], vec![(name.clone(), "mtp".to_string())]).unwrap_err();
+
+        match err {
+            ConfigError::Invalid { key, origin, message } => {
+                assert_eq!(key, "device.transport");
+                assert_eq!(origin, ConfigSource::Env(name));
+                assert!(message.contains("unknown variant `mtp`"), "{}", message);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let file = write_config("unknown_mode", "[download]\nleftovers = \"keep\"\n");
+        let err = Config::load_from(std::slice::from_ref(&file), Vec::new()).unwrap_err();
+        assert!(matches!(&err, ConfigError::Parse { path, .. } if path == &file));
+        assert!(err.to_string().contains("leftovers"), "{}", err);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn load_from_reads_the_modes() {
+        let vars = vec![
+            ("MONSIEUR_DLP_DEVICE__WHEN_FULL".to_string(), "fit".to_string()),
+            ("MONSIEUR_DLP_DOWNLOAD__BACKEND".to_string(), "local".to_string()),
+            ("MONSIEUR_DLP_DOWNLOAD__LOUDNESS".to_string(), "replaygain".to_string()),
+        ];
+
+        let config = Config::load_from(&[], vars).unwrap();
+
+        assert_eq!(config.device.transport, Transport::Afc);
+        assert_eq!(config.device.when_full, WhenFull::Fit);
+        assert_eq!(config.download.backend, Backend::Local);
+        assert_eq!(config.download.leftovers, Leftovers::Resume);
+        assert_eq!(config.download.loudness, LoudnessMode::Replaygain);
+    }
+
+    #[test]
+    fn load_from_zero_host_limit_points_at_host() {
+        let file = write_config("zero_host_limit", "[download.hosts]\n\"youtube.com\" = 0\n");
+
+        let err = Config::load_from(std::slice::from_ref(&file), Vec::new()).unwrap_err();
+
+        match err {
+            ConfigError::Invalid { key, origin, .. } => {
+                assert_eq!(key, "download.hosts.youtube.com");
+                assert_eq!(origin, ConfigSource::File(file.clone()));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn load_from_env_sets_a_table_inline() {
+        let file = write_config("env_hosts", "[download.hosts]\n\"soundcloud.com\" = 3\n");
+        let name = "MONSIEUR_DLP_DOWNLOAD__HOSTS".to_string();
+        let vars = vec![(name.clone(), "{ \"youtube.com\" = 1 }".to_string())];
+
+        let config = Config::load_from(std::slice::from_ref(&file), vars).unwrap();
+
+        assert_eq!(config.download.hosts.get("youtube.com"), Some(&1));
+        assert_eq!(config.download.hosts.get("soundcloud.com"), Some(&3));
+        assert_eq!(config.origin("download.hosts.youtube.com"), ConfigSource::Env(name));
+        assert_eq!(config.origin("download.hosts.soundcloud.com"), ConfigSource::File(file.clone()));
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn load_from_env_cannot_name_the_keys_of_a_table() {
+        let vars = vec![("MONSIEUR_DLP_DOWNLOAD__HOSTS__YOUTUBE_COM".to_string(), "1".to_string())];
+
+        let err = Config::load_from(&[], vars).unwrap_err();
+
+        match &err {
+            ConfigError::Invalid { key, .. } => assert_eq!(key, "download.hosts.youtube_com"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(err.to_string().contains("MONSIEUR_DLP_DOWNLOAD__HOSTS='{ \"key\" = value }'"));
+    }
+
+    #[test]
+    fn is_bundle_id_accepts_reverse_dns() {
+        assert!(is_bundle_id("org.videolan.vlc-ios"));
+        assert!(!is_bundle_id("vlc"));
+        assert!(!is_bundle_id("org..vlc"));
+        assert!(!is_bundle_id("org.vlc ios"));
+    }
+}
@@ -1,29 +1,64 @@
 use std::env;
-use std::path::{PathBuf, Path};
+use std::path::PathBuf;
+
+use super::config;
 
 // The VLC APP_ID
-pub const APP_BUNDLE_ID: &str = "org.videolan.vlc-ios";
+pub const DEFAULT_APP_BUNDLE_ID: &str = "org.videolan.vlc-ios";
+pub const DEFAULT_USBMUXD_SOCKET: &str = "/var/run/usbmuxd";
+pub const DEFAULT_YOUTUBE_SONGS_FILE: &str = "youtube_files/ytb-songs.txt";
+pub const DEFAULT_YOUTUBE_SONGS_HISTORIC_PATH: &str = "youtube_files/ytb-songs-historic.txt";
+pub const DEFAULT_DEAD_LETTERS_PATH: &str = "youtube_files/ytb-songs-dead.txt";
+pub const DEFAULT_LIBRARY_PATH: &str = "youtube_files/library.db";
+pub const DEFAULT_MOUNTING_PATH: &str = "~/VLC";
+pub const DEFAULT_DOWNLOAD_PATH: &str = "~/Music/DLP/";
+pub const DEFAULT_ARCHIVE_PATH: &str = "~/Music/DLP-library/";
+pub const DEFAULT_PROFILE: &str = "mp3-320";
+/// The song files are named after the title, see `youtube::naming`
+pub const DEFAULT_TEMPLATE: &str = "{name}.{ext}";
+/// The archive keeps the songs in artist and album folders
+pub const DEFAULT_ARCHIVE_TEMPLATE: &str = "{artist}/{album}/{name}.{ext}";
+
+/// The bundle id of the app receiving the songs (`device.app_bundle_id`)
+pub fn app_bundle_id() -> &'static str {
+    &config::get().device.app_bundle_id
+}
 
 pub fn youtube_songs_file() -> PathBuf {
-    Path::new("youtube_files").join("ytb-songs.txt")
+    config::get().paths.youtube_songs_file.clone()
 }
 
 pub fn youtube_songs_historic_path() -> PathBuf {
-    Path::new("youtube_files").join("ytb-songs-historic.txt")
+    config::get().paths.youtube_songs_historic_path.clone()
+}
+
+/// The songs that can never be downloaded, with the reason
+pub fn dead_letters_path() -> PathBuf {
+    config::get().paths.dead_letters_path.clone()
+}
+
+/// The SQLite database of the downloaded songs
+pub fn library_path() -> PathBuf {
+    config::get().paths.library_path.clone()
 }
 
 /// The mounting path for the ios device
 pub fn mounting_path() -> PathBuf {
-    convert_path_string_to_pathbuf("~/VLC")
+    config::get().paths.mounting_path.clone()
 }
 
 // The path where ytb-dlp download the songs
 pub fn download_path() -> PathBuf {
-    convert_path_string_to_pathbuf("~/Music/DLP/")
+    config::get().paths.download_path.clone()
+}
+
+/// The library of artist and album folders of the archive mode
+pub fn archive_path() -> PathBuf {
+    config::get().paths.archive_path.clone()
 }
 
 /// Convert given string to pathbuf with converting home character (~) to the actual emplacement
-fn convert_path_string_to_pathbuf(path: &str) -> PathBuf {
+pub fn convert_path_string_to_pathbuf(path: &str) -> PathBuf {
     // Check if path starts with "~/"
     if let Some(stripped) = path.strip_prefix("~/") {
          // Get the user's home directory
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Every AFC packet starts with it
+pub const MAGIC: &[u8; 8] = b"CFA6LPAA";
+/// Magic, entire length, header length, packet number and operation
+pub const HEADER_SIZE: usize = 40;
+/// Guard against garbage lengths
+const MAX_PACKET_SIZE: u64 = 64 * 1024 * 1024;
+/// Size of the chunks read from and written to the device
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub const OP_STATUS: u64 = 0x01;
+pub const OP_DATA: u64 = 0x02;
+pub const OP_READ_DIR: u64 = 0x03;
+pub const OP_REMOVE_PATH: u64 = 0x08;
+pub const OP_MAKE_DIR: u64 = 0x09;
+pub const OP_GET_FILE_INFO: u64 = 0x0a;
+pub const OP_GET_DEVICE_INFO: u64 = 0x0b;
+pub const OP_FILE_OPEN: u64 = 0x0d;
+pub const OP_FILE_OPEN_RESULT: u64 = 0x0e;
+pub const OP_FILE_READ: u64 = 0x0f;
+pub const OP_FILE_WRITE: u64 = 0x10;
+pub const OP_FILE_CLOSE: u64 = 0x14;
+pub const OP_RENAME_PATH: u64 = 0x18;
+
+/// fopen "r"
+pub const MODE_READ_ONLY: u64 = 1;
+/// fopen "w", creating or truncating the file
+pub const MODE_WRITE_ONLY: u64 = 3;
+
+pub const STATUS_SUCCESS: u64 = 0;
+pub const STATUS_OBJECT_NOT_FOUND: u64 = 8;
+pub const STATUS_OBJECT_EXISTS: u64 = 16;
+pub const STATUS_NO_SPACE_LEFT: u64 = 18;
+
+#[derive(Debug, Error)]
+pub enum AfcError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("AFC {operation} '{path}' failed: {} (status {code})", status_message(*.code))]
+    Status {
+        operation: &'static str,
+        path: String,
+        code: u64,
+    },
+
+    #[error("Unexpected AFC reply: {0}")]
+    Protocol(String),
+}
+
+impl AfcError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, AfcError::Status { code, .. } if *code == STATUS_OBJECT_NOT_FOUND)
+    }
+}
+
+fn status_message(code: u64) -> &'static str {
+    match code {
+        1 => "unknown error",
+        2 => "invalid operation header",
+        3 => "no resources",
+        4 => "read error",
+        5 => "write error",
+        6 => "unknown packet type",
+        7 => "invalid argument",
+        STATUS_OBJECT_NOT_FOUND => "no such file or directory",
+        9 => "is a directory",
+        10 => "permission denied",
+        11 => "service not connected",
+        12 => "operation timed out",
+        13 => "too much data",
+        14 => "end of data",
+        15 => "operation not supported",
+        STATUS_OBJECT_EXISTS => "file exists",
+        17 => "object busy",
+        STATUS_NO_SPACE_LEFT => "no space left on device",
+        19 => "operation would block",
+        20 => "input/output error",
+        21 => "operation interrupted",
+        22 => "operation in progress",
+        23 => "internal error",
+        _ => "unexpected error",
+    }
+}
+
+/// What a path of the device is
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Other(String),
+}
+
+/// Metadata of a file of the device
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileInfo {
+    pub size: u64,
+    pub kind: FileKind,
+    pub modified: Option<SystemTime>,
+}
+
+/// Storage of the device
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub block_size: u64,
+}
+
+/// One AFC message, the header data holds the arguments and the payload the file contents
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Packet {
+    pub number: u64,
+    pub operation: u64,
+    pub header: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// A client of the Apple File Conduit service, the file server of the device
+pub struct AfcClient<S> {
+    stream: S,
+    packet_number: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AfcClient<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            packet_number: 0,
+        }
+    }
+
+    /// Names of the entries of a directory, without `.` and `..`
+    pub async fn list(&mut self, path: &str) -> Result<Vec<String>, AfcError> {
+        let reply = self.request("ReadDir", path, OP_READ_DIR, &c_string(path), &[]).await?;
+        Ok(c_strings(&reply.payload)
+            .into_iter()
+            .filter(|name| name != "." && name != "..")
+            .collect())
+    }
+
+    pub async fn stat(&mut self, path: &str) -> Result<FileInfo, AfcError> {
+        let reply = self
+            .request("GetFileInfo", path, OP_GET_FILE_INFO, &c_string(path), &[])
+            .await?;
+        let info = key_values(&reply.payload);
+        let number = |key: &str| info.get(key).and_then(|value| value.parse::<u64>().ok());
+
+        Ok(FileInfo {
+            size: number("st_size").unwrap_or(0),
+            kind: match info.get("st_ifmt").map(String::as_str) {
+                Some("S_IFREG") => FileKind::File,
+                Some("S_IFDIR") => FileKind::Directory,
+                Some("S_IFLNK") => FileKind::Symlink,
+                other => FileKind::Other(other.unwrap_or_default().to_string()),
+            },
+            modified: number("st_mtime").map(|nanos| SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos)),
+        })
+    }
+
+    /// Whether the path exists, other failures are errors
+    pub async fn exists(&mut self, path: &str) -> Result<bool, AfcError> {
+        match self.stat(path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn device_info(&mut self) -> Result<DeviceInfo, AfcError> {
+        let reply = self.request("GetDeviceInfo", "", OP_GET_DEVICE_INFO, &[], &[]).await?;
+        let info = key_values(&reply.payload);
+        let number = |key: &str| info.get(key).and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+
+        Ok(DeviceInfo {
+            model: info.get("Model").cloned().unwrap_or_default(),
+            total_bytes: number("FSTotalBytes"),
+            free_bytes: number("FSFreeBytes"),
+            block_size: number("FSBlockSize"),
+        })
+    }
+
+    /// Create a directory and its missing parents
+    pub async fn make_dir(&mut self, path: &str) -> Result<(), AfcError> {
+        self.request("MakeDir", path, OP_MAKE_DIR, &c_string(path), &[]).await?;
+        Ok(())
+    }
+
+    /// Remove a file or an empty directory
+    pub async fn remove(&mut self, path: &str) -> Result<(), AfcError> {
+        self.request("RemovePath", path, OP_REMOVE_PATH, &c_string(path), &[])
+            .await?;
+        Ok(())
+    }
+
+    /// Rename a path, replacing the destination
+    pub async fn rename(&mut self, from: &str, to: &str) -> Result<(), AfcError> {
+        let mut arguments = c_string(from);
+        arguments.extend(c_string(to));
+        self.request("RenamePath", from, OP_RENAME_PATH, &arguments, &[]).await?;
+        Ok(())
+    }
+
+    pub async fn read_file(&mut self, path: &str) -> Result<Vec<u8>, AfcError> {
+        let mut contents = Vec::new();
+        self.read_chunks(path, |chunk| contents.extend_from_slice(chunk)).await?;
+        Ok(contents)
+    }
+
+    /// Read the file a chunk at a time, returns the number of bytes read
+    pub async fn read_chunks<F: FnMut(&[u8])>(&mut self, path: &str, mut on_chunk: F) -> Result<u64, AfcError> {
+        let handle = self.open(path, MODE_READ_ONLY).await?;
+        let mut read = 0;
+        let result = async {
+            loop {
+                let mut arguments = handle.to_le_bytes().to_vec();
+                arguments.extend((CHUNK_SIZE as u64).to_le_bytes());
+                let reply = self.request("FileRead", path, OP_FILE_READ, &arguments, &[]).await?;
+                if reply.payload.is_empty() {
+                    return Ok(());
+                }
+                on_chunk(&reply.payload);
+                read += reply.payload.len() as u64;
+            }
+        }
+        .await;
+        self.close(path, handle, result).await?;
+        Ok(read)
+    }
+
+    /// Create or replace a file with the given contents
+    pub async fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), AfcError> {
+        let handle = self.open(path, MODE_WRITE_ONLY).await?;
+        let mut result = Ok(());
+        for chunk in contents.chunks(CHUNK_SIZE) {
+            result = self.write_chunk(path, handle, chunk).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        self.close(path, handle, result).await
+    }
+
+    /// Copy a local file to the device, returns the number of bytes sent
+    pub async fn upload(&mut self, local: &Path, path: &str) -> Result<u64, AfcError> {
+        let mut file = File::open(local).await?;
+        let handle = self.open(path, MODE_WRITE_ONLY).await?;
+
+        let mut sent = 0;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let result = async {
+            loop {
+                let read = file.read(&mut buffer).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                self.write_chunk(path, handle, &buffer[..read]).await?;
+                sent += read as u64;
+            }
+        }
+        .await;
+        self.close(path, handle, result).await?;
+        Ok(sent)
+    }
+
+    async fn open(&mut self, path: &str, mode: u64) -> Result<u64, AfcError> {
+        let mut arguments = mode.to_le_bytes().to_vec();
+        arguments.extend(c_string(path));
+        let reply = self.request("FileOpen", path, OP_FILE_OPEN, &arguments, &[]).await?;
+
+        if reply.operation != OP_FILE_OPEN_RESULT || reply.header.len() < 8 {
+            return Err(AfcError::Protocol(format!(
+                "FileOpen answered with operation {:#x}",
+                reply.operation
+            )));
+        }
+        Ok(u64::from_le_bytes(reply.header[..8].try_into().unwrap()))
+    }
+
+    async fn write_chunk(&mut self, path: &str, handle: u64, chunk: &[u8]) -> Result<(), AfcError> {
+        self.request("FileWrite", path, OP_FILE_WRITE, &handle.to_le_bytes(), chunk)
+            .await?;
+        Ok(())
+    }
+
+    /// Close the handle, the error of `result` wins over the one of closing
+    async fn close(&mut self, path: &str, handle: u64, result: Result<(), AfcError>) -> Result<(), AfcError> {
+        let closed = self
+            .request("FileClose", path, OP_FILE_CLOSE, &handle.to_le_bytes(), &[])
+            .await;
+        result?;
+        closed.map(|_| ())
+    }
+
+    /// Send a packet and wait for its reply, turning error statuses into errors
+    async fn request(
+        &mut self,
+        name: &'static str,
+        path: &str,
+        operation: u64,
+        header: &[u8],
+        payload: &[u8],
+    ) -> Result<Packet, AfcError> {
+        let number = self.packet_number;
+        self.packet_number += 1;
+        write_packet(
+            &mut self.stream,
+            &Packet {
+                number,
+                operation,
+                header: header.to_vec(),
+                payload: payload.to_vec(),
+            },
+        )
+        .await?;
+
+        let reply = read_packet(&mut self.stream).await?;
+        if reply.operation == OP_STATUS {
+            let code = reply
+                .header
+                .get(..8)
+                .map(|code| u64::from_le_bytes(code.try_into().unwrap()))
+                .unwrap_or(STATUS_SUCCESS);
+            if code != STATUS_SUCCESS {
+                return Err(AfcError::Status {
+                    operation: name,
+                    path: path.to_string(),
+                    code,
+                });
+            }
+        }
+        Ok(reply)
+    }
+}
+
+pub async fn write_packet<W>(writer: &mut W, packet: &Packet) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let this_length = (HEADER_SIZE + packet.header.len()) as u64;
+    let entire_length = this_length + packet.payload.len() as u64;
+
+    let mut message = Vec::with_capacity(entire_length as usize);
+    message.extend(MAGIC);
+    message.extend(entire_length.to_le_bytes());
+    message.extend(this_length.to_le_bytes());
+    message.extend(packet.number.to_le_bytes());
+    message.extend(packet.operation.to_le_bytes());
+    message.extend(&packet.header);
+    message.extend(&packet.payload);
+    writer.write_all(&message).await?;
+    writer.flush().await
+}
+
+pub async fn read_packet<R>(reader: &mut R) -> io::Result<Packet>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    if &header[..8] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid AFC magic"));
+    }
+
+    let field = |index: usize| u64::from_le_bytes(header[8 + index * 8..16 + index * 8].try_into().unwrap());
+    let (entire_length, this_length) = (field(0), field(1));
+    if this_length < HEADER_SIZE as u64 || entire_length < this_length || entire_length > MAX_PACKET_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid AFC packet lengths {}/{}", this_length, entire_length),
+        ));
+    }
+
+    let mut arguments = vec![0u8; this_length as usize - HEADER_SIZE];
+    reader.read_exact(&mut arguments).await?;
+    let mut payload = vec![0u8; (entire_length - this_length) as usize];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Packet {
+        number: field(2),
+        operation: field(3),
+        header: arguments,
+        payload,
+    })
+}
+
+/// A path as AFC expects it, NUL terminated
+pub fn c_string(value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// The NUL separated strings of a payload
+pub fn c_strings(payload: &[u8]) -> Vec<String> {
+    payload
+        .split(|byte| *byte == 0)
+        .filter(|value| !value.is_empty())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .collect()
+}
+
+/// The key\0value\0 pairs of GetFileInfo and GetDeviceInfo
+fn key_values(payload: &[u8]) -> HashMap<String, String> {
+    c_strings(payload)
+        .chunks_exact(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::testing::FakeAfc;
+    use std::env;
+    use std::fs;
+
+    #[tokio::test]
+    async fn packets_round_trip() {
+        let packet = Packet {
+            number: 7,
+            operation: OP_FILE_WRITE,
+            header: 42u64.to_le_bytes().to_vec(),
+            payload: b"some bytes".to_vec(),
+        };
+        let mut buffer = Vec::new();
+        write_packet(&mut buffer, &packet).await.unwrap();
+
+        assert_eq!(&buffer[..8], MAGIC);
+        assert_eq!(read_packet(&mut buffer.as_slice()).await.unwrap(), packet);
+    }
+
+    #[tokio::test]
+    async fn read_packet_rejects_bad_magic() {
+        let buffer = [0u8; HEADER_SIZE];
+
+        let result = read_packet(&mut buffer.as_slice()).await;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn write_read_list_stat_files() {
+        let afc = FakeAfc::new();
+        let mut client = AfcClient::new(afc.connect());
+
+        client.make_dir("/Documents/Music").await.unwrap();
+        client.write_file("/Documents/Music/song.mp3", b"ID3 song").await.unwrap();
+
+        assert_eq!(client.list("/Documents").await.unwrap(), vec!["Music"]);
+        assert_eq!(client.list("/Documents/Music").await.unwrap(), vec!["song.mp3"]);
+        assert_eq!(client.read_file("/Documents/Music/song.mp3").await.unwrap(), b"ID3 song");
+
+        let info = client.stat("/Documents/Music/song.mp3").await.unwrap();
+        assert_eq!(info.size, 8);
+        assert_eq!(info.kind, FileKind::File);
+        assert_eq!(client.stat("/Documents/Music").await.unwrap().kind, FileKind::Directory);
+    }
+
+    #[tokio::test]
+    async fn upload_sends_large_files_in_chunks() {
+        let local = env::temp_dir().join("monsieur_dlp_afc_upload.bin");
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+        fs::write(&local, &contents).unwrap();
+        let afc = FakeAfc::new();
+        let mut client = AfcClient::new(afc.connect());
+
+        let sent = client.upload(&local, "/Documents/big.bin").await.unwrap();
+
+        assert_eq!(sent, contents.len() as u64);
+        assert_eq!(afc.file("/Documents/big.bin"), Some(contents.clone()));
+        assert_eq!(client.read_file("/Documents/big.bin").await.unwrap(), contents);
+        fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_and_remove() {
+        let afc = FakeAfc::new();
+        afc.put("/Documents/a.mp3", b"a");
+        let mut client = AfcClient::new(afc.connect());
+
+        client.rename("/Documents/a.mp3", "/Documents/b.mp3").await.unwrap();
+        assert!(!client.exists("/Documents/a.mp3").await.unwrap());
+        assert!(client.exists("/Documents/b.mp3").await.unwrap());
+
+        client.remove("/Documents/b.mp3").await.unwrap();
+        assert_eq!(afc.file("/Documents/b.mp3"), None);
+    }
+
+    #[tokio::test]
+    async fn missing_paths_are_not_found() {
+        let afc = FakeAfc::new();
+        let mut client = AfcClient::new(afc.connect());
+
+        let error = client.stat("/Documents/missing.mp3").await.unwrap_err();
+        assert!(error.is_not_found());
+        assert_eq!(
+            error.to_string(),
+            "AFC GetFileInfo '/Documents/missing.mp3' failed: no such file or directory (status 8)"
+        );
+
+        assert!(client.read_file("/Documents/missing.mp3").await.unwrap_err().is_not_found());
+        assert!(client.remove("/Documents/missing.mp3").await.unwrap_err().is_not_found());
+    }
+
+    #[tokio::test]
+    async fn device_info_reports_free_space() {
+        let afc = FakeAfc::new();
+        afc.set_free_bytes(1024);
+        let mut client = AfcClient::new(afc.connect());
+
+        let info = client.device_info().await.unwrap();
+
+        assert_eq!(info.free_bytes, 1024);
+        assert!(info.total_bytes >= info.free_bytes);
+        assert_eq!(info.model, "iPhone12,1");
+    }
+}
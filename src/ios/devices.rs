@@ -0,0 +1,196 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use plist::Value;
+
+use super::lockdown::LockdownClient;
+use super::pairing::PairingError;
+use super::usbmux::{ConnectionType, Device, UsbmuxClient};
+
+/// A connected device with what lockdownd tells about it
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceDetails {
+    pub device: Device,
+    /// The name set by the user, such as "Work iPhone"
+    pub name: Option<String>,
+    pub ios_version: Option<String>,
+    /// A pair record with this computer exists
+    pub paired: bool,
+}
+
+impl DeviceDetails {
+    pub fn udid(&self) -> &str {
+        &self.device.udid
+    }
+
+    /// Whether the `--device` value designates this device, by UDID or name
+    pub fn matches(&self, selector: &str) -> bool {
+        self.device.udid.eq_ignore_ascii_case(selector)
+            || self
+                .name
+                .as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(selector))
+    }
+}
+
+impl fmt::Display for DeviceDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (iOS {}, {})",
+            self.device.udid,
+            self.name.as_deref().unwrap_or("unknown name"),
+            self.ios_version.as_deref().unwrap_or("?"),
+            if self.paired { "paired" } else { "not paired" }
+        )
+    }
+}
+
+/// Every device connected over USB, in the order usbmuxd knows them
+pub async fn connected_devices_at<P: AsRef<Path>>(socket: P) -> Result<Vec<DeviceDetails>, PairingError> {
+    let socket = socket.as_ref();
+    let devices = UsbmuxClient::connect(socket).await?.list_devices().await?;
+
+    let mut details = vec![];
+    for device in devices {
+        if device.connection_type != ConnectionType::Usb {
+            continue;
+        }
+        details.push(describe(socket, device).await?);
+    }
+    Ok(details)
+}
+
+pub async fn connected_devices() -> Result<Vec<DeviceDetails>, PairingError> {
+    connected_devices_at(&crate::common::config::get().device.usbmuxd_socket).await
+}
+
+/// Read the name and version of the device, a device whose lockdownd does not
+/// answer (still booting, locked...) is listed without them
+async fn describe(socket: &Path, device: Device) -> Result<DeviceDetails, PairingError> {
+    let paired = UsbmuxClient::connect(socket)
+        .await?
+        .read_pair_record(&device.udid)
+        .await?
+        .is_some();
+
+    let (mut name, mut ios_version) = (None, None);
+    if let Ok(mut lockdown) = LockdownClient::connect(socket, &device).await {
+        name = string_value(&mut lockdown, "DeviceName").await;
+        ios_version = string_value(&mut lockdown, "ProductVersion").await;
+    }
+
+    Ok(DeviceDetails {
+        device,
+        name,
+        ios_version,
+        paired,
+    })
+}
+
+async fn string_value(lockdown: &mut LockdownClient, key: &str) -> Option<String> {
+    match lockdown.get_value(key).await {
+        Ok(Some(Value::String(value))) => Some(value),
+        _ => None,
+    }
+}
+
+/// The devices designated by `--device`, all of them without selector
+pub fn select(devices: Vec<DeviceDetails>, selector: Option<&str>) -> Result<Vec<DeviceDetails>, PairingError> {
+    if devices.is_empty() {
+        return Err(PairingError::NoDevice);
+    }
+    let Some(selector) = selector else {
+        return Ok(devices);
+    };
+
+    let selected: Vec<DeviceDetails> = devices.into_iter().filter(|device| device.matches(selector)).collect();
+    if selected.is_empty() {
+        return Err(PairingError::UnknownDevice(selector.to_string()));
+    }
+    Ok(selected)
+}
+
+/// The ifuse mountpoint of the device, a folder named after its UDID in the mounting path
+/// The UDIDs the selector designates among the mountpoints, which are named by UDID: the
+/// connected devices it matches by UDID or name, else the selector itself for an unplugged device
+pub fn selected_udids(devices: &[DeviceDetails], selector: &str) -> Vec<String> {
+    let udids: Vec<String> =
+        devices.iter().filter(|device| device.matches(selector)).map(|device| device.udid().to_string()).collect();
+    if udids.is_empty() { vec![selector.to_string()] } else { udids }
+}
+
+pub fn mountpoint<P: AsRef<Path>>(mounting_path: P, device: &Device) -> PathBuf {
+    mounting_path.as_ref().join(&device.udid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::testing::{FakeUsbmuxd, pair_record, usb_device};
+
+    fn details(udid: &str, name: &str) -> DeviceDetails {
+        DeviceDetails {
+            device: usb_device(1, udid),
+            name: Some(name.to_string()),
+            ios_version: Some("17.5".to_string()),
+            paired: true,
+        }
+    }
+
+    #[test]
+    fn selected_udids_resolves_names_and_keeps_unplugged_udids() {
+        let devices = vec![details("UDID-A", "Work iPhone"), details("UDID-B", "iPad")];
+
+        assert_eq!(selected_udids(&devices, "work iphone"), vec!["UDID-A"]);
+        assert_eq!(selected_udids(&devices, "udid-b"), vec!["UDID-B"]);
+        assert_eq!(selected_udids(&devices, "UDID-GONE"), vec!["UDID-GONE"]);
+        assert_eq!(selected_udids(&[], "Work iPhone"), vec!["Work iPhone"]);
+    }
+
+    #[tokio::test]
+    async fn connected_devices_lists_usb_devices_with_details() {
+        let usbmuxd = FakeUsbmuxd::start("devices_list").await;
+        usbmuxd.attach(usb_device(3, "UDID-A")).await;
+        usbmuxd.attach(usb_device(4, "UDID-B")).await;
+        usbmuxd.set_value("UDID-B", "DeviceName", "Test iPad").await;
+        usbmuxd.set_value("UDID-B", "ProductVersion", "16.7").await;
+        usbmuxd.pair("UDID-A", pair_record("HOST-1")).await;
+
+        let devices = connected_devices_at(usbmuxd.path()).await.unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].udid(), "UDID-A");
+        assert!(devices[0].paired);
+        assert_eq!(devices[1].name.as_deref(), Some("Test iPad"));
+        assert_eq!(devices[1].ios_version.as_deref(), Some("16.7"));
+        assert!(!devices[1].paired);
+        assert_eq!(devices[1].to_string(), "UDID-B Test iPad (iOS 16.7, not paired)");
+    }
+
+    #[test]
+    fn select_by_udid_or_name() {
+        let devices = vec![details("UDID-A", "Work iPhone"), details("UDID-B", "Test iPad")];
+
+        assert_eq!(select(devices.clone(), None).unwrap().len(), 2);
+        assert_eq!(select(devices.clone(), Some("udid-b")).unwrap()[0].udid(), "UDID-B");
+        assert_eq!(select(devices.clone(), Some("work iphone")).unwrap()[0].udid(), "UDID-A");
+        assert!(matches!(
+            select(devices, Some("Other")),
+            Err(PairingError::UnknownDevice(selector)) if selector == "Other"
+        ));
+    }
+
+    #[test]
+    fn select_without_devices_fails() {
+        assert!(matches!(select(vec![], None), Err(PairingError::NoDevice)));
+    }
+
+    #[test]
+    fn mountpoint_is_named_after_udid() {
+        assert_eq!(
+            mountpoint("/home/user/VLC", &usb_device(3, "UDID-A")),
+            PathBuf::from("/home/user/VLC/UDID-A")
+        );
+    }
+}
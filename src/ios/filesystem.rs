@@ -1,51 +1,618 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{PathBuf, Path};
-use std::fs;
-use tokio::process::Command;
 use std::io;
+
+use async_trait::async_trait;
+use indicatif::HumanBytes;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use super::afc::{AfcError, FileInfo, FileKind};
+use super::house_arrest::Documents;
+use crate::common::config::WhenFull;
 
+/// The bytes read at once from a mounted file to check it
+const CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Error)]
 pub enum FileSystemError {
     #[error("IO Error: {0}")]
     Io(#[from] io::Error),
 
-    #[error("Failed to move file '{path}': {message}")]
-    MoveError {
-        path: PathBuf,
-        message: String,
-    },
-}
-
-pub async fn move_music_to_device<P: AsRef<Path>>(mountpoint: P) -> Result<String, FileSystemError> {
-    let mut moved_files = vec![];
-
-    for entry in fs::read_dir(&crate::common::constants::download_path())? {
-        let entry = entry?;
-        let path = entry.path();
-
-        if path.is_file() {
-            let output = Command::new("mv")
-                .arg(&path)
-                .arg(mountpoint.as_ref())
-                .output()
-                .await?;
-
-            if output.status.success() {
-                moved_files.push(path.file_name().unwrap().to_string_lossy().into_owned());
-            } else {
-                return Err(FileSystemError::MoveError {
-                    path: path,
-                    message: String::from_utf8_lossy(&output.stderr).to_string(),
-                });
+    #[error("AFC Error: {0}")]
+    Afc(#[from] AfcError),
+
+    #[error("{name} has {found} bytes on the device instead of {expected}")]
+    Truncated { name: String, expected: u64, found: u64 },
+
+    #[error("{0} on the device differs from the local file")]
+    Corrupted(String),
+
+    #[error("{0} files could not be copied, they are kept for the next sync")]
+    Incomplete(usize),
+
+    #[error(
+        "{location} has {} free but the songs need {} with the margin, make room on the device \
+         or set device.when_full = \"fit\" to send the songs that fit",
+        HumanBytes(*.free),
+        HumanBytes(*.needed)
+    )]
+    NoSpace { location: String, needed: u64, free: u64 },
+}
+
+/// Called with every chunk of a file read back from the device
+pub type OnChunk<'a> = dyn for<'c> FnMut(&'c [u8]) + Send + 'a;
+
+/// What became of one file of the transfer
+#[derive(Debug)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    pub name: String,
+    /// The bytes copied once read back and checked
+    pub result: Result<u64, FileSystemError>,
+}
+
+/// The files copied to one device
+#[derive(Debug)]
+pub struct CopyReport {
+    pub location: String,
+    pub outcomes: Vec<FileOutcome>,
+}
+
+impl CopyReport {
+    pub fn summary(&self) -> String {
+        let copied: Vec<&str> = self
+            .outcomes
+            .iter()
+            .filter(|outcome| outcome.result.is_ok())
+            .map(|outcome| outcome.name.as_str())
+            .collect();
+        let mark = if copied.len() == self.outcomes.len() { "✅" } else { "⚠️" };
+        format!(
+            "Copied and verified {} of {} files to {} {}: {:?}",
+            copied.len(),
+            self.outcomes.len(),
+            self.location,
+            mark,
+            copied
+        )
+    }
+}
+
+/// The Documents folder of the app receiving the songs, however it is reached
+#[async_trait]
+pub trait DeviceStorage: Send {
+    /// Where the files go, for messages
+    fn location(&self) -> String;
+
+    /// Copy a local file as `name`, returns the number of bytes copied
+    async fn upload(&mut self, local: &Path, name: &str) -> Result<u64, FileSystemError>;
+
+    /// Read the file back from the device a chunk at a time, returns the number of bytes read
+    async fn read_chunks(&mut self, name: &str, on_chunk: &mut OnChunk<'_>) -> Result<u64, FileSystemError>;
+
+    /// The names in the folder, "" for the Documents folder itself
+    async fn list(&mut self, folder: &str) -> Result<Vec<String>, FileSystemError>;
+
+    /// None when the file does not exist
+    async fn stat(&mut self, name: &str) -> Result<Option<FileInfo>, FileSystemError>;
+
+    /// Create the folder and its parents
+    async fn make_dir(&mut self, name: &str) -> Result<(), FileSystemError>;
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError>;
+
+    async fn remove(&mut self, name: &str) -> Result<(), FileSystemError>;
+
+    /// The bytes the app can still write
+    async fn free_bytes(&mut self) -> Result<u64, FileSystemError>;
+}
+
+/// The Documents folder mounted by ifuse
+pub struct MountedDir {
+    path: PathBuf,
+}
+
+impl MountedDir {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for MountedDir {
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    async fn upload(&mut self, local: &Path, name: &str) -> Result<u64, FileSystemError> {
+        Ok(tokio::fs::copy(local, self.path.join(name)).await?)
+    }
+
+    async fn read_chunks(&mut self, name: &str, on_chunk: &mut OnChunk<'_>) -> Result<u64, FileSystemError> {
+        let mut file = tokio::fs::File::open(self.path.join(name)).await?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut read = 0;
+        loop {
+            let count = file.read(&mut buffer).await?;
+            if count == 0 {
+                return Ok(read);
             }
+            on_chunk(&buffer[..count]);
+            read += count as u64;
+        }
+    }
+
+    async fn list(&mut self, folder: &str) -> Result<Vec<String>, FileSystemError> {
+        let mut names = vec![];
+        let mut entries = tokio::fs::read_dir(self.path.join(folder)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name().to_string_lossy().into_owned());
         }
+        names.sort();
+        Ok(names)
     }
 
-    Ok(format!(
-        "Moved {} files to {} ✅: {:?}",
-        moved_files.len(),
-        mountpoint.as_ref().display(),
-        moved_files
-    ))
-}
\ No newline at end of file
+    async fn stat(&mut self, name: &str) -> Result<Option<FileInfo>, FileSystemError> {
+        let metadata = match tokio::fs::symlink_metadata(self.path.join(name)).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let kind = if metadata.is_dir() {
+            FileKind::Directory
+        } else if metadata.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::File
+        };
+
+        Ok(Some(FileInfo {
+            size: metadata.len(),
+            kind,
+            modified: metadata.modified().ok(),
+        }))
+    }
+
+    async fn make_dir(&mut self, name: &str) -> Result<(), FileSystemError> {
+        Ok(tokio::fs::create_dir_all(self.path.join(name)).await?)
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        Ok(tokio::fs::rename(self.path.join(from), self.path.join(to)).await?)
+    }
+
+    async fn remove(&mut self, name: &str) -> Result<(), FileSystemError> {
+        Ok(tokio::fs::remove_file(self.path.join(name)).await?)
+    }
+
+    /// statvfs of the FUSE mount, which ifuse answers with the free space of the device
+    async fn free_bytes(&mut self) -> Result<u64, FileSystemError> {
+        let path = CString::new(self.path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+        // SAFETY: `path` is a nul-terminated string and `stat` a plain struct statvfs fills
+        let stat = unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            stat
+        };
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> DeviceStorage for Documents<S> {
+    fn location(&self) -> String {
+        format!("{} Documents", self.bundle_id())
+    }
+
+    async fn upload(&mut self, local: &Path, name: &str) -> Result<u64, FileSystemError> {
+        Ok(Documents::upload(self, local, name).await?)
+    }
+
+    async fn read_chunks(&mut self, name: &str, on_chunk: &mut OnChunk<'_>) -> Result<u64, FileSystemError> {
+        Ok(Documents::read_chunks(self, name, on_chunk).await?)
+    }
+
+    async fn list(&mut self, folder: &str) -> Result<Vec<String>, FileSystemError> {
+        Ok(Documents::list(self, folder).await?)
+    }
+
+    async fn stat(&mut self, name: &str) -> Result<Option<FileInfo>, FileSystemError> {
+        Ok(Documents::stat(self, name).await?)
+    }
+
+    async fn make_dir(&mut self, name: &str) -> Result<(), FileSystemError> {
+        Ok(Documents::make_dir(self, name).await?)
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        Ok(Documents::rename(self, from, to).await?)
+    }
+
+    async fn remove(&mut self, name: &str) -> Result<(), FileSystemError> {
+        Ok(Documents::remove(self, name).await?)
+    }
+
+    async fn free_bytes(&mut self) -> Result<u64, FileSystemError> {
+        Ok(self.afc().device_info().await?.free_bytes)
+    }
+}
+
+/// Check the room of the device before sending the files, sorted by priority: they all go
+/// when they fit with `margin` bytes to spare. Else `when_full` "abort" fails and "fit"
+/// keeps the first files that fit, skipping the ones too big. Returns the files to send
+/// and the files left out.
+pub async fn preflight<D>(
+    files: &[(PathBuf, String)],
+    storage: &mut D,
+    margin: u64,
+    when_full: WhenFull,
+) -> Result<(Vec<(PathBuf, String)>, Vec<(PathBuf, String)>), FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    if files.is_empty() {
+        return Ok((vec![], vec![]));
+    }
+    let mut sizes = vec![];
+    for (path, _) in files {
+        sizes.push(tokio::fs::metadata(path).await?.len());
+    }
+    let free = storage.free_bytes().await?;
+    let needed = sizes.iter().sum::<u64>() + margin;
+    if needed <= free {
+        return Ok((files.to_vec(), vec![]));
+    }
+    if when_full != WhenFull::Fit {
+        return Err(FileSystemError::NoSpace { location: storage.location(), needed, free });
+    }
+
+    let mut room = free.saturating_sub(margin);
+    let (mut fitting, mut left) = (vec![], vec![]);
+    for (file, size) in files.iter().zip(sizes) {
+        if size <= room {
+            room -= size;
+            fitting.push(file.clone());
+        } else {
+            left.push(file.clone());
+        }
+    }
+    Ok((fitting, left))
+}
+
+/// Copy the local files to their name on the device, creating their folders. Every file
+/// is written under a hidden name, read back and compared with the local one before
+/// taking its name, a failure only loses that file. A name another file took on the
+/// device gets the ` (2)`, ` (3)`... suffix of the downloads, a file already there is
+/// not sent again.
+pub async fn copy_files_to_device<D>(files: &[(PathBuf, String)], storage: &mut D) -> CopyReport
+where
+    D: DeviceStorage + ?Sized,
+{
+    let mut outcomes = vec![];
+    for (path, name) in files {
+        let (name, result) = match free_name(path, name, storage).await {
+            Ok(Free::Name(free)) => {
+                let result = copy_verified(path, &free, storage).await;
+                (free, result)
+            }
+            Ok(Free::Copied(copied, size)) => (copied, Ok(size)),
+            Err(e) => (name.clone(), Err(e)),
+        };
+        outcomes.push(FileOutcome {
+            path: path.clone(),
+            name,
+            result,
+        });
+    }
+
+    CopyReport {
+        location: storage.location(),
+        outcomes,
+    }
+}
+
+/// Where the local file goes on the device
+enum Free {
+    /// A name no file has
+    Name(String),
+    /// The name of a copy already there, with its size
+    Copied(String, u64),
+}
+
+/// `name`, or the first of `name (2)`, `name (3)`... free on the device. A file of the
+/// same size and hash as the local one is the copy an earlier sync left.
+async fn free_name<D>(path: &Path, name: &str, storage: &mut D) -> Result<Free, FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    let (folder, file) = match name.rsplit_once('/') {
+        Some((folder, file)) => (format!("{}/", folder), file),
+        None => (String::new(), name),
+    };
+    let (stem, extension) = match file.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (file, String::new()),
+    };
+    let size = tokio::fs::metadata(path).await?.len();
+    let mut checksum = None;
+
+    let mut suffix = 0;
+    loop {
+        suffix += 1;
+        let candidate = match suffix {
+            1 => name.to_string(),
+            n => format!("{}{} ({}){}", folder, stem, n, extension),
+        };
+        let Some(info) = storage.stat(&candidate).await? else {
+            return Ok(Free::Name(candidate));
+        };
+        if info.kind != FileKind::File || info.size != size {
+            continue;
+        }
+        if checksum.is_none() {
+            let local_path = path.to_path_buf();
+            let local = tokio::task::spawn_blocking(move || crate::library::checksum(local_path))
+                .await
+                .map_err(io::Error::other)??;
+            checksum = Some(local);
+        }
+        if checksum.as_deref() == Some(device_checksum(storage, &candidate).await?.as_str()) {
+            return Ok(Free::Copied(candidate, size));
+        }
+    }
+}
+
+/// Copy one file under a hidden name, check it and give it its name, returns its size.
+/// A file already named so on the device is replaced.
+pub async fn copy_verified<D>(path: &Path, name: &str, storage: &mut D) -> Result<u64, FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    let expected = tokio::fs::metadata(path).await?.len();
+    let local_path = path.to_path_buf();
+    let checksum = tokio::task::spawn_blocking(move || crate::library::checksum(local_path))
+        .await
+        .map_err(io::Error::other)??;
+
+    if let Some((folder, _)) = name.rsplit_once('/') {
+        storage.make_dir(folder).await?;
+    }
+    let temp = partial_name(name);
+
+    let result = async {
+        storage.upload(path, &temp).await?;
+        let found = storage.stat(&temp).await?.map(|info| info.size).unwrap_or_default();
+        if found != expected {
+            return Err(FileSystemError::Truncated { name: name.to_string(), expected, found });
+        }
+        if device_checksum(storage, &temp).await? != checksum {
+            return Err(FileSystemError::Corrupted(name.to_string()));
+        }
+        storage.rename(&temp, name).await
+    }
+    .await;
+    if result.is_err() {
+        // The partial copy would only take room, the next sync writes it again
+        _ = storage.remove(&temp).await;
+    }
+    result.map(|_| expected)
+}
+
+/// The hidden name a file has on the device until checked
+fn partial_name(name: &str) -> String {
+    match name.rsplit_once('/') {
+        Some((folder, file)) => format!("{}/.{}.part", folder, file),
+        None => format!(".{}.part", name),
+    }
+}
+
+/// Whether the device file is a copy an interrupted transfer left under its hidden name
+pub fn is_partial(name: &str) -> bool {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    file.starts_with('.') && file.ends_with(".part")
+}
+
+/// SHA-256 of the device file, in hex
+pub async fn device_checksum<D>(storage: &mut D, name: &str) -> Result<String, FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    let mut hasher = Sha256::new();
+    storage.read_chunks(name, &mut |chunk| hasher.update(chunk)).await?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Every file of the Documents folder and its subfolders with its size, sorted by name
+pub async fn walk<D>(storage: &mut D) -> Result<Vec<(String, u64)>, FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    let mut found = vec![];
+    let mut folders = vec![String::new()];
+    while let Some(folder) = folders.pop() {
+        for entry in storage.list(&folder).await? {
+            let name = if folder.is_empty() { entry } else { format!("{}/{}", folder, entry) };
+            match storage.stat(&name).await? {
+                Some(info) if info.kind == FileKind::Directory => folders.push(name),
+                Some(info) if info.kind == FileKind::File => found.push((name, info.size)),
+                _ => {}
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::afc::AfcClient;
+    use crate::ios::testing::FakeAfc;
+    use crate::youtube::staging::files;
+    use std::fs;
+    use std::env;
+    use tokio::io::DuplexStream;
+
+    fn source_dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("monsieur_dlp_move_{}", name));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn copy_files_to_mounted_dir() {
+        let source = source_dir("mounted_source", &[("b.mp3", b"bb"), ("a.mp3", b"a")]);
+        let target = source_dir("mounted_target", &[]);
+        let mut storage = MountedDir::new(&target);
+
+        let files = files(&source).unwrap();
+        let report = copy_files_to_device(&named(&files), &mut storage).await;
+
+        assert_eq!(files, vec![source.join("a.mp3"), source.join("b.mp3")]);
+        assert!(report.summary().starts_with("Copied and verified 2 of 2 files"));
+        assert_eq!(report.outcomes[1].result.as_ref().unwrap(), &2);
+        assert_eq!(storage.list("").await.unwrap(), vec!["a.mp3", "b.mp3"]);
+        assert_eq!(storage.stat("b.mp3").await.unwrap().unwrap().size, 2);
+        assert_eq!(storage.stat("c.mp3").await.unwrap(), None);
+        fs::remove_dir_all(source).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_files_through_afc() {
+        let source = source_dir("afc_source", &[("a.mp3", b"a")]);
+        let afc = FakeAfc::new();
+        let mut documents = documents(&afc);
+
+        let files = vec![(source.join("a.mp3"), "a.mp3".to_string()), (source.join("a.mp3"), "Trips/b.mp3".to_string())];
+        let report = copy_files_to_device(&files, &mut documents).await;
+
+        assert!(report.summary().contains("org.videolan.vlc-ios Documents"));
+        assert!(report.outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        assert_eq!(afc.file("/Documents/a.mp3"), Some(b"a".to_vec()));
+        assert_eq!(afc.file("/Documents/Trips/b.mp3"), Some(b"a".to_vec()));
+        assert_eq!(afc.file("/Documents/Trips/.b.mp3.part"), None);
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_files_keeps_the_songs_already_on_the_device() {
+        let source = source_dir("afc_taken", &[("a.mp3", b"new"), ("b.mp3", b"bb")]);
+        let afc = FakeAfc::new();
+        afc.put("/Documents/Trips/a.mp3", b"old");
+        afc.put("/Documents/b.mp3", b"bb");
+        let mut documents = documents(&afc);
+
+        let files = vec![(source.join("a.mp3"), "Trips/a.mp3".to_string()), (source.join("b.mp3"), "b.mp3".to_string())];
+        let report = copy_files_to_device(&files, &mut documents).await;
+
+        assert!(report.outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        assert_eq!(report.outcomes[0].name, "Trips/a (2).mp3");
+        assert_eq!(report.outcomes[1].name, "b.mp3");
+        assert_eq!(afc.file("/Documents/Trips/a.mp3"), Some(b"old".to_vec()));
+        assert_eq!(afc.file("/Documents/Trips/a (2).mp3"), Some(b"new".to_vec()));
+        assert_eq!(documents.list("").await.unwrap(), vec!["Trips", "b.mp3"]);
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_files_goes_on_after_a_failed_upload() {
+        let source = source_dir("afc_full", &[("a.mp3", b"aaaa"), ("b.mp3", b"b")]);
+        let afc = FakeAfc::new();
+        afc.set_free_bytes(2);
+        let mut documents = documents(&afc);
+
+        let files = files(&source).unwrap();
+        let report = copy_files_to_device(&named(&files), &mut documents).await;
+
+        assert!(matches!(report.outcomes[0].result, Err(FileSystemError::Afc(_))));
+        assert_eq!(report.outcomes[1].result.as_ref().unwrap(), &1);
+        assert!(report.summary().starts_with("Copied and verified 1 of 2 files"));
+        assert_eq!(documents.list("").await.unwrap(), vec!["b.mp3"]);
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_files_rejects_a_truncated_copy() {
+        let source = source_dir("afc_flaky", &[("a.mp3", b"song")]);
+        let afc = FakeAfc::new();
+        afc.set_flaky(true);
+        let mut documents = documents(&afc);
+
+        let files = files(&source).unwrap();
+        let report = copy_files_to_device(&named(&files), &mut documents).await;
+
+        assert!(matches!(
+            report.outcomes[0].result,
+            Err(FileSystemError::Truncated { expected: 4, found: 3, .. })
+        ));
+        assert!(documents.list("").await.unwrap().is_empty());
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn preflight_aborts_or_fits_the_first_files() {
+        let source = source_dir("afc_room", &[("a.mp3", b"aaaa"), ("b.mp3", b"bbbbbbbb"), ("c.mp3", b"cc")]);
+        let afc = FakeAfc::new();
+        afc.set_free_bytes(16);
+        let mut documents = documents(&afc);
+        let files = named(&files(&source).unwrap());
+
+        let (fitting, left) = preflight(&files, &mut documents, 2, WhenFull::Abort).await.unwrap();
+        assert_eq!((fitting.len(), left.len()), (3, 0));
+
+        let result = preflight(&files, &mut documents, 6, WhenFull::Abort).await;
+        assert!(matches!(result, Err(FileSystemError::NoSpace { needed: 20, free: 16, .. })));
+
+        let (fitting, left) = preflight(&files, &mut documents, 6, WhenFull::Fit).await.unwrap();
+        assert_eq!(fitting, vec![files[0].clone(), files[2].clone()]);
+        assert_eq!(left, vec![files[1].clone()]);
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn device_checksum_reads_in_chunks() {
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 7).map(|i| (i % 251) as u8).collect();
+        let expected = hex::encode(Sha256::digest(&contents));
+        let target = source_dir("checksum", &[("big.flac", &contents)]);
+        let afc = FakeAfc::new();
+        afc.put("/Documents/big.flac", &contents);
+        let mut documents = documents(&afc);
+
+        let mut chunks = vec![];
+        let read = documents.read_chunks("big.flac", |chunk: &[u8]| chunks.push(chunk.len())).await.unwrap();
+        assert_eq!((read, chunks.len()), (contents.len() as u64, 4));
+        assert_eq!(device_checksum(&mut documents, "big.flac").await.unwrap(), expected);
+        assert_eq!(device_checksum(&mut MountedDir::new(&target), "big.flac").await.unwrap(), expected);
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mounted_dir_reads_free_space() {
+        let mut storage = MountedDir::new(env::temp_dir());
+
+        assert!(storage.free_bytes().await.unwrap() > 0);
+    }
+
+    fn named(files: &[PathBuf]) -> Vec<(PathBuf, String)> {
+        files
+            .iter()
+            .map(|file| (file.clone(), file.file_name().unwrap().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn documents(afc: &FakeAfc) -> Documents<DuplexStream> {
+        Documents::new(AfcClient::new(afc.connect()), "org.videolan.vlc-ios")
+    }
+}
@@ -0,0 +1,224 @@
+use std::path::Path;
+
+use plist::{Dictionary, Value};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::afc::{AfcClient, AfcError, FileInfo};
+use super::lockdown::{self, LockdownError, ServiceStream};
+use super::pairing::PairedDevice;
+
+/// The lockdownd service giving access to the containers of the apps
+pub const SERVICE: &str = "com.apple.mobile.house_arrest";
+/// Where the Documents folder of the app is in the vended container
+pub const DOCUMENTS: &str = "/Documents";
+
+#[derive(Debug, Error)]
+pub enum HouseArrestError {
+    #[error("lockdownd Error: {0}")]
+    Lockdown(#[from] LockdownError),
+
+    #[error("house_arrest refused to vend the Documents of {bundle_id}: {error}")]
+    Refused { bundle_id: String, error: String },
+
+    #[error("Unexpected house_arrest reply: {0}")]
+    Protocol(String),
+}
+
+/// The Documents folder of an app, what `ifuse --documents` mounts
+pub struct Documents<S = ServiceStream> {
+    afc: AfcClient<S>,
+    bundle_id: String,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Documents<S> {
+    /// Documents of a container already vended on the AFC connection
+    pub fn new(afc: AfcClient<S>, bundle_id: &str) -> Self {
+        Self {
+            afc,
+            bundle_id: bundle_id.to_string(),
+        }
+    }
+
+    pub fn bundle_id(&self) -> &str {
+        &self.bundle_id
+    }
+
+    /// The AFC client of the container, paths are relative to its root
+    pub fn afc(&mut self) -> &mut AfcClient<S> {
+        &mut self.afc
+    }
+
+    /// The container path of a file of the Documents folder
+    pub fn path(name: &str) -> String {
+        format!("{}/{}", DOCUMENTS, name.trim_start_matches('/'))
+    }
+
+    pub async fn upload(&mut self, local: &Path, name: &str) -> Result<u64, AfcError> {
+        self.afc.upload(local, &Self::path(name)).await
+    }
+
+    /// Read the file a chunk at a time, returns the number of bytes read
+    pub async fn read_chunks<F: FnMut(&[u8])>(&mut self, name: &str, on_chunk: F) -> Result<u64, AfcError> {
+        self.afc.read_chunks(&Self::path(name), on_chunk).await
+    }
+
+    /// The names in the folder, "" for the Documents folder itself
+    pub async fn list(&mut self, folder: &str) -> Result<Vec<String>, AfcError> {
+        match folder {
+            "" => self.afc.list(DOCUMENTS).await,
+            folder => self.afc.list(&Self::path(folder)).await,
+        }
+    }
+
+    /// None when the file does not exist
+    pub async fn stat(&mut self, name: &str) -> Result<Option<FileInfo>, AfcError> {
+        match self.afc.stat(&Self::path(name)).await {
+            Ok(info) => Ok(Some(info)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create the folder and its parents
+    pub async fn make_dir(&mut self, name: &str) -> Result<(), AfcError> {
+        self.afc.make_dir(&Self::path(name)).await
+    }
+
+    pub async fn rename(&mut self, from: &str, to: &str) -> Result<(), AfcError> {
+        self.afc.rename(&Self::path(from), &Self::path(to)).await
+    }
+
+    pub async fn remove(&mut self, name: &str) -> Result<(), AfcError> {
+        self.afc.remove(&Self::path(name)).await
+    }
+}
+
+/// Ask house_arrest for the container of the app, the connection then speaks AFC
+pub async fn vend_documents<S>(mut stream: S, bundle_id: &str) -> Result<Documents<S>, HouseArrestError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = Dictionary::new();
+    request.insert("Command".to_string(), Value::from("VendDocuments"));
+    request.insert("Identifier".to_string(), Value::from(bundle_id));
+    lockdown::write_plist(&mut stream, &request).await?;
+
+    let response: Dictionary = lockdown::read_plist(&mut stream).await?;
+    if let Some(error) = response.get("Error").and_then(Value::as_string) {
+        return Err(HouseArrestError::Refused {
+            bundle_id: bundle_id.to_string(),
+            error: error.to_string(),
+        });
+    }
+    match response.get("Status").and_then(Value::as_string) {
+        Some("Complete") => Ok(Documents::new(AfcClient::new(stream), bundle_id)),
+        other => Err(HouseArrestError::Protocol(format!(
+            "VendDocuments answered with status {:?}",
+            other
+        ))),
+    }
+}
+
+/// Open the Documents folder of the app on the paired device
+pub async fn open_documents_at<P: AsRef<Path>>(
+    socket: P,
+    paired: &PairedDevice,
+    bundle_id: &str,
+) -> Result<Documents, HouseArrestError> {
+    let stream = lockdown::connect_service(socket, &paired.device, &paired.record, SERVICE).await?;
+    vend_documents(stream, bundle_id).await
+}
+
+/// Open the Documents folder of the configured app
+pub async fn open_documents(paired: &PairedDevice) -> Result<Documents, HouseArrestError> {
+    let config = crate::common::config::get();
+    open_documents_at(&config.device.usbmuxd_socket, paired, &config.device.app_bundle_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::pairing::PairedDevice;
+    use crate::ios::testing::{FakeUsbmuxd, pair_record, tls_pair_record, usb_device};
+
+    const UDID: &str = "00008030-0001";
+    const VLC: &str = "org.videolan.vlc-ios";
+
+    fn paired(host_id: &str) -> PairedDevice {
+        PairedDevice {
+            device: usb_device(3, UDID),
+            record: pair_record(host_id),
+        }
+    }
+
+    #[tokio::test]
+    async fn open_documents_of_installed_app() {
+        let usbmuxd = FakeUsbmuxd::start("house_arrest_vend").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, pair_record("HOST-1")).await;
+        let afc = usbmuxd.install_app(VLC).await;
+        afc.put("/Documents/old.mp3", b"old");
+
+        let mut documents = open_documents_at(usbmuxd.path(), &paired("HOST-1"), VLC).await.unwrap();
+
+        assert_eq!(documents.bundle_id(), VLC);
+        assert_eq!(documents.list("").await.unwrap(), vec!["old.mp3"]);
+        assert_eq!(documents.stat("old.mp3").await.unwrap().unwrap().size, 3);
+        assert_eq!(documents.stat("missing.mp3").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn documents_upload_rename_remove() {
+        let usbmuxd = FakeUsbmuxd::start("house_arrest_files").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, pair_record("HOST-1")).await;
+        let afc = usbmuxd.install_app(VLC).await;
+        let local = std::env::temp_dir().join("monsieur_dlp_house_arrest.mp3");
+        std::fs::write(&local, b"song").unwrap();
+
+        let mut documents = open_documents_at(usbmuxd.path(), &paired("HOST-1"), VLC).await.unwrap();
+        documents.upload(&local, "song.mp3").await.unwrap();
+        assert_eq!(afc.file("/Documents/song.mp3"), Some(b"song".to_vec()));
+
+        documents.rename("song.mp3", "renamed.mp3").await.unwrap();
+        assert_eq!(documents.list("").await.unwrap(), vec!["renamed.mp3"]);
+
+        documents.remove("renamed.mp3").await.unwrap();
+        assert!(documents.list("").await.unwrap().is_empty());
+        std::fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_documents_over_tls() {
+        let usbmuxd = FakeUsbmuxd::start("house_arrest_tls").await;
+        let (record, server) = tls_pair_record("HOST-1");
+        usbmuxd.enable_ssl(server).await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, record.clone()).await;
+        let afc = usbmuxd.install_app(VLC).await;
+        afc.put("/Documents/old.mp3", b"old");
+        let paired = PairedDevice {
+            device: usb_device(3, UDID),
+            record,
+        };
+
+        let mut documents = open_documents_at(usbmuxd.path(), &paired, VLC).await.unwrap();
+
+        assert_eq!(documents.afc().read_file("/Documents/old.mp3").await.unwrap(), b"old");
+    }
+
+    #[tokio::test]
+    async fn open_documents_of_missing_app_is_refused() {
+        let usbmuxd = FakeUsbmuxd::start("house_arrest_missing").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, pair_record("HOST-1")).await;
+
+        let result = open_documents_at(usbmuxd.path(), &paired("HOST-1"), VLC).await;
+
+        assert!(matches!(
+            result,
+            Err(HouseArrestError::Refused { error, .. }) if error == "ApplicationLookupFailed"
+        ));
+    }
+}
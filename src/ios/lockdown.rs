@@ -0,0 +1,425 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use plist::{Dictionary, Value};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme};
+
+use super::usbmux::{Device, PairRecord, UsbmuxClient, UsbmuxError};
+
+/// The lockdownd port, answering on every device
+pub const LOCKDOWN_PORT: u16 = 62078;
+/// What lockdownd answers to QueryType
+pub const LOCKDOWN_TYPE: &str = "com.apple.mobile.lockdown";
+const LABEL: &str = "monsieur_dlp";
+/// Guard against garbage lengths, lockdownd messages are a few KB
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum LockdownError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid plist: {0}")]
+    Plist(#[from] plist::Error),
+
+    #[error("usbmuxd Error: {0}")]
+    Usbmux(#[from] UsbmuxError),
+
+    #[error("TLS Error: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("Invalid pair record: {0}")]
+    PairRecord(String),
+
+    #[error("lockdownd refused {request}: {error}")]
+    Refused { request: String, error: String },
+
+    #[error("Unexpected lockdownd reply: {0}")]
+    Protocol(String),
+}
+
+/// Any stream to a device service, plain or wrapped in TLS
+pub trait DeviceStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> DeviceStream for T {}
+
+pub type ServiceStream = Box<dyn DeviceStream>;
+
+/// A service started by lockdownd, to connect to through usbmuxd
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    pub port: u16,
+    /// The service connection must be wrapped in TLS
+    pub ssl: bool,
+}
+
+/// A connection to lockdownd, the device daemon starting the other services
+pub struct LockdownClient {
+    stream: ServiceStream,
+}
+
+impl LockdownClient {
+    pub fn new(stream: ServiceStream) -> Self {
+        Self { stream }
+    }
+
+    /// Connect to lockdownd of the device through usbmuxd
+    pub async fn connect<P: AsRef<Path>>(socket: P, device: &Device) -> Result<Self, LockdownError> {
+        let stream = UsbmuxClient::connect(socket)
+            .await?
+            .connect_to_port(device.device_id, LOCKDOWN_PORT)
+            .await?;
+        Ok(Self::new(Box::new(stream)))
+    }
+
+    /// The kind of daemon answering, `LOCKDOWN_TYPE` for lockdownd
+    pub async fn query_type(&mut self) -> Result<String, LockdownError> {
+        let response = self.request("QueryType", Dictionary::new()).await?;
+        string(&response, "Type")
+    }
+
+    /// A value of the device such as DeviceName or ProductVersion, None when unset
+    pub async fn get_value(&mut self, key: &str) -> Result<Option<Value>, LockdownError> {
+        let mut fields = Dictionary::new();
+        fields.insert("Key".to_string(), Value::from(key));
+        let mut response = self.request("GetValue", fields).await?;
+        Ok(response.remove("Value"))
+    }
+
+    /// Open a session with the keys of the pair record, upgrading the connection
+    /// to TLS when the device asks for it
+    pub async fn start_session(mut self, record: &PairRecord) -> Result<Self, LockdownError> {
+        let mut fields = Dictionary::new();
+        fields.insert("HostID".to_string(), Value::from(record.host_id.as_str()));
+        fields.insert("SystemBUID".to_string(), Value::from(record.system_buid.as_str()));
+        let response = self.request("StartSession", fields).await?;
+
+        if flag(&response, "EnableSessionSSL") {
+            self.stream = tls_connect(self.stream, record).await?;
+        }
+        Ok(self)
+    }
+
+    /// Start a service such as house_arrest, requires a session
+    pub async fn start_service(&mut self, name: &str, record: &PairRecord) -> Result<Service, LockdownError> {
+        let mut fields = Dictionary::new();
+        fields.insert("Service".to_string(), Value::from(name));
+        if let Some(escrow_bag) = &record.escrow_bag {
+            fields.insert("EscrowBag".to_string(), Value::Data(escrow_bag.clone().into()));
+        }
+        let response = self.request("StartService", fields).await?;
+
+        let port = response
+            .get("Port")
+            .and_then(Value::as_unsigned_integer)
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or_else(|| LockdownError::Protocol(format!("StartService {} reply without Port", name)))?;
+
+        Ok(Service {
+            port,
+            ssl: flag(&response, "EnableServiceSSL"),
+        })
+    }
+
+    async fn request(&mut self, request: &str, mut fields: Dictionary) -> Result<Dictionary, LockdownError> {
+        fields.insert("Label".to_string(), Value::from(LABEL));
+        fields.insert("Request".to_string(), Value::from(request));
+        write_plist(&mut self.stream, &fields).await?;
+
+        let response: Dictionary = read_plist(&mut self.stream).await?;
+        if let Some(error) = response.get("Error").and_then(Value::as_string) {
+            return Err(LockdownError::Refused {
+                request: request.to_string(),
+                error: error.to_string(),
+            });
+        }
+        match response.get("Request").and_then(Value::as_string) {
+            Some(answered) if answered == request => Ok(response),
+            other => Err(LockdownError::Protocol(format!(
+                "expected a reply to {}, got {:?}",
+                request, other
+            ))),
+        }
+    }
+}
+
+/// Start a service on the device and connect to it
+pub async fn connect_service<P: AsRef<Path>>(
+    socket: P,
+    device: &Device,
+    record: &PairRecord,
+    name: &str,
+) -> Result<ServiceStream, LockdownError> {
+    let socket = socket.as_ref();
+    let mut lockdown = LockdownClient::connect(socket, device)
+        .await?
+        .start_session(record)
+        .await?;
+    let service = lockdown.start_service(name, record).await?;
+
+    let stream: ServiceStream = Box::new(
+        UsbmuxClient::connect(socket)
+            .await?
+            .connect_to_port(device.device_id, service.port)
+            .await?,
+    );
+    if service.ssl {
+        tls_connect(stream, record).await
+    } else {
+        Ok(stream)
+    }
+}
+
+/// Wrap the connection in TLS, authenticating with the host keys of the pair record
+pub async fn tls_connect(stream: ServiceStream, record: &PairRecord) -> Result<ServiceStream, LockdownError> {
+    let connector = TlsConnector::from(Arc::new(tls_config(record)?));
+    let name = ServerName::try_from("lockdownd").expect("valid server name");
+    let stream = connector.connect(name, stream).await.map_err(|e| {
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+            Some(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)) => {
+                LockdownError::PairRecord("the device certificate is not the DeviceCertificate of the record".into())
+            }
+            _ => e.into(),
+        }
+    })?;
+    Ok(Box::new(stream))
+}
+
+fn tls_config(record: &PairRecord) -> Result<ClientConfig, LockdownError> {
+    let certificate = CertificateDer::from_pem_slice(record.host_certificate.as_ref())
+        .map_err(|e| LockdownError::PairRecord(format!("HostCertificate: {}", e)))?;
+    let key = PrivateKeyDer::from_pem_slice(record.host_private_key.as_ref())
+        .map_err(|e| LockdownError::PairRecord(format!("HostPrivateKey: {}", e)))?;
+    let device_certificate = CertificateDer::from_pem_slice(record.device_certificate.as_ref())
+        .map_err(|e| LockdownError::PairRecord(format!("DeviceCertificate: {}", e)))?;
+
+    let provider = Arc::new(crypto::ring::default_provider());
+    Ok(ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(DeviceVerifier { provider, device_certificate }))
+        .with_client_auth_cert(vec![certificate], key)?)
+}
+
+/// Accepts the certificate the device gave when pairing, the one of the pair record, and
+/// checks the handshake signatures. The device certificate is self-signed, no chain to verify
+#[derive(Debug)]
+struct DeviceVerifier {
+    provider: Arc<CryptoProvider>,
+    device_certificate: CertificateDer<'static>,
+}
+
+impl ServerCertVerifier for DeviceVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.device_certificate.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn string(response: &Dictionary, key: &str) -> Result<String, LockdownError> {
+    response
+        .get(key)
+        .and_then(Value::as_string)
+        .map(str::to_string)
+        .ok_or_else(|| LockdownError::Protocol(format!("reply without {}", key)))
+}
+
+fn flag(response: &Dictionary, key: &str) -> bool {
+    response.get(key).and_then(Value::as_boolean).unwrap_or(false)
+}
+
+/// Write a lockdownd message: big endian length then the XML plist
+pub async fn write_plist<W, T>(writer: &mut W, value: &T) -> Result<(), LockdownError>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+    T: Serialize,
+{
+    let mut payload = Vec::new();
+    plist::to_writer_xml(&mut payload, value)?;
+
+    let mut message = Vec::with_capacity(4 + payload.len());
+    message.extend((payload.len() as u32).to_be_bytes());
+    message.extend(payload);
+    writer.write_all(&message).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a lockdownd message, the plist may be XML or binary
+pub async fn read_plist<R, T>(reader: &mut R) -> Result<T, LockdownError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    T: DeserializeOwned,
+{
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(LockdownError::Protocol(format!("message of {} bytes", length)));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    Ok(plist::from_bytes(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::testing::{FakeUsbmuxd, pair_record, tls_pair_record, usb_device};
+
+    const UDID: &str = "00008030-0001";
+
+    #[tokio::test]
+    async fn query_type_is_lockdown() {
+        let usbmuxd = FakeUsbmuxd::start("lockdown_query_type").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+
+        let mut lockdown = LockdownClient::connect(usbmuxd.path(), &usb_device(3, UDID)).await.unwrap();
+
+        assert_eq!(lockdown.query_type().await.unwrap(), LOCKDOWN_TYPE);
+    }
+
+    #[tokio::test]
+    async fn get_value_reads_device_values() {
+        let usbmuxd = FakeUsbmuxd::start("lockdown_get_value").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.set_value(UDID, "DeviceName", "Test iPhone").await;
+
+        let mut lockdown = LockdownClient::connect(usbmuxd.path(), &usb_device(3, UDID)).await.unwrap();
+
+        let name = lockdown.get_value("DeviceName").await.unwrap();
+        assert_eq!(name.as_ref().and_then(Value::as_string), Some("Test iPhone"));
+        assert_eq!(lockdown.get_value("BasebandVersion").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn start_session_requires_known_host() {
+        let usbmuxd = FakeUsbmuxd::start("lockdown_unknown_host").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, pair_record("HOST-1")).await;
+
+        let lockdown = LockdownClient::connect(usbmuxd.path(), &usb_device(3, UDID)).await.unwrap();
+        let result = lockdown.start_session(&pair_record("HOST-2")).await;
+
+        assert!(matches!(
+            result,
+            Err(LockdownError::Refused { request, error }) if request == "StartSession" && error == "InvalidHostID"
+        ));
+    }
+
+    #[tokio::test]
+    async fn start_service_returns_port() {
+        let usbmuxd = FakeUsbmuxd::start("lockdown_start_service").await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, pair_record("HOST-1")).await;
+
+        let record = pair_record("HOST-1");
+        let mut lockdown = LockdownClient::connect(usbmuxd.path(), &usb_device(3, UDID))
+            .await
+            .unwrap()
+            .start_session(&record)
+            .await
+            .unwrap();
+
+        let service = lockdown.start_service("com.apple.mobile.house_arrest", &record).await.unwrap();
+        assert!(!service.ssl);
+
+        let result = lockdown.start_service("com.apple.unknown", &record).await;
+        assert!(matches!(result, Err(LockdownError::Refused { .. })));
+    }
+
+    #[tokio::test]
+    async fn session_and_service_upgrade_to_tls() {
+        let usbmuxd = FakeUsbmuxd::start("lockdown_tls").await;
+        let (record, server) = tls_pair_record("HOST-1");
+        usbmuxd.enable_ssl(server).await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, record.clone()).await;
+
+        let mut lockdown = LockdownClient::connect(usbmuxd.path(), &usb_device(3, UDID))
+            .await
+            .unwrap()
+            .start_session(&record)
+            .await
+            .unwrap();
+        let service = lockdown.start_service("com.apple.mobile.house_arrest", &record).await.unwrap();
+        assert!(service.ssl);
+
+        // Only a TLS client can talk to the service
+        let mut stream = connect_service(usbmuxd.path(), &usb_device(3, UDID), &record, "com.apple.mobile.house_arrest")
+            .await
+            .unwrap();
+        let mut request = Dictionary::new();
+        request.insert("Command".to_string(), Value::from("VendDocuments"));
+        request.insert("Identifier".to_string(), Value::from("org.videolan.vlc-ios"));
+        write_plist(&mut stream, &request).await.unwrap();
+        let response: Dictionary = read_plist(&mut stream).await.unwrap();
+        assert!(response.contains_key("Error") || response.contains_key("Status"));
+    }
+
+    #[tokio::test]
+    async fn tls_rejects_a_device_certificate_other_than_the_paired_one() {
+        let usbmuxd = FakeUsbmuxd::start("lockdown_tls_other_device").await;
+        let (record, server) = tls_pair_record("HOST-1");
+        usbmuxd.enable_ssl(server).await;
+        usbmuxd.attach(usb_device(3, UDID)).await;
+        usbmuxd.pair(UDID, record.clone()).await;
+        let (other, _) = tls_pair_record("HOST-1");
+
+        let lockdown = LockdownClient::connect(usbmuxd.path(), &usb_device(3, UDID)).await.unwrap();
+        let result = lockdown.start_session(&other).await;
+
+        assert!(matches!(result, Err(LockdownError::PairRecord(_))));
+    }
+
+    #[tokio::test]
+    async fn read_plist_rejects_huge_messages() {
+        let mut data: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+
+        let result: Result<Dictionary, _> = read_plist(&mut data).await;
+
+        assert!(matches!(result, Err(LockdownError::Protocol(_))));
+    }
+}
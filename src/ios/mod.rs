@@ -1,4 +1,16 @@
 pub mod service;
+pub mod usbmux;
+pub mod lockdown;
+// The full AFC file API, the sync only uses part of it
+#[allow(dead_code)]
+pub mod afc;
+#[allow(dead_code)]
+pub mod house_arrest;
+pub mod devices;
 pub mod pairing;
 pub mod mounting;
-pub mod filesystem;
\ No newline at end of file
+pub mod filesystem;
+pub mod plan;
+
+#[cfg(test)]
+mod testing;
@@ -1,33 +1,99 @@
 use tokio::process::Command;
 use std::io;
 use thiserror::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::fs;
 
 #[derive(Debug, Error)]
 pub enum MountingError {
     #[error("IO Error: {0}")]
     Io(#[from] io::Error),
+
+    #[error("ifuse could not mount {bundle_id} on {}: {stderr}", .mountpoint.display())]
+    Ifuse { bundle_id: String, mountpoint: PathBuf, stderr: String },
+
+    #[error("fusermount could not unmount {}: {stderr}", .mountpoint.display())]
+    Fusermount { mountpoint: PathBuf, stderr: String },
+
+    #[error("{} is not mounted, the songs would stay on the computer", .0.display())]
+    NotMounted(PathBuf),
 }
 
-//ifuse --documents org.videolan.vlc-ios VLC
-pub async fn mount_app<P: AsRef<Path>>(bundle_id: &str, mountpoint: P) -> Result<String, MountingError> {
-    _ = Command::new("ifuse")
+//ifuse -u <UDID> --documents org.videolan.vlc-ios VLC/<UDID>
+pub async fn mount_app<P: AsRef<Path>>(bundle_id: &str, udid: &str, mountpoint: P) -> Result<String, MountingError> {
+    let output = Command::new("ifuse")
+    .arg("-u")
+    .arg(udid)
     .arg("--documents")
     .arg(bundle_id)
     .arg(mountpoint.as_ref())
     .output()
     .await?;
+    if !output.status.success() {
+        return Err(MountingError::Ifuse {
+            bundle_id: bundle_id.to_string(),
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
 
     Ok(format!("Mounting {} {} ✅", bundle_id, mountpoint.as_ref().display()))
 }
 
 //fusermount -u /home/nra/VLC
 pub async fn unmount<P: AsRef<Path>>(mountpoint: P) -> Result<String, MountingError> {
-    _ = Command::new("fusermount")
+    let output = Command::new("fusermount")
     .arg("-u")
     .arg(mountpoint.as_ref())
     .output()
     .await?;
+    if !output.status.success() {
+        return Err(MountingError::Fusermount {
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
 
     Ok(format!("Unmounting {} ✅", mountpoint.as_ref().display()))
-}
\ No newline at end of file
+}
+
+/// Check /proc/self/mounts to know if something is mounted on the mountpoint
+pub fn is_mounted<P: AsRef<Path>>(mountpoint: P) -> Result<bool, MountingError> {
+    let mounts = match fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mountpoint = mountpoint.as_ref();
+    let mountpoint = fs::canonicalize(mountpoint).unwrap_or_else(|_| mountpoint.to_path_buf());
+
+    Ok(mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|target| Path::new(&target.replace("\\040", " ")) == mountpoint))
+}
+
+/// Fail unless the mountpoint is mounted: a copy into the bare folder would look done
+pub fn require_mounted<P: AsRef<Path>>(mountpoint: P) -> Result<(), MountingError> {
+    if is_mounted(&mountpoint)? {
+        Ok(())
+    } else {
+        Err(MountingError::NotMounted(mountpoint.as_ref().to_path_buf()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn a_plain_folder_is_not_mounted() {
+        let dir = env::temp_dir().join("monsieur_dlp_not_mounted");
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(!is_mounted(&dir).unwrap());
+        assert!(matches!(require_mounted(&dir), Err(MountingError::NotMounted(path)) if path == dir));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
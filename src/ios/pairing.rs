@@ -1,58 +1,141 @@
-use tokio::process::Command;
-use std::io;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
 use thiserror::Error;
 
+use super::lockdown::{LOCKDOWN_TYPE, LockdownClient, LockdownError};
+use super::usbmux::{Device, DeviceEvent, PairRecord, UsbmuxClient, UsbmuxError};
+
 #[derive(Debug, Error)]
 pub enum PairingError {
-    #[error("IO Error: {0}")]
-    Io(#[from] io::Error),
-    #[error("Command failed: {0}")]
-    CommandError(String),
+    #[error("usbmuxd Error: {0}")]
+    Usbmux(#[from] UsbmuxError),
+
+    #[error("No iOS device connected over USB")]
+    NoDevice,
+
+    #[error("No connected device has the UDID or name '{0}'")]
+    UnknownDevice(String),
+
+    #[error("Device {0} is not trusted, unlock it and accept the \"Trust This Computer\" prompt")]
+    NotTrusted(String),
+
+    #[error("Device {0} was unplugged")]
+    Detached(String),
+
+    #[error("Device {udid} does not answer on lockdownd: {source}")]
+    Unreachable { udid: String, source: LockdownError },
 }
-async fn execute_idevice_command(command: &str) -> Result<String, PairingError> {
-    let output = Command::new("idevicepair")
-        .arg(command)
-        .output()
-        .await?;
-
-    // Check for non-UTF-8 output
-    match String::from_utf8(output.stdout) {
-        Ok(stdout) => {
-            if stdout.contains("ERROR") {
-                Err(PairingError::CommandError(stdout))
-            } else {
-                Ok(stdout)
+
+/// A device paired with this computer
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairedDevice {
+    pub device: Device,
+    /// Keys shared by this computer and the device
+    pub record: PairRecord,
+}
+
+impl fmt::Display for PairedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device {} paired with host {}", self.device.udid, self.record.host_id)
+    }
+}
+
+/// Get the pair record of the device, waiting up to `timeout` for the user to trust
+/// the computer when there is none yet (usbmuxd asks the device as soon as it is plugged)
+pub async fn pair_device_at<P: AsRef<Path>>(
+    socket: P,
+    device: &Device,
+    timeout: Duration,
+) -> Result<PairedDevice, PairingError> {
+    let socket = socket.as_ref();
+    let device = device.clone();
+
+    let mut client = UsbmuxClient::connect(socket).await?;
+    if let Some(record) = client.read_pair_record(&device.udid).await? {
+        return Ok(PairedDevice {
+            device,
+            record,
+        });
+    }
+
+    let mut events = UsbmuxClient::connect(socket).await?.listen().await?;
+    let wait = async {
+        loop {
+            match events.next().await? {
+                DeviceEvent::Paired { device_id } if device_id == device.device_id => return Ok(()),
+                DeviceEvent::Detached { device_id } if device_id == device.device_id => {
+                    return Err(PairingError::Detached(device.udid.clone()));
+                }
+                _ => continue,
             }
         }
-        Err(_) => {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(PairingError::CommandError(format!(
-                "Non-UTF-8 output: {}",
-                stderr
-            )))
-        }
+    };
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(result) => result?,
+        Err(_) => return Err(PairingError::NotTrusted(device.udid)),
+    }
+
+    let mut client = UsbmuxClient::connect(socket).await?;
+    match client.read_pair_record(&device.udid).await? {
+        Some(record) => Ok(PairedDevice {
+            device,
+            record,
+        }),
+        None => Err(PairingError::NotTrusted(device.udid)),
     }
 }
 
-pub async fn pair_device() -> Result<String, PairingError> {
-    execute_idevice_command("pair").await
+/// Check that the device is paired and that its lockdownd answers through usbmuxd
+pub async fn validate_device_at<P: AsRef<Path>>(socket: P, device: &Device) -> Result<PairedDevice, PairingError> {
+    let socket = socket.as_ref();
+    let device = device.clone();
+
+    let mut client = UsbmuxClient::connect(socket).await?;
+    let record = client
+        .read_pair_record(&device.udid)
+        .await?
+        .ok_or_else(|| PairingError::NotTrusted(device.udid.clone()))?;
+
+    let unreachable = |source| PairingError::Unreachable {
+        udid: device.udid.clone(),
+        source,
+    };
+    let mut lockdown = LockdownClient::connect(socket, &device).await.map_err(unreachable)?;
+    match lockdown.query_type().await.map_err(unreachable)? {
+        kind if kind == LOCKDOWN_TYPE => Ok(PairedDevice { device, record }),
+        kind => Err(unreachable(LockdownError::Protocol(format!("QueryType answered {}", kind)))),
+    }
 }
 
-pub async fn validate_device() -> Result<String, PairingError> {
-    execute_idevice_command("validate").await
+pub async fn pair_device(device: &Device) -> Result<PairedDevice, PairingError> {
+    let config = &crate::common::config::get().device;
+    pair_device_at(&config.usbmuxd_socket, device, Duration::from_secs(config.pair_timeout_secs)).await
+}
+
+pub async fn validate_device(device: &Device) -> Result<PairedDevice, PairingError> {
+    validate_device_at(&crate::common::config::get().device.usbmuxd_socket, device).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ios::devices::connected_devices;
+    use crate::ios::lockdown::LOCKDOWN_PORT;
+    use crate::ios::testing::{FakeUsbmuxd, pair_record, usb_device};
     use tokio;
 
     #[tokio::test]
     async fn pair_device_output() {
-        let result = pair_device().await;
+        let result = match connected_devices().await.map(|devices| devices.into_iter().next()) {
+            Ok(Some(details)) => pair_device(&details.device).await,
+            Ok(None) => Err(PairingError::NoDevice),
+            Err(e) => Err(e),
+        };
         match result {
             Ok(output) => {
-                assert!(!output.is_empty());
+                assert!(!output.to_string().is_empty());
                 println!("Pair success: {output}");
             }
             Err(e) => {
@@ -63,10 +146,14 @@ mod tests {
 
     #[tokio::test]
     async fn validate_device_output() {
-        let result = validate_device().await;
+        let result = match connected_devices().await.map(|devices| devices.into_iter().next()) {
+            Ok(Some(details)) => validate_device(&details.device).await,
+            Ok(None) => Err(PairingError::NoDevice),
+            Err(e) => Err(e),
+        };
         match result {
             Ok(output) => {
-                assert!(!output.is_empty());
+                assert!(!output.to_string().is_empty());
                 println!("Validate success: {output}");
             }
             Err(e) => {
@@ -74,4 +161,95 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn pair_device_unplugged_while_waiting_fails() {
+        let usbmuxd = FakeUsbmuxd::start("pair_detached").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let device = usb_device(3, "00008030-0001");
+        let pairing = pair_device_at(usbmuxd.path(), &device, Duration::from_secs(5));
+        let unplug = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            usbmuxd.detach(3).await;
+        };
+        let (result, _) = tokio::join!(pairing, unplug);
+
+        assert!(matches!(result, Err(PairingError::Detached(udid)) if udid == "00008030-0001"));
+    }
+
+    #[tokio::test]
+    async fn pair_devices_independently() {
+        let usbmuxd = FakeUsbmuxd::start("pair_two").await;
+        usbmuxd.attach(usb_device(3, "UDID-A")).await;
+        usbmuxd.attach(usb_device(4, "UDID-B")).await;
+        usbmuxd.pair("UDID-B", pair_record("HOST-1")).await;
+
+        let timeout = Duration::from_millis(50);
+        let first = pair_device_at(usbmuxd.path(), &usb_device(3, "UDID-A"), timeout).await;
+        let second = pair_device_at(usbmuxd.path(), &usb_device(4, "UDID-B"), timeout).await;
+
+        assert!(matches!(first, Err(PairingError::NotTrusted(udid)) if udid == "UDID-A"));
+        assert_eq!(second.unwrap().device.udid, "UDID-B");
+    }
+
+    #[tokio::test]
+    async fn pair_device_with_pair_record_is_paired() {
+        let usbmuxd = FakeUsbmuxd::start("pair_paired").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+        usbmuxd.pair("00008030-0001", pair_record("HOST-1")).await;
+
+        let paired = pair_device_at(usbmuxd.path(), &usb_device(3, "00008030-0001"), Duration::from_millis(10)).await.unwrap();
+
+        assert_eq!(paired.device.udid, "00008030-0001");
+        assert_eq!(paired.record.host_id, "HOST-1");
+    }
+
+    #[tokio::test]
+    async fn pair_device_waits_for_trust() {
+        let usbmuxd = FakeUsbmuxd::start("pair_wait").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let device = usb_device(3, "00008030-0001");
+        let pairing = pair_device_at(usbmuxd.path(), &device, Duration::from_secs(5));
+        let trust = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            usbmuxd.pair("00008030-0001", pair_record("HOST-1")).await;
+        };
+        let (paired, _) = tokio::join!(pairing, trust);
+
+        assert_eq!(paired.unwrap().record.host_id, "HOST-1");
+    }
+
+    #[tokio::test]
+    async fn pair_device_not_trusted_in_time() {
+        let usbmuxd = FakeUsbmuxd::start("pair_timeout").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let result = pair_device_at(usbmuxd.path(), &usb_device(3, "00008030-0001"), Duration::from_millis(50)).await;
+
+        assert!(matches!(result, Err(PairingError::NotTrusted(udid)) if udid == "00008030-0001"));
+    }
+
+    #[tokio::test]
+    async fn validate_device_connects_to_lockdownd() {
+        let usbmuxd = FakeUsbmuxd::start("validate").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+        usbmuxd.pair("00008030-0001", pair_record("HOST-1")).await;
+
+        let paired = validate_device_at(usbmuxd.path(), &usb_device(3, "00008030-0001")).await.unwrap();
+
+        assert_eq!(paired.record.host_id, "HOST-1");
+        assert_eq!(usbmuxd.connected_ports().await, vec![(3, LOCKDOWN_PORT)]);
+    }
+
+    #[tokio::test]
+    async fn validate_device_not_paired_fails() {
+        let usbmuxd = FakeUsbmuxd::start("validate_not_paired").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let result = validate_device_at(usbmuxd.path(), &usb_device(3, "00008030-0001")).await;
+
+        assert!(matches!(result, Err(PairingError::NotTrusted(_))));
+    }
 }
@@ -0,0 +1,305 @@
+//! Differential sync: compare the app Documents with the library and work out the
+//! uploads, renames and deletions bringing the device to it. A file of the right name and
+//! size is taken as the song, the device files are only read back to recognise a moved
+//! or duplicate song by the hash the library recorded.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use super::filesystem::{self, DeviceStorage, FileSystemError};
+
+/// A song of the library as it should be on the device
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wanted {
+    pub song_id: i64,
+    /// Its path in the app Documents
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 of the file, in hex
+    pub checksum: String,
+    /// The file on the computer, None once deleted
+    pub local: Option<PathBuf>,
+    /// Priority of the song in the songs file, the highest uploaded first
+    pub priority: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Rename { song_id: i64, from: String, to: String },
+    Delete { name: String, reason: String },
+    Upload { song_id: i64, local: PathBuf, name: String },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Rename { from, to, .. } => write!(f, "rename {} to {}", from, to),
+            Action::Delete { name, reason } => write!(f, "delete {} ({})", name, reason),
+            Action::Upload { local, name, .. } => write!(f, "upload {} to {}", local.display(), name),
+        }
+    }
+}
+
+/// What the device needs to match the library
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    /// The songs already on the device under their name
+    pub unchanged: Vec<i64>,
+    /// The renames first, then the deletions freeing room, then the uploads
+    pub actions: Vec<Action>,
+    /// The songs neither on the device nor on the computer anymore
+    pub missing: Vec<String>,
+    /// The uploads taken out of the actions for want of room on the device
+    pub left_out: Vec<Action>,
+}
+
+impl Plan {
+    /// The local files and device names of the uploads, the highest priority first
+    pub fn uploads(&self) -> Vec<(PathBuf, String)> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Upload { local, name, .. } => Some((local.clone(), name.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Take the uploads of these files out of the actions
+    pub fn leave_out(&mut self, files: &[(PathBuf, String)]) {
+        let (left, kept) = self.actions.drain(..).partition(|action| match action {
+            Action::Upload { local, name, .. } => files.iter().any(|file| file.0 == *local && file.1 == *name),
+            _ => false,
+        });
+        self.actions = kept;
+        self.left_out = left;
+    }
+}
+
+/// The outcome of an action of the plan
+#[derive(Debug)]
+pub struct Applied {
+    pub action: Action,
+    pub result: Result<(), FileSystemError>,
+}
+
+/// Compare the device files with the wanted songs. With `prune` the files the library
+/// does not know are deleted too, else only the duplicates and interrupted copies are.
+pub async fn plan<D>(wanted: &[Wanted], storage: &mut D, prune: bool) -> Result<Plan, FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    let files = filesystem::walk(storage).await?;
+    let sizes: HashMap<&str, u64> = files.iter().map(|(name, size)| (name.as_str(), *size)).collect();
+    let mut checksums: HashMap<String, String> = HashMap::new();
+    let mut claimed: HashSet<&str> = HashSet::new();
+    let mut plan = Plan::default();
+    let (mut renames, mut uploads) = (vec![], vec![]);
+
+    let mut unplaced = vec![];
+    for song in wanted {
+        if sizes.get(song.name.as_str()) == Some(&song.size) {
+            claimed.insert(&song.name);
+            plan.unchanged.push(song.song_id);
+        } else {
+            unplaced.push(song);
+        }
+    }
+
+    unplaced.sort_by_key(|song| Reverse(song.priority));
+    for song in unplaced {
+        let mut moved = None;
+        for (name, size) in &files {
+            if *size != song.size || claimed.contains(name.as_str()) || filesystem::is_partial(name) {
+                continue;
+            }
+            if checksum(storage, &mut checksums, name).await? == song.checksum {
+                moved = Some(name);
+                break;
+            }
+        }
+
+        // The file taking the name of the song is replaced
+        claimed.insert(&song.name);
+        match (moved, &song.local) {
+            (Some(from), _) => {
+                claimed.insert(from);
+                renames.push(Action::Rename { song_id: song.song_id, from: from.clone(), to: song.name.clone() });
+            }
+            (None, Some(local)) => {
+                uploads.push(Action::Upload { song_id: song.song_id, local: local.clone(), name: song.name.clone() })
+            }
+            (None, None) => plan.missing.push(song.name.clone()),
+        }
+    }
+
+    let placed: HashMap<&str, &str> = wanted
+        .iter()
+        .filter(|song| !plan.missing.contains(&song.name))
+        .map(|song| (song.checksum.as_str(), song.name.as_str()))
+        .collect();
+    let sized: HashSet<u64> = wanted.iter().map(|song| song.size).collect();
+    let mut deletes = vec![];
+    for (name, size) in &files {
+        if claimed.contains(name.as_str()) {
+            continue;
+        }
+        let file = name.rsplit('/').next().unwrap_or(name);
+        let reason = if filesystem::is_partial(name) {
+            "interrupted copy".to_string()
+        } else if file.starts_with('.') {
+            continue;
+        } else if sized.contains(size)
+            && let Some(original) = placed.get(checksum(storage, &mut checksums, name).await?.as_str())
+        {
+            format!("duplicate of {}", original)
+        } else if prune {
+            "not in the library".to_string()
+        } else {
+            continue;
+        };
+        deletes.push(Action::Delete { name: name.clone(), reason });
+    }
+
+    plan.actions = renames.into_iter().chain(deletes).chain(uploads).collect();
+    Ok(plan)
+}
+
+/// The hash of the device file, read once
+async fn checksum<D>(storage: &mut D, checksums: &mut HashMap<String, String>, name: &str) -> Result<String, FileSystemError>
+where
+    D: DeviceStorage + ?Sized,
+{
+    if let Some(checksum) = checksums.get(name) {
+        return Ok(checksum.clone());
+    }
+    let checksum = filesystem::device_checksum(storage, name).await?;
+    checksums.insert(name.to_string(), checksum.clone());
+    Ok(checksum)
+}
+
+/// Run the actions in order, the uploads verified. A failure only loses its action.
+pub async fn apply<D>(actions: Vec<Action>, storage: &mut D) -> Vec<Applied>
+where
+    D: DeviceStorage + ?Sized,
+{
+    let mut applied = vec![];
+    for action in actions {
+        let result = match &action {
+            Action::Rename { from, to, .. } => async {
+                if let Some((folder, _)) = to.rsplit_once('/') {
+                    storage.make_dir(folder).await?;
+                }
+                storage.rename(from, to).await
+            }
+            .await,
+            Action::Delete { name, .. } => storage.remove(name).await,
+            Action::Upload { local, name, .. } => filesystem::copy_verified(local, name, storage).await.map(|_| ()),
+        };
+        applied.push(Applied { action, result });
+    }
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::filesystem::MountedDir;
+    use sha2::{Digest, Sha256};
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    fn device_dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("monsieur_dlp_plan_{}", name));
+        _ = fs::remove_dir_all(&dir);
+        for (file, contents) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn wanted(song_id: i64, name: &str, contents: &[u8], local: Option<&Path>) -> Wanted {
+        Wanted {
+            song_id,
+            name: name.to_string(),
+            size: contents.len() as u64,
+            checksum: hex::encode(Sha256::digest(contents)),
+            local: local.map(Path::to_path_buf),
+            priority: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn plan_renames_deletes_and_uploads() {
+        let device = device_dir(
+            "device",
+            &[
+                ("A.mp3", b"aaa"),
+                ("Old/B.mp3", b"bbbb"),
+                ("B copy.mp3", b"bbbb"),
+                ("Mine.mp3", b"mine"),
+                (".C.mp3.part", b"c"),
+            ],
+        );
+        let local = device_dir("local", &[("C.mp3", b"cc")]);
+        let mut storage = MountedDir::new(&device);
+        let songs = [
+            wanted(1, "A.mp3", b"aaa", None),
+            wanted(2, "Band/B.mp3", b"bbbb", None),
+            wanted(3, "C.mp3", b"cc", Some(&local.join("C.mp3"))),
+            wanted(4, "D.mp3", b"dd", None),
+        ];
+
+        let plan = plan(&songs, &mut storage, false).await.unwrap();
+
+        assert_eq!(plan.unchanged, vec![1]);
+        assert_eq!(plan.missing, vec!["D.mp3"]);
+        let actions: Vec<String> = plan.actions.iter().map(Action::to_string).collect();
+        assert_eq!(
+            actions,
+            vec![
+                "rename B copy.mp3 to Band/B.mp3".to_string(),
+                "delete .C.mp3.part (interrupted copy)".to_string(),
+                "delete Old/B.mp3 (duplicate of Band/B.mp3)".to_string(),
+                format!("upload {} to C.mp3", local.join("C.mp3").display()),
+            ]
+        );
+
+        let applied = apply(plan.actions, &mut storage).await;
+        assert!(applied.iter().all(|applied| applied.result.is_ok()));
+        assert_eq!(
+            filesystem::walk(&mut storage).await.unwrap(),
+            vec![("A.mp3".to_string(), 3), ("Band/B.mp3".to_string(), 4), ("C.mp3".to_string(), 2), ("Mine.mp3".to_string(), 4)]
+        );
+        let pruned = super::plan(&songs, &mut storage, true).await.unwrap();
+        assert_eq!(pruned.actions, vec![Action::Delete { name: "Mine.mp3".into(), reason: "not in the library".into() }]);
+        fs::remove_dir_all(device).unwrap();
+        fs::remove_dir_all(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn plan_uploads_by_priority_over_a_different_file() {
+        let device = device_dir("changed", &[("A.mp3", b"old")]);
+        let local = device_dir("changed_local", &[("A.mp3", b"new!")]);
+        let mut storage = MountedDir::new(&device);
+        let songs = [
+            wanted(1, "A.mp3", b"new!", Some(&local.join("A.mp3"))),
+            Wanted { priority: 5, ..wanted(2, "B.mp3", b"new!", Some(&local.join("A.mp3"))) },
+        ];
+
+        let mut plan = plan(&songs, &mut storage, true).await.unwrap();
+
+        let upload = |song_id, name: &str| Action::Upload { song_id, local: local.join("A.mp3"), name: name.into() };
+        assert_eq!(plan.actions, vec![upload(2, "B.mp3"), upload(1, "A.mp3")]);
+        plan.leave_out(&[(local.join("A.mp3"), "A.mp3".to_string())]);
+        assert_eq!((plan.actions, plan.left_out), (vec![upload(2, "B.mp3")], vec![upload(1, "A.mp3")]));
+        fs::remove_dir_all(device).unwrap();
+        fs::remove_dir_all(local).unwrap();
+    }
+}
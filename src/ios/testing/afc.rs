@@ -0,0 +1,257 @@
+//! An AFC server keeping the files in memory
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+
+use crate::ios::afc::*;
+
+const TOTAL_BYTES: u64 = 64 * 1024 * 1024 * 1024;
+
+struct OpenFile {
+    path: String,
+    position: usize,
+}
+
+struct Files {
+    files: BTreeMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+    free_bytes: u64,
+    /// Every write loses its last byte, as over a bad cable
+    flaky: bool,
+}
+
+/// The container of an app, shared by every connection to it
+#[derive(Clone)]
+pub struct FakeAfc {
+    files: Arc<Mutex<Files>>,
+}
+
+impl FakeAfc {
+    /// An empty container with its Documents folder
+    pub fn new() -> Self {
+        let dirs = BTreeSet::from(["/".to_string(), "/Documents".to_string()]);
+        Self {
+            files: Arc::new(Mutex::new(Files {
+                files: BTreeMap::new(),
+                dirs,
+                free_bytes: TOTAL_BYTES / 2,
+                flaky: false,
+            })),
+        }
+    }
+
+    /// A client stream served by a new task
+    pub fn connect(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(256 * 1024);
+        tokio::spawn(self.clone().serve(server));
+        client
+    }
+
+    /// Add a file and its folders
+    pub fn put(&self, path: &str, contents: &[u8]) {
+        let mut files = self.files.lock().unwrap();
+        let mut dir = parent(path);
+        while dir.len() > 1 && files.dirs.insert(dir.clone()) {
+            dir = parent(&dir);
+        }
+        files.files.insert(path.to_string(), contents.to_vec());
+    }
+
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().files.get(path).cloned()
+    }
+
+    pub fn set_free_bytes(&self, free_bytes: u64) {
+        self.files.lock().unwrap().free_bytes = free_bytes;
+    }
+
+    pub fn set_flaky(&self, flaky: bool) {
+        self.files.lock().unwrap().flaky = flaky;
+    }
+
+    /// Answer AFC packets until the client leaves
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(self, mut stream: S) {
+        let mut handles = HashMap::new();
+        let mut next_handle = 1;
+
+        while let Ok(packet) = read_packet(&mut stream).await {
+            let reply = self.handle(&packet, &mut handles, &mut next_handle);
+            let reply = Packet {
+                number: packet.number,
+                ..reply
+            };
+            if write_packet(&mut stream, &reply).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle(&self, packet: &Packet, handles: &mut HashMap<u64, OpenFile>, next_handle: &mut u64) -> Packet {
+        let mut files = self.files.lock().unwrap();
+        let path = || c_strings(&packet.header).into_iter().next().unwrap_or_default();
+        let handle = || u64::from_le_bytes(packet.header[..8].try_into().unwrap());
+
+        match packet.operation {
+            OP_READ_DIR => {
+                let dir = path();
+                if !files.dirs.contains(&dir) {
+                    return status(STATUS_OBJECT_NOT_FOUND);
+                }
+                let mut names = vec![".".to_string(), "..".to_string()];
+                names.extend(files.children(&dir));
+                data(names.iter().flat_map(|name| c_string(name)).collect())
+            }
+            OP_GET_FILE_INFO => {
+                let path = path();
+                let (size, kind) = match files.files.get(&path) {
+                    Some(contents) => (contents.len(), "S_IFREG"),
+                    None if files.dirs.contains(&path) => (0, "S_IFDIR"),
+                    None => return status(STATUS_OBJECT_NOT_FOUND),
+                };
+                data(key_values(&[
+                    ("st_size", size.to_string()),
+                    ("st_blocks", size.div_ceil(512).to_string()),
+                    ("st_nlink", "1".to_string()),
+                    ("st_ifmt", kind.to_string()),
+                    ("st_mtime", "1700000000000000000".to_string()),
+                    ("st_birthtime", "1700000000000000000".to_string()),
+                ]))
+            }
+            OP_GET_DEVICE_INFO => data(key_values(&[
+                ("Model", "iPhone12,1".to_string()),
+                ("FSTotalBytes", TOTAL_BYTES.to_string()),
+                ("FSFreeBytes", files.free_bytes.to_string()),
+                ("FSBlockSize", "4096".to_string()),
+            ])),
+            OP_MAKE_DIR => {
+                let mut dir = String::new();
+                for part in path().split('/').filter(|part| !part.is_empty()) {
+                    dir = format!("{}/{}", dir, part);
+                    files.dirs.insert(dir.clone());
+                }
+                status(STATUS_SUCCESS)
+            }
+            OP_REMOVE_PATH => {
+                let path = path();
+                if files.files.remove(&path).is_some() {
+                    status(STATUS_SUCCESS)
+                } else if files.dirs.contains(&path) && files.children(&path).is_empty() {
+                    files.dirs.remove(&path);
+                    status(STATUS_SUCCESS)
+                } else {
+                    status(STATUS_OBJECT_NOT_FOUND)
+                }
+            }
+            OP_RENAME_PATH => {
+                let paths = c_strings(&packet.header);
+                let (Some(from), Some(to)) = (paths.first(), paths.get(1)) else {
+                    return status(7);
+                };
+                match files.files.remove(from) {
+                    Some(contents) => {
+                        files.files.insert(to.clone(), contents);
+                        status(STATUS_SUCCESS)
+                    }
+                    None => status(STATUS_OBJECT_NOT_FOUND),
+                }
+            }
+            OP_FILE_OPEN => {
+                let mode = handle();
+                let path = c_strings(&packet.header[8..]).into_iter().next().unwrap_or_default();
+                let parent = parent(&path);
+                if !files.dirs.contains(&parent) {
+                    return status(STATUS_OBJECT_NOT_FOUND);
+                }
+                if mode == MODE_READ_ONLY {
+                    if !files.files.contains_key(&path) {
+                        return status(STATUS_OBJECT_NOT_FOUND);
+                    }
+                } else {
+                    files.files.insert(path.clone(), Vec::new());
+                }
+                handles.insert(*next_handle, OpenFile { path, position: 0 });
+                *next_handle += 1;
+                Packet {
+                    operation: OP_FILE_OPEN_RESULT,
+                    header: (*next_handle - 1).to_le_bytes().to_vec(),
+                    ..Packet::default()
+                }
+            }
+            OP_FILE_WRITE => {
+                let Some(file) = handles.get(&handle()) else {
+                    return status(7);
+                };
+                let written = packet.payload.len() as u64;
+                if written > files.free_bytes {
+                    return status(STATUS_NO_SPACE_LEFT);
+                }
+                files.free_bytes -= written;
+                let kept = if files.flaky { packet.payload.len().saturating_sub(1) } else { packet.payload.len() };
+                files.files.entry(file.path.clone()).or_default().extend(&packet.payload[..kept]);
+                status(STATUS_SUCCESS)
+            }
+            OP_FILE_READ => {
+                let length = u64::from_le_bytes(packet.header[8..16].try_into().unwrap()) as usize;
+                let Some(file) = handles.get_mut(&handle()) else {
+                    return status(7);
+                };
+                let contents = files.files.get(&file.path).map(Vec::as_slice).unwrap_or_default();
+                let end = (file.position + length).min(contents.len());
+                let start = file.position.min(end);
+                file.position = end;
+                data(contents[start..end].to_vec())
+            }
+            OP_FILE_CLOSE => match handles.remove(&handle()) {
+                Some(_) => status(STATUS_SUCCESS),
+                None => status(7),
+            },
+            _ => status(15),
+        }
+    }
+}
+
+impl Files {
+    fn children(&self, dir: &str) -> Vec<String> {
+        let prefix = if dir == "/" { "/".to_string() } else { format!("{}/", dir) };
+        self.dirs
+            .iter()
+            .chain(self.files.keys())
+            .filter_map(|path| path.strip_prefix(&prefix))
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+fn parent(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/".to_string(),
+        Some((parent, _)) => parent.to_string(),
+    }
+}
+
+fn status(code: u64) -> Packet {
+    Packet {
+        operation: OP_STATUS,
+        header: code.to_le_bytes().to_vec(),
+        ..Packet::default()
+    }
+}
+
+fn data(payload: Vec<u8>) -> Packet {
+    Packet {
+        operation: OP_DATA,
+        payload,
+        ..Packet::default()
+    }
+}
+
+fn key_values(values: &[(&str, String)]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|(key, value)| [c_string(key), c_string(value)])
+        .flatten()
+        .collect()
+}
@@ -0,0 +1,178 @@
+//! lockdownd and house_arrest answering like a device would
+
+use std::sync::Arc;
+
+use plist::{Dictionary, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+use super::State;
+use crate::ios::house_arrest;
+use crate::ios::lockdown::{LOCKDOWN_TYPE, read_plist, write_plist};
+use crate::ios::usbmux::PairRecord;
+
+/// Port returned by StartService for house_arrest
+pub const HOUSE_ARREST_PORT: u16 = 49152;
+
+/// A pair record with real keys, and the TLS config of the device matching it
+pub fn tls_pair_record(host_id: &str) -> (PairRecord, Arc<ServerConfig>) {
+    let device = rcgen::generate_simple_self_signed(vec!["device".to_string()]).unwrap();
+    let host = rcgen::generate_simple_self_signed(vec!["host".to_string()]).unwrap();
+
+    let record = PairRecord {
+        host_certificate: host.cert.pem().into_bytes().into(),
+        host_private_key: host.signing_key.serialize_pem().into_bytes().into(),
+        device_certificate: device.cert.pem().into_bytes().into(),
+        root_certificate: device.cert.pem().into_bytes().into(),
+        ..super::pair_record(host_id)
+    };
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(device.signing_key.serialize_der()));
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![CertificateDer::clone(device.cert.der())], key)
+        .unwrap();
+
+    (record, Arc::new(config))
+}
+
+/// Answer lockdownd requests of the device `device_id`
+pub async fn serve_lockdown<S>(mut stream: S, state: Arc<Mutex<State>>, device_id: u32)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(upgrade) = serve_requests(&mut stream, &state, device_id, false).await else {
+        return;
+    };
+    if let Ok(mut stream) = TlsAcceptor::from(upgrade).accept(stream).await {
+        serve_requests(&mut stream, &state, device_id, true).await;
+    }
+}
+
+/// Returns the TLS config to switch to when a session asks for it
+async fn serve_requests<S>(
+    stream: &mut S,
+    state: &Arc<Mutex<State>>,
+    device_id: u32,
+    mut session: bool,
+) -> Option<Arc<ServerConfig>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Ok(request) = read_plist::<_, Dictionary>(stream).await {
+        let name = request.get("Request").and_then(Value::as_string).unwrap_or_default().to_string();
+        let mut reply = Dictionary::new();
+        reply.insert("Request".to_string(), Value::from(name.as_str()));
+        let mut upgrade = None;
+
+        match name.as_str() {
+            "QueryType" => {
+                reply.insert("Type".to_string(), Value::from(LOCKDOWN_TYPE));
+            }
+            "GetValue" => {
+                let state = state.lock().await;
+                let key = request.get("Key").and_then(Value::as_string).unwrap_or_default();
+                let value = state
+                    .devices
+                    .iter()
+                    .find(|device| device.device_id == device_id)
+                    .and_then(|device| state.values.get(&device.udid))
+                    .and_then(|values| values.get(key));
+                if let Some(value) = value {
+                    reply.insert("Value".to_string(), value.clone());
+                }
+            }
+            "StartSession" => {
+                let state = state.lock().await;
+                let host_id = request.get("HostID").and_then(Value::as_string);
+                let known = state
+                    .devices
+                    .iter()
+                    .find(|device| device.device_id == device_id)
+                    .and_then(|device| state.records.get(&device.udid))
+                    .is_some_and(|record| Some(record.host_id.as_str()) == host_id);
+                if known {
+                    session = true;
+                    reply.insert("SessionID".to_string(), Value::from("SESSION"));
+                    reply.insert("EnableSessionSSL".to_string(), Value::from(state.ssl.is_some()));
+                    upgrade = state.ssl.clone();
+                } else {
+                    reply.insert("Error".to_string(), Value::from("InvalidHostID"));
+                }
+            }
+            "StartService" => {
+                let service = request.get("Service").and_then(Value::as_string);
+                if !session {
+                    reply.insert("Error".to_string(), Value::from("NoRunningSession"));
+                } else if service == Some(house_arrest::SERVICE) {
+                    reply.insert("Service".to_string(), Value::from(house_arrest::SERVICE));
+                    reply.insert("Port".to_string(), Value::from(HOUSE_ARREST_PORT as u64));
+                    let ssl = state.lock().await.ssl.is_some();
+                    reply.insert("EnableServiceSSL".to_string(), Value::from(ssl));
+                } else {
+                    reply.insert("Error".to_string(), Value::from("InvalidService"));
+                }
+            }
+            _ => {
+                reply.insert("Error".to_string(), Value::from("UnknownRequest"));
+            }
+        }
+
+        if write_plist(stream, &reply).await.is_err() {
+            return None;
+        }
+        if upgrade.is_some() {
+            return upgrade;
+        }
+    }
+    None
+}
+
+/// Vend the container of an installed app, then serve AFC on it
+pub async fn serve_house_arrest<S>(stream: S, state: Arc<Mutex<State>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ssl = state.lock().await.ssl.clone();
+    match ssl {
+        Some(config) => {
+            if let Ok(stream) = TlsAcceptor::from(config).accept(stream).await {
+                vend(stream, state).await;
+            }
+        }
+        None => vend(stream, state).await,
+    }
+}
+
+async fn vend<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, state: Arc<Mutex<State>>) {
+    let Ok(request) = read_plist::<_, Dictionary>(&mut stream).await else {
+        return;
+    };
+    let command = request.get("Command").and_then(Value::as_string);
+    let identifier = request.get("Identifier").and_then(Value::as_string).unwrap_or_default();
+    let app = state.lock().await.apps.get(identifier).cloned();
+
+    let mut reply = Dictionary::new();
+    match (command, app) {
+        (Some("VendDocuments"), Some(app)) => {
+            reply.insert("Status".to_string(), Value::from("Complete"));
+            if write_plist(&mut stream, &reply).await.is_ok() {
+                app.serve(stream).await;
+            }
+        }
+        (Some("VendDocuments"), None) => {
+            reply.insert("Error".to_string(), Value::from("ApplicationLookupFailed"));
+            _ = write_plist(&mut stream, &reply).await;
+        }
+        _ => {
+            reply.insert("Error".to_string(), Value::from("UnknownCommand"));
+            _ = write_plist(&mut stream, &reply).await;
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! In-process fakes of the iOS daemons, to test the protocols without a device
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use plist::{Dictionary, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ServerConfig;
+
+use super::lockdown::LOCKDOWN_PORT;
+use super::usbmux::{ConnectionType, Device, PairRecord, read_message, write_message};
+
+mod afc;
+mod lockdown;
+
+pub use afc::FakeAfc;
+pub use lockdown::tls_pair_record;
+
+#[derive(Default)]
+struct State {
+    devices: Vec<Device>,
+    records: HashMap<String, PairRecord>,
+    listeners: Vec<UnboundedSender<Dictionary>>,
+    connected_ports: Vec<(u32, u16)>,
+    /// Containers of the installed apps by bundle id
+    apps: HashMap<String, FakeAfc>,
+    /// lockdownd values of the devices by udid
+    values: HashMap<String, Dictionary>,
+    /// Sessions and services use TLS when set
+    ssl: Option<Arc<ServerConfig>>,
+}
+
+/// A usbmuxd daemon listening on a socket of the temp directory.
+/// Every device answers lockdownd and house_arrest requests, connections
+/// to other ports echo back what they receive.
+pub struct FakeUsbmuxd {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl FakeUsbmuxd {
+    /// `name` makes the socket path unique to the test
+    pub async fn start(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("monsieur_dlp_usbmuxd_{}.sock", name));
+        if path.exists() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let task_state = Arc::clone(&state);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, Arc::clone(&task_state)));
+            }
+        });
+
+        Self { path, state, task }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Plug a device, named "iPhone" and running iOS 17.5 unless set otherwise
+    pub async fn attach(&self, device: Device) {
+        let mut state = self.state.lock().await;
+        let values = state.values.entry(device.udid.clone()).or_default();
+        for (key, value) in [("DeviceName", "iPhone"), ("ProductVersion", "17.5")] {
+            if !values.contains_key(key) {
+                values.insert(key.to_string(), Value::from(value));
+            }
+        }
+        state.broadcast(device_dictionary(&device));
+        state.devices.push(device);
+    }
+
+    /// Unplug a device
+    pub async fn detach(&self, device_id: u32) {
+        let mut state = self.state.lock().await;
+        state.devices.retain(|device| device.device_id != device_id);
+        state.broadcast(event_dictionary("Detached", device_id));
+    }
+
+    /// Save the pair record of a device, as usbmuxd does once the user trusts the computer
+    pub async fn pair(&self, udid: &str, record: PairRecord) {
+        let mut state = self.state.lock().await;
+        state.records.insert(udid.to_string(), record);
+        let device_id = state
+            .devices
+            .iter()
+            .find(|device| device.udid == udid)
+            .map(|device| device.device_id);
+        if let Some(device_id) = device_id {
+            state.broadcast(event_dictionary("Paired", device_id));
+        }
+    }
+
+    /// Set a lockdownd value of the device
+    pub async fn set_value(&self, udid: &str, key: &str, value: &str) {
+        let mut state = self.state.lock().await;
+        let values = state.values.entry(udid.to_string()).or_default();
+        values.insert(key.to_string(), Value::from(value));
+    }
+
+    /// Install an app on every device, returns its container
+    pub async fn install_app(&self, bundle_id: &str) -> FakeAfc {
+        let afc = FakeAfc::new();
+        self.state.lock().await.apps.insert(bundle_id.to_string(), afc.clone());
+        afc
+    }
+
+    /// Make lockdownd ask for TLS sessions and services
+    pub async fn enable_ssl(&self, config: Arc<ServerConfig>) {
+        self.state.lock().await.ssl = Some(config);
+    }
+
+    /// Every (device id, port) a client connected to
+    pub async fn connected_ports(&self) -> Vec<(u32, u16)> {
+        self.state.lock().await.connected_ports.clone()
+    }
+}
+
+impl Drop for FakeUsbmuxd {
+    fn drop(&mut self) {
+        self.task.abort();
+        _ = fs::remove_file(&self.path);
+    }
+}
+
+impl State {
+    fn broadcast(&mut self, event: Dictionary) {
+        self.listeners.retain(|listener| listener.send(event.clone()).is_ok());
+    }
+}
+
+/// A USB device as seen by usbmuxd
+pub fn usb_device(device_id: u32, udid: &str) -> Device {
+    Device {
+        device_id,
+        udid: udid.to_string(),
+        connection_type: ConnectionType::Usb,
+        product_id: Some(0x12a8),
+    }
+}
+
+/// A pair record with fake keys
+pub fn pair_record(host_id: &str) -> PairRecord {
+    PairRecord {
+        host_id: host_id.to_string(),
+        system_buid: "SYSTEM-BUID".to_string(),
+        host_certificate: b"host certificate".to_vec().into(),
+        host_private_key: b"host private key".to_vec().into(),
+        device_certificate: b"device certificate".to_vec().into(),
+        root_certificate: b"root certificate".to_vec().into(),
+        root_private_key: None,
+        escrow_bag: None,
+        wifi_mac_address: Some("00:00:00:00:00:00".to_string()),
+    }
+}
+
+async fn serve(mut stream: UnixStream, state: Arc<Mutex<State>>) {
+    while let Ok((tag, payload)) = read_message(&mut stream).await {
+        let Ok(request) = plist::from_bytes::<Dictionary>(&payload) else {
+            return;
+        };
+        let message_type = request
+            .get("MessageType")
+            .and_then(Value::as_string)
+            .unwrap_or_default();
+
+        match message_type {
+            "ListDevices" => {
+                let devices = state.lock().await.devices.iter().map(device_dictionary).map(Value::from).collect();
+                let mut reply = Dictionary::new();
+                reply.insert("DeviceList".to_string(), Value::Array(devices));
+                send(&mut stream, tag, reply).await;
+            }
+            "ReadPairRecord" => {
+                let udid = request
+                    .get("PairRecordID")
+                    .and_then(Value::as_string)
+                    .unwrap_or_default();
+                let record = state.lock().await.records.get(udid).cloned();
+                match record {
+                    Some(record) => {
+                        let mut data = Vec::new();
+                        plist::to_writer_xml(&mut data, &record).unwrap();
+                        let mut reply = Dictionary::new();
+                        reply.insert("PairRecordData".to_string(), Value::Data(data));
+                        send(&mut stream, tag, reply).await;
+                    }
+                    None => send(&mut stream, tag, result_dictionary(2)).await,
+                }
+            }
+            "Listen" => {
+                send(&mut stream, tag, result_dictionary(0)).await;
+                let (sender, mut receiver) = mpsc::unbounded_channel();
+                {
+                    let mut state = state.lock().await;
+                    for device in &state.devices {
+                        sender.send(device_dictionary(device)).unwrap();
+                    }
+                    state.listeners.push(sender);
+                }
+                while let Some(event) = receiver.recv().await {
+                    send(&mut stream, 0, event).await;
+                }
+                return;
+            }
+            "Connect" => {
+                let device_id = request
+                    .get("DeviceID")
+                    .and_then(Value::as_unsigned_integer)
+                    .unwrap_or_default() as u32;
+                let port = u16::from_be(
+                    request
+                        .get("PortNumber")
+                        .and_then(Value::as_unsigned_integer)
+                        .unwrap_or_default() as u16,
+                );
+
+                let known = {
+                    let mut state = state.lock().await;
+                    let known = state.devices.iter().any(|device| device.device_id == device_id);
+                    if known {
+                        state.connected_ports.push((device_id, port));
+                    }
+                    known
+                };
+                if !known {
+                    send(&mut stream, tag, result_dictionary(2)).await;
+                    continue;
+                }
+
+                send(&mut stream, tag, result_dictionary(0)).await;
+                match port {
+                    LOCKDOWN_PORT => lockdown::serve_lockdown(stream, state, device_id).await,
+                    lockdown::HOUSE_ARREST_PORT => lockdown::serve_house_arrest(stream, state).await,
+                    _ => echo(stream).await,
+                }
+                return;
+            }
+            _ => send(&mut stream, tag, result_dictionary(1)).await,
+        }
+    }
+}
+
+/// Behave like a device service answering with what it receives
+async fn echo(mut stream: UnixStream) {
+    let mut buffer = [0u8; 4096];
+    while let Ok(read) = stream.read(&mut buffer).await {
+        if read == 0 || stream.write_all(&buffer[..read]).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn send(stream: &mut UnixStream, tag: u32, reply: Dictionary) {
+    let mut payload = Vec::new();
+    plist::to_writer_xml(&mut payload, &reply).unwrap();
+    _ = write_message(stream, tag, &payload).await;
+}
+
+fn result_dictionary(number: u64) -> Dictionary {
+    let mut reply = Dictionary::new();
+    reply.insert("MessageType".to_string(), Value::from("Result"));
+    reply.insert("Number".to_string(), Value::from(number));
+    reply
+}
+
+fn event_dictionary(message_type: &str, device_id: u32) -> Dictionary {
+    let mut event = Dictionary::new();
+    event.insert("MessageType".to_string(), Value::from(message_type));
+    event.insert("DeviceID".to_string(), Value::from(device_id as u64));
+    event
+}
+
+fn device_dictionary(device: &Device) -> Dictionary {
+    let mut properties = Dictionary::new();
+    properties.insert(
+        "ConnectionType".to_string(),
+        Value::from(match &device.connection_type {
+            ConnectionType::Usb => "USB",
+            ConnectionType::Network => "Network",
+            ConnectionType::Other(other) => other.as_str(),
+        }),
+    );
+    properties.insert("DeviceID".to_string(), Value::from(device.device_id as u64));
+    properties.insert("SerialNumber".to_string(), Value::from(device.udid.as_str()));
+    if let Some(product_id) = device.product_id {
+        properties.insert("ProductID".to_string(), Value::from(product_id as u64));
+    }
+
+    let mut event = event_dictionary("Attached", device.device_id);
+    event.insert("Properties".to_string(), Value::Dictionary(properties));
+    event
+}
@@ -0,0 +1,451 @@
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// The usbmuxd protocol version speaking plists
+const PLIST_VERSION: u32 = 1;
+/// The only message type of the plist protocol
+const PLIST_MESSAGE: u32 = 8;
+const HEADER_SIZE: usize = 16;
+/// Guard against garbage lengths, pair records are a few KB
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+const PROG_NAME: &str = "monsieur_dlp";
+/// Result number of usbmuxd when a pair record does not exist (ENOENT)
+const NO_SUCH_RECORD: i64 = 2;
+
+#[derive(Debug, Error)]
+pub enum UsbmuxError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid plist: {0}")]
+    Plist(#[from] plist::Error),
+
+    #[error("Unexpected usbmuxd reply: {0}")]
+    Protocol(String),
+
+    #[error("usbmuxd refused the request with code {0}")]
+    Refused(i64),
+}
+
+/// How the device is connected to the computer
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionType {
+    Usb,
+    Network,
+    Other(String),
+}
+
+/// A device known by usbmuxd
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    /// Id of the device for the lifetime of the connection, used by `connect`
+    pub device_id: u32,
+    /// Unique identifier of the device (its serial number for usbmuxd)
+    pub udid: String,
+    pub connection_type: ConnectionType,
+    pub product_id: Option<u32>,
+}
+
+/// Event sent by usbmuxd in listen mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    Attached(Device),
+    Detached { device_id: u32 },
+    /// The user trusted the computer and usbmuxd saved the pair record
+    Paired { device_id: u32 },
+}
+
+/// The keys shared by the computer and a device after pairing
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PairRecord {
+    #[serde(rename = "HostID")]
+    pub host_id: String,
+    #[serde(rename = "SystemBUID")]
+    pub system_buid: String,
+    pub host_certificate: plist::Data,
+    pub host_private_key: plist::Data,
+    pub device_certificate: plist::Data,
+    pub root_certificate: plist::Data,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_private_key: Option<plist::Data>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow_bag: Option<plist::Data>,
+    #[serde(rename = "WiFiMACAddress", default, skip_serializing_if = "Option::is_none")]
+    pub wifi_mac_address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    #[serde(rename = "MessageType")]
+    message_type: &'a str,
+    #[serde(rename = "ClientVersionString")]
+    client_version: &'a str,
+    #[serde(rename = "ProgName")]
+    prog_name: &'a str,
+    #[serde(rename = "kLibUSBMuxVersion")]
+    lib_version: u32,
+    #[serde(rename = "DeviceID", skip_serializing_if = "Option::is_none")]
+    device_id: Option<u32>,
+    #[serde(rename = "PortNumber", skip_serializing_if = "Option::is_none")]
+    port_number: Option<u16>,
+    #[serde(rename = "PairRecordID", skip_serializing_if = "Option::is_none")]
+    pair_record_id: Option<&'a str>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Response {
+    #[serde(rename = "MessageType")]
+    message_type: Option<String>,
+    #[serde(rename = "Number")]
+    number: Option<i64>,
+    #[serde(rename = "DeviceList")]
+    device_list: Option<Vec<Response>>,
+    #[serde(rename = "DeviceID")]
+    device_id: Option<u32>,
+    #[serde(rename = "Properties")]
+    properties: Option<Properties>,
+    #[serde(rename = "PairRecordData")]
+    pair_record_data: Option<plist::Data>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Properties {
+    #[serde(rename = "DeviceID")]
+    device_id: Option<u32>,
+    #[serde(rename = "SerialNumber")]
+    serial_number: Option<String>,
+    #[serde(rename = "ConnectionType")]
+    connection_type: Option<String>,
+    #[serde(rename = "ProductID")]
+    product_id: Option<u32>,
+}
+
+impl<'a> Request<'a> {
+    fn new(message_type: &'a str) -> Self {
+        Self {
+            message_type,
+            client_version: PROG_NAME,
+            prog_name: PROG_NAME,
+            lib_version: 3,
+            device_id: None,
+            port_number: None,
+            pair_record_id: None,
+        }
+    }
+}
+
+impl Response {
+    /// The result number, usbmuxd sends negative errnos as unsigned 32 bits integers
+    fn result(&self) -> Option<i64> {
+        if self.message_type.as_deref() != Some("Result") {
+            return None;
+        }
+        self.number.map(|number| number as u32 as i32 as i64).map(i64::abs)
+    }
+
+    fn into_device(self) -> Result<Device, UsbmuxError> {
+        let properties = self.properties.unwrap_or_default();
+        let device_id = self
+            .device_id
+            .or(properties.device_id)
+            .ok_or_else(|| UsbmuxError::Protocol("device without DeviceID".to_string()))?;
+        let udid = properties
+            .serial_number
+            .ok_or_else(|| UsbmuxError::Protocol("device without SerialNumber".to_string()))?;
+
+        Ok(Device {
+            device_id,
+            udid,
+            connection_type: match properties.connection_type.as_deref() {
+                Some("USB") => ConnectionType::Usb,
+                Some("Network") => ConnectionType::Network,
+                other => ConnectionType::Other(other.unwrap_or_default().to_string()),
+            },
+            product_id: properties.product_id,
+        })
+    }
+}
+
+/// A connection to the usbmuxd daemon
+pub struct UsbmuxClient {
+    stream: UnixStream,
+    tag: u32,
+}
+
+/// The connection of `UsbmuxClient::listen`, receiving device events
+pub struct DeviceEvents {
+    client: UsbmuxClient,
+}
+
+impl UsbmuxClient {
+    /// Connect to the usbmuxd socket, usually /var/run/usbmuxd
+    pub async fn connect<P: AsRef<Path>>(socket: P) -> Result<Self, UsbmuxError> {
+        let stream = UnixStream::connect(socket).await?;
+        Ok(Self { stream, tag: 0 })
+    }
+
+    /// Every device currently known by usbmuxd
+    pub async fn list_devices(&mut self) -> Result<Vec<Device>, UsbmuxError> {
+        let response = self.request(&Request::new("ListDevices")).await?;
+        if let Some(code) = response.result().filter(|code| *code != 0) {
+            return Err(UsbmuxError::Refused(code));
+        }
+
+        response
+            .device_list
+            .ok_or_else(|| UsbmuxError::Protocol("ListDevices reply without DeviceList".to_string()))?
+            .into_iter()
+            .map(Response::into_device)
+            .collect()
+    }
+
+    /// The pair record of the device, None if it was never paired with this computer
+    pub async fn read_pair_record(&mut self, udid: &str) -> Result<Option<PairRecord>, UsbmuxError> {
+        let mut request = Request::new("ReadPairRecord");
+        request.pair_record_id = Some(udid);
+        let response = self.request(&request).await?;
+
+        match response.result() {
+            Some(NO_SUCH_RECORD) => return Ok(None),
+            Some(0) | None => {}
+            Some(code) => return Err(UsbmuxError::Refused(code)),
+        }
+
+        let data = response
+            .pair_record_data
+            .ok_or_else(|| UsbmuxError::Protocol("ReadPairRecord reply without PairRecordData".to_string()))?;
+        Ok(Some(plist::from_bytes(data.as_ref())?))
+    }
+
+    /// Switch to listen mode, usbmuxd then sends an event for every (un)plugged device,
+    /// starting with the ones already connected
+    pub async fn listen(mut self) -> Result<DeviceEvents, UsbmuxError> {
+        self.expect_ok(&Request::new("Listen")).await?;
+        Ok(DeviceEvents { client: self })
+    }
+
+    /// Open a tunnel to a TCP port of the device, the returned stream talks to the device
+    pub async fn connect_to_port(mut self, device_id: u32, port: u16) -> Result<UnixStream, UsbmuxError> {
+        let mut request = Request::new("Connect");
+        request.device_id = Some(device_id);
+        // usbmuxd expects the port in network byte order
+        request.port_number = Some(port.to_be());
+        self.expect_ok(&request).await?;
+        Ok(self.stream)
+    }
+
+    async fn expect_ok(&mut self, request: &Request<'_>) -> Result<(), UsbmuxError> {
+        let response = self.request(request).await?;
+        match response.result() {
+            Some(0) => Ok(()),
+            Some(code) => Err(UsbmuxError::Refused(code)),
+            None => Err(UsbmuxError::Protocol(format!(
+                "expected a Result to {}, got {:?}",
+                request.message_type, response.message_type
+            ))),
+        }
+    }
+
+    async fn request(&mut self, request: &Request<'_>) -> Result<Response, UsbmuxError> {
+        self.tag += 1;
+        let mut payload = Vec::new();
+        plist::to_writer_xml(&mut payload, request)?;
+        write_message(&mut self.stream, self.tag, &payload).await?;
+        self.receive().await
+    }
+
+    async fn receive(&mut self) -> Result<Response, UsbmuxError> {
+        let (_, payload) = read_message(&mut self.stream).await?;
+        Ok(plist::from_bytes(&payload)?)
+    }
+}
+
+impl DeviceEvents {
+    /// Wait for the next attach, detach or paired event
+    pub async fn next(&mut self) -> Result<DeviceEvent, UsbmuxError> {
+        loop {
+            let response = self.client.receive().await?;
+            match response.message_type.as_deref() {
+                Some("Attached") => return Ok(DeviceEvent::Attached(response.into_device()?)),
+                Some("Detached") | Some("Paired") => {
+                    let device_id = response
+                        .device_id
+                        .ok_or_else(|| UsbmuxError::Protocol("event without DeviceID".to_string()))?;
+                    return Ok(match response.message_type.as_deref() {
+                        Some("Detached") => DeviceEvent::Detached { device_id },
+                        _ => DeviceEvent::Paired { device_id },
+                    });
+                }
+                // Other notifications are not interesting to us
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Write a usbmuxd message: little endian length, version, message type and tag, then the plist
+pub async fn write_message<W>(writer: &mut W, tag: u32, payload: &[u8]) -> io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.extend(((HEADER_SIZE + payload.len()) as u32).to_le_bytes());
+    message.extend(PLIST_VERSION.to_le_bytes());
+    message.extend(PLIST_MESSAGE.to_le_bytes());
+    message.extend(tag.to_le_bytes());
+    message.extend(payload);
+    writer.write_all(&message).await?;
+    writer.flush().await
+}
+
+/// Read a usbmuxd message, returns its tag and plist payload
+pub async fn read_message<R>(reader: &mut R) -> io::Result<(u32, Vec<u8>)>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+
+    let field = |index: usize| u32::from_le_bytes(header[index * 4..index * 4 + 4].try_into().unwrap());
+    let length = field(0) as usize;
+    let (version, message, tag) = (field(1), field(2), field(3));
+
+    if version != PLIST_VERSION || message != PLIST_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported usbmuxd message (version {}, type {})", version, message),
+        ));
+    }
+    if !(HEADER_SIZE..=MAX_MESSAGE_SIZE).contains(&length) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid usbmuxd message length {}", length),
+        ));
+    }
+
+    let mut payload = vec![0u8; length - HEADER_SIZE];
+    reader.read_exact(&mut payload).await?;
+    Ok((tag, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ios::testing::{FakeUsbmuxd, pair_record, usb_device};
+
+    #[tokio::test]
+    async fn list_devices_reads_device_list() {
+        let usbmuxd = FakeUsbmuxd::start("list_devices").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+        usbmuxd.attach(Device {
+            device_id: 4,
+            udid: "00008030-0002".to_string(),
+            connection_type: ConnectionType::Network,
+            product_id: None,
+        })
+        .await;
+
+        let mut client = UsbmuxClient::connect(usbmuxd.path()).await.unwrap();
+        let devices = client.list_devices().await.unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0], usb_device(3, "00008030-0001"));
+        assert_eq!(devices[1].connection_type, ConnectionType::Network);
+    }
+
+    #[tokio::test]
+    async fn read_pair_record_returns_none_when_not_paired() {
+        let usbmuxd = FakeUsbmuxd::start("read_pair_record_none").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let mut client = UsbmuxClient::connect(usbmuxd.path()).await.unwrap();
+
+        assert_eq!(client.read_pair_record("00008030-0001").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_pair_record_decodes_record() {
+        let usbmuxd = FakeUsbmuxd::start("read_pair_record").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+        usbmuxd.pair("00008030-0001", pair_record("HOST-1")).await;
+
+        let mut client = UsbmuxClient::connect(usbmuxd.path()).await.unwrap();
+        let record = client.read_pair_record("00008030-0001").await.unwrap().unwrap();
+
+        assert_eq!(record, pair_record("HOST-1"));
+    }
+
+    #[tokio::test]
+    async fn listen_receives_attach_detach_and_paired_events() {
+        let usbmuxd = FakeUsbmuxd::start("listen").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let client = UsbmuxClient::connect(usbmuxd.path()).await.unwrap();
+        let mut events = client.listen().await.unwrap();
+
+        // Already connected devices come first
+        assert_eq!(
+            events.next().await.unwrap(),
+            DeviceEvent::Attached(usb_device(3, "00008030-0001"))
+        );
+
+        usbmuxd.attach(usb_device(5, "00008030-0005")).await;
+        assert_eq!(
+            events.next().await.unwrap(),
+            DeviceEvent::Attached(usb_device(5, "00008030-0005"))
+        );
+
+        usbmuxd.pair("00008030-0005", pair_record("HOST-1")).await;
+        assert_eq!(events.next().await.unwrap(), DeviceEvent::Paired { device_id: 5 });
+
+        usbmuxd.detach(3).await;
+        assert_eq!(events.next().await.unwrap(), DeviceEvent::Detached { device_id: 3 });
+    }
+
+    #[tokio::test]
+    async fn connect_to_port_tunnels_to_device() {
+        let usbmuxd = FakeUsbmuxd::start("connect").await;
+        usbmuxd.attach(usb_device(3, "00008030-0001")).await;
+
+        let client = UsbmuxClient::connect(usbmuxd.path()).await.unwrap();
+        let mut stream = client.connect_to_port(3, 8080).await.unwrap();
+
+        // The fake device echoes what it receives
+        stream.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(usbmuxd.connected_ports().await, vec![(3, 8080)]);
+    }
+
+    #[tokio::test]
+    async fn connect_to_port_of_unknown_device_is_refused() {
+        let usbmuxd = FakeUsbmuxd::start("connect_refused").await;
+
+        let client = UsbmuxClient::connect(usbmuxd.path()).await.unwrap();
+
+        assert!(matches!(
+            client.connect_to_port(42, 62078).await,
+            Err(UsbmuxError::Refused(2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_message_rejects_other_protocol_versions() {
+        let mut message = Vec::new();
+        message.extend(16u32.to_le_bytes());
+        message.extend(0u32.to_le_bytes());
+        message.extend(8u32.to_le_bytes());
+        message.extend(1u32.to_le_bytes());
+
+        let err = read_message(&mut message.as_slice()).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,121 @@
+//! Archive mode: the downloaded songs leave the download path for a library of artist and
+//! album folders on the computer, and the devices only get copies of them. A new or second
+//! device is then filled from the archive without downloading anything again.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{Library, LibraryError};
+use crate::common::config::{Config, ConfigError};
+use crate::youtube::downloader;
+use crate::youtube::naming::{Namer, Template};
+
+/// Check `download.archive_template`
+pub fn validate_config(config: &Config) -> Result<(), ConfigError> {
+    match Template::parse(&config.download.archive_template) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(config.invalid("download.archive_template", &e.to_string())),
+    }
+}
+
+/// Where the waiting files of the download path go in the archive, named by the template
+/// with the metadata of their song
+pub fn destinations(
+    library: &Library,
+    download_path: &Path,
+    archive_path: &Path,
+    template: &Template,
+) -> Result<Vec<(PathBuf, PathBuf)>, LibraryError> {
+    let mut namer = Namer::new(archive_path);
+    let mut moves: Vec<(PathBuf, PathBuf)> = vec![];
+    for song in library.waiting()? {
+        let Some(path) = song.path.clone().filter(|path| path.starts_with(download_path)) else {
+            continue;
+        };
+        // An older download of the same file moves with it
+        if moves.iter().any(|(from, _)| *from == path) {
+            continue;
+        }
+        let output = namer.output(template, &song.song());
+        let archived = downloader::output_file(&output, path.extension().unwrap_or_default());
+        moves.push((path, archived));
+    }
+    Ok(moves)
+}
+
+/// Move the file, copying it when the archive is on another file system
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::constants;
+    use crate::library::tests::song_by_path;
+    use crate::youtube::Song;
+    use crate::youtube::downloader::{Download, SongMetadata};
+    use chrono::Utc;
+    use std::env;
+
+    fn dirs(name: &str) -> (PathBuf, PathBuf) {
+        let root = env::temp_dir().join(format!("monsieur_dlp_archive_{}", name));
+        _ = fs::remove_dir_all(&root);
+        let (download_path, archive_path) = (root.join("dl"), root.join("library"));
+        fs::create_dir_all(download_path.join(".staging/20250101-120000")).unwrap();
+        fs::create_dir_all(archive_path.join("Band/Album")).unwrap();
+        (download_path, archive_path)
+    }
+
+    fn record(library: &Library, file: &Path, name: &str, album: Option<&str>) {
+        fs::write(file, name).unwrap();
+        let song = Song {
+            album: album.map(String::from),
+            ..Song::new(format!("https://example.com/{}.mp3", name), "Band".into(), name.into())
+        };
+        let download = Download {
+            files: vec![file.to_path_buf()],
+            metadata: SongMetadata::default(),
+            profile: None,
+        };
+        library.record_download(&song, &download, Utc::now()).unwrap();
+    }
+
+    #[test]
+    fn destinations_follow_the_template() {
+        let (download_path, archive_path) = dirs("destinations");
+        let batch = download_path.join(".staging/20250101-120000");
+        let library = Library::open_in_memory().unwrap();
+        record(&library, &batch.join("A.mp3"), "A", Some("Album"));
+        record(&library, &batch.join("B.m4a"), "B", None);
+        record(&library, &archive_path.join("Band/Album/C.mp3"), "C", Some("Album"));
+        fs::write(archive_path.join("Band/Album/A.mp3"), b"older").unwrap();
+
+        let template = Template::parse(constants::DEFAULT_ARCHIVE_TEMPLATE).unwrap();
+        let moves = destinations(&library, &download_path, &archive_path, &template).unwrap();
+
+        assert_eq!(
+            moves,
+            vec![
+                (batch.join("A.mp3"), archive_path.join("Band/Album/A (2).mp3")),
+                (batch.join("B.m4a"), archive_path.join("Band/B.m4a")),
+            ]
+        );
+        for (from, to) in &moves {
+            move_file(from, to).unwrap();
+            library.move_path(from, to).unwrap();
+        }
+        assert_eq!(fs::read(archive_path.join("Band/B.m4a")).unwrap(), b"B");
+        assert!(song_by_path(&library, archive_path.join("Band/Album/A (2).mp3")).is_some());
+        assert!(destinations(&library, &download_path, &archive_path, &Template::default()).unwrap().is_empty());
+        fs::remove_dir_all(download_path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+
+use super::LibraryError;
+use crate::youtube;
+
+/// A schema or data change
+enum Migration {
+    Sql(&'static str),
+    Code(fn(&Transaction) -> Result<(), LibraryError>),
+}
+
+/// Changes in order, `PRAGMA user_version` is the number of the applied ones
+const MIGRATIONS: &[Migration] = &[
+    // 1: songs, their transfers to the devices and the library state
+    Migration::Sql(
+        "CREATE TABLE songs (
+        id INTEGER PRIMARY KEY,
+        url TEXT NOT NULL,
+        video_id TEXT,
+        artist TEXT NOT NULL,
+        title TEXT NOT NULL,
+        path TEXT,
+        checksum TEXT,
+        size INTEGER,
+        downloaded_at TEXT
+    );
+    CREATE INDEX songs_video_id ON songs (video_id);
+    CREATE INDEX songs_path ON songs (path);
+    CREATE TABLE transfers (
+        song_id INTEGER NOT NULL REFERENCES songs (id) ON DELETE CASCADE,
+        device_udid TEXT NOT NULL,
+        state TEXT NOT NULL,
+        error TEXT,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (song_id, device_udid)
+    );
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+    ),
+    // 2: the video id of the songs imported without it
+    Migration::Code(fill_video_ids),
+    // 3: the options of the songs file
+    Migration::Sql(
+        "ALTER TABLE songs ADD COLUMN section TEXT;
+    ALTER TABLE songs ADD COLUMN album TEXT;
+    ALTER TABLE songs ADD COLUMN track INTEGER;
+    ALTER TABLE songs ADD COLUMN year INTEGER;
+    ALTER TABLE songs ADD COLUMN genre TEXT;
+    ALTER TABLE songs ADD COLUMN folder TEXT;",
+    ),
+    // 4: the output profile of the downloaded files
+    Migration::Sql("ALTER TABLE songs ADD COLUMN profile TEXT;"),
+    // 5: the loudness measured by ffmpeg, so that the songs are analysed once
+    Migration::Sql(
+        "ALTER TABLE songs ADD COLUMN loudness REAL;
+    ALTER TABLE songs ADD COLUMN true_peak REAL;
+    ALTER TABLE songs ADD COLUMN loudness_range REAL;
+    ALTER TABLE songs ADD COLUMN loudness_threshold REAL;",
+    ),
+    // 6: the priority of the songs file, the first songs sent to a device short of room
+    Migration::Sql("ALTER TABLE songs ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;"),
+];
+
+/// Key of the `meta` table set once the historic file is imported
+const HISTORIC_IMPORTED: &str = "historic_imported";
+
+/// Bring the schema to the latest version
+pub fn migrate(conn: &mut Connection) -> Result<(), LibraryError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (number, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        match migration {
+            Migration::Sql(sql) => tx.execute_batch(sql)?,
+            Migration::Code(change) => change(&tx)?,
+        }
+        tx.pragma_update(None, "user_version", number as i64 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn fill_video_ids(tx: &Transaction) -> Result<(), LibraryError> {
+    let mut statement = tx.prepare("SELECT id, url FROM songs WHERE video_id IS NULL")?;
+    let songs = statement
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (id, url) in songs {
+        if let Some(video_id) = youtube::url::video_id(&url) {
+            tx.execute("UPDATE songs SET video_id = ?1 WHERE id = ?2", params![video_id, id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Import the songs of the old `url|artist|name` history file, only once.
+/// They have no file nor download time, returns how many were imported.
+pub fn import_historic<P: AsRef<Path>>(conn: &mut Connection, historic_path: P) -> Result<usize, LibraryError> {
+    let historic_path = historic_path.as_ref();
+    let imported: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = ?1", [HISTORIC_IMPORTED], |row| row.get(0))
+        .optional()?;
+    if imported.is_some() || !historic_path.is_file() {
+        return Ok(0);
+    }
+
+    let songs = youtube::filesystem::serialize_file(youtube::filesystem::read_songs(historic_path)?);
+    let tx = conn.transaction()?;
+    for song in &songs {
+        tx.execute(
+            "INSERT INTO songs (url, video_id, artist, title) VALUES (?1, ?2, ?3, ?4)",
+            params![song.url, youtube::url::video_id(&song.url), song.artist, song.name],
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2)",
+        params![HISTORIC_IMPORTED, historic_path.display().to_string()],
+    )?;
+    tx.commit()?;
+
+    Ok(songs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn migrate_sets_schema_version_and_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrate_fills_video_ids_of_first_version_songs() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let Migration::Sql(first) = &MIGRATIONS[0] else {
+            panic!("the first migration creates the tables");
+        };
+        conn.execute_batch(first).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO songs (url, artist, title) VALUES ('https://youtu.be/dQw4w9WgXcQ', '', ''), ('file:///a.mp3', '', '')",
+            [],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let mut statement = conn.prepare("SELECT video_id FROM songs ORDER BY id").unwrap();
+        let ids: Vec<Option<String>> = statement.query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect();
+        assert_eq!(ids, vec![Some("dQw4w9WgXcQ".to_string()), None]);
+    }
+
+    #[test]
+    fn import_historic_only_once() {
+        let historic = std::env::temp_dir().join("monsieur_dlp_import_historic.txt");
+        fs::write(&historic, "https://url1.com|Artist1|Title1\nhttps://url2.com||Title2\nbroken\n").unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(import_historic(&mut conn, &historic).unwrap(), 2);
+        assert_eq!(import_historic(&mut conn, &historic).unwrap(), 0);
+
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM songs", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+        fs::remove_file(historic).unwrap();
+    }
+
+    #[test]
+    fn import_missing_historic_file_imports_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(import_historic(&mut conn, "/nonexistent/ytb-songs-historic.txt").unwrap(), 0);
+    }
+}
@@ -0,0 +1,555 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row, params};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::common::constants;
+use crate::youtube::{self, Song};
+use crate::youtube::downloader::Download;
+use crate::youtube::loudness::Loudness;
+use crate::youtube::{naming, staging};
+
+pub mod archive;
+pub mod migrations;
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("SQLite Error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Where a song is on a device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferState {
+    Transferred,
+    Failed,
+}
+
+impl TransferState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferState::Transferred => "transferred",
+            TransferState::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for TransferState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transferred" => Ok(TransferState::Transferred),
+            "failed" => Ok(TransferState::Failed),
+            other => Err(format!("unknown transfer state '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for TransferState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A song of the library
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibrarySong {
+    pub id: i64,
+    pub url: String,
+    /// Id of the video on the source site
+    pub video_id: Option<String>,
+    pub artist: String,
+    pub title: String,
+    /// The downloaded file, None for the songs imported from the historic file
+    pub path: Option<PathBuf>,
+    /// SHA-256 of the downloaded file, in hex
+    pub checksum: Option<String>,
+    pub size: Option<u64>,
+    pub downloaded_at: Option<DateTime<Utc>>,
+    /// The `[section]` of the songs file the song was listed under
+    pub section: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    /// Folder of the app Documents receiving the song
+    pub folder: Option<String>,
+    /// Output profile of the file, None when the source file was kept as is
+    pub profile: Option<String>,
+    /// Loudness of the downloaded file before any normalization, None until measured
+    pub loudness: Option<Loudness>,
+    /// Priority of the song in the songs file, the highest first to a device short of room
+    pub priority: i32,
+}
+
+impl LibrarySong {
+    /// The song as listed in the songs file, for the file name templates
+    pub fn song(&self) -> Song {
+        Song {
+            section: self.section.clone(),
+            album: self.album.clone(),
+            track: self.track,
+            year: self.year,
+            genre: self.genre.clone(),
+            folder: self.folder.clone(),
+            profile: self.profile.clone(),
+            priority: self.priority,
+            ..Song::new(self.url.clone(), self.artist.clone(), self.title.clone())
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            url: row.get("url")?,
+            video_id: row.get("video_id")?,
+            artist: row.get("artist")?,
+            title: row.get("title")?,
+            path: row.get::<_, Option<String>>("path")?.map(PathBuf::from),
+            checksum: row.get("checksum")?,
+            size: row.get::<_, Option<i64>>("size")?.map(|size| size as u64),
+            downloaded_at: row.get("downloaded_at")?,
+            section: row.get("section")?,
+            album: row.get("album")?,
+            track: row.get("track")?,
+            year: row.get("year")?,
+            genre: row.get("genre")?,
+            folder: row.get("folder")?,
+            profile: row.get("profile")?,
+            priority: row.get("priority")?,
+            loudness: match row.get::<_, Option<f64>>("loudness")? {
+                Some(integrated) => Some(Loudness {
+                    integrated,
+                    true_peak: row.get::<_, Option<f64>>("true_peak")?.unwrap_or_default(),
+                    range: row.get::<_, Option<f64>>("loudness_range")?.unwrap_or_default(),
+                    threshold: row.get::<_, Option<f64>>("loudness_threshold")?.unwrap_or_default(),
+                }),
+                None => None,
+            },
+        })
+    }
+}
+
+/// The transfer of a song to a device
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub device_udid: String,
+    pub state: TransferState,
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// The songs downloaded so far and where they have been transferred
+pub struct Library {
+    conn: Connection,
+}
+
+impl Library {
+    /// Open the database, creating it and its folder if needed, and migrate it
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LibraryError> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::new(Connection::open(path)?)
+    }
+
+    /// Copy the database in memory and migrate the copy, the file is only read and not
+    /// even created. What the library would be, for --dry-run.
+    pub fn open_copy<P: AsRef<Path>>(path: P) -> Result<Self, LibraryError> {
+        let mut conn = Connection::open_in_memory()?;
+        if path.as_ref().is_file() {
+            let disk = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            Backup::new(&disk, &mut conn)?.run_to_completion(64, Duration::ZERO, None)?;
+        }
+        Self::new(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, LibraryError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut conn: Connection) -> Result<Self, LibraryError> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrations::migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Import the old historic file if not done yet, returns how many songs were imported
+    pub fn import_historic<P: AsRef<Path>>(&mut self, historic_path: P) -> Result<usize, LibraryError> {
+        migrations::import_historic(&mut self.conn, historic_path)
+    }
+
+    /// Add a downloaded song with the checksum of its file
+    pub fn record_download(
+        &self,
+        song: &Song,
+        download: &Download,
+        downloaded_at: DateTime<Utc>,
+    ) -> Result<i64, LibraryError> {
+        let path = download.files.first();
+        let (checksum, size) = match path {
+            Some(path) => (Some(checksum(path)?), Some(std::fs::metadata(path)?.len() as i64)),
+            None => (None, None),
+        };
+
+        self.conn.execute(
+            "INSERT INTO songs (url, video_id, artist, title, path, checksum, size, downloaded_at,
+                                section, album, track, year, genre, folder, profile, priority)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![
+                song.url,
+                youtube::url::video_id(&song.url).or_else(|| download.metadata.id.clone()),
+                song.artist,
+                song.name,
+                path.map(|path| path.display().to_string()),
+                checksum,
+                size,
+                downloaded_at,
+                song.section,
+                song.album,
+                song.track,
+                song.year,
+                song.genre,
+                song.folder,
+                download.profile,
+                song.priority
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record the loudness measured of the song file
+    pub fn record_loudness(&self, song_id: i64, loudness: &Loudness) -> Result<(), LibraryError> {
+        self.conn.execute(
+            "UPDATE songs SET loudness = ?2, true_peak = ?3, loudness_range = ?4, loudness_threshold = ?5
+             WHERE id = ?1",
+            params![song_id, loudness.integrated, loudness.true_peak, loudness.range, loudness.threshold],
+        )?;
+        Ok(())
+    }
+
+    /// Record the checksum and size of the song file again, once changed in place
+    pub fn update_file<P: AsRef<Path>>(&self, song_id: i64, path: P) -> Result<(), LibraryError> {
+        let path = path.as_ref();
+        self.conn.execute(
+            "UPDATE songs SET checksum = ?2, size = ?3 WHERE id = ?1",
+            params![song_id, checksum(path)?, std::fs::metadata(path)?.len() as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Record that the file of the songs was moved, its checksum is the same
+    pub fn move_path<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> Result<(), LibraryError> {
+        self.conn.execute(
+            "UPDATE songs SET path = ?2 WHERE path = ?1",
+            params![from.as_ref().display().to_string(), to.as_ref().display().to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Every song, the oldest first
+    pub fn songs(&self) -> Result<Vec<LibrarySong>, LibraryError> {
+        let mut statement = self.conn.prepare("SELECT * FROM songs ORDER BY id")?;
+        let songs = statement.query_map([], LibrarySong::from_row)?.collect::<Result<_, _>>()?;
+        Ok(songs)
+    }
+
+    /// The songs whose file is still on the computer, waiting for the devices
+    pub fn waiting(&self) -> Result<Vec<LibrarySong>, LibraryError> {
+        let songs = self.songs()?.into_iter();
+        Ok(songs.filter(|song| song.path.as_ref().is_some_and(|path| path.is_file())).collect())
+    }
+
+    /// The last download of the song, whatever the variant of its YouTube url
+    pub fn find_by_url(&self, url: &str) -> Result<Option<LibrarySong>, LibraryError> {
+        let song = match youtube::url::video_id(url) {
+            Some(video_id) => self.conn.query_row(
+                "SELECT * FROM songs WHERE video_id = ?1 ORDER BY id DESC LIMIT 1",
+                [video_id],
+                LibrarySong::from_row,
+            ),
+            None => self.conn.query_row(
+                "SELECT * FROM songs WHERE url = ?1 ORDER BY id DESC LIMIT 1",
+                [url.trim()],
+                LibrarySong::from_row,
+            ),
+        };
+        Ok(song.optional()?)
+    }
+
+    /// Record the outcome of the last transfer of the song to the device
+    pub fn set_transfer(
+        &self,
+        song_id: i64,
+        device_udid: &str,
+        state: TransferState,
+        error: Option<&str>,
+    ) -> Result<(), LibraryError> {
+        self.conn.execute(
+            "INSERT INTO transfers (song_id, device_udid, state, error, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (song_id, device_udid)
+             DO UPDATE SET state = excluded.state, error = excluded.error, updated_at = excluded.updated_at",
+            params![song_id, device_udid, state.as_str(), error, Utc::now()],
+        )?;
+        Ok(())
+    }
+
+    /// The devices the song was sent to
+    pub fn transfers(&self, song_id: i64) -> Result<Vec<Transfer>, LibraryError> {
+        let mut statement = self.conn.prepare(
+            "SELECT device_udid, state, error, updated_at FROM transfers WHERE song_id = ?1 ORDER BY device_udid",
+        )?;
+        let transfers = statement
+            .query_map([song_id], |row| {
+                let state: String = row.get("state")?;
+                Ok(Transfer {
+                    device_udid: row.get("device_udid")?,
+                    state: state.parse().map_err(|e: String| {
+                        rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, e.into())
+                    })?,
+                    error: row.get("error")?,
+                    updated_at: row.get("updated_at")?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(transfers)
+    }
+}
+
+/// Where the file downloaded in `root` goes in the app Documents: the song folder, then its
+/// path in its batch directory, named by `download.template`, or in the archive
+pub fn device_name<P: AsRef<Path>>(folder: Option<&str>, root: &Path, path: P) -> String {
+    let path = path.as_ref();
+    let relative = match path.strip_prefix(root) {
+        Ok(_) => staging::relative(root, path),
+        Err(_) => PathBuf::from(path.file_name().unwrap_or_default()),
+    };
+    let mut segments: Vec<String> = match folder {
+        Some(folder) => folder
+            .split('/')
+            .map(|segment| naming::sanitize(segment, naming::MAX_NAME_BYTES))
+            .filter(|segment| !segment.is_empty())
+            .collect(),
+        None => vec![],
+    };
+    segments.extend(relative.iter().map(|segment| segment.to_string_lossy().into_owned()));
+    segments.join("/")
+}
+
+/// Open the configured library, importing the historic file the first time
+pub fn open() -> Result<Library, LibraryError> {
+    let mut library = Library::open(constants::library_path())?;
+    library.import_historic(constants::youtube_songs_historic_path())?;
+    Ok(library)
+}
+
+/// The configured library as [`open`] would give it, in memory so that nothing is written
+pub fn open_copy() -> Result<Library, LibraryError> {
+    let mut library = Library::open_copy(constants::library_path())?;
+    library.import_historic(constants::youtube_songs_historic_path())?;
+    Ok(library)
+}
+
+/// SHA-256 of the file, in hex
+pub fn checksum<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::downloader::SongMetadata;
+    use std::fs;
+
+    /// The last song downloaded to this file
+    pub fn song_by_path<P: AsRef<Path>>(library: &Library, path: P) -> Option<LibrarySong> {
+        let path = path.as_ref().display().to_string();
+        library
+            .conn
+            .query_row(
+                "SELECT * FROM songs WHERE path = ?1 ORDER BY id DESC LIMIT 1",
+                [path],
+                LibrarySong::from_row,
+            )
+            .optional()
+            .unwrap()
+    }
+
+    fn downloaded(name: &str, contents: &[u8]) -> (Song, Download) {
+        let file = std::env::temp_dir().join(format!("monsieur_dlp_library_{}.mp3", name));
+        fs::write(&file, contents).unwrap();
+        let song = Song::new(format!("https://youtu.be/{}", name), "Rust".into(), name.into());
+        let download = Download {
+            files: vec![file],
+            metadata: SongMetadata {
+                id: Some(name.to_string()),
+                ..Default::default()
+            },
+            profile: Some("mp3-320".into()),
+        };
+        (song, download)
+    }
+
+    #[test]
+    fn record_download_keeps_file_details() {
+        let library = Library::open_in_memory().unwrap();
+        let (song, download) = downloaded("record", b"abc");
+        let now = Utc::now();
+
+        let id = library.record_download(&song, &download, now).unwrap();
+        let songs = library.songs().unwrap();
+
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].id, id);
+        assert_eq!(songs[0].url, "https://youtu.be/record");
+        assert_eq!(songs[0].video_id.as_deref(), Some("record"));
+        assert_eq!(songs[0].title, "record");
+        assert_eq!(songs[0].path.as_ref(), download.files.first());
+        assert_eq!(songs[0].size, Some(3));
+        assert_eq!(
+            songs[0].checksum.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(songs[0].downloaded_at, Some(now));
+        assert_eq!(songs[0].profile.as_deref(), Some("mp3-320"));
+        fs::remove_file(&download.files[0]).unwrap();
+    }
+
+    #[test]
+    fn find_by_url_matches_youtube_variants() {
+        let mut library = Library::open_in_memory().unwrap();
+        let historic = std::env::temp_dir().join("monsieur_dlp_library_find_by_url.txt");
+        fs::write(&historic, "https://youtu.be/dQw4w9WgXcQ|Rick|Roll\nhttps://example.com/song|A|B\n").unwrap();
+        library.import_historic(&historic).unwrap();
+
+        let found = library.find_by_url("https://m.youtube.com/watch?v=dQw4w9WgXcQ&list=PL1&t=3s").unwrap();
+        assert_eq!(found.unwrap().title, "Roll");
+        assert!(library.find_by_url("https://example.com/song").unwrap().is_some());
+        assert!(library.find_by_url("https://youtu.be/aaaaaaaaaaa").unwrap().is_none());
+        fs::remove_file(historic).unwrap();
+    }
+
+    #[test]
+    fn record_loudness_and_update_file() {
+        let library = Library::open_in_memory().unwrap();
+        let (song, download) = downloaded("loudness", b"abc");
+        let id = library.record_download(&song, &download, Utc::now()).unwrap();
+        let loudness = Loudness {
+            integrated: -9.87,
+            true_peak: 0.42,
+            range: 5.1,
+            threshold: -20.01,
+        };
+        assert_eq!(library.songs().unwrap()[0].loudness, None);
+
+        library.record_loudness(id, &loudness).unwrap();
+        fs::write(&download.files[0], b"abcd").unwrap();
+        library.update_file(id, &download.files[0]).unwrap();
+
+        let recorded = &library.songs().unwrap()[0];
+        assert_eq!(recorded.loudness, Some(loudness));
+        assert_eq!(recorded.size, Some(4));
+        fs::remove_file(&download.files[0]).unwrap();
+    }
+
+    #[test]
+    fn device_name_puts_song_in_its_folder() {
+        let library = Library::open_in_memory().unwrap();
+        let (song, download) = downloaded("folder", b"abc");
+        let song = Song {
+            folder: Some("Trips/2024".into()),
+            album: Some("Road".into()),
+            track: Some(2),
+            ..song
+        };
+        library.record_download(&song, &download, Utc::now()).unwrap();
+
+        let recorded = song_by_path(&library, &download.files[0]).unwrap();
+        assert_eq!((recorded.album.as_deref(), recorded.track), (Some("Road"), Some(2)));
+        let root = download.files[0].parent().unwrap();
+        let folder = recorded.folder.as_deref();
+        assert_eq!(device_name(folder, root, &download.files[0]), "Trips/2024/monsieur_dlp_library_folder.mp3");
+        assert_eq!(device_name(None, root, "/elsewhere/unknown.mp3"), "unknown.mp3");
+        assert_eq!(device_name(None, Path::new("/tmp"), "/tmp/Band/01 a.mp3"), "Band/01 a.mp3");
+        let staged = "/tmp/.staging/20250101-120000/Band/01 a.mp3";
+        assert_eq!(device_name(None, Path::new("/tmp"), staged), "Band/01 a.mp3");
+        fs::remove_file(&download.files[0]).unwrap();
+    }
+
+    #[test]
+    fn set_transfer_keeps_last_state_per_device() {
+        let library = Library::open_in_memory().unwrap();
+        let (song, download) = downloaded("transfer", b"abc");
+        let id = library.record_download(&song, &download, Utc::now()).unwrap();
+
+        library.set_transfer(id, "UDID-B", TransferState::Failed, Some("disk full")).unwrap();
+        library.set_transfer(id, "UDID-A", TransferState::Transferred, None).unwrap();
+        library.set_transfer(id, "UDID-B", TransferState::Transferred, None).unwrap();
+
+        let transfers = library.transfers(id).unwrap();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].device_udid, "UDID-A");
+        assert_eq!(transfers[1].state, TransferState::Transferred);
+        assert_eq!(transfers[1].error, None);
+        fs::remove_file(&download.files[0]).unwrap();
+    }
+
+    #[test]
+    fn open_creates_database_file() {
+        let dir = std::env::temp_dir().join("monsieur_dlp_library_open");
+        _ = fs::remove_dir_all(&dir);
+
+        let library = Library::open(dir.join("library.db")).unwrap();
+
+        assert!(library.songs().unwrap().is_empty());
+        assert!(dir.join("library.db").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn open_copy_leaves_the_database_file_alone() {
+        let dir = std::env::temp_dir().join("monsieur_dlp_library_copy");
+        _ = fs::remove_dir_all(&dir);
+        let path = dir.join("library.db");
+        assert!(Library::open_copy(&path).unwrap().songs().unwrap().is_empty());
+        assert!(!dir.exists());
+
+        let library = Library::open(&path).unwrap();
+        let song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into());
+        let download = Download { files: vec![], metadata: Default::default(), profile: None };
+        library.record_download(&song, &download, Utc::now()).unwrap();
+        let written = fs::read(&path).unwrap();
+
+        let copy = Library::open_copy(&path).unwrap();
+        assert_eq!(copy.songs().unwrap().len(), 1);
+        copy.record_download(&song, &download, Utc::now()).unwrap();
+        assert_eq!(copy.songs().unwrap().len(), 2);
+        assert_eq!(fs::read(&path).unwrap(), written);
+        assert_eq!(library.songs().unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! Cover art: the video thumbnail downloaded next to the song, or an image of the computer,
+//! cropped to a square and shrunk to the configured size
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{ImageError, ImageReader};
+use thiserror::Error;
+
+use crate::common::constants::convert_path_string_to_pathbuf;
+
+/// Extensions of the images yt-dlp writes as thumbnails and of the `cover` option
+pub const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// Quality of the JPEG covers, out of 100
+const JPEG_QUALITY: u8 = 90;
+
+#[derive(Debug, Error)]
+pub enum CoverError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Unreadable image: {0}")]
+    Image(#[from] ImageError),
+}
+
+/// Whether the path has the extension of an image
+pub fn is_image<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// The thumbnail yt-dlp wrote next to the downloaded file, under the same name
+pub fn thumbnail_of<P: AsRef<Path>>(file: P) -> Option<PathBuf> {
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|extension| file.as_ref().with_extension(extension))
+        .find(|thumbnail| thumbnail.is_file())
+}
+
+/// The `cover` image of a song, relative to the folder of the songs file
+pub fn local_image(cover: &str, songs_file: &Path) -> PathBuf {
+    let path = convert_path_string_to_pathbuf(cover);
+    match songs_file.parent() {
+        Some(folder) if path.is_relative() => folder.join(path),
+        _ => path,
+    }
+}
+
+/// The centered square of the image, resized to `size` pixels and encoded as JPEG
+pub fn prepare<P: AsRef<Path>>(path: P, size: u32) -> Result<Vec<u8>, CoverError> {
+    let image = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    let side = image.width().min(image.height());
+    let square = image
+        .crop_imm((image.width() - side) / 2, (image.height() - side) / 2, side, side)
+        .resize_exact(size, size, FilterType::Lanczos3);
+
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&square.to_rgb8())?;
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use std::fs;
+
+    #[test]
+    fn prepare_crops_the_center_square() {
+        let path = std::env::temp_dir().join("monsieur_dlp_cover_wide.png");
+        // A 16:9 thumbnail, red in the middle and blue on the sides cut by the crop
+        let wide = RgbImage::from_fn(160, 90, |x, _| {
+            if (35..125).contains(&x) { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) }
+        });
+        wide.save_with_format(&path, ImageFormat::Png).unwrap();
+
+        let jpeg = prepare(&path, 32).unwrap();
+
+        let cover = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap().to_rgb8();
+        assert_eq!(cover.dimensions(), (32, 32));
+        for pixel in [cover.get_pixel(1, 1), cover.get_pixel(30, 16)] {
+            assert!(pixel[0] > 200 && pixel[2] < 60, "{:?}", pixel);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn prepare_rejects_other_files() {
+        let path = std::env::temp_dir().join("monsieur_dlp_cover_text.jpg");
+        fs::write(&path, "not an image").unwrap();
+
+        assert!(matches!(prepare(&path, 32), Err(CoverError::Image(_))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn thumbnail_of_finds_the_image_with_the_same_name() {
+        let dir = std::env::temp_dir().join("monsieur_dlp_cover_thumbnail");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Mr. Blue.mp3"), "").unwrap();
+        fs::write(dir.join("Mr. Blue.webp"), "").unwrap();
+
+        assert_eq!(thumbnail_of(dir.join("Mr. Blue.mp3")), Some(dir.join("Mr. Blue.webp")));
+        assert_eq!(thumbnail_of(dir.join("Other.mp3")), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn local_image_is_relative_to_the_songs_file() {
+        let songs_file = Path::new("/music/ytb-songs.txt");
+
+        assert_eq!(local_image("covers/a.jpg", songs_file), Path::new("/music/covers/a.jpg"));
+        assert_eq!(local_image("/tmp/a.png", songs_file), Path::new("/tmp/a.png"));
+        assert!(is_image("a.JPEG"));
+        assert!(!is_image("a.gif"));
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use super::{Download, DownloadError, Downloader, SongMetadata, output_file};
+use crate::youtube::progress::{DownloadProgress, ProgressKind, Reporter};
+use crate::youtube::song::Song;
+
+pub const NAME: &str = "local";
+
+/// Import a file already on the computer, given as `file:///path` or as a plain path
+pub struct LocalFileImporter;
+
+/// The file pointed by the song url, if it is a local one
+fn source_path(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Some(PathBuf::from(path));
+    }
+    if url.contains("://") {
+        return None;
+    }
+    Some(PathBuf::from(url))
+}
+
+#[async_trait]
+impl Downloader for LocalFileImporter {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn supports(&self, song: &Song) -> bool {
+        match source_path(&song.url) {
+            Some(path) => song.url.starts_with("file://") || path.is_file(),
+            None => false,
+        }
+    }
+
+    async fn download(
+        &self,
+        song: &Song,
+        output: &Path,
+        progress: &Reporter,
+    ) -> Result<Download, DownloadError> {
+        let source = source_path(&song.url).ok_or_else(|| DownloadError::NoBackend(song.url.clone()))?;
+        let stem = source
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let name = if song.name.is_empty() { stem } else { song.name.clone() };
+        if name.is_empty() {
+            return Err(DownloadError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no file name for '{}'", song.url),
+            )));
+        }
+
+        let target = match source.extension() {
+            Some(ext) => output_file(output, ext),
+            None => output.to_path_buf(),
+        };
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let size = tokio::fs::copy(&source, &target).await?;
+        progress.send(ProgressKind::Downloading(DownloadProgress::new(
+            size,
+            Some(size),
+            None,
+            None,
+        )));
+
+        Ok(Download {
+            files: vec![target],
+            metadata: SongMetadata {
+                title: Some(name),
+                artist: Some(song.artist.clone()).filter(|artist| !artist.is_empty()),
+                extractor: Some(NAME.to_string()),
+                ..Default::default()
+            },
+            profile: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn source_path_from_urls() {
+        assert_eq!(source_path("file:///tmp/a.mp3"), Some(PathBuf::from("/tmp/a.mp3")));
+        assert_eq!(source_path("music/a.mp3"), Some(PathBuf::from("music/a.mp3")));
+        assert_eq!(source_path("https://youtu.be/abc"), None);
+    }
+
+    #[tokio::test]
+    async fn download_copies_file_with_song_name() {
+        let dir = std::env::temp_dir().join("monsieur_dlp_local_download_copies_file");
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.opus");
+        fs::write(&source, b"audio").unwrap();
+        let target_dir = dir.join("target");
+
+        let song = Song::new(format!("file://{}", source.display()), "Rust".into(), "Lang".into());
+        assert!(LocalFileImporter.supports(&song));
+
+        let download = LocalFileImporter
+            .download(&song, &target_dir.join("Rust/Lang"), &Reporter::default())
+            .await
+            .unwrap();
+
+        assert_eq!(download.files, vec![target_dir.join("Rust/Lang.opus")]);
+        assert_eq!(fs::read(target_dir.join("Rust/Lang.opus")).unwrap(), b"audio");
+        assert_eq!(download.metadata.artist.as_deref(), Some("Rust"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
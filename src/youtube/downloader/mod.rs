@@ -0,0 +1,384 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::common::config::{Backend, Config};
+use crate::youtube::naming::TemplateError;
+use crate::youtube::profile;
+use crate::youtube::progress::Reporter;
+use crate::youtube::song::Song;
+
+pub mod local;
+pub mod ytdlp;
+
+pub use local::LocalFileImporter;
+pub use ytdlp::YtDlp;
+
+/// Names accepted by the `backend` option of the songs
+pub const BACKENDS: [&str; 2] = [ytdlp::NAME, local::NAME];
+
+impl Backend {
+    /// Name of the backend, as `download.backend` and the songs spell it
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::YtDlp => ytdlp::NAME,
+            Backend::Local => local::NAME,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("Failed to spawn process: {0}")]
+    Spawn(io::Error),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Network error: {0}")]
+    Network(String),
+
+    #[error("Video unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Video blocked in this country: {0}")]
+    GeoBlocked(String),
+
+    #[error("Video is age restricted: {0}")]
+    AgeRestricted(String),
+
+    #[error("Post-processing failed: {0}")]
+    PostProcessing(String),
+
+    #[error("Download failed with status {code}: {message}")]
+    Failed { code: i32, message: String },
+
+    #[error("Download succeeded but produced no file")]
+    NoOutput,
+
+    #[error("No downloader backend can fetch '{0}'")]
+    NoBackend(String),
+
+    #[error("Unknown downloader backend '{0}'")]
+    UnknownBackend(String),
+
+    #[error("Unknown output profile '{0}'")]
+    UnknownProfile(String),
+
+    #[error("Invalid file name template: {0}")]
+    Template(#[from] TemplateError),
+
+    #[error("Unreadable playlist: {0}")]
+    Playlist(String),
+
+    #[error("Unreadable video metadata: {0}")]
+    Metadata(String),
+}
+
+/// What to do with a song whose download failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureKind {
+    /// Worth retrying right away (network hiccup, rate limiting)
+    Transient,
+    /// The song itself cannot be downloaded, it goes to the dead letters
+    Permanent,
+    /// Our side is broken (yt-dlp or ffmpeg missing, disk full...), the song is kept for next time
+    Environment,
+}
+
+impl DownloadError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            DownloadError::Network(_) => FailureKind::Transient,
+            DownloadError::Unavailable(_)
+            | DownloadError::GeoBlocked(_)
+            | DownloadError::AgeRestricted(_)
+            | DownloadError::NoBackend(_) => FailureKind::Permanent,
+            DownloadError::Spawn(_)
+            | DownloadError::Io(_)
+            | DownloadError::PostProcessing(_)
+            | DownloadError::Failed { .. }
+            | DownloadError::NoOutput
+            | DownloadError::UnknownBackend(_)
+            | DownloadError::UnknownProfile(_)
+            | DownloadError::Template(_)
+            | DownloadError::Playlist(_)
+            | DownloadError::Metadata(_) => FailureKind::Environment,
+        }
+    }
+}
+
+/// What a backend knows about the downloaded song
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SongMetadata {
+    /// Id of the video on the source site
+    pub id: Option<String>,
+    pub title: Option<String>,
+    /// Song tags some sites know (YouTube Music, Bandcamp...)
+    pub artist: Option<String>,
+    pub track: Option<String>,
+    pub uploader: Option<String>,
+    pub channel: Option<String>,
+    /// Duration in seconds
+    pub duration: Option<f64>,
+    /// Site the song comes from (youtube, soundcloud, local...)
+    pub extractor: Option<String>,
+}
+
+/// The result of a successful download
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Download {
+    /// Files produced next to the output path
+    pub files: Vec<PathBuf>,
+    pub metadata: SongMetadata,
+    /// Output profile the files were converted with, None when the source file is kept as is
+    pub profile: Option<String>,
+}
+
+/// The output path of a download with the extension of the file, the output file name may
+/// have dots of its own
+pub fn output_file<E: AsRef<std::ffi::OsStr>>(output: &Path, extension: E) -> PathBuf {
+    let mut file = output.as_os_str().to_os_string();
+    file.push(".");
+    file.push(extension);
+    PathBuf::from(file)
+}
+
+/// A way to fetch a song into a file
+#[async_trait]
+pub trait Downloader: Send + Sync {
+    /// Name of the backend in the config (`download.backend`)
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend knows how to fetch the song url
+    fn supports(&self, song: &Song) -> bool;
+
+    /// Download the song to `output`, a path without extension the backend adds the one of the
+    /// produced file to, publishing its progress on `progress`
+    async fn download(
+        &self,
+        song: &Song,
+        output: &Path,
+        progress: &Reporter,
+    ) -> Result<Download, DownloadError>;
+
+    /// What the site knows about the song without downloading it, None when the backend cannot tell
+    async fn metadata(&self, _song: &Song) -> Result<Option<SongMetadata>, DownloadError> {
+        Ok(None)
+    }
+
+    /// The songs of a playlist, channel or album url, None when the url is a single song
+    async fn expand(&self, _song: &Song) -> Result<Option<Vec<Song>>, DownloadError> {
+        Ok(None)
+    }
+}
+
+/// The available backends, the configured default one first
+#[derive(Clone)]
+pub struct Downloaders {
+    backends: Vec<Arc<dyn Downloader>>,
+}
+
+impl Downloaders {
+    /// `backends` are tried in order, the first one is the default
+    pub fn new(backends: Vec<Arc<dyn Downloader>>) -> Self {
+        Self { backends }
+    }
+
+    /// Every built-in backend, with `download.backend` as the default
+    pub fn from_config(config: &Config) -> Result<Self, DownloadError> {
+        let profile = profile::find(&config.download.profile)
+            .ok_or_else(|| DownloadError::UnknownProfile(config.download.profile.clone()))?;
+        let ytdlp = YtDlp::new(&config.download.yt_dlp_path)
+            .with_profile(profile)
+            .with_thumbnails(config.download.covers);
+        let mut backends: Vec<Arc<dyn Downloader>> = vec![
+            Arc::new(ytdlp),
+            Arc::new(LocalFileImporter),
+        ];
+
+        let default = backends
+            .iter()
+            .position(|backend| backend.name() == config.download.backend.name())
+            .ok_or_else(|| DownloadError::UnknownBackend(config.download.backend.name().to_string()))?;
+        backends.swap(0, default);
+
+        Ok(Self::new(backends))
+    }
+
+    /// Pick the backend set on the song, else the default backend when it supports the song,
+    /// otherwise the first one that does
+    pub fn for_song(&self, song: &Song) -> Result<Arc<dyn Downloader>, DownloadError> {
+        if let Some(name) = &song.backend {
+            return self
+                .backends
+                .iter()
+                .find(|backend| backend.name() == name)
+                .cloned()
+                .ok_or_else(|| DownloadError::UnknownBackend(name.clone()));
+        }
+        self.backends
+            .iter()
+            .find(|backend| backend.supports(song))
+            .cloned()
+            .ok_or_else(|| DownloadError::NoBackend(song.url.clone()))
+    }
+
+    /// Download the song with the backend selected for it
+    pub async fn download(
+        &self,
+        song: &Song,
+        output: &Path,
+        progress: &Reporter,
+    ) -> Result<Download, DownloadError> {
+        self.for_song(song)?.download(song, output, progress).await
+    }
+
+    /// What the backend of the song knows about it without downloading it
+    pub async fn metadata(&self, song: &Song) -> Result<Option<SongMetadata>, DownloadError> {
+        self.for_song(song)?.metadata(song).await
+    }
+
+    /// The songs listed by the url of the song, None when it is a single song
+    pub async fn expand(&self, song: &Song) -> Result<Option<Vec<Song>>, DownloadError> {
+        self.for_song(song)?.expand(song).await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Backend writing an empty file, for tests that should not spawn yt-dlp
+    pub struct FakeDownloader {
+        pub name: &'static str,
+        pub prefix: &'static str,
+    }
+
+    #[async_trait]
+    impl Downloader for FakeDownloader {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn supports(&self, song: &Song) -> bool {
+            song.url.starts_with(self.prefix)
+        }
+
+        async fn download(
+            &self,
+            song: &Song,
+            output: &Path,
+            _progress: &Reporter,
+        ) -> Result<Download, DownloadError> {
+            let file = output_file(output, "mp3");
+            tokio::fs::write(&file, b"").await?;
+            Ok(Download {
+                files: vec![file],
+                metadata: SongMetadata {
+                    title: Some(song.name.clone()),
+                    extractor: Some(self.name.to_string()),
+                    ..Default::default()
+                },
+                profile: None,
+            })
+        }
+    }
+
+    fn downloaders() -> Downloaders {
+        Downloaders::new(vec![
+            Arc::new(FakeDownloader {
+                name: "default",
+                prefix: "https://",
+            }),
+            Arc::new(FakeDownloader {
+                name: "other",
+                prefix: "",
+            }),
+        ])
+    }
+
+    #[test]
+    fn backend_names_read_back_in_the_config() {
+        for name in BACKENDS {
+            let backend: Backend = toml::Value::from(name).try_into().unwrap();
+            assert_eq!(backend.name(), name);
+        }
+    }
+
+    #[test]
+    fn for_song_prefers_default_backend() {
+        let song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into());
+
+        assert_eq!(downloaders().for_song(&song).unwrap().name(), "default");
+    }
+
+    #[test]
+    fn for_song_falls_back_to_supporting_backend() {
+        let song = Song::new("ftp://example.com".into(), "Rust".into(), "Lang".into());
+
+        assert_eq!(downloaders().for_song(&song).unwrap().name(), "other");
+    }
+
+    #[test]
+    fn for_song_uses_backend_of_song() {
+        let song = Song {
+            backend: Some("other".into()),
+            ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())
+        };
+        let unknown = Song {
+            backend: Some("curl".into()),
+            ..song.clone()
+        };
+
+        assert_eq!(downloaders().for_song(&song).unwrap().name(), "other");
+        assert!(matches!(
+            downloaders().for_song(&unknown),
+            Err(DownloadError::UnknownBackend(name)) if name == "curl"
+        ));
+    }
+
+    #[test]
+    fn for_song_without_supporting_backend_fails() {
+        let downloaders = Downloaders::new(vec![]);
+        let song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into());
+
+        assert!(matches!(
+            downloaders.for_song(&song),
+            Err(DownloadError::NoBackend(_))
+        ));
+    }
+
+    #[test]
+    fn from_config_puts_configured_backend_first() {
+        let mut config = Config::default();
+        config.download.backend = Backend::Local;
+
+        let downloaders = Downloaders::from_config(&config).unwrap();
+
+        assert_eq!(downloaders.backends[0].name(), local::NAME);
+        assert_eq!(downloaders.backends[1].name(), ytdlp::NAME);
+    }
+
+    #[tokio::test]
+    async fn download_uses_selected_backend() {
+        let dir = std::env::temp_dir().join("monsieur_dlp_download_uses_selected_backend");
+        std::fs::create_dir_all(&dir).unwrap();
+        let song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into());
+
+        let download = downloaders()
+            .download(&song, &dir.join("Mr. Lang"), &Reporter::default())
+            .await
+            .unwrap();
+
+        assert_eq!(download.files, vec![dir.join("Mr. Lang.mp3")]);
+        assert_eq!(download.metadata.extractor.as_deref(), Some("default"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
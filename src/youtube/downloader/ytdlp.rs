@@ -0,0 +1,683 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use super::{Download, DownloadError, Downloader, SongMetadata};
+use crate::youtube::profile::{self, Profile};
+use crate::youtube::progress::{DownloadProgress, ProgressKind, Reporter};
+use crate::youtube::song::Song;
+use crate::youtube::url;
+
+pub const NAME: &str = "yt-dlp";
+
+/// Prefix of the line yt-dlp prints once the song is at its final place
+const OUTPUT_MARKER: &str = "[monsieur_dlp] ";
+
+/// Fields printed after the download, as a JSON object
+const OUTPUT_TEMPLATE: &str = "%(.{filepath,id,title,artist,track,uploader,channel,duration,extractor})j";
+
+/// Prefix of the download progress lines
+const PROGRESS_MARKER: &str = "[monsieur_dlp:progress] ";
+
+/// Download progress fields separated by '|', "NA" when unknown
+const PROGRESS_TEMPLATE: &str = "%(progress.downloaded_bytes)s|%(progress.total_bytes)s|%(progress.total_bytes_estimate)s|%(progress.speed)s|%(progress.eta)s";
+
+/// Prefix of the post-processing progress lines
+const POSTPROCESS_MARKER: &str = "[monsieur_dlp:postprocess] ";
+
+/// Post-processor name and status ("started", "processing", "finished")
+const POSTPROCESS_TEMPLATE: &str = "%(progress.postprocessor)s|%(progress.status)s";
+
+/// Titles yt-dlp gives to the playlist entries that cannot be watched anymore
+const UNAVAILABLE_ENTRIES: &[&str] = &["[Private video]", "[Deleted video]", "[Unavailable video]"];
+
+/// The yt-dlp command line program
+pub struct YtDlp {
+    program: String,
+    /// Output profile of the songs without a `profile` option
+    profile: &'static Profile,
+    /// Also download the video thumbnail, next to the song
+    thumbnails: bool,
+}
+
+/// The JSON object printed by `--print after_move:`
+#[derive(Debug, Deserialize)]
+struct PrintedInfo {
+    filepath: Option<PathBuf>,
+    #[serde(flatten)]
+    metadata: SongMetadata,
+}
+
+/// The JSON object printed by `--flat-playlist --dump-single-json`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FlatPlaylist {
+    entries: Vec<FlatEntry>,
+}
+
+/// An entry of a flat playlist, only the fields yt-dlp knows without opening the video
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FlatEntry {
+    id: Option<String>,
+    url: Option<String>,
+    webpage_url: Option<String>,
+    ie_key: Option<String>,
+    title: Option<String>,
+    track: Option<String>,
+    artist: Option<String>,
+    channel: Option<String>,
+    uploader: Option<String>,
+}
+
+impl YtDlp {
+    /// `program` is the yt-dlp executable name or path
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            profile: &profile::PROFILES[0],
+            thumbnails: false,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: &'static Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// The output profile of the song, its own or the default one
+    fn profile(&self, song: &Song) -> &'static Profile {
+        song.profile.as_deref().and_then(profile::find).unwrap_or(self.profile)
+    }
+
+    /// Write the thumbnail of the songs without a `cover` image, as `<name>.<image ext>`
+    pub fn with_thumbnails(mut self, thumbnails: bool) -> Self {
+        self.thumbnails = thumbnails;
+        self
+    }
+
+    // yt-dlp -x -f bestaudio --audio-format mp3 --audio-quality 320K -o "~/Music/Rust/SONGNAME.%(ext)s" "URL"
+    // The tags are written once downloaded, see `youtube::tagging`
+    fn args(&self, song: &Song, output: &Path) -> Vec<OsString> {
+        // '%' starts a field in yt-dlp output templates
+        let output_template = format!("{}.%(ext)s", output.to_string_lossy().replace('%', "%%"));
+
+        let mut args: Vec<OsString> = self.profile(song).args().into_iter().map(OsString::from).collect();
+        // A `watch?v=...&list=...` url is the one video, playlists are expanded beforehand
+        args.push("--no-playlist".into());
+
+        if self.thumbnails && song.cover.is_none() {
+            args.push("--write-thumbnail".into());
+        }
+        // Only download the [start, end] part of the song
+        if song.start.is_some() || song.end.is_some() {
+            let start = song.start.map_or(0.0, |start| start.as_secs_f64());
+            let end = song.end.map_or("inf".to_string(), |end| end.as_secs_f64().to_string());
+            args.push("--download-sections".into());
+            args.push(format!("*{}-{}", start, end).into());
+            args.push("--force-keyframes-at-cuts".into());
+        }
+        // --print implies --quiet, keep the progress, one line per update
+        args.extend(["--no-simulate", "--progress", "--newline", "--progress-template"].map(OsString::from));
+        args.push(format!("download:{}{}", PROGRESS_MARKER, PROGRESS_TEMPLATE).into());
+        args.push("--progress-template".into());
+        args.push(format!("postprocess:{}{}", POSTPROCESS_MARKER, POSTPROCESS_TEMPLATE).into());
+        args.push("--print".into());
+        args.push(format!("after_move:{}{}", OUTPUT_MARKER, OUTPUT_TEMPLATE).into());
+        args.push("-o".into());
+        args.push(output_template.into());
+        args.push("--".into());
+        args.push(song.url.clone().into());
+        args
+    }
+
+    fn metadata_args(url: &str) -> Vec<String> {
+        ["--dump-json", "--skip-download", "--no-playlist", "--no-warnings", "--", url]
+            .map(String::from)
+            .to_vec()
+    }
+
+    /// Run yt-dlp for its JSON output, a failure is classified like a download one
+    async fn json_output(&self, args: Vec<String>) -> Result<Vec<u8>, DownloadError> {
+        let output = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(DownloadError::Spawn)?;
+        if !output.status.success() {
+            return Err(classify_failure(output.status.code(), &String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(output.stdout)
+    }
+
+    fn expand_args(url: &str) -> Vec<String> {
+        ["--flat-playlist", "--dump-single-json", "--no-warnings", "--", url]
+            .map(String::from)
+            .to_vec()
+    }
+}
+
+#[async_trait]
+impl Downloader for YtDlp {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn supports(&self, song: &Song) -> bool {
+        song.url.starts_with("https://") || song.url.starts_with("http://")
+    }
+
+    async fn download(
+        &self,
+        song: &Song,
+        output: &Path,
+        progress: &Reporter,
+    ) -> Result<Download, DownloadError> {
+        let mut child = Command::new(&self.program)
+            .args(self.args(song, output))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Own process group so that Ctrl-C only reaches us, we kill yt-dlp when cancelled
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(DownloadError::Spawn)?;
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+
+        let read_stdout = async {
+            let mut download = Download {
+                profile: Some(self.profile(song).name.to_string()),
+                ..Default::default()
+            };
+            if let Some(stdout) = stdout {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Some(line) = lines.next_line().await? {
+                    if let Some(info) = parse_output_line(&line) {
+                        download.files.extend(info.filepath);
+                        download.metadata = info.metadata;
+                    } else if let Some(kind) = parse_progress_line(&line) {
+                        progress.send(kind);
+                    } else {
+                        progress.send(ProgressKind::Message(line));
+                    }
+                }
+            }
+            Ok::<_, std::io::Error>(download)
+        };
+
+        // Keep stderr to classify the failure, while still publishing it
+        let read_stderr = async {
+            let mut captured = String::new();
+            if let Some(stderr) = stderr {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Some(line) = lines.next_line().await? {
+                    progress.send(ProgressKind::Message(line.clone()));
+                    captured.push_str(&line);
+                    captured.push('\n');
+                }
+            }
+            Ok::<_, std::io::Error>(captured)
+        };
+
+        let (download, stderr) = tokio::join!(read_stdout, read_stderr);
+        let (download, stderr) = (download?, stderr?);
+        let status = child.wait().await?;
+
+        if !status.success() {
+            return Err(classify_failure(status.code(), &stderr));
+        }
+        if download.files.is_empty() {
+            return Err(DownloadError::NoOutput);
+        }
+        // The converted file, not the downloaded stream, goes to the library and the device
+        let extension = self.profile(song).extension();
+        let unconverted = download.files.iter().find(|file| {
+            !file.extension().is_some_and(|found| found.eq_ignore_ascii_case(extension))
+        });
+        if let Some(file) = unconverted {
+            return Err(DownloadError::PostProcessing(format!(
+                "expected a .{} file, yt-dlp produced '{}'",
+                extension,
+                file.display()
+            )));
+        }
+        Ok(download)
+    }
+
+    async fn expand(&self, song: &Song) -> Result<Option<Vec<Song>>, DownloadError> {
+        if !url::is_collection(&song.url) {
+            return Ok(None);
+        }
+
+        let json = self.json_output(Self::expand_args(&url::collection_url(&song.url))).await?;
+        let songs = playlist_songs(song, &json).map_err(|e| DownloadError::Playlist(e.to_string()))?;
+        Ok(Some(songs))
+    }
+
+    async fn metadata(&self, song: &Song) -> Result<Option<SongMetadata>, DownloadError> {
+        let json = self.json_output(Self::metadata_args(&song.url)).await?;
+        let metadata = serde_json::from_slice(&json).map_err(|e| DownloadError::Metadata(e.to_string()))?;
+        Ok(Some(metadata))
+    }
+}
+
+/// The songs of a flat playlist, taking the options of the playlist song. The artist of the
+/// playlist song wins over the channel of the entries, entries are numbered when it sets an album.
+fn playlist_songs(playlist: &Song, json: &[u8]) -> Result<Vec<Song>, serde_json::Error> {
+    let flat: FlatPlaylist = serde_json::from_slice(json)?;
+
+    let mut songs = vec![];
+    for entry in flat.entries {
+        let title = entry.track.or(entry.title).or(entry.id.clone()).unwrap_or_default();
+        if UNAVAILABLE_ENTRIES.contains(&title.as_str()) {
+            continue;
+        }
+        let entry_url = entry.url.or(entry.webpage_url).or_else(|| match (entry.ie_key.as_deref(), &entry.id) {
+            (Some("Youtube"), Some(id)) => Some(format!("https://www.youtube.com/watch?v={}", id)),
+            _ => None,
+        });
+        // Tabs and nested playlists of a channel are not songs
+        let Some(entry_url) = entry_url.filter(|entry_url| !url::is_collection(entry_url)) else {
+            continue;
+        };
+
+        let artist = match playlist.artist.as_str() {
+            "" => entry.artist.or(entry.channel).or(entry.uploader).unwrap_or_default(),
+            artist => artist.to_string(),
+        };
+        // YouTube Music channels of a single artist are called "<artist> - Topic"
+        let artist = artist.strip_suffix(" - Topic").unwrap_or(&artist).to_string();
+
+        let position = songs.len() as u32 + 1;
+        songs.push(Song {
+            url: entry_url,
+            artist,
+            name: title,
+            track: playlist.album.is_some().then_some(position),
+            start: None,
+            end: None,
+            ..playlist.clone()
+        });
+    }
+    Ok(songs)
+}
+
+/// Snippets of the yt-dlp errors, checked in order: a geo-blocked video is also "unavailable"
+const GEO_BLOCKED: &[&str] = &[
+    "not available in your country",
+    "made this video available in your country",
+    "blocked it in your country",
+    "geo restriction",
+    "geo-restricted",
+    "geo restricted",
+];
+const AGE_RESTRICTED: &[&str] = &[
+    "confirm your age",
+    "age-restricted",
+    "age restricted",
+    "inappropriate for some users",
+];
+const UNAVAILABLE: &[&str] = &[
+    "video unavailable",
+    "private video",
+    "has been removed",
+    "no longer available",
+    "account associated with this video has been terminated",
+    "members-only",
+    "unsupported url",
+    "is not a valid url",
+    "http error 404",
+    "http error 410",
+];
+const POST_PROCESSING: &[&str] = &[
+    "postprocessing:",
+    "ffmpeg not found",
+    "ffprobe not found",
+    "ffprobe and ffmpeg not found",
+    "conversion failed",
+];
+const NETWORK: &[&str] = &[
+    "unable to download webpage",
+    "unable to download video data",
+    "unable to download json metadata",
+    "urlopen error",
+    "timed out",
+    "connection reset",
+    "connection refused",
+    "connection aborted",
+    "remote end closed connection",
+    "temporary failure in name resolution",
+    "name or service not known",
+    "network is unreachable",
+    "incompleteread",
+    "incomplete read",
+    "http error 429",
+    "too many requests",
+    "http error 500",
+    "http error 502",
+    "http error 503",
+    "http error 504",
+    "confirm you're not a bot",
+    "confirm you\u{2019}re not a bot",
+];
+
+/// Turn the exit code and stderr of a failed yt-dlp run into a typed error
+pub fn classify_failure(code: Option<i32>, stderr: &str) -> DownloadError {
+    // yt-dlp prefixes its fatal errors with "ERROR:", the last one is the relevant one. The
+    // warnings are left out, they may mention an unavailable format of a song that downloads
+    let errors: Vec<&str> = stderr.lines().filter_map(|line| line.trim().strip_prefix("ERROR:")).collect();
+    let message = errors
+        .last()
+        .copied()
+        .or_else(|| stderr.lines().rev().find(|line| !line.trim().is_empty()))
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    let lowercase = if errors.is_empty() { message.to_lowercase() } else { errors.join("\n").to_lowercase() };
+    let matches = |snippets: &[&str]| snippets.iter().any(|snippet| lowercase.contains(snippet));
+
+    if matches(GEO_BLOCKED) {
+        DownloadError::GeoBlocked(message)
+    } else if matches(AGE_RESTRICTED) {
+        DownloadError::AgeRestricted(message)
+    } else if matches(UNAVAILABLE) {
+        DownloadError::Unavailable(message)
+    } else if matches(POST_PROCESSING) {
+        DownloadError::PostProcessing(message)
+    } else if matches(NETWORK) {
+        DownloadError::Network(message)
+    } else {
+        // Killed by a signal when there is no code
+        DownloadError::Failed {
+            code: code.unwrap_or(-1),
+            message,
+        }
+    }
+}
+
+/// Parse the lines printed by `--progress-template`, None for any other output
+fn parse_progress_line(line: &str) -> Option<ProgressKind> {
+    if let Some(values) = line.strip_prefix(PROGRESS_MARKER) {
+        let mut values = values.split('|').map(|value| value.trim().parse::<f64>().ok());
+        let mut next = || values.next().flatten();
+
+        let downloaded_bytes = next()?;
+        let total_bytes = next();
+        let total_bytes_estimate = next();
+        let speed = next();
+        let eta = next();
+
+        return Some(ProgressKind::Downloading(DownloadProgress::new(
+            downloaded_bytes as u64,
+            total_bytes.or(total_bytes_estimate).map(|total| total as u64),
+            speed,
+            eta.filter(|eta| *eta >= 0.0).map(Duration::from_secs_f64),
+        )));
+    }
+
+    let values = line.strip_prefix(POSTPROCESS_MARKER)?;
+    let (postprocessor, status) = values.split_once('|').unwrap_or((values, ""));
+    Some(ProgressKind::PostProcessing {
+        stage: match status {
+            "" | "NA" => postprocessor.to_string(),
+            status => format!("{} ({})", postprocessor, status),
+        },
+    })
+}
+
+/// Parse the line printed by `--print after_move:`, None for any other output
+fn parse_output_line(line: &str) -> Option<PrintedInfo> {
+    let json = line.strip_prefix(OUTPUT_MARKER)?;
+    serde_json::from_str(json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::downloader::FailureKind;
+
+    #[test]
+    fn args_escape_output_template_and_end_with_url() {
+        let song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "100% Lang".into());
+
+        let args = YtDlp::new(NAME).args(&song, Path::new("/tmp/dlp/100% Lang"));
+        let position = args.iter().position(|arg| arg == "-o").unwrap();
+
+        assert_eq!(args[position + 1], "/tmp/dlp/100%% Lang.%(ext)s");
+        assert_eq!(args[args.len() - 2], "--");
+        assert_eq!(args[args.len() - 1], "https://youtu.be/abc");
+    }
+
+    #[test]
+    fn args_trim_sections_without_tags() {
+        let song = Song {
+            album: Some("Album".into()),
+            start: Some(Duration::from_secs(30)),
+            ..Song::new("https://youtu.be/abc".into(), "Guns N' Roses".into(), "Lang".into())
+        };
+
+        let args = YtDlp::new(NAME).args(&song, Path::new("/tmp/dlp/Lang"));
+        let sections = args.iter().position(|arg| arg == "--download-sections").unwrap();
+
+        assert_eq!(args[sections + 1], "*30-inf");
+        assert!(!args.iter().any(|arg| arg == "--postprocessor-args"));
+    }
+
+    #[test]
+    fn args_use_the_song_profile_else_the_default_one() {
+        let mut song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into());
+        let ytdlp = YtDlp::new(NAME).with_profile(profile::find("m4a-aac-256").unwrap());
+        let format = |args: Vec<OsString>| {
+            let position = args.iter().position(|arg| arg == "--audio-format").unwrap();
+            args[position + 1].clone()
+        };
+
+        assert_eq!(format(ytdlp.args(&song, Path::new("/tmp/dlp/Lang"))), "m4a");
+        song.profile = Some("opus-original".into());
+        assert_eq!(format(ytdlp.args(&song, Path::new("/tmp/dlp/Lang"))), "opus");
+    }
+
+    #[test]
+    fn args_write_thumbnail_unless_the_song_has_a_cover() {
+        let mut song = Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into());
+        let ytdlp = YtDlp::new(NAME).with_thumbnails(true);
+        let thumbnail = |args: Vec<OsString>| args.iter().any(|arg| arg == "--write-thumbnail");
+
+        assert!(thumbnail(ytdlp.args(&song, Path::new("/tmp/dlp/Lang"))));
+        assert!(!thumbnail(YtDlp::new(NAME).args(&song, Path::new("/tmp/dlp/Lang"))));
+        song.cover = Some("lang.png".into());
+        assert!(!thumbnail(ytdlp.args(&song, Path::new("/tmp/dlp/Lang"))));
+    }
+
+    #[test]
+    fn args_download_a_single_video() {
+        let song = Song::new("https://youtube.com/watch?v=abc&list=PL1".into(), "Rust".into(), "Lang".into());
+
+        let args = YtDlp::new(NAME).args(&song, Path::new("/tmp/dlp/Lang"));
+
+        assert!(args.iter().any(|arg| arg == "--no-playlist"));
+    }
+
+    #[test]
+    fn playlist_songs_from_flat_entries() {
+        let json = br#"{"_type": "playlist", "title": "Mix", "entries": [
+            {"_type": "url", "ie_key": "Youtube", "id": "aaaaaaaaaaa", "url": "https://www.youtube.com/watch?v=aaaaaaaaaaa", "title": "One", "channel": "Band - Topic"},
+            {"_type": "url", "ie_key": "Youtube", "id": "bbbbbbbbbbb", "title": "[Private video]", "channel": null},
+            {"_type": "url", "ie_key": "Youtube", "id": "ccccccccccc", "title": "Two", "uploader": "Someone"},
+            {"_type": "url", "ie_key": "YoutubeTab", "url": "https://www.youtube.com/@band/shorts", "title": "Shorts"}
+        ]}"#;
+        let playlist = Song {
+            folder: Some("Mixes".into()),
+            start: Some(Duration::from_secs(10)),
+            ..Song::new("https://www.youtube.com/playlist?list=PL1".into(), "".into(), "".into())
+        };
+
+        let songs = playlist_songs(&playlist, json).unwrap();
+
+        assert_eq!(songs.len(), 2);
+        assert_eq!(
+            songs[0],
+            Song {
+                folder: Some("Mixes".into()),
+                ..Song::new("https://www.youtube.com/watch?v=aaaaaaaaaaa".into(), "Band".into(), "One".into())
+            }
+        );
+        assert_eq!(songs[1].url, "https://www.youtube.com/watch?v=ccccccccccc");
+        assert_eq!(songs[1].artist, "Someone");
+    }
+
+    #[test]
+    fn playlist_songs_of_an_album_are_numbered_with_its_artist() {
+        let json = br#"{"entries": [
+            {"url": "https://www.youtube.com/watch?v=aaaaaaaaaaa", "title": "One", "channel": "Label"},
+            {"url": "https://www.youtube.com/watch?v=bbbbbbbbbbb", "title": "Two", "channel": "Label"}
+        ]}"#;
+        let album = Song {
+            album: Some("Record".into()),
+            ..Song::new("https://music.youtube.com/playlist?list=OLAK5uy_1".into(), "Band".into(), "".into())
+        };
+
+        let songs = playlist_songs(&album, json).unwrap();
+
+        let tags: Vec<_> = songs.iter().map(|song| (song.artist.as_str(), song.track)).collect();
+        assert_eq!(tags, vec![("Band", Some(1)), ("Band", Some(2))]);
+    }
+
+    #[test]
+    fn parse_output_line_reads_printed_json() {
+        let line = r#"[monsieur_dlp] {"filepath": "/tmp/dlp/Lang.mp3", "id": "abc", "title": "Lang", "artist": null, "uploader": "Rust", "duration": 61.5, "extractor": "youtube"}"#;
+
+        let info = parse_output_line(line).unwrap();
+
+        assert_eq!(info.filepath, Some(PathBuf::from("/tmp/dlp/Lang.mp3")));
+        assert_eq!(
+            info.metadata,
+            SongMetadata {
+                id: Some("abc".into()),
+                title: Some("Lang".into()),
+                artist: None,
+                track: None,
+                uploader: Some("Rust".into()),
+                channel: None,
+                duration: Some(61.5),
+                extractor: Some("youtube".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn classify_failure_geo_blocked_before_unavailable() {
+        let stderr = "ERROR: [youtube] abc: Video unavailable. The uploader has not made this video available in your country\n";
+
+        match classify_failure(Some(1), stderr) {
+            DownloadError::GeoBlocked(message) => assert_eq!(
+                message,
+                "[youtube] abc: Video unavailable. The uploader has not made this video available in your country"
+            ),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn classify_failure_kinds() {
+        let cases = [
+            (
+                "ERROR: [youtube] abc: Sign in to confirm your age. This video may be inappropriate for some users.",
+                FailureKind::Permanent,
+            ),
+            ("ERROR: [youtube] abc: Private video. Sign in if you've been granted access", FailureKind::Permanent),
+            (
+                "ERROR: [youtube] abc: Unable to download webpage: <urlopen error [Errno -3] Temporary failure in name resolution>",
+                FailureKind::Transient,
+            ),
+            ("ERROR: unable to download video data: HTTP Error 429: Too Many Requests", FailureKind::Transient),
+            (
+                "ERROR: [youtube] abc: Sign in to confirm you\u{2019}re not a bot. Use --cookies-from-browser",
+                FailureKind::Transient,
+            ),
+            (
+                "WARNING: [youtube] abc: video unavailable in your country, trying another client\n\
+                 ERROR: [youtube] abc: Unable to download webpage: timed out",
+                FailureKind::Transient,
+            ),
+            ("ERROR: [youtube] abc: Requested format is not available in your country", FailureKind::Permanent),
+            ("ERROR: Postprocessing: audio conversion failed: Error opening output files", FailureKind::Environment),
+            ("ERROR: something new", FailureKind::Environment),
+        ];
+
+        for (stderr, kind) in cases {
+            assert_eq!(classify_failure(Some(1), stderr).kind(), kind, "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn classify_failure_unknown_keeps_code_and_last_error() {
+        let stderr = "WARNING: something\nERROR: first\nERROR: second\n";
+
+        match classify_failure(Some(2), stderr) {
+            DownloadError::Failed { code, message } => {
+                assert_eq!(code, 2);
+                assert_eq!(message, "second");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_progress_line_reads_download_progress() {
+        let line = "[monsieur_dlp:progress] 1048576|4194304|NA|524288.5|6";
+
+        assert_eq!(
+            parse_progress_line(line),
+            Some(ProgressKind::Downloading(DownloadProgress {
+                downloaded_bytes: 1048576,
+                total_bytes: Some(4194304),
+                percent: Some(25.0),
+                speed: Some(524288.5),
+                eta: Some(Duration::from_secs(6)),
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_progress_line_uses_estimate_and_unknown_values() {
+        let line = "[monsieur_dlp:progress] 2048|NA|8192.0|NA|NA";
+
+        assert_eq!(
+            parse_progress_line(line),
+            Some(ProgressKind::Downloading(DownloadProgress {
+                downloaded_bytes: 2048,
+                total_bytes: Some(8192),
+                percent: Some(25.0),
+                speed: None,
+                eta: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_progress_line_reads_postprocessing_stage() {
+        assert_eq!(
+            parse_progress_line("[monsieur_dlp:postprocess] ExtractAudio|started"),
+            Some(ProgressKind::PostProcessing {
+                stage: "ExtractAudio (started)".to_string()
+            })
+        );
+        assert_eq!(parse_progress_line("[download] 42.0% of 3.00MiB"), None);
+    }
+
+    #[test]
+    fn parse_output_line_ignores_other_output() {
+        assert!(parse_output_line("[download] 42.0% of 3.00MiB").is_none());
+    }
+}
@@ -1,4 +1,6 @@
+use crate::youtube::list;
 use crate::youtube::song::*;
+use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
 use std::io::Result;
 use std::io::{BufRead, BufReader, Write};
@@ -9,7 +11,10 @@ pub fn read_songs<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
     let file = match OpenOptions::new().read(true).open(&path) {
         Ok(f) => f,
         Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-            // Create empty file if not found
+            // Create empty file (and its folder) if not found
+            if let Some(parent) = path.as_ref().parent() {
+                fs::create_dir_all(parent)?;
+            }
             File::create(&path)?;
             println!("File not found, created empty file ({:?}).", path.as_ref());
             return Ok(Vec::new());
@@ -27,73 +32,117 @@ pub fn read_songs<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
     Ok(lines)
 }
 
-pub fn add_failed_downloads<P: AsRef<Path>>(songs: &Vec<Song>, path: P) -> Result<()> {
-    let temp_file_name = path.as_ref().with_extension("txt.temp");
+/// Remove the lines of the given songs from the songs file, keeping the comments,
+/// sections, lines in error and any other song where they are
+pub fn remove_songs<P: AsRef<Path>>(songs: &[Song], path: P) -> Result<()> {
+    let replacements: Vec<(Song, Vec<Song>)> = songs.iter().map(|song| (song.clone(), vec![])).collect();
+    replace_songs(&replacements, path)
+}
+
+/// Write the songs of each pair in place of the line of its first song, the rest of the
+/// songs file is kept as is
+pub fn replace_songs<P: AsRef<Path>>(replacements: &[(Song, Vec<Song>)], path: P) -> Result<()> {
+    let written: Vec<&Song> = replacements.iter().flat_map(|(_, songs)| songs).collect();
+    upgrade_version(&written, &path)?;
+    let lines = read_songs(&path)?;
+    let mut listed = list::parse(&lines).songs;
+    let mut replaced = HashMap::new();
+    for (song, songs) in replacements {
+        if let Some(index) = listed.iter().position(|(_, listed)| listed == song) {
+            replaced.insert(listed.remove(index).0, songs);
+        }
+    }
 
+    let temp_file_name = path.as_ref().with_extension("txt.temp");
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(&temp_file_name)?;
 
-    for song in songs {
-        writeln!(file, "{}", song)?;
+    for (index, line) in lines.iter().enumerate() {
+        match replaced.get(&(index + 1)) {
+            Some(songs) => {
+                for song in songs.iter() {
+                    writeln!(file, "{}", song)?;
+                }
+            }
+            None => writeln!(file, "{}", line)?,
+        }
     }
     fs::rename(temp_file_name, path)?;
     Ok(())
 }
 
-pub fn add_success_downloads<P: AsRef<Path>>(
-    songs: &Vec<Song>,
-    historic_file_name: P,
-) -> Result<()> {
+/// Append the songs that can never be downloaded with the reason as a comment above each one
+pub fn add_dead_letters<P: AsRef<Path>>(songs: &[(Song, String)], path: P) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    upgrade_version(&songs.iter().map(|(song, _)| song).collect::<Vec<_>>(), &path)?;
+
     let mut file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
-        .open(&historic_file_name)?;
+        .open(&path)?;
 
-    for song in songs {
+    for (song, reason) in songs {
+        writeln!(file, "# {}", reason.replace('\n', " "))?;
         writeln!(file, "{}", song)?;
     }
     Ok(())
 }
 
-/// Convert the lines from the file into Song objects
-pub fn serialize_file(lines: Vec<String>) -> Vec<Song> {
-    let mut songs = Vec::new();
-
-    for line in lines {
-        let line = line.trim();
-
-        if line.is_empty() {
-            continue; // skip empty lines
-        }
+/// Append songs at the end of the given file, creating it if needed.
+/// A section left open at the end of the file is closed first.
+pub fn append_songs<P: AsRef<Path>>(songs: &[Song], path: P) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    upgrade_version(&songs.iter().collect::<Vec<_>>(), &path)?;
+    let open_section = path.as_ref().is_file() && list::parse(&read_songs(&path)?).open_section.is_some();
 
-        let mut parts = line.splitn(3, '|');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
 
-        let url = match parts.next() {
-            Some(u) => u,
-            None => continue,
-        };
+    if open_section {
+        writeln!(file, "[]")?;
+    }
 
-        let artist = match parts.next() {
-            Some(a) => a,
-            None => continue,
-        };
+    for song in songs {
+        writeln!(file, "{}", song)?;
+    }
+    Ok(())
+}
 
-        let name = match parts.next() {
-            Some(n) => n,
-            None => continue,
-        };
+/// Put the `#!version` line on top of a version 1 songs file before songs with options
+/// are written to it, as version 1 reads them as part of the title. Its lines are kept:
+/// a title holding '|' turns into a line in error to fix by hand.
+fn upgrade_version<P: AsRef<Path>>(songs: &[&Song], path: P) -> Result<()> {
+    if songs.iter().all(|song| song.options().is_empty()) {
+        return Ok(());
+    }
+    let lines = if path.as_ref().is_file() { read_songs(&path)? } else { vec![] };
+    if list::parse(&lines).version >= 2 {
+        return Ok(());
+    }
 
-        songs.push(Song::new(
-            url.to_string(),
-            artist.to_string(),
-            name.to_string(),
-        ));
+    let temp_file_name = path.as_ref().with_extension("txt.temp");
+    let mut file = File::create(&temp_file_name)?;
+    writeln!(file, "#!version {}", list::FORMAT_VERSION)?;
+    for line in lines.iter().filter(|line| !line.trim().starts_with("#!version")) {
+        writeln!(file, "{}", line)?;
     }
+    fs::rename(temp_file_name, path)?;
+    Ok(())
+}
 
-    songs
+/// Convert the lines from the file into Song objects, skipping the lines in error
+pub fn serialize_file(lines: Vec<String>) -> Vec<Song> {
+    list::parse(&lines).into_songs()
 }
 
 #[cfg(test)]
@@ -187,55 +236,105 @@ mod tests {
     }
 
     #[test]
-    fn add_success_downloads_writes_correct_data() {
-        let songs = vec![
-            Song::new("https://url1.com".into(), "Artist1".into(), "Title1".into()),
-            Song::new("https://url2.com".into(), "Artist2".into(), "Title2".into()),
-        ];
+    fn remove_songs_keeps_comments_sections_and_other_songs() {
+        let test_file = PathBuf::from("test_remove_songs_keeps_comments_sections_and_other_songs.txt");
+        fs::write(
+            &test_file,
+            "# Favourites\nhttps://url1.com|Artist1|Title1\n[Trip|album=Road]\nhttps://url2.com|Artist2|Title2|track=2\nbroken line\nhttps://url3.com|Artist3|Title3\n",
+        )
+        .unwrap();
+        let songs = serialize_file(read_songs(&test_file).unwrap());
 
-        let temp_file_name = PathBuf::from("test_add_success_downloads_writes_correct_data.txt");
+        remove_songs(&[songs[0].clone(), songs[1].clone()], &test_file).unwrap();
 
-        if temp_file_name.exists() {
-            // Cleanup
-            fs::remove_file(&temp_file_name).unwrap();
-        }
+        let content = fs::read_to_string(&test_file).unwrap();
+        let expected = "# Favourites\n[Trip|album=Road]\nbroken line\nhttps://url3.com|Artist3|Title3\n";
+        assert_eq!(content, expected);
+
+        // Cleanup
+        fs::remove_file(test_file).unwrap();
+    }
 
-        // Run the function using the test-specific file
-        add_success_downloads(&songs, &temp_file_name).unwrap();
+    #[test]
+    fn replace_songs_writes_songs_in_place_of_the_line() {
+        let test_file = PathBuf::from("test_replace_songs_writes_songs_in_place_of_the_line.txt");
+        fs::write(&test_file, "# Mixes\nhttps://list.com|Band|\nhttps://url3.com|Artist3|Title3\n").unwrap();
+        let songs = serialize_file(read_songs(&test_file).unwrap());
+        let entries = vec![
+            Song::new("https://url1.com".into(), "Band".into(), "One".into()),
+            Song::new("https://url2.com".into(), "Band".into(), "Two".into()),
+        ];
 
-        // Read back the content
-        let content = fs::read_to_string(&temp_file_name).unwrap();
+        replace_songs(&[(songs[0].clone(), entries)], &test_file).unwrap();
 
-        let expected = "https://url1.com|Artist1|Title1\nhttps://url2.com|Artist2|Title2\n";
+        let content = fs::read_to_string(&test_file).unwrap();
+        let expected = "# Mixes\nhttps://url1.com|Band|One\nhttps://url2.com|Band|Two\nhttps://url3.com|Artist3|Title3\n";
         assert_eq!(content, expected);
 
         // Cleanup
-        fs::remove_file(temp_file_name).unwrap();
+        fs::remove_file(test_file).unwrap();
+    }
+
+    #[test]
+    fn append_songs_closes_open_section() {
+        let test_file = PathBuf::from("test_append_songs_closes_open_section.txt");
+        fs::write(&test_file, "[Trip|folder=Trips]\nhttps://url1.com|Artist1|Title1\n").unwrap();
+        let song = Song::new("https://url2.com".into(), "Artist2".into(), "Title2".into());
+
+        append_songs(std::slice::from_ref(&song), &test_file).unwrap();
+
+        let songs = serialize_file(read_songs(&test_file).unwrap());
+        assert_eq!(songs[1], song);
+
+        // Cleanup
+        fs::remove_file(test_file).unwrap();
+    }
+
+    #[test]
+    fn append_songs_with_options_upgrades_a_version_1_file() {
+        let test_file = PathBuf::from("test_append_songs_with_options_upgrades_a_version_1_file.txt");
+        fs::write(&test_file, "https://url1.com|Artist1|Live | Acoustic\n").unwrap();
+        let song = Song {
+            track: Some(2),
+            ..Song::new("https://url2.com".into(), "Artist2".into(), "Title2".into())
+        };
+
+        append_songs(std::slice::from_ref(&song), &test_file).unwrap();
+
+        let content = fs::read_to_string(&test_file).unwrap();
+        assert_eq!(content, "#!version 2\nhttps://url1.com|Artist1|Live | Acoustic\nhttps://url2.com|Artist2|Title2|track=2\n");
+        assert_eq!(serialize_file(read_songs(&test_file).unwrap()), vec![song.clone()]);
+        append_songs(&[song], &test_file).unwrap();
+        assert_eq!(fs::read_to_string(&test_file).unwrap().matches("#!version").count(), 1);
+
+        // Cleanup
+        fs::remove_file(test_file).unwrap();
     }
 
     #[test]
-    fn add_failed_downloads_writes_correct_data() {
-        let songs = vec![
+    fn add_dead_letters_writes_reason_above_song() {
+        let songs = vec![(
             Song::new("https://url1.com".into(), "Artist1".into(), "Title1".into()),
-            Song::new("https://url2.com".into(), "Artist2".into(), "Title2".into()),
-        ];
+            "Video unavailable: Private video\nSign in".to_string(),
+        )];
 
-        let test_file = PathBuf::from("test_add_failed_downloads_writes_correct_data.txt");
+        let test_file = PathBuf::from("test_add_dead_letters_writes_reason_above_song.txt");
 
         if test_file.exists() {
             // Cleanup
             fs::remove_file(&test_file).unwrap();
         }
 
-        // Run the function using the test-specific file
-        add_failed_downloads(&songs, &test_file).unwrap();
+        add_dead_letters(&songs, &test_file).unwrap();
 
-        // Read back the content
         let content = fs::read_to_string(&test_file).unwrap();
 
-        let expected = "https://url1.com|Artist1|Title1\nhttps://url2.com|Artist2|Title2\n";
+        let expected = "# Video unavailable: Private video Sign in\nhttps://url1.com|Artist1|Title1\n";
         assert_eq!(content, expected);
 
+        // The comment line is not read back as a song
+        assert_eq!(serialize_file(read_songs(&test_file).unwrap()).len(), 1);
+
         // Cleanup
         fs::remove_file(test_file).unwrap();
     }
@@ -268,6 +367,7 @@ mod tests {
     fn read_songs_from_existing_file() {
         // Define a temp file path but don't create the file
         let file_path = Path::new("youtube_files").join("test_read_songs_from_existing_file.txt");
+        fs::create_dir_all("youtube_files").unwrap();
 
         if file_path.exists() {
             // Cleanup
@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use super::list;
+use super::song::Song;
+
+/// Song fields an imported file can set, the songs file options after url, artist and title
+pub const FIELDS: [&str; 12] = [
+    "url", "artist", "title", "album", "track", "year", "genre", "start", "end", "folder",
+    "priority", "backend",
+];
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("CSV Error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("JSON Error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Cannot tell the format of '{0}', expected a .csv, .json or .m3u file")]
+    UnknownFormat(PathBuf),
+
+    #[error("Invalid column mapping '{0}', expected FIELD=HEADER with FIELD one of {FIELDS:?}")]
+    Mapping(String),
+
+    #[error("No '{0}' column in the CSV header, map one with --column {0}=HEADER")]
+    MissingColumn(String),
+}
+
+/// The formats songs can be imported from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A header line then one song per line, see [`Columns`]
+    Csv,
+    /// An array of objects with the song fields
+    Json,
+    /// A playlist, extended (`#EXTINF`) or not
+    M3u,
+}
+
+impl Format {
+    /// The format of the file, from its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            "m3u" | "m3u8" => Some(Format::M3u),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "m3u" | "m3u8" => Ok(Format::M3u),
+            other => Err(format!(
+                "unknown format '{}', expected csv, json or m3u",
+                other
+            )),
+        }
+    }
+}
+
+/// Which CSV column holds each song field, a column named after the field by default
+/// (`name` is also accepted for the title)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Columns {
+    headers: BTreeMap<String, String>,
+}
+
+impl Columns {
+    /// Read `FIELD=HEADER` mappings
+    pub fn parse<S: AsRef<str>>(mappings: &[S]) -> Result<Self, ImportError> {
+        let mut headers = BTreeMap::new();
+        for mapping in mappings {
+            let mapping = mapping.as_ref();
+            match mapping.split_once('=') {
+                Some((field, header))
+                    if FIELDS.contains(&field.trim()) && !header.trim().is_empty() =>
+                {
+                    headers.insert(field.trim().to_string(), header.trim().to_string());
+                }
+                _ => return Err(ImportError::Mapping(mapping.to_string())),
+            }
+        }
+        Ok(Self { headers })
+    }
+
+    /// The position of the column of every field found in the header
+    fn positions(&self, header: &csv::StringRecord) -> BTreeMap<&'static str, usize> {
+        let find = |name: &str| {
+            header
+                .iter()
+                .position(|column| column.trim().eq_ignore_ascii_case(name))
+        };
+
+        let mut positions = BTreeMap::new();
+        for field in FIELDS {
+            let position = match self.headers.get(field) {
+                Some(header) => find(header),
+                None if field == "title" => find(field).or_else(|| find("name")),
+                None => find(field),
+            };
+            if let Some(position) = position {
+                positions.insert(field, position);
+            }
+        }
+        positions
+    }
+}
+
+/// The songs of an imported file, and why the other entries were left out
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Imported {
+    pub songs: Vec<Song>,
+    pub skipped: Vec<String>,
+}
+
+impl Imported {
+    fn push(&mut self, place: String, song: Result<Song, String>) {
+        match song {
+            Ok(song) => self.songs.push(song),
+            Err(message) => self.skipped.push(format!("{}: {}", place, message)),
+        }
+    }
+}
+
+/// Read the songs of the file in the given format, guessed from the extension by default
+pub fn import<P: AsRef<Path>>(
+    path: P,
+    format: Option<Format>,
+    columns: &Columns,
+) -> Result<Imported, ImportError> {
+    let path = path.as_ref();
+    let format = format
+        .or_else(|| Format::from_path(path))
+        .ok_or_else(|| ImportError::UnknownFormat(path.to_path_buf()))?;
+
+    match format {
+        Format::Csv => read_csv(path, columns),
+        Format::Json => read_json(path),
+        Format::M3u => read_m3u(path),
+    }
+}
+
+/// One song per record, the columns are found by their header
+pub fn read_csv<P: AsRef<Path>>(path: P, columns: &Columns) -> Result<Imported, ImportError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(path)?;
+    let positions = columns.positions(reader.headers()?);
+    if !positions.contains_key("url") {
+        return Err(ImportError::MissingColumn("url".to_string()));
+    }
+
+    let mut imported = Imported::default();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |position| position.line());
+        let field = |name: &str| {
+            positions
+                .get(name)
+                .and_then(|position| record.get(*position))
+                .unwrap_or_default()
+        };
+
+        let options: Vec<(&str, &str)> = FIELDS[3..]
+            .iter()
+            .map(|name| (*name, field(name)))
+            .collect();
+        let song = list::song_from_fields(field("url"), field("artist"), field("title"), &options);
+        imported.push(format!("line {}", line), song);
+    }
+    Ok(imported)
+}
+
+/// An array of objects with the song fields, `name` is also accepted for the title
+pub fn read_json<P: AsRef<Path>>(path: P) -> Result<Imported, ImportError> {
+    let entries: Vec<Map<String, Value>> = serde_json::from_reader(File::open(path)?)?;
+
+    let mut imported = Imported::default();
+    for (index, entry) in entries.iter().enumerate() {
+        imported.push(format!("song {}", index + 1), json_song(entry));
+    }
+    Ok(imported)
+}
+
+fn json_song(entry: &Map<String, Value>) -> Result<Song, String> {
+    let mut fields = BTreeMap::new();
+    for (key, value) in entry {
+        let field = if key == "name" { "title" } else { key.as_str() };
+        if !FIELDS.contains(&field) {
+            return Err(format!("unknown field '{}'", key));
+        }
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            Value::Null => continue,
+            other => {
+                return Err(format!(
+                    "'{}' must be a string or a number, found {}",
+                    key, other
+                ));
+            }
+        };
+        if fields.insert(field, value).is_some() {
+            return Err(format!("'{}' is set twice", field));
+        }
+    }
+
+    let field = |name: &str| fields.get(name).map(String::as_str).unwrap_or_default();
+    let options: Vec<(&str, &str)> = FIELDS[3..]
+        .iter()
+        .map(|name| (*name, field(name)))
+        .collect();
+    list::song_from_fields(field("url"), field("artist"), field("title"), &options)
+}
+
+/// A playlist: `#EXTINF:<seconds>,<artist> - <title>` (and `#EXTALB`, `#EXTGENRE`) describe
+/// the url or path on the next line. Relative paths are relative to the playlist.
+pub fn read_m3u<P: AsRef<Path>>(path: P) -> Result<Imported, ImportError> {
+    let path = path.as_ref();
+    let base = path.parent().unwrap_or(Path::new(""));
+    let content = fs::read_to_string(path)?;
+
+    let mut imported = Imported::default();
+    let (mut artist, mut title, mut album, mut genre) =
+        (String::new(), String::new(), String::new(), String::new());
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let name = extinf_name(info);
+            (artist, title) = match name.split_once(" - ") {
+                Some((artist, title)) => (artist.trim().to_string(), title.trim().to_string()),
+                None => (String::new(), name.to_string()),
+            };
+        } else if let Some(value) = line.strip_prefix("#EXTALB:") {
+            album = value.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("#EXTGENRE:") {
+            genre = value.trim().to_string();
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let url = if line.contains("://") || Path::new(line).is_absolute() {
+                line.to_string()
+            } else {
+                base.join(line).display().to_string()
+            };
+            if title.is_empty() && !line.contains("://") {
+                title = Path::new(line)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+            }
+
+            let song = list::song_from_fields(
+                &url,
+                &artist,
+                &title,
+                &[("album", &album), ("genre", &genre)],
+            );
+            imported.push(format!("line {}", index + 1), song);
+            (artist, title) = (String::new(), String::new());
+        }
+    }
+    Ok(imported)
+}
+
+/// The display name of an `#EXTINF:` line, after the first comma outside the quoted
+/// attributes (`tvg-name="a,b"` and such): the name itself may hold commas
+fn extinf_name(info: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in info.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => return info[index + 1..].trim(),
+            _ => {}
+        }
+    }
+    ""
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn write_file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("monsieur_dlp_import_{}", name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn format_from_extension_or_name() {
+        assert_eq!(Format::from_path(Path::new("list.CSV")), Some(Format::Csv));
+        assert_eq!(Format::from_path(Path::new("list.m3u8")), Some(Format::M3u));
+        assert_eq!(Format::from_path(Path::new("list.txt")), None);
+        assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn read_csv_with_default_and_mapped_columns() {
+        let path = write_file(
+            "songs.csv",
+            "Link,Artist,Name,Track,Notes\nhttps://youtu.be/abc,Rust,Lang,3,great\n,No,Url,,\nhttps://youtu.be/def,\"Crates, Inc\",Io,zero,\n",
+        );
+        let columns = Columns::parse(&["url=link"]).unwrap();
+
+        let imported = read_csv(&path, &columns).unwrap();
+
+        assert_eq!(imported.songs.len(), 1);
+        assert_eq!(
+            imported.songs[0],
+            Song {
+                track: Some(3),
+                ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())
+            }
+        );
+        assert_eq!(imported.skipped.len(), 2);
+        assert_eq!(imported.skipped[0], "line 3: missing url");
+        assert!(imported.skipped[1].starts_with("line 4: invalid track"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_csv_without_url_column_fails() {
+        let path = write_file("no_url.csv", "Artist,Title\nRust,Lang\n");
+
+        let result = read_csv(&path, &Columns::default());
+
+        assert!(matches!(result, Err(ImportError::MissingColumn(field)) if field == "url"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn columns_parse_rejects_unknown_fields() {
+        assert!(Columns::parse(&["colour=Colour"]).is_err());
+        assert!(Columns::parse(&["title"]).is_err());
+        assert!(Columns::parse(&["title=Song", "artist=Band"]).is_ok());
+    }
+
+    #[test]
+    fn read_json_array_of_songs() {
+        let path = write_file(
+            "songs.json",
+            r#"[
+                {"url": "https://youtu.be/abc", "artist": "Rust", "name": "Lang", "year": 2015, "album": null},
+                {"url": "https://youtu.be/def", "title": "Io", "colour": "red"},
+                {"url": "https://youtu.be/ghi", "title": "Io", "start": "0:30", "end": 95}
+            ]"#,
+        );
+
+        let imported = read_json(&path).unwrap();
+
+        assert_eq!(imported.songs.len(), 2);
+        assert_eq!(imported.songs[0].year, Some(2015));
+        assert_eq!(imported.songs[0].name, "Lang");
+        assert_eq!(
+            imported.songs[1].end,
+            Some(std::time::Duration::from_secs(95))
+        );
+        assert_eq!(imported.skipped, vec!["song 2: unknown field 'colour'"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_extended_m3u() {
+        let path = write_file(
+            "playlist.m3u",
+            "#EXTM3U\n#EXTINF:212,Rust - Lang\n#EXTALB:Album\nhttps://youtu.be/abc\n\
+             #EXTINF:-1 tvg-name=\"a,b\",Crates\nmusic/io.mp3\nother.mp3\n#EXTINF:215,Earth, Wind & Fire - September\nhttps://youtu.be/def\n",
+        );
+
+        let imported = read_m3u(&path).unwrap();
+
+        assert!(imported.skipped.is_empty());
+        assert_eq!(
+            imported.songs[0],
+            Song {
+                album: Some("Album".into()),
+                ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())
+            }
+        );
+        assert_eq!(imported.songs[1].artist, "");
+        assert_eq!(imported.songs[1].name, "Crates");
+        assert_eq!(
+            imported.songs[1].url,
+            env::temp_dir().join("music/io.mp3").display().to_string()
+        );
+        assert_eq!(imported.songs[2].name, "other");
+        assert_eq!(imported.songs[3].artist, "Earth, Wind & Fire");
+        assert_eq!(imported.songs[3].name, "September");
+        fs::remove_file(path).unwrap();
+    }
+}
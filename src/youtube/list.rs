@@ -0,0 +1,385 @@
+//! The songs file format.
+//!
+//! ```text
+//! #!version 2
+//! # Comments start with '#'
+//! https://youtu.be/abc|Artist|Title
+//!
+//! [Road trip|album=Road trip|folder=Trips]
+//! https://youtu.be/def|Artist|Title|track=3|year=2001|start=0:30|end=3:15|priority=5
+//! ```
+//!
+//! Songs are `url|artist|title` followed by optional `|key=value` options. A `[section]`
+//! line names the songs below it until the next one (`[]` ends it), its options apply to
+//! every song of the section unless the song sets them again. Version 1 files are the
+//! plain three-field lines, they need no `#!version` line: everything after the second
+//! `|` is the title there, the options are only read from version 2.
+
+use std::fmt;
+use std::path::Path;
+
+use super::{cover, downloader, profile};
+use super::song::{Song, parse_time};
+
+/// Latest version of the songs file format this build reads
+pub const FORMAT_VERSION: u32 = 2;
+
+/// A line of the songs file that could not be read
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// Line number, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// The songs of a songs file with the line each one comes from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SongList {
+    pub version: u32,
+    pub songs: Vec<(usize, Song)>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The section still open at the end of the file, songs appended to it would join it
+    pub open_section: Option<String>,
+}
+
+impl SongList {
+    pub fn into_songs(self) -> Vec<Song> {
+        self.songs.into_iter().map(|(_, song)| song).collect()
+    }
+}
+
+/// What a line of the songs file is
+enum Line {
+    Blank,
+    Version(u32),
+    Section(Option<Song>),
+    Song(Song),
+}
+
+/// Read the lines of a songs file, the lines in error are reported instead of the songs
+pub fn parse<S: AsRef<str>>(lines: &[S]) -> SongList {
+    let mut list = SongList {
+        version: 1,
+        ..Default::default()
+    };
+    let mut section = None;
+
+    for (index, line) in lines.iter().enumerate() {
+        let number = index + 1;
+        match parse_line(line.as_ref(), section.as_ref(), list.version) {
+            Ok(Line::Blank) => {}
+            Ok(Line::Version(version)) if version > FORMAT_VERSION => {
+                list.diagnostics.push(Diagnostic {
+                    line: number,
+                    message: format!(
+                        "songs file version {} is newer than the supported version {}",
+                        version, FORMAT_VERSION
+                    ),
+                });
+                list.songs.clear();
+                return list;
+            }
+            Ok(Line::Version(version)) => list.version = version,
+            Ok(Line::Section(defaults)) => section = defaults,
+            Ok(Line::Song(song)) => list.songs.push((number, song)),
+            Err(message) => list.diagnostics.push(Diagnostic { line: number, message }),
+        }
+    }
+    list.open_section = section.and_then(|section| section.section);
+    list
+}
+
+fn parse_line(line: &str, section: Option<&Song>, version: u32) -> Result<Line, String> {
+    let line = line.trim();
+
+    if let Some(directive) = line.strip_prefix("#!") {
+        return match directive.trim().strip_prefix("version") {
+            Some(version) => version
+                .trim()
+                .parse()
+                .map(Line::Version)
+                .map_err(|_| format!("invalid version '{}'", version.trim())),
+            None => Err(format!("unknown directive '#!{}'", directive)),
+        };
+    }
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Line::Blank);
+    }
+
+    if let Some(header) = line.strip_prefix('[') {
+        let header = header
+            .strip_suffix(']')
+            .ok_or_else(|| "section line must end with ']'".to_string())?;
+        let mut fields = header.split('|');
+        let name = fields.next().unwrap_or_default().trim();
+        if name.is_empty() {
+            return match fields.next() {
+                Some(_) => Err("section options need a section name".to_string()),
+                None => Ok(Line::Section(None)),
+            };
+        }
+
+        let mut defaults = Song {
+            section: Some(name.to_string()),
+            ..Default::default()
+        };
+        set_options(&mut defaults, fields)?;
+        return Ok(Line::Section(Some(defaults)));
+    }
+
+    let mut fields = line.splitn(3, '|');
+    let (Some(url), Some(artist), Some(rest)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err("expected 'url|artist|title', optionally followed by '|key=value' options".to_string());
+    };
+    // A version 1 title may contain '|'
+    let (name, options) = match rest.split_once('|') {
+        Some((name, options)) if version >= 2 => (name, options.split('|').collect()),
+        _ => (rest, vec![]),
+    };
+
+    let mut song = section.cloned().unwrap_or_default();
+    song.url = url.to_string();
+    song.artist = artist.to_string();
+    song.name = name.to_string();
+    set_options(&mut song, options.into_iter())?;
+
+    if let (Some(start), Some(end)) = (song.start, song.end)
+        && start >= end
+    {
+        return Err("start must be before end".to_string());
+    }
+    Ok(Line::Song(song))
+}
+
+/// Set the `key=value` fields on the song, every key at most once
+fn set_options<'a>(song: &mut Song, fields: impl Iterator<Item = &'a str>) -> Result<(), String> {
+    let mut seen = vec![];
+
+    for field in fields {
+        let Some((key, value)) = field.split_once('=') else {
+            return Err(format!("expected a 'key=value' option, found '{}'", field));
+        };
+        let key = key.trim();
+        if seen.contains(&key) {
+            return Err(format!("option '{}' is set twice", key));
+        }
+        seen.push(key);
+        set_option(song, key, value.trim())?;
+    }
+    Ok(())
+}
+
+fn set_option(song: &mut Song, key: &str, value: &str) -> Result<(), String> {
+    let invalid = |expected: &str| format!("invalid {} '{}', expected {}", key, value, expected);
+    match key {
+        "album" => song.album = Some(value.to_string()),
+        "track" => {
+            let track = value.parse().ok().filter(|track| *track > 0);
+            song.track = Some(track.ok_or_else(|| invalid("a positive number"))?);
+        }
+        "year" => song.year = Some(value.parse().map_err(|_| invalid("a year"))?),
+        "genre" => song.genre = Some(value.to_string()),
+        "start" => song.start = Some(parse_time(value).ok_or_else(|| invalid("[[h:]m:]s"))?),
+        "end" => song.end = Some(parse_time(value).ok_or_else(|| invalid("[[h:]m:]s"))?),
+        "folder" => {
+            let folder = value.trim_matches('/');
+            let relative = !folder.is_empty() && Path::new(folder).components().all(|part| {
+                matches!(part, std::path::Component::Normal(_))
+            });
+            if !relative {
+                return Err(invalid("a folder inside the app Documents"));
+            }
+            song.folder = Some(folder.to_string());
+        }
+        "priority" => song.priority = value.parse().map_err(|_| invalid("a number"))?,
+        "backend" => {
+            if !downloader::BACKENDS.contains(&value) {
+                return Err(invalid(&format!("one of {:?}", downloader::BACKENDS)));
+            }
+            song.backend = Some(value.to_string());
+        }
+        "profile" => {
+            if profile::find(value).is_none() {
+                return Err(invalid(&format!("one of {:?}", profile::names())));
+            }
+            song.profile = Some(value.to_string());
+        }
+        "cover" => {
+            if !cover::is_image(value) {
+                return Err(invalid(&format!("an image file ({})", cover::IMAGE_EXTENSIONS.join(", "))));
+            }
+            song.cover = Some(value.to_string());
+        }
+        _ => return Err(format!("unknown option '{}'", key)),
+    }
+    Ok(())
+}
+
+/// Build a song from separate fields, as the importers read them. The empty options are
+/// ignored, the values must fit on a songs file line.
+pub fn song_from_fields(url: &str, artist: &str, title: &str, options: &[(&str, &str)]) -> Result<Song, String> {
+    for value in [url, artist, title].iter().chain(options.iter().map(|(_, value)| value)) {
+        if value.contains(['|', '\n', '\r']) {
+            return Err(format!("'{}' cannot contain '|' or a line break", value));
+        }
+    }
+    if url.trim().is_empty() {
+        return Err("missing url".to_string());
+    }
+
+    let mut song = Song::new(url.trim().to_string(), artist.trim().to_string(), title.trim().to_string());
+    for (key, value) in options {
+        if !value.trim().is_empty() {
+            set_option(&mut song, key, value.trim())?;
+        }
+    }
+    if let (Some(start), Some(end)) = (song.start, song.end)
+        && start >= end
+    {
+        return Err("start must be before end".to_string());
+    }
+    Ok(song)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_three_field_lines_as_version_1() {
+        let list = parse(&["https://youtu.be/abc|Rust|Lang", "", "  https://crates.io/|Crates|Io  "]);
+
+        assert_eq!(list.version, 1);
+        assert!(list.diagnostics.is_empty());
+        assert_eq!(
+            list.songs,
+            vec![
+                (1, Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())),
+                (3, Song::new("https://crates.io/".into(), "Crates".into(), "Io".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_version_1_titles_with_pipes() {
+        let list = parse(&["https://youtu.be/abc|Rust|Live | Acoustic|album=Unplugged"]);
+
+        assert!(list.diagnostics.is_empty(), "{:?}", list.diagnostics);
+        assert_eq!(
+            list.into_songs(),
+            vec![Song::new("https://youtu.be/abc".into(), "Rust".into(), "Live | Acoustic|album=Unplugged".into())]
+        );
+    }
+
+    #[test]
+    fn parse_options_comments_and_sections() {
+        let list = parse(&[
+            "#!version 2",
+            "# Favourites",
+            "[Road trip|album=Road trip|folder=Trips/2024]",
+            "https://youtu.be/abc|Rust|Lang|track=3|year=2001|genre=Rock|start=0:30|end=1:02:03.5",
+            "https://youtu.be/def|Rust|Other|album=Single|priority=5|backend=local",
+            "[]",
+            "https://youtu.be/ghi|Rust|Last",
+        ]);
+
+        assert_eq!(list.version, 2);
+        assert!(list.diagnostics.is_empty(), "{:?}", list.diagnostics);
+        let songs = list.into_songs();
+        assert_eq!(
+            songs[0],
+            Song {
+                section: Some("Road trip".into()),
+                album: Some("Road trip".into()),
+                track: Some(3),
+                year: Some(2001),
+                genre: Some("Rock".into()),
+                start: Some(Duration::from_secs(30)),
+                end: Some(Duration::from_millis(3_723_500)),
+                folder: Some("Trips/2024".into()),
+                ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())
+            }
+        );
+        assert_eq!(songs[1].album.as_deref(), Some("Single"));
+        assert_eq!(songs[1].folder.as_deref(), Some("Trips/2024"));
+        assert_eq!(songs[1].priority, 5);
+        assert_eq!(songs[1].backend.as_deref(), Some("local"));
+        assert_eq!(songs[2], Song::new("https://youtu.be/ghi".into(), "Rust".into(), "Last".into()));
+        assert_eq!(parse(&["[Open]"]).open_section.as_deref(), Some("Open"));
+    }
+
+    #[test]
+    fn parse_reports_line_numbers() {
+        let list = parse(&[
+            "#!version 2",
+            "https://youtu.be/abc|Rust",
+            "https://youtu.be/abc|Rust|Lang|colour=red",
+            "https://youtu.be/abc|Rust|Lang|track=zero",
+            "https://youtu.be/abc|Rust|Lang|start=2:00|end=1:00",
+            "https://youtu.be/abc|Rust|Lang|folder=../outside",
+            "https://youtu.be/abc|Rust|Lang|year=2001|year=2002",
+            "https://youtu.be/abc|Rust|Lang|backend=curl",
+            "https://youtu.be/abc|Rust|Lang|cover=notes.txt|profile=wav",
+            "[Unclosed",
+            "https://youtu.be/abc|Rust|Lang|Remix",
+            "https://youtu.be/abc|Rust|Lang",
+        ]);
+
+        assert_eq!(list.songs.len(), 1);
+        assert_eq!(list.songs[0].0, 12);
+        let lines: Vec<usize> = list.diagnostics.iter().map(|diagnostic| diagnostic.line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(list.diagnostics[1].to_string(), "line 3: unknown option 'colour'");
+    }
+
+    #[test]
+    fn song_from_fields_validates_like_the_songs_file() {
+        let song = song_from_fields(" https://youtu.be/abc ", "Rust", "Lang", &[("track", "2"), ("album", "")]).unwrap();
+
+        assert_eq!(song.url, "https://youtu.be/abc");
+        assert_eq!(song.track, Some(2));
+        assert_eq!(song.album, None);
+        assert!(song_from_fields("https://youtu.be/abc", "Rust", "A|B", &[]).is_err());
+        assert!(song_from_fields("", "Rust", "Lang", &[]).is_err());
+        assert!(song_from_fields("https://youtu.be/abc", "Rust", "Lang", &[("colour", "red")]).is_err());
+    }
+
+    #[test]
+    fn parse_newer_version_reads_no_song() {
+        let list = parse(&["#!version 3", "https://youtu.be/abc|Rust|Lang"]);
+
+        assert!(list.songs.is_empty());
+        assert_eq!(list.diagnostics.len(), 1);
+        assert_eq!(list.diagnostics[0].line, 1);
+    }
+
+    #[test]
+    fn display_round_trips_options() {
+        let song = Song {
+            album: Some("Album".into()),
+            track: Some(1),
+            start: Some(Duration::from_secs(90)),
+            end: Some(Duration::from_millis(3_723_500)),
+            folder: Some("Trips".into()),
+            priority: -2,
+            profile: Some("flac".into()),
+            cover: Some("covers/lang.jpg".into()),
+            ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())
+        };
+
+        let line = song.to_string();
+
+        assert_eq!(
+            line,
+            "https://youtu.be/abc|Rust|Lang|album=Album|track=1|start=1:30|end=1:02:03.500|folder=Trips|priority=-2|profile=flac|cover=covers/lang.jpg"
+        );
+        assert_eq!(parse(&["#!version 2".to_string(), line]).into_songs(), vec![song]);
+    }
+}
@@ -0,0 +1,223 @@
+//! EBU R128 loudness of the downloaded songs with ffmpeg's `loudnorm` filter, to write
+//! ReplayGain tags or to re-encode the songs at the target loudness
+
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::process::Command;
+
+use super::profile;
+use super::tagging::ReplayGain;
+
+/// Highest true peak of the normalized songs, in dBTP
+const TRUE_PEAK: f64 = -1.0;
+
+/// Loudness range the normalization may compress the songs to, in LU
+const LOUDNESS_RANGE: f64 = 11.0;
+
+/// Sample rate of the normalized songs, `loudnorm` works at 192 kHz
+const SAMPLE_RATE: &str = "48000";
+
+#[derive(Debug, Error)]
+pub enum LoudnessError {
+    #[error("Failed to spawn ffmpeg: {0}")]
+    Spawn(io::Error),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("ffmpeg failed with status {code}: {message}")]
+    Failed { code: i32, message: String },
+
+    #[error("Unreadable loudnorm report: {0}")]
+    Report(String),
+
+    #[error("The song is silent")]
+    Silent,
+
+    #[error("No output profile produces '{0}' files")]
+    Unsupported(String),
+}
+
+/// What `loudnorm` measured of a song
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS
+    pub integrated: f64,
+    /// In dBTP
+    pub true_peak: f64,
+    /// Loudness range, in LU
+    pub range: f64,
+    /// Gating threshold, in LUFS
+    pub threshold: f64,
+}
+
+impl Loudness {
+    /// The ReplayGain bringing the song to `target` LUFS
+    pub fn replay_gain(&self, target: f64) -> ReplayGain {
+        ReplayGain::new(target - self.integrated, 10f64.powf(self.true_peak / 20.0))
+    }
+}
+
+/// The JSON report `loudnorm` prints at the end, every value is a string
+#[derive(Debug, Deserialize)]
+struct Report {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+}
+
+/// The measures of the last JSON object ffmpeg printed
+fn parse_report(stderr: &str) -> Result<Loudness, LoudnessError> {
+    let start = stderr.rfind('{').ok_or_else(|| LoudnessError::Report("no JSON report".to_string()))?;
+    let end = stderr.rfind('}').filter(|end| *end > start);
+    let json = &stderr[start..=end.ok_or_else(|| LoudnessError::Report("truncated JSON report".to_string()))?];
+    let report: Report = serde_json::from_str(json).map_err(|e| LoudnessError::Report(e.to_string()))?;
+
+    let value = |value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| LoudnessError::Report(format!("'{}' is not a number", value)))
+    };
+    let loudness = Loudness {
+        integrated: value(&report.input_i)?,
+        true_peak: value(&report.input_tp)?,
+        range: value(&report.input_lra)?,
+        threshold: value(&report.input_thresh)?,
+    };
+    // Silence measures -inf
+    if !loudness.integrated.is_finite() || !loudness.true_peak.is_finite() {
+        return Err(LoudnessError::Silent);
+    }
+    Ok(loudness)
+}
+
+/// The `loudnorm` filter aiming at `target` LUFS, with the first pass measures if any
+fn filter(target: f64, measured: Option<&Loudness>) -> String {
+    let mut filter = format!("loudnorm=I={}:TP={}:LRA={}", target, TRUE_PEAK, LOUDNESS_RANGE);
+    match measured {
+        Some(measured) => filter.push_str(&format!(
+            ":measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:linear=true",
+            measured.integrated, measured.true_peak, measured.range, measured.threshold
+        )),
+        None => filter.push_str(":print_format=json"),
+    }
+    filter
+}
+
+/// Run ffmpeg, its stderr when it succeeds
+async fn ffmpeg(program: &str, args: &[&str]) -> Result<String, LoudnessError> {
+    let output = Command::new(program)
+        .args(["-hide_banner", "-nostdin", "-nostats"])
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(LoudnessError::Spawn)?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        let message = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default();
+        return Err(LoudnessError::Failed {
+            code: output.status.code().unwrap_or(-1),
+            message: message.to_string(),
+        });
+    }
+    Ok(stderr)
+}
+
+/// Measure the loudness of the song, decoding it once
+pub async fn measure(program: &str, path: &Path, target: f64) -> Result<Loudness, LoudnessError> {
+    let path = path.to_string_lossy();
+    let filter = filter(target, None);
+    let stderr = ffmpeg(program, &["-i", &path, "-map", "0:a:0", "-af", &filter, "-f", "null", "-"]).await?;
+    parse_report(&stderr)
+}
+
+/// Re-encode the song at `target` LUFS with its measures, in the format of its profile.
+/// The tags are copied, the cover is written again by the tagging.
+pub async fn normalize(program: &str, path: &Path, measured: &Loudness, target: f64) -> Result<(), LoudnessError> {
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let profile = profile::PROFILES
+        .iter()
+        .find(|profile| profile.extension() == extension)
+        .ok_or_else(|| LoudnessError::Unsupported(extension.clone()))?;
+
+    // Same extension for ffmpeg to pick the container
+    let temp_path = path.with_extension(format!("loudnorm.{}", extension));
+    let (input, output) = (path.to_string_lossy(), temp_path.to_string_lossy());
+    let filter = filter(target, Some(measured));
+    let mut args = vec!["-y", "-i", &input, "-map", "0:a:0", "-map_metadata", "0", "-af", &filter];
+    args.extend(["-ar", SAMPLE_RATE]);
+    let encoder = profile.encoder_args();
+    args.extend(encoder.iter().map(String::as_str));
+    args.push(&output);
+
+    if let Err(e) = ffmpeg(program, &args).await {
+        _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STDERR: &str = r#"Input #0, mp3, from 'song.mp3':
+  Duration: 00:03:12.05, start: 0.025057, bitrate: 320 kb/s
+[Parsed_loudnorm_0 @ 0x5581]
+{
+	"input_i" : "-9.87",
+	"input_tp" : "0.42",
+	"input_lra" : "5.10",
+	"input_thresh" : "-20.01",
+	"output_i" : "-18.02",
+	"output_tp" : "-1.00",
+	"output_lra" : "4.80",
+	"output_thresh" : "-28.14",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.02"
+}
+"#;
+
+    #[test]
+    fn parse_report_reads_the_input_measures() {
+        let loudness = parse_report(STDERR).unwrap();
+
+        assert_eq!(
+            loudness,
+            Loudness {
+                integrated: -9.87,
+                true_peak: 0.42,
+                range: 5.1,
+                threshold: -20.01,
+            }
+        );
+        assert_eq!(loudness.replay_gain(-18.0), ReplayGain::new(-8.13, 1.049542));
+    }
+
+    #[test]
+    fn parse_report_rejects_silence_and_garbage() {
+        let silent = STDERR.replace("\"-9.87\"", "\"-inf\"");
+
+        assert!(matches!(parse_report(&silent), Err(LoudnessError::Silent)));
+        assert!(matches!(parse_report("Invalid data found"), Err(LoudnessError::Report(_))));
+    }
+
+    #[test]
+    fn filter_uses_the_measures_for_the_second_pass() {
+        assert_eq!(filter(-18.0, None), "loudnorm=I=-18:TP=-1:LRA=11:print_format=json");
+        let measured = parse_report(STDERR).unwrap();
+        assert_eq!(
+            filter(-16.0, Some(&measured)),
+            "loudnorm=I=-16:TP=-1:LRA=11:measured_I=-9.87:measured_TP=0.42:measured_LRA=5.1:measured_thresh=-20.01:linear=true"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Guess the artist and title tags of a song from what the site knows about the video
+
+use super::downloader::SongMetadata;
+use super::song::Song;
+
+/// Separators between the artist and the title in a video title
+const SEPARATORS: [&str; 4] = [" - ", " – ", " — ", " -- "];
+
+/// Words of a bracketed or `|` suffix that only describe the video, not the song
+const NOISE: [&str; 14] = [
+    "official", "video", "audio", "lyric", "lyrics", "clip", "visualizer", "visualiser", "hd", "hq", "4k",
+    "videoclip", "mv", "m/v",
+];
+
+/// Fill the empty artist and title of the song, keeping the ones already set
+pub fn fill(song: &mut Song, metadata: &SongMetadata) {
+    let title = metadata.title.as_deref().map(clean_title).unwrap_or_default();
+    let (title_artist, title_name) = match split_title(&title) {
+        Some((artist, name)) => (Some(artist), name),
+        None => (None, title.clone()),
+    };
+
+    if song.artist.is_empty() {
+        // The tags of YouTube Music first, then the "Artist - Title" of the video, then the channel
+        let artist = metadata
+            .artist
+            .clone()
+            .or(title_artist)
+            .or_else(|| metadata.channel.clone().or(metadata.uploader.clone()).map(|channel| clean_channel(&channel)));
+        song.artist = artist.unwrap_or_default().trim().to_string();
+    }
+    if song.name.is_empty() {
+        let name = metadata.track.clone().unwrap_or(title_name);
+        song.name = name.trim().to_string();
+    }
+}
+
+/// Whether the song misses a tag that [`fill`] could find
+pub fn is_incomplete(song: &Song) -> bool {
+    song.artist.trim().is_empty() || song.name.trim().is_empty()
+}
+
+/// Split "Artist - Title" at the first separator
+pub fn split_title(title: &str) -> Option<(String, String)> {
+    let (artist, name) = SEPARATORS
+        .iter()
+        .filter_map(|separator| title.split_once(separator))
+        .min_by_key(|(artist, _)| artist.len())?;
+    let (artist, name) = (artist.trim(), name.trim());
+    (!artist.is_empty() && !name.is_empty()).then(|| (artist.to_string(), name.to_string()))
+}
+
+/// The video title without the "(Official Video)", "[Lyrics]" or "| Official Audio" decorations
+pub fn clean_title(title: &str) -> String {
+    let mut title = title.trim().to_string();
+
+    // "Title | Official Video"
+    if let Some((rest, suffix)) = title.rsplit_once(" | ")
+        && is_noise(suffix)
+    {
+        title = rest.to_string();
+    }
+
+    // "Title (Official Video) [HD]", the other groups such as "(feat. Other)" stay
+    let mut cleaned = String::new();
+    let mut rest = title.as_str();
+    while let Some(open) = rest.find(['(', '[']) {
+        let close = if rest[open..].starts_with('(') { ')' } else { ']' };
+        let Some(length) = rest[open..].find(close) else {
+            break;
+        };
+        let group = &rest[open + 1..open + length];
+        cleaned.push_str(&rest[..open]);
+        if !is_noise(group) {
+            cleaned.push_str(&rest[open..=open + length]);
+        }
+        rest = &rest[open + length + 1..];
+    }
+    cleaned.push_str(rest);
+
+    cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The artist behind a channel name: "Artist - Topic" or "ArtistVEVO"
+fn clean_channel(channel: &str) -> String {
+    let channel = channel.trim();
+    let channel = channel.strip_suffix(" - Topic").unwrap_or(channel);
+    let channel = channel.strip_suffix("VEVO").unwrap_or(channel);
+    channel.trim().to_string()
+}
+
+fn is_noise(text: &str) -> bool {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text.split([' ', '-']).filter(|word| !word.is_empty()).collect();
+    // "Music" alone may be part of the song title, "Official Music Video" is not
+    words.iter().all(|word| NOISE.contains(word) || *word == "music") && words.iter().any(|word| NOISE.contains(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(title: &str, uploader: &str) -> SongMetadata {
+        SongMetadata {
+            title: Some(title.to_string()),
+            uploader: Some(uploader.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn clean_title_drops_video_decorations() {
+        assert_eq!(clean_title("Band - Song (Official Music Video) [HD]"), "Band - Song");
+        assert_eq!(clean_title("Band - Song | Official Audio"), "Band - Song");
+        assert_eq!(clean_title("Band - Song (Lyrics)"), "Band - Song");
+        assert_eq!(clean_title("Band - Song (feat. Other) [Live at Home]"), "Band - Song (feat. Other) [Live at Home]");
+        assert_eq!(clean_title("Song (unclosed"), "Song (unclosed");
+    }
+
+    #[test]
+    fn split_title_at_first_separator() {
+        assert_eq!(split_title("Band - Song - Remix"), Some(("Band".into(), "Song - Remix".into())));
+        assert_eq!(split_title("Band – Song"), Some(("Band".into(), "Song".into())));
+        assert_eq!(split_title("Song"), None);
+        assert_eq!(split_title(" - Song"), None);
+    }
+
+    #[test]
+    fn fill_prefers_site_tags_then_title_then_channel() {
+        let mut song = Song::new("https://youtu.be/abc".into(), "".into(), "".into());
+        let tagged = SongMetadata {
+            artist: Some("Band".into()),
+            track: Some("Song".into()),
+            ..metadata("Whatever (Official Video)", "Label")
+        };
+        fill(&mut song, &tagged);
+        assert_eq!((song.artist.as_str(), song.name.as_str()), ("Band", "Song"));
+
+        let mut song = Song::new("https://youtu.be/abc".into(), "".into(), "".into());
+        fill(&mut song, &metadata("Band - Song (Official Video)", "BandVEVO"));
+        assert_eq!((song.artist.as_str(), song.name.as_str()), ("Band", "Song"));
+
+        let mut song = Song::new("https://youtu.be/abc".into(), "".into(), "".into());
+        fill(&mut song, &metadata("Song [Official Audio]", "Band - Topic"));
+        assert_eq!((song.artist.as_str(), song.name.as_str()), ("Band", "Song"));
+    }
+
+    #[test]
+    fn fill_keeps_tags_already_set() {
+        let mut song = Song::new("https://youtu.be/abc".into(), "Mine".into(), "".into());
+
+        fill(&mut song, &metadata("Band - Song", "Band"));
+
+        assert_eq!((song.artist.as_str(), song.name.as_str()), ("Mine", "Song"));
+        assert!(!is_incomplete(&song));
+    }
+}
@@ -1,5 +1,18 @@
+pub mod cover;
 pub mod downloader;
 pub mod filesystem;
+pub mod import;
+pub mod list;
+pub mod loudness;
+pub mod metadata;
+pub mod naming;
+pub mod profile;
+pub mod progress;
+pub mod retry;
+pub mod scheduler;
 pub mod song;
+pub mod staging;
+pub mod tagging;
+pub mod url;
 
 pub use song::Song;
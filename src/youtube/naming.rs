@@ -0,0 +1,345 @@
+//! Output file names of the songs, from a template such as `{artist}/{album}/{track:02} {name}`.
+//! The names are safe on iOS and FAT file systems and unique in the download directory.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use super::song::Song;
+use super::url;
+use crate::common::config::{Config, ConfigError};
+use crate::common::constants;
+
+/// Fields of the templates, `title` is the same as `name`
+pub const FIELDS: [&str; 8] = ["artist", "name", "title", "album", "track", "year", "genre", "id"];
+
+/// The extension, only allowed at the end of the template as the downloader picks it
+const EXTENSION: &str = ".{ext}";
+
+/// Characters iOS, FAT or AFC refuse in a file name, replaced by '_'
+const UNSAFE: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Device names of FAT, a file named like them cannot be created
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Longest file or folder name of APFS, FAT and ext4, in bytes
+pub const MAX_NAME_BYTES: usize = 255;
+
+/// Room kept in file names for the collision suffix and the extension
+const SUFFIX_BYTES: usize = 24;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TemplateError {
+    #[error("'{{' is never closed")]
+    Unclosed,
+
+    #[error("Unknown field '{{{0}}}', expected one of {FIELDS:?}")]
+    UnknownField(String),
+
+    #[error("Invalid width '{0}', only numbers can be padded such as {{track:02}}")]
+    Width(String),
+
+    #[error("'{{ext}}' can only end the template, as '.{{ext}}'")]
+    Extension,
+
+    #[error("No file name at the end of the template")]
+    NoFileName,
+}
+
+/// A piece of template
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field { name: &'static str, width: usize },
+}
+
+/// A parsed file name template
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self::parse(constants::DEFAULT_TEMPLATE).expect("the default template is valid")
+    }
+}
+
+/// Check `download.template`
+pub fn validate_config(config: &Config) -> Result<(), ConfigError> {
+    match Template::parse(&config.download.template) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(config.invalid("download.template", &e.to_string())),
+    }
+}
+
+impl Template {
+    /// Parse `{field}` and `{field:0N}` fields and text, '/' separating the folders.
+    /// The extension is always added, `.{ext}` at the end is optional.
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let template = template.strip_suffix(EXTENSION).unwrap_or(template);
+        if template.is_empty() || template.ends_with('/') {
+            return Err(TemplateError::NoFileName);
+        }
+
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or(TemplateError::Unclosed)? + start;
+            let (field, width) = match rest[start + 1..end].split_once(':') {
+                Some((field, width)) => (field, Some(width)),
+                None => (&rest[start + 1..end], None),
+            };
+            if field == "ext" {
+                return Err(TemplateError::Extension);
+            }
+            let name = FIELDS
+                .into_iter()
+                .find(|name| *name == field)
+                .ok_or_else(|| TemplateError::UnknownField(field.to_string()))?;
+            let width = match width {
+                None => 0,
+                Some(width) if matches!(name, "track" | "year") => {
+                    width.parse().map_err(|_| TemplateError::Width(width.to_string()))?
+                }
+                Some(width) => return Err(TemplateError::Width(width.to_string())),
+            };
+            parts.push(Part::Field { name, width });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// The path of the song relative to the download directory, without extension.
+    /// The empty folders, such as `{album}` of a song without album, are left out.
+    pub fn render(&self, song: &Song) -> PathBuf {
+        let mut segments = vec![String::new()];
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => {
+                    let mut folders = text.split('/');
+                    segments.last_mut().unwrap().push_str(folders.next().unwrap_or_default());
+                    segments.extend(folders.map(String::from));
+                }
+                // A '/' of the value is not a folder
+                Part::Field { name, width } => {
+                    let value = field(song, name, *width).replace(['/', '\\'], "_");
+                    segments.last_mut().unwrap().push_str(&value);
+                }
+            }
+        }
+
+        let file = segments.pop().unwrap_or_default();
+        let mut path: PathBuf = segments
+            .iter()
+            .map(|segment| sanitize(segment, MAX_NAME_BYTES))
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let file = match sanitize(&file, MAX_NAME_BYTES - SUFFIX_BYTES) {
+            file if file.is_empty() => sanitize(&fallback_name(song), MAX_NAME_BYTES - SUFFIX_BYTES),
+            file => file,
+        };
+        path.push(file);
+        path
+    }
+}
+
+/// The value of the field for the song, empty when unknown
+fn field(song: &Song, name: &str, width: usize) -> String {
+    let number = |number: Option<i64>| number.map_or(String::new(), |number| format!("{:0width$}", number));
+    match name {
+        "artist" => song.artist.clone(),
+        "name" | "title" => song.name.clone(),
+        "album" => song.album.clone().unwrap_or_default(),
+        "track" => number(song.track.map(i64::from)),
+        "year" => number(song.year.map(i64::from)),
+        "genre" => song.genre.clone().unwrap_or_default(),
+        "id" => url::video_id(&song.url).unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// The name of a song whose template gives an empty file name: its video id, or the name of
+/// the file its url points to
+fn fallback_name(song: &Song) -> String {
+    if let Some(id) = url::video_id(&song.url) {
+        return id;
+    }
+    let last = song.url.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    match Path::new(last).file_stem() {
+        Some(stem) if !stem.is_empty() => stem.to_string_lossy().into_owned(),
+        _ => "song".to_string(),
+    }
+}
+
+/// The name made safe as one file or folder name of iOS and FAT: the unsafe and control
+/// characters replaced, no leading dot, no trailing dot or space, no reserved device name,
+/// at most `max_bytes` long
+pub fn sanitize(name: &str, max_bytes: usize) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| if UNSAFE.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+
+    let mut end = replaced.len().min(max_bytes);
+    while !replaced.is_char_boundary(end) {
+        end -= 1;
+    }
+    let trimmed = replaced[..end]
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' '])
+        .trim_start();
+
+    let device = trimmed.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(device)) {
+        return format!("{}_{}", device, &trimmed[device.len()..]);
+    }
+    trimmed.to_string()
+}
+
+/// Gives the songs of a batch their output path, adding ` (2)`, ` (3)`... to the names taken
+/// by another song of the batch, a file of the batch directory or a reserved file. File systems of
+/// devices ignore the case, so do the comparisons.
+pub struct Namer {
+    dir: PathBuf,
+    taken: HashSet<String>,
+}
+
+impl Namer {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            taken: HashSet::new(),
+        }
+    }
+
+    /// Take a name, relative to the directory and without extension, no song can have it then
+    pub fn reserve(&mut self, name: &Path) {
+        let key = self.dir.join(name).to_string_lossy().to_lowercase();
+        self.taken.insert(key);
+    }
+
+    /// The output path of the song, without extension, reserved for it
+    pub fn output(&mut self, template: &Template, song: &Song) -> PathBuf {
+        let relative = template.render(song);
+        let stem = relative.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let folder = self.dir.join(relative.parent().unwrap_or(Path::new("")));
+        let existing = existing_stems(&folder);
+
+        let mut suffix = 1;
+        loop {
+            let name = match suffix {
+                1 => stem.clone(),
+                n => format!("{} ({})", stem, n),
+            };
+            let path = folder.join(&name);
+            let key = path.to_string_lossy().to_lowercase();
+            if !existing.contains(&name.to_lowercase()) && self.taken.insert(key) {
+                return path;
+            }
+            suffix += 1;
+        }
+    }
+}
+
+/// The lowercase names without extension of the files of the folder
+fn existing_stems(folder: &Path) -> HashSet<String> {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return HashSet::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path();
+            let stem = if path.is_dir() { path.file_name() } else { path.file_stem() };
+            stem.map(|stem| stem.to_string_lossy().to_lowercase())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song() -> Song {
+        Song {
+            album: Some("Use Your Illusion I".into()),
+            track: Some(3),
+            year: Some(1991),
+            ..Song::new("https://youtu.be/dQw4w9WgXcQ".into(), "Guns N' Roses".into(), "Don't Cry".into())
+        }
+    }
+
+    #[test]
+    fn parse_reads_fields_widths_and_extension() {
+        let template = Template::parse("{artist}/{album}/{track:02} {name}.{ext}").unwrap();
+
+        assert_eq!(template.render(&song()), PathBuf::from("Guns N' Roses/Use Your Illusion I/03 Don't Cry"));
+        let title = Template::parse("{year} {title} [{id}]").unwrap();
+        assert_eq!(title.render(&song()), PathBuf::from("1991 Don't Cry [dQw4w9WgXcQ]"));
+        assert_eq!(Template::default().render(&song()), PathBuf::from("Don't Cry"));
+        assert_eq!(Template::parse("{nme}").unwrap_err(), TemplateError::UnknownField("nme".into()));
+        assert_eq!(Template::parse("{name:02}").unwrap_err(), TemplateError::Width("02".into()));
+        assert_eq!(Template::parse("{name").unwrap_err(), TemplateError::Unclosed);
+        assert_eq!(Template::parse("{ext}/{name}").unwrap_err(), TemplateError::Extension);
+        assert_eq!(Template::parse("{artist}/").unwrap_err(), TemplateError::NoFileName);
+    }
+
+    #[test]
+    fn render_keeps_the_values_in_their_folder() {
+        let template = Template::parse("{artist}/{album}/{track:02} - {name}").unwrap();
+        let song = Song {
+            album: None,
+            track: None,
+            ..Song::new("https://youtu.be/dQw4w9WgXcQ".into(), "AC/DC".into(), "../../etc/passwd".into())
+        };
+
+        assert_eq!(template.render(&song), PathBuf::from("AC_DC/- .._.._etc_passwd"));
+        let unnamed = Song::new("file:///music/Take Five.flac".into(), String::new(), String::new());
+        assert_eq!(Template::default().render(&unnamed), PathBuf::from("Take Five"));
+        let unnamed = Song::new("https://youtu.be/dQw4w9WgXcQ".into(), String::new(), ". ".into());
+        assert_eq!(Template::default().render(&unnamed), PathBuf::from("dQw4w9WgXcQ"));
+    }
+
+    #[test]
+    fn sanitize_makes_names_safe_for_fat_and_ios() {
+        assert_eq!(sanitize("What? Why: \"Me\" <3 | you*", 255), "What_ Why_ _Me_ _3 _ you_");
+        assert_eq!(sanitize("..hidden. ", 255), "hidden");
+        assert_eq!(sanitize("tab\there", 255), "tab_here");
+        assert_eq!(sanitize("con.mp3", 255), "con_.mp3");
+        assert_eq!(sanitize("Console", 255), "Console");
+        // Cut on a character boundary
+        assert_eq!(sanitize("ééé", 5), "éé");
+        assert_eq!(sanitize(&"a".repeat(300), MAX_NAME_BYTES).len(), 255);
+    }
+
+    #[test]
+    fn namer_suffixes_the_names_already_taken() {
+        let dir = std::env::temp_dir().join("monsieur_dlp_naming_namer");
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Song.mp3"), b"").unwrap();
+
+        let mut namer = Namer::new(&dir);
+        let template = Template::default();
+        let song = |name: &str| Song::new("https://youtu.be/abc".into(), String::new(), name.into());
+
+        assert_eq!(namer.output(&template, &song("Song")), dir.join("Song (2)"));
+        assert_eq!(namer.output(&template, &song("SONG")), dir.join("SONG (3)"));
+        assert_eq!(namer.output(&template, &song("Other")), dir.join("Other"));
+        assert_eq!(namer.output(&template, &song("other")), dir.join("other (2)"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Output profiles: the audio format and quality yt-dlp produces the songs in
+
+use crate::common::config::{Config, ConfigError};
+
+/// A named audio format and quality
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Profile {
+    pub name: &'static str,
+    /// Format selection of the stream the audio is extracted from
+    pub source: &'static str,
+    /// `--audio-format`, also the extension of the produced file
+    pub format: &'static str,
+    /// `--audio-quality`, None keeps the source quality for the lossless and copied formats
+    pub quality: Option<&'static str>,
+    /// ffmpeg encoder of the format, when the songs are encoded again
+    pub encoder: &'static str,
+}
+
+/// Every profile
+pub const PROFILES: [Profile; 4] = [
+    Profile {
+        name: "mp3-320",
+        source: "bestaudio",
+        format: "mp3",
+        quality: Some("320K"),
+        encoder: "libmp3lame",
+    },
+    Profile {
+        name: "m4a-aac-256",
+        source: "bestaudio",
+        format: "m4a",
+        quality: Some("256K"),
+        encoder: "aac",
+    },
+    // The opus stream of YouTube is only remuxed, never transcoded
+    Profile {
+        name: "opus-original",
+        source: "bestaudio[acodec=opus]/bestaudio",
+        format: "opus",
+        quality: None,
+        encoder: "libopus",
+    },
+    Profile {
+        name: "flac",
+        source: "bestaudio",
+        format: "flac",
+        quality: None,
+        encoder: "flac",
+    },
+];
+
+/// The profile with this name
+pub fn find(name: &str) -> Option<&'static Profile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
+
+/// The profile names, for the error messages
+pub fn names() -> Vec<&'static str> {
+    PROFILES.iter().map(|profile| profile.name).collect()
+}
+
+/// Check `download.profile`
+pub fn validate_config(config: &Config) -> Result<(), ConfigError> {
+    if find(&config.download.profile).is_none() {
+        return Err(config.invalid("download.profile", &format!("expected one of {:?}", names())));
+    }
+
+    Ok(())
+}
+
+impl Profile {
+    /// Extension of the files of the profile
+    pub fn extension(&self) -> &'static str {
+        self.format
+    }
+
+    /// The yt-dlp arguments selecting the source stream and converting it
+    pub fn args(&self) -> Vec<&'static str> {
+        let mut args = vec!["-x", "-f", self.source, "--audio-format", self.format];
+        if let Some(quality) = self.quality {
+            args.extend(["--audio-quality", quality]);
+        }
+        args
+    }
+
+    /// The ffmpeg arguments encoding a stream in the profile format and quality
+    pub fn encoder_args(&self) -> Vec<String> {
+        let mut args = vec!["-c:a".to_string(), self.encoder.to_string()];
+        if let Some(quality) = self.quality {
+            args.extend(["-b:a".to_string(), quality.to_lowercase()]);
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_convert_to_the_profile_format() {
+        assert_eq!(
+            find("m4a-aac-256").unwrap().args(),
+            vec!["-x", "-f", "bestaudio", "--audio-format", "m4a", "--audio-quality", "256K"]
+        );
+        let opus = find("opus-original").unwrap();
+        assert!(!opus.args().contains(&"--audio-quality"));
+        assert_eq!(opus.extension(), "opus");
+        assert_eq!(find("mp3-320").unwrap().encoder_args(), vec!["-c:a", "libmp3lame", "-b:a", "320k"]);
+        assert_eq!(find("wav"), None);
+    }
+}
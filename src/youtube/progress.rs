@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Bytes and timing of a running download
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DownloadProgress {
+    pub downloaded_bytes: u64,
+    /// Exact size when known, otherwise the estimate of the backend
+    pub total_bytes: Option<u64>,
+    pub percent: Option<f64>,
+    /// Bytes per second
+    pub speed: Option<f64>,
+    pub eta: Option<Duration>,
+}
+
+/// What happens to a song during a download run
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProgressKind {
+    /// The song is waiting in the queue
+    Queued { name: String },
+    Started,
+    Downloading(DownloadProgress),
+    /// A post-processor (audio extraction, tagging...) is running
+    PostProcessing { stage: String },
+    Retrying {
+        attempt: u32,
+        delay: Duration,
+        reason: String,
+    },
+    /// Any other output of the backend
+    Message(String),
+    Finished,
+    Failed { reason: String },
+}
+
+/// A progress update of the song `id` (its position in the queue)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressEvent {
+    pub id: usize,
+    pub kind: ProgressKind,
+}
+
+/// Publishes the progress of one song, does nothing when nobody listens
+#[derive(Clone, Debug, Default)]
+pub struct Reporter {
+    id: usize,
+    sender: Option<UnboundedSender<ProgressEvent>>,
+}
+
+impl Reporter {
+    pub fn new(id: usize, sender: Option<UnboundedSender<ProgressEvent>>) -> Self {
+        Self { id, sender }
+    }
+
+    pub fn send(&self, kind: ProgressKind) {
+        if let Some(sender) = &self.sender {
+            // The receiver is gone when the renderer stopped, nothing to do then
+            _ = sender.send(ProgressEvent { id: self.id, kind });
+        }
+    }
+}
+
+impl DownloadProgress {
+    /// Build the progress from raw values, computing the percentage when possible
+    pub fn new(
+        downloaded_bytes: u64,
+        total_bytes: Option<u64>,
+        speed: Option<f64>,
+        eta: Option<Duration>,
+    ) -> Self {
+        let percent = total_bytes
+            .filter(|total| *total > 0)
+            .map(|total| (downloaded_bytes as f64 / total as f64 * 100.0).min(100.0));
+
+        Self {
+            downloaded_bytes,
+            total_bytes,
+            percent,
+            speed,
+            eta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn download_progress_computes_percent() {
+        let progress = DownloadProgress::new(250, Some(1000), None, None);
+        assert_eq!(progress.percent, Some(25.0));
+
+        let progress = DownloadProgress::new(250, None, None, None);
+        assert_eq!(progress.percent, None);
+
+        let progress = DownloadProgress::new(250, Some(0), None, None);
+        assert_eq!(progress.percent, None);
+    }
+
+    #[test]
+    fn reporter_sends_events_with_its_id() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let reporter = Reporter::new(7, Some(sender));
+
+        reporter.send(ProgressKind::Started);
+
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            ProgressEvent {
+                id: 7,
+                kind: ProgressKind::Started
+            }
+        );
+    }
+
+    #[test]
+    fn reporter_without_listener_does_nothing() {
+        Reporter::default().send(ProgressKind::Finished);
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        drop(receiver);
+        Reporter::new(0, Some(sender)).send(ProgressKind::Finished);
+    }
+}
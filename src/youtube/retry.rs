@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::common::config::DownloadConfig;
+use crate::youtube::downloader::{DownloadError, FailureKind};
+
+/// Retry transient download failures with an exponential backoff
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every next one
+    pub base_delay: Duration,
+    /// Upper bound of the wait
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &DownloadConfig) -> Self {
+        Self {
+            retries: config.retries,
+            base_delay: Duration::from_millis(config.backoff_ms),
+            max_delay: Duration::from_millis(config.backoff_max_ms),
+        }
+    }
+
+    /// Wait before the retry number `retry` (starting at 1)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Run `attempt` until it succeeds, fails with a non transient error or runs out of retries.
+    /// `on_retry` is called with the retry number, the wait and the error before every retry.
+    pub async fn run<T, A, Fut, R>(&self, mut attempt: A, mut on_retry: R) -> Result<T, DownloadError>
+    where
+        A: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, DownloadError>>,
+        R: FnMut(u32, Duration, &DownloadError),
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(e) if e.kind() == FailureKind::Transient && retry < self.retries => {
+                    retry += 1;
+                    let delay = self.delay(retry);
+                    on_retry(retry, delay, &e);
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy = policy(10);
+
+        assert_eq!(policy.delay(1), Duration::from_millis(1));
+        assert_eq!(policy.delay(2), Duration::from_millis(2));
+        assert_eq!(policy.delay(3), Duration::from_millis(4));
+        assert_eq!(policy.delay(8), Duration::from_millis(4));
+        assert_eq!(policy.delay(40), Duration::from_millis(4));
+    }
+
+    #[tokio::test]
+    async fn run_retries_transient_errors_until_success() {
+        let attempts = Cell::new(0);
+        let mut retries = Vec::new();
+
+        let result = policy(3)
+            .run(
+                || {
+                    attempts.set(attempts.get() + 1);
+                    let attempt = attempts.get();
+                    async move {
+                        if attempt < 3 {
+                            Err(DownloadError::Network("timed out".into()))
+                        } else {
+                            Ok(attempt)
+                        }
+                    }
+                },
+                |retry, _, _| retries.push(retry),
+            )
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(retries, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn run_gives_up_after_retries() {
+        let attempts = Cell::new(0);
+
+        let result: Result<(), _> = policy(2)
+            .run(
+                || {
+                    attempts.set(attempts.get() + 1);
+                    async { Err(DownloadError::Network("timed out".into())) }
+                },
+                |_, _, _| {},
+            )
+            .await;
+
+        assert!(matches!(result, Err(DownloadError::Network(_))));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn run_does_not_retry_permanent_errors() {
+        let attempts = Cell::new(0);
+
+        let result: Result<(), _> = policy(5)
+            .run(
+                || {
+                    attempts.set(attempts.get() + 1);
+                    async { Err(DownloadError::Unavailable("Private video".into())) }
+                },
+                |_, _, _| {},
+            )
+            .await;
+
+        assert!(matches!(result, Err(DownloadError::Unavailable(_))));
+        assert_eq!(attempts.get(), 1);
+    }
+}
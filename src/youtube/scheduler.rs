@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::{Id, JoinSet};
+
+use crate::common::config::DownloadConfig;
+use crate::youtube::downloader::{Download, DownloadError, Downloaders};
+use crate::youtube::progress::{ProgressEvent, ProgressKind, Reporter};
+use crate::youtube::retry::RetryPolicy;
+use crate::youtube::song::Song;
+
+/// How many downloads may run at the same time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// Downloads running at the same time
+    pub workers: usize,
+    /// Downloads running at the same time on a host without its own limit
+    pub per_host: usize,
+    /// Limit of specific hosts, e.g. "youtube.com" = 1
+    pub hosts: BTreeMap<String, usize>,
+}
+
+impl Limits {
+    pub fn from_config(config: &DownloadConfig) -> Self {
+        Self {
+            workers: config.workers,
+            per_host: config.per_host,
+            hosts: config.hosts.clone(),
+        }
+    }
+
+    fn for_host(&self, host: &str) -> usize {
+        self.hosts.get(host).copied().unwrap_or(self.per_host)
+    }
+}
+
+/// Outcome of a scheduler run
+#[derive(Debug, Default)]
+pub struct ScheduleReport {
+    pub success: Vec<(Song, Download)>,
+    pub fails: Vec<(Song, DownloadError)>,
+    /// Songs queued or running when the run was cancelled
+    pub cancelled: Vec<Song>,
+}
+
+/// Download queue running songs by priority within the worker and host limits
+pub struct Scheduler {
+    downloaders: Arc<Downloaders>,
+    limits: Limits,
+    retry: Arc<RetryPolicy>,
+    progress: Option<UnboundedSender<ProgressEvent>>,
+}
+
+impl Scheduler {
+    pub fn new(downloaders: Downloaders, limits: Limits) -> Self {
+        Self {
+            downloaders: Arc::new(downloaders),
+            limits,
+            retry: Arc::new(RetryPolicy::default()),
+            progress: None,
+        }
+    }
+
+    /// Publish the progress of every song on this channel, the id of a song is
+    /// its position in the priority ordered queue
+    pub fn with_progress(mut self, sender: UnboundedSender<ProgressEvent>) -> Self {
+        self.progress = Some(sender);
+        self
+    }
+
+    /// Retry the transient failures with this policy, no retry by default
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Arc::new(retry);
+        self
+    }
+
+    /// Download every song to its output path (without extension), highest priority first
+    /// (file order among equal priorities).
+    /// `on_done` is called as soon as a song ends, `cancel` stops the run and aborts
+    /// the running downloads, which are then reported as cancelled.
+    pub async fn run<C, F>(&self, songs: Vec<(Song, PathBuf)>, cancel: C, mut on_done: F) -> ScheduleReport
+    where
+        C: Future<Output = ()>,
+        F: FnMut(&Song, &Result<Download, DownloadError>),
+    {
+        let mut songs = songs;
+        // Stable sort keeps the file order among equal priorities
+        songs.sort_by_key(|(song, _)| std::cmp::Reverse(song.priority));
+
+        let mut queue: Vec<(usize, Song, PathBuf)> =
+            songs.into_iter().enumerate().map(|(id, (song, output))| (id, song, output)).collect();
+        for (id, song, _) in &queue {
+            self.reporter(*id).send(ProgressKind::Queued {
+                name: song.name.clone(),
+            });
+        }
+
+        let mut report = ScheduleReport::default();
+        let mut set = JoinSet::new();
+        let mut running: HashMap<Id, (usize, Song, String)> = HashMap::new();
+        let mut per_host: HashMap<String, usize> = HashMap::new();
+
+        tokio::pin!(cancel);
+
+        loop {
+            // Start the first queued songs whose host still has room
+            while running.len() < self.limits.workers.max(1) {
+                let next = queue.iter().position(|(_, song, _)| {
+                    let host = host(&song.url);
+                    per_host.get(&host).copied().unwrap_or(0) < self.limits.for_host(&host).max(1)
+                });
+                let Some(index) = next else { break };
+
+                let (id, song, output) = queue.remove(index);
+                let host = host(&song.url);
+                *per_host.entry(host.clone()).or_insert(0) += 1;
+
+                let downloaders = Arc::clone(&self.downloaders);
+                let retry = Arc::clone(&self.retry);
+                let task_song = song.clone();
+                let reporter = self.reporter(id);
+                reporter.send(ProgressKind::Started);
+                let handle = set.spawn(async move {
+                    retry
+                        .run(
+                            || downloaders.download(&task_song, &output, &reporter),
+                            |attempt, delay, e| {
+                                reporter.send(ProgressKind::Retrying {
+                                    attempt,
+                                    delay,
+                                    reason: e.to_string(),
+                                })
+                            },
+                        )
+                        .await
+                });
+                running.insert(handle.id(), (id, song, host));
+            }
+
+            if running.is_empty() {
+                break;
+            }
+
+            let joined = tokio::select! {
+                joined = set.join_next_with_id() => joined,
+                _ = &mut cancel => {
+                    set.abort_all();
+                    report.cancelled.extend(running.drain().map(|(_, (_, song, _))| song));
+                    report.cancelled.extend(queue.drain(..).map(|(_, song, _)| song));
+                    break;
+                }
+            };
+
+            let Some(joined) = joined else { break };
+            let (id, result) = match joined {
+                Ok((id, result)) => (id, result),
+                Err(e) => (e.id(), Err(DownloadError::Io(std::io::Error::other(e)))),
+            };
+
+            if let Some((id, song, host)) = running.remove(&id) {
+                if let Some(count) = per_host.get_mut(&host) {
+                    *count -= 1;
+                }
+                self.reporter(id).send(match &result {
+                    Ok(_) => ProgressKind::Finished,
+                    Err(e) => ProgressKind::Failed {
+                        reason: e.to_string(),
+                    },
+                });
+                on_done(&song, &result);
+                match result {
+                    Ok(download) => report.success.push((song, download)),
+                    Err(e) => report.fails.push((song, e)),
+                }
+            }
+        }
+
+        report
+    }
+
+    fn reporter(&self, id: usize) -> Reporter {
+        Reporter::new(id, self.progress.clone())
+    }
+}
+
+/// The host a song is downloaded from, used for the per-host limits
+pub fn host(url: &str) -> String {
+    let Some((_, rest)) = url.split_once("://") else {
+        return "local".to_string();
+    };
+
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority.rsplit('@').next().unwrap_or_default();
+    let host = authority.split(':').next().unwrap_or_default().to_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("m."))
+        .or_else(|| host.strip_prefix("music."))
+        .unwrap_or(&host);
+
+    match host {
+        "" => "local".to_string(),
+        "youtu.be" => "youtube.com".to_string(),
+        host => host.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::downloader::{Downloader, SongMetadata};
+    use async_trait::async_trait;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Records the order of the downloads and the highest concurrency per host
+    #[derive(Default)]
+    struct Recorder {
+        started: Mutex<Vec<String>>,
+        running: Mutex<HashMap<String, usize>>,
+        max_running: Mutex<HashMap<String, usize>>,
+        max_total: Mutex<usize>,
+    }
+
+    struct SlowDownloader {
+        recorder: Arc<Recorder>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl Downloader for SlowDownloader {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn supports(&self, _song: &Song) -> bool {
+            true
+        }
+
+        async fn download(
+            &self,
+            song: &Song,
+            _output: &Path,
+            _progress: &Reporter,
+        ) -> Result<Download, DownloadError> {
+            let host = host(&song.url);
+            {
+                self.recorder.started.lock().unwrap().push(song.name.clone());
+                let mut running = self.recorder.running.lock().unwrap();
+                let count = running.entry(host.clone()).or_insert(0);
+                *count += 1;
+                let mut max_running = self.recorder.max_running.lock().unwrap();
+                let max = max_running.entry(host.clone()).or_insert(0);
+                *max = (*max).max(*count);
+                let total: usize = running.values().sum();
+                let mut max_total = self.recorder.max_total.lock().unwrap();
+                *max_total = (*max_total).max(total);
+            }
+
+            tokio::time::sleep(self.delay).await;
+
+            *self.recorder.running.lock().unwrap().get_mut(&host).unwrap() -= 1;
+            if song.name.starts_with("flaky") && self.recorder.started.lock().unwrap().len() < 2 {
+                return Err(DownloadError::Network("timed out".to_string()));
+            }
+            if song.name.starts_with("fail") {
+                return Err(DownloadError::Failed {
+                    code: 1,
+                    message: "failed".to_string(),
+                });
+            }
+            Ok(Download {
+                files: vec![PathBuf::from(&song.name)],
+                metadata: SongMetadata::default(),
+                profile: None,
+            })
+        }
+    }
+
+    fn scheduler(recorder: &Arc<Recorder>, delay: Duration, limits: Limits) -> Scheduler {
+        let downloader = SlowDownloader {
+            recorder: Arc::clone(recorder),
+            delay,
+        };
+        Scheduler::new(Downloaders::new(vec![Arc::new(downloader)]), limits)
+    }
+
+    fn song(url: &str, name: &str) -> Song {
+        Song::new(url.into(), "Artist".into(), name.into())
+    }
+
+    /// The songs with an output path named after them
+    fn outputs(songs: Vec<Song>) -> Vec<(Song, PathBuf)> {
+        songs.into_iter().map(|song| (song.clone(), PathBuf::from("/tmp").join(&song.name))).collect()
+    }
+
+    fn song_with_priority(url: &str, name: &str, priority: i32) -> Song {
+        Song {
+            priority,
+            ..song(url, name)
+        }
+    }
+
+    #[test]
+    fn host_normalises_youtube_variants() {
+        assert_eq!(host("https://www.youtube.com/watch?v=abc"), "youtube.com");
+        assert_eq!(host("https://m.youtube.com/watch?v=abc"), "youtube.com");
+        assert_eq!(host("https://music.youtube.com/watch?v=abc"), "youtube.com");
+        assert_eq!(host("https://youtu.be/abc"), "youtube.com");
+        assert_eq!(host("https://user@SoundCloud.com:443/a"), "soundcloud.com");
+        assert_eq!(host("/home/me/song.mp3"), "local");
+        assert_eq!(host("file:///home/me/song.mp3"), "local");
+    }
+
+    #[tokio::test]
+    async fn run_respects_worker_and_host_limits() {
+        let recorder = Arc::new(Recorder::default());
+        let limits = Limits {
+            workers: 3,
+            per_host: 2,
+            hosts: BTreeMap::from([("soundcloud.com".to_string(), 1)]),
+        };
+        let songs = (0..4)
+            .map(|i| song("https://youtu.be/a", &format!("yt{}", i)))
+            .chain((0..3).map(|i| song("https://soundcloud.com/a", &format!("sc{}", i))))
+            .collect();
+
+        let report = scheduler(&recorder, Duration::from_millis(20), limits)
+            .run(outputs(songs), std::future::pending(), |_, _| {})
+            .await;
+
+        assert_eq!(report.success.len(), 7);
+        assert!(report.fails.is_empty());
+        let max_running = recorder.max_running.lock().unwrap();
+        assert_eq!(max_running["youtube.com"], 2);
+        assert_eq!(max_running["soundcloud.com"], 1);
+        assert_eq!(*recorder.max_total.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn run_starts_highest_priority_first() {
+        let recorder = Arc::new(Recorder::default());
+        let limits = Limits {
+            workers: 1,
+            per_host: 1,
+            hosts: BTreeMap::new(),
+        };
+        let songs = vec![
+            song("https://youtu.be/a", "low"),
+            song_with_priority("https://youtu.be/b", "high", 10),
+            song("https://youtu.be/c", "fail-normal"),
+            song_with_priority("https://youtu.be/d", "medium", 5),
+        ];
+
+        let report = scheduler(&recorder, Duration::from_millis(1), limits)
+            .run(outputs(songs), std::future::pending(), |_, _| {})
+            .await;
+
+        assert_eq!(
+            *recorder.started.lock().unwrap(),
+            vec!["high", "medium", "low", "fail-normal"]
+        );
+        assert_eq!(report.success.len(), 3);
+        assert_eq!(report.fails.len(), 1);
+        assert_eq!(report.fails[0].0.name, "fail-normal");
+    }
+
+    #[tokio::test]
+    async fn run_retries_transient_failures() {
+        let recorder = Arc::new(Recorder::default());
+        let limits = Limits {
+            workers: 1,
+            per_host: 1,
+            hosts: BTreeMap::new(),
+        };
+        let retry = RetryPolicy {
+            retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+
+        let report = scheduler(&recorder, Duration::from_millis(1), limits)
+            .with_retry(retry)
+            .run(outputs(vec![song("https://youtu.be/a", "flaky")]), std::future::pending(), |_, _| {})
+            .await;
+
+        assert_eq!(report.success.len(), 1);
+        assert_eq!(*recorder.started.lock().unwrap(), vec!["flaky", "flaky"]);
+    }
+
+    #[tokio::test]
+    async fn run_cancelled_reports_running_and_queued_songs() {
+        let recorder = Arc::new(Recorder::default());
+        let limits = Limits {
+            workers: 2,
+            per_host: 2,
+            hosts: BTreeMap::new(),
+        };
+        let songs = (0..5)
+            .map(|i| song("https://youtu.be/a", &format!("song{}", i)))
+            .collect();
+
+        let report = scheduler(&recorder, Duration::from_secs(60), limits)
+            .run(
+                outputs(songs),
+                tokio::time::sleep(Duration::from_millis(20)),
+                |_, _| {},
+            )
+            .await;
+
+        assert!(report.success.is_empty());
+        assert!(report.fails.is_empty());
+        let mut cancelled: Vec<String> = report.cancelled.into_iter().map(|song| song.name).collect();
+        cancelled.sort();
+        assert_eq!(cancelled, vec!["song0", "song1", "song2", "song3", "song4"]);
+    }
+}
@@ -1,8 +1,32 @@
-#[derive(Clone, Debug, PartialEq)]
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Song {
     pub url: String,
     pub artist: String,
     pub name: String,
+    /// Songs with a higher priority are downloaded first
+    pub priority: i32,
+    /// The `[section]` of the songs file the song is listed under
+    pub section: Option<String>,
+    pub album: Option<String>,
+    /// Track number in the album
+    pub track: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    /// Only keep the song from this time...
+    pub start: Option<Duration>,
+    /// ...to this one
+    pub end: Option<Duration>,
+    /// Folder of the app Documents receiving the song, the root by default
+    pub folder: Option<String>,
+    /// Downloader backend to use instead of the default one
+    pub backend: Option<String>,
+    /// Output profile instead of the `download.profile` one
+    pub profile: Option<String>,
+    /// Image embedded as the cover instead of the video thumbnail, relative to the songs file
+    pub cover: Option<String>,
 }
 
 impl Song {
@@ -11,11 +35,94 @@ impl Song {
             url,
             artist,
             name,
+            ..Default::default()
+        }
+    }
+
+    /// The `key=value` options of the song, in the songs file order
+    pub fn options(&self) -> Vec<(&'static str, String)> {
+        let mut options = vec![];
+        if let Some(album) = &self.album {
+            options.push(("album", album.clone()));
+        }
+        if let Some(track) = self.track {
+            options.push(("track", track.to_string()));
+        }
+        if let Some(year) = self.year {
+            options.push(("year", year.to_string()));
+        }
+        if let Some(genre) = &self.genre {
+            options.push(("genre", genre.clone()));
+        }
+        if let Some(start) = self.start {
+            options.push(("start", format_time(start)));
+        }
+        if let Some(end) = self.end {
+            options.push(("end", format_time(end)));
+        }
+        if let Some(folder) = &self.folder {
+            options.push(("folder", folder.clone()));
+        }
+        if self.priority != 0 {
+            options.push(("priority", self.priority.to_string()));
+        }
+        if let Some(backend) = &self.backend {
+            options.push(("backend", backend.clone()));
+        }
+        if let Some(profile) = &self.profile {
+            options.push(("profile", profile.clone()));
+        }
+        if let Some(cover) = &self.cover {
+            options.push(("cover", cover.clone()));
         }
+        options
     }
 }
-impl std::fmt::Display for Song {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}|{}|{}", self.url, self.artist, self.name)
+
+/// The line of the song in the songs file, its section is written on its own line
+impl fmt::Display for Song {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}|{}|{}", self.url, self.artist, self.name)?;
+        for (key, value) in self.options() {
+            write!(f, "|{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// `[[h:]m:]s[.fff]` as written in the songs file
+pub fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.rsplit(':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+
+    let mut total = seconds;
+    for unit in [60.0, 3600.0] {
+        match parts.next() {
+            Some(part) => total += part.parse::<u32>().ok()? as f64 * unit,
+            None => break,
+        }
+    }
+    if parts.next().is_some() {
+        return None;
     }
-}
\ No newline at end of file
+    Some(Duration::from_secs_f64(total))
+}
+
+/// `m:ss` or `h:mm:ss`, with the milliseconds when there are some
+pub fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let millis = match time.subsec_millis() {
+        0 => String::new(),
+        millis => format!(".{:03}", millis),
+    };
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}{}", hours, minutes, seconds, millis)
+    } else {
+        format!("{}:{:02}{}", minutes, seconds, millis)
+    }
+}
@@ -0,0 +1,373 @@
+//! Staging directories: every download run writes its songs in its own batch directory under
+//! `<download_path>/.staging`, and only the files the library recorded leave it for the devices.
+//! A batch no run holds anymore is a leftover of a crashed or cancelled run: the partial
+//! downloads of its songs still to download are resumed, the rest is cleaned up.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+
+use super::naming::{Namer, Template};
+use super::song::Song;
+
+/// Directory of the batches in the download path
+pub const STAGING_DIR: &str = ".staging";
+
+
+/// Output of every song of the batch by url, relative to the batch directory
+const MANIFEST: &str = "batch.json";
+
+/// Locked while a run owns the batch
+const LOCK: &str = ".lock";
+
+/// Endings of the files yt-dlp writes while downloading
+const PARTIAL_SUFFIXES: [&str; 3] = [".part", ".ytdl", ".temp"];
+
+/// The batch directory of the running download
+pub struct Batch {
+    dir: PathBuf,
+    /// Released when the batch is dropped, the batch is then a leftover
+    _lock: File,
+    outputs: BTreeMap<String, PathBuf>,
+}
+
+/// The batch directory of a run that is over
+#[derive(Debug)]
+pub struct Leftover {
+    pub dir: PathBuf,
+    outputs: BTreeMap<String, PathBuf>,
+}
+
+impl Batch {
+    /// A new batch directory named after the current time
+    pub fn create(download_path: &Path) -> io::Result<Self> {
+        let root = download_path.join(STAGING_DIR);
+        fs::create_dir_all(&root)?;
+        let name = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut dir = root.join(&name);
+        for suffix in 2.. {
+            if !dir.exists() {
+                break;
+            }
+            dir = root.join(format!("{}-{}", name, suffix));
+        }
+        fs::create_dir(&dir)?;
+        Self::lock(dir, BTreeMap::new())
+    }
+
+    /// Continue the leftover, its songs download to the outputs they had
+    pub fn resume(leftover: Leftover) -> io::Result<Self> {
+        Self::lock(leftover.dir, leftover.outputs)
+    }
+
+    fn lock(dir: PathBuf, outputs: BTreeMap<String, PathBuf>) -> io::Result<Self> {
+        let lock = File::options().create(true).truncate(false).write(true).open(dir.join(LOCK))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(Self { dir, _lock: lock, outputs }),
+            Err(TryLockError::WouldBlock) => Err(io::Error::other(format!("{} is used by another run", dir.display()))),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// A namer for the new songs of the batch, the outputs of its songs are taken
+    pub fn namer(&self) -> Namer {
+        let mut namer = Namer::new(&self.dir);
+        for output in self.outputs.values() {
+            namer.reserve(output);
+        }
+        namer
+    }
+
+    /// The output path of the song without extension: the one it had when the batch is
+    /// resumed, else a new one from `namer`
+    pub fn output(&mut self, namer: &mut Namer, template: &Template, song: &Song) -> PathBuf {
+        if let Some(relative) = self.outputs.get(&song.url) {
+            return self.dir.join(relative);
+        }
+        let output = namer.output(template, song);
+        let relative = output.strip_prefix(&self.dir).unwrap_or(&output).to_path_buf();
+        self.outputs.insert(song.url.clone(), relative);
+        output
+    }
+
+    /// Write the outputs of the songs, before downloading them
+    pub fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.outputs).map_err(io::Error::other)?;
+        let temp_path = self.dir.join(format!("{}.temp", MANIFEST));
+        fs::write(&temp_path, json)?;
+        fs::rename(temp_path, self.dir.join(MANIFEST))
+    }
+
+    /// Release the batch, removing its directory if no file was kept in it
+    pub fn close(self) -> io::Result<()> {
+        if files(&self.dir)?.is_empty() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl Leftover {
+    /// Whether a song of this url was downloading in the batch
+    pub fn has_song(&self, url: &str) -> bool {
+        self.outputs.contains_key(url)
+    }
+
+    /// The files of the batch that are not `kept` and not, for the songs of `resumed`, a
+    /// partial download the next download goes on with
+    pub fn garbage(&self, kept: &HashSet<PathBuf>, resumed: &HashSet<&str>) -> io::Result<Vec<PathBuf>> {
+        let outputs: Vec<PathBuf> = self
+            .outputs
+            .iter()
+            .filter(|(url, _)| resumed.contains(url.as_str()))
+            .map(|(_, output)| self.dir.join(output))
+            .collect();
+        Ok(files(&self.dir)?
+            .into_iter()
+            .filter(|file| !kept.contains(file))
+            .filter(|file| !outputs.iter().any(|output| is_partial(file, output)))
+            .collect())
+    }
+}
+
+/// Whether the file is a partial download yt-dlp left for `output`, the path without
+/// extension: `<output>.<ext>.part` (or `.ytdl`, `.part-Frag<n>`), `<output>.temp.<ext>`,
+/// or a format `<output>.f<id>.<ext>` waiting to be merged
+fn is_partial(file: &Path, output: &Path) -> bool {
+    let (Some(name), Some(stem)) = (file.file_name().and_then(OsStr::to_str), output.file_name().and_then(OsStr::to_str))
+    else {
+        return false;
+    };
+    let Some(rest) = name.strip_prefix(stem).and_then(|rest| rest.strip_prefix('.')) else {
+        return false;
+    };
+    if file.parent() != output.parent() {
+        return false;
+    }
+
+    let parts: Vec<&str> = rest.split('.').collect();
+    let format = parts.len() >= 2
+        && parts[0]
+            .strip_prefix('f')
+            .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    format
+        || parts.len() >= 2 && parts[0] == "temp"
+        || PARTIAL_SUFFIXES.iter().any(|suffix| rest.ends_with(suffix))
+        || rest.contains(".part-Frag")
+}
+
+/// The batch directories no run holds, the oldest first
+pub fn leftovers(download_path: &Path) -> io::Result<Vec<Leftover>> {
+    let root = download_path.join(STAGING_DIR);
+    if !root.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut leftovers = vec![];
+    for entry in fs::read_dir(root)? {
+        let dir = entry?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        let lock = File::options().create(true).truncate(false).write(true).open(dir.join(LOCK))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => continue,
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        // A batch that crashed before writing its manifest only has files to clean up
+        let outputs = fs::read(dir.join(MANIFEST))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        leftovers.push(Leftover { dir, outputs });
+    }
+    leftovers.sort_by(|a, b| a.dir.cmp(&b.dir));
+    Ok(leftovers)
+}
+
+/// Remove the leftovers without any file, once their songs are on the devices
+pub fn remove_finished(download_path: &Path) -> io::Result<()> {
+    for leftover in leftovers(download_path)? {
+        remove_empty_dirs(&leftover.dir)?;
+        if files(&leftover.dir)?.is_empty() {
+            fs::remove_dir_all(&leftover.dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// The path of a waiting file on the devices: relative to its batch directory, or to the
+/// download path for the files downloaded before the batches
+pub fn relative(download_path: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(download_path.join(STAGING_DIR)) {
+        Ok(in_batch) => in_batch.iter().skip(1).collect(),
+        Err(_) => path.strip_prefix(download_path).unwrap_or(path).to_path_buf(),
+    }
+}
+
+/// The files of the directory and its subfolders sorted by path, the batch manifest and
+/// lock aside
+pub fn files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default();
+        if path.is_dir() {
+            found.extend(files(&path)?);
+        } else if path.is_file() && name != MANIFEST && name != LOCK {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Remove the subfolders left empty by the moved or cleaned files
+pub fn remove_empty_dirs(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("monsieur_dlp_staging_{}", name));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn song(url: &str, name: &str) -> Song {
+        Song::new(url.into(), "Band".into(), name.into())
+    }
+
+    #[test]
+    fn batch_is_a_leftover_once_released() {
+        let download_path = download_path("released");
+        let mut batch = Batch::create(&download_path).unwrap();
+        let mut namer = batch.namer();
+        let output = batch.output(&mut namer, &Template::default(), &song("https://a", "A"));
+        batch.save().unwrap();
+
+        assert_eq!(output, batch.dir().join("A"));
+        assert!(leftovers(&download_path).unwrap().is_empty());
+        let dir = batch.dir().to_path_buf();
+        fs::write(dir.join("A.webm.part"), b"half").unwrap();
+        drop(batch);
+
+        let leftovers = leftovers(&download_path).unwrap();
+        assert_eq!(leftovers.len(), 1);
+        assert!(leftovers[0].has_song("https://a"));
+
+        // Resumed, the song keeps its output whatever the other names
+        let leftover = leftovers.into_iter().next().unwrap();
+        let mut batch = Batch::resume(leftover).unwrap();
+        let mut namer = batch.namer();
+        assert_eq!(batch.output(&mut namer, &Template::default(), &song("https://a", "A")), dir.join("A"));
+        assert_eq!(batch.output(&mut namer, &Template::default(), &song("https://b", "A")), dir.join("A (2)"));
+        fs::remove_dir_all(download_path).unwrap();
+    }
+
+    #[test]
+    fn garbage_keeps_recorded_files_and_resumed_partials() {
+        let download_path = download_path("garbage");
+        let mut batch = Batch::create(&download_path).unwrap();
+        let mut namer = batch.namer();
+        let template = Template::parse("{artist}/{name}").unwrap();
+        for (url, name) in [("https://a", "A"), ("https://b", "B"), ("https://c", "C")] {
+            batch.output(&mut namer, &template, &song(url, name));
+        }
+        batch.save().unwrap();
+        let dir = batch.dir().to_path_buf();
+        drop(batch);
+        fs::create_dir_all(dir.join("Band")).unwrap();
+        for file in ["Band/A.mp3", "Band/B.f251.webm.part", "Band/B. Live.mp3", "Band/C.webm", "Band/AB.mp3"] {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        let leftover = leftovers(&download_path).unwrap().pop().unwrap();
+        let kept = HashSet::from([dir.join("Band/A.mp3")]);
+        let garbage = leftover.garbage(&kept, &HashSet::from(["https://b"])).unwrap();
+
+        assert_eq!(
+            garbage,
+            vec![dir.join("Band/AB.mp3"), dir.join("Band/B. Live.mp3"), dir.join("Band/C.webm"), dir.join("notes.txt")]
+        );
+        for file in garbage.iter().chain(&kept) {
+            fs::remove_file(file).unwrap();
+        }
+        remove_finished(&download_path).unwrap();
+        assert!(dir.join("Band/B.f251.webm.part").exists());
+        fs::remove_file(dir.join("Band/B.f251.webm.part")).unwrap();
+        remove_finished(&download_path).unwrap();
+        assert!(!dir.exists());
+        fs::remove_dir_all(download_path).unwrap();
+    }
+
+    #[test]
+    fn partials_are_the_exact_output_with_a_ytdlp_suffix() {
+        let output = Path::new("/batch/Band/Song");
+
+        let partials = [
+            "Song.webm.part",
+            "Song.f251.webm",
+            "Song.fhls-1080p.mp4.part",
+            "Song.temp.mp3",
+            "Song.mp3.ytdl",
+            "Song.webm.part-Frag3",
+        ];
+        for file in partials {
+            assert!(is_partial(&Path::new("/batch/Band").join(file), output), "{}", file);
+        }
+        for file in ["Song. Live.mp3", "Song.mp3", "Song.flac", "Songs.webm.part", "Song (2).webm.part"] {
+            assert!(!is_partial(&Path::new("/batch/Band").join(file), output), "{}", file);
+        }
+        assert!(!is_partial(Path::new("/batch/Song.webm.part"), output));
+    }
+
+    #[test]
+    fn files_walk_the_subfolders() {
+        let dir = download_path("nested");
+        fs::create_dir_all(dir.join("Artist/Album")).unwrap();
+        fs::create_dir_all(dir.join("Empty")).unwrap();
+        for file in ["b.mp3", "Artist/Album/01 a.mp3", MANIFEST] {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let found = files(&dir).unwrap();
+        assert_eq!(found, vec![dir.join("Artist/Album/01 a.mp3"), dir.join("b.mp3")]);
+
+        fs::remove_file(&found[0]).unwrap();
+        remove_empty_dirs(&dir).unwrap();
+        assert!(!dir.join("Artist").exists() && !dir.join("Empty").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relative_strips_the_batch_directory() {
+        let root = Path::new("/music");
+
+        assert_eq!(relative(root, Path::new("/music/.staging/20250101-120000/Band/A.mp3")), PathBuf::from("Band/A.mp3"));
+        assert_eq!(relative(root, Path::new("/music/A.mp3")), PathBuf::from("A.mp3"));
+    }
+}
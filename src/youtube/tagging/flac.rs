@@ -0,0 +1,191 @@
+//! Tags of FLAC files: Vorbis comments and the front cover in their own metadata blocks,
+//! between the `fLaC` marker and the audio frames.
+
+use std::fs;
+use std::path::Path;
+
+use super::vorbis::{self, Comments};
+use super::{Tags, TaggingError};
+
+const MARKER: &[u8; 4] = b"fLaC";
+
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+
+/// Metadata blocks store their length on 24 bits
+const MAX_BLOCK_LENGTH: usize = (1 << 24) - 1;
+
+/// A metadata block, without its header
+#[derive(Clone, Debug, PartialEq)]
+struct Block {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+fn invalid(message: impl Into<String>) -> TaggingError {
+    TaggingError::Flac(message.into())
+}
+
+/// The metadata blocks and the offset of the audio frames following them
+fn blocks(data: &[u8]) -> Result<(Vec<Block>, usize), TaggingError> {
+    if !data.starts_with(MARKER) {
+        return Err(invalid("no fLaC marker"));
+    }
+
+    let mut blocks = vec![];
+    let mut position = MARKER.len();
+    loop {
+        let header = data.get(position..position + 4).ok_or_else(|| invalid("truncated metadata block"))?;
+        let last = header[0] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let block = data
+            .get(position + 4..position + 4 + length)
+            .ok_or_else(|| invalid("truncated metadata block"))?;
+        blocks.push(Block {
+            kind: header[0] & 0x7F,
+            data: block.to_vec(),
+        });
+        position += 4 + length;
+        if last {
+            return Ok((blocks, position));
+        }
+    }
+}
+
+/// Whether the block is a picture block holding the front cover
+fn is_front_cover(block: &Block) -> bool {
+    block.kind == PICTURE && vorbis::front_cover(&block.data).is_some()
+}
+
+/// Write the tags in the Vorbis comment block, and the cover in a picture block replacing the
+/// front cover one. The other blocks and the audio frames are kept.
+pub fn write(path: &Path, tags: &Tags) -> Result<(), TaggingError> {
+    let data = fs::read(path)?;
+    let (blocks, audio) = blocks(&data)?;
+    if blocks.first().map(|block| block.kind) != Some(STREAMINFO) {
+        return Err(invalid("the first metadata block is not STREAMINFO"));
+    }
+
+    let mut comments = match blocks.iter().find(|block| block.kind == VORBIS_COMMENT) {
+        Some(block) => Comments::from_bytes(&block.data).map_err(|_| invalid("truncated Vorbis comment block"))?,
+        None => Comments::default(),
+    };
+    vorbis::set_text_tags(&mut comments, tags);
+
+    // STREAMINFO stays first, our blocks follow it
+    let mut written = vec![blocks[0].clone()];
+    written.push(Block {
+        kind: VORBIS_COMMENT,
+        data: comments.to_bytes(),
+    });
+    if let Some(cover) = &tags.cover {
+        written.push(Block {
+            kind: PICTURE,
+            data: vorbis::picture_block(cover),
+        });
+    }
+    for block in &blocks[1..] {
+        let replaced = block.kind == VORBIS_COMMENT || (tags.cover.is_some() && is_front_cover(block));
+        if !replaced {
+            written.push(block.clone());
+        }
+    }
+
+    let mut tagged = MARKER.to_vec();
+    for (index, block) in written.iter().enumerate() {
+        if block.data.len() > MAX_BLOCK_LENGTH {
+            return Err(invalid("metadata block too large"));
+        }
+        let last = if index + 1 == written.len() { 0x80 } else { 0 };
+        tagged.push(block.kind | last);
+        tagged.extend_from_slice(&(block.data.len() as u32).to_be_bytes()[1..]);
+        tagged.extend_from_slice(&block.data);
+    }
+    tagged.extend_from_slice(&data[audio..]);
+
+    let temp_path = path.with_extension("tagging.temp");
+    fs::write(&temp_path, tagged)?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Tags, TaggingError> {
+    let (blocks, _) = blocks(&fs::read(path)?)?;
+
+    let mut tags = match blocks.iter().find(|block| block.kind == VORBIS_COMMENT) {
+        Some(block) => {
+            let comments =
+                Comments::from_bytes(&block.data).map_err(|_| invalid("truncated Vorbis comment block"))?;
+            vorbis::text_tags(&comments)
+        }
+        None => Tags::default(),
+    };
+    tags.cover = blocks.iter().find_map(|block| match block.kind {
+        PICTURE => vorbis::front_cover(&block.data),
+        _ => None,
+    });
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::tagging::ReplayGain;
+
+    const AUDIO: &[u8] = b"\xFF\xF8not really flac frames";
+
+    /// fLaC, STREAMINFO, an application block, padding then the audio frames
+    fn sample() -> Vec<u8> {
+        let mut file = MARKER.to_vec();
+        file.extend_from_slice(&[STREAMINFO, 0, 0, 34]);
+        file.extend_from_slice(&[0x11; 34]);
+        file.extend_from_slice(&[2, 0, 0, 4]);
+        file.extend_from_slice(b"test");
+        file.extend_from_slice(&[0x80 | 1, 0, 0, 8]);
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(AUDIO);
+        file
+    }
+
+    fn tags() -> Tags {
+        Tags {
+            artist: Some("Guns N' Roses".into()),
+            title: Some("Don't Cry".into()),
+            album: Some("Use Your Illusion I".into()),
+            track: Some(13),
+            year: Some(1991),
+            genre: Some("Rock".into()),
+            cover: Some(b"\xFF\xD8\xFF\xE0cover".to_vec()),
+            replay_gain: Some(ReplayGain::new(6.5, 0.5)),
+        }
+    }
+
+    #[test]
+    fn write_then_read_round_trip() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_round_trip.flac");
+        fs::write(&path, sample()).unwrap();
+
+        write(&path, &tags()).unwrap();
+        // Writing again replaces the blocks rather than adding new ones
+        write(&path, &tags()).unwrap();
+
+        assert_eq!(read(&path).unwrap(), tags());
+        let data = fs::read(&path).unwrap();
+        let (blocks, audio) = blocks(&data).unwrap();
+        let kinds: Vec<u8> = blocks.iter().map(|block| block.kind).collect();
+        assert_eq!(kinds, vec![STREAMINFO, VORBIS_COMMENT, PICTURE, 2, 1]);
+        assert_eq!(blocks[0].data, vec![0x11; 34]);
+        assert_eq!(&data[audio..], AUDIO);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_rejects_other_files() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_not_flac.flac");
+        fs::write(&path, b"ID3\x04").unwrap();
+
+        assert!(matches!(write(&path, &tags()), Err(TaggingError::Flac(_))));
+        fs::remove_file(path).unwrap();
+    }
+}
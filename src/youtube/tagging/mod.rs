@@ -0,0 +1,265 @@
+//! Write the tags of the songs in the downloaded files: ID3v2.4 for mp3, MP4 atoms for m4a and
+//! Vorbis comments for opus, ogg and flac
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use super::downloader::Download;
+use super::song::Song;
+
+pub mod flac;
+pub mod mp3;
+pub mod mp4;
+pub mod vorbis;
+
+#[derive(Debug, Error)]
+pub enum TaggingError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid ID3 tag: {0}")]
+    Id3(#[from] id3::Error),
+
+    #[error("Invalid MP4 file: {0}")]
+    Mp4(String),
+
+    #[error("Invalid Ogg file: {0}")]
+    Ogg(String),
+
+    #[error("Invalid FLAC file: {0}")]
+    Flac(String),
+
+    #[error("Cannot tag '{0}', expected an mp3, m4a, opus, ogg or flac file")]
+    Unsupported(PathBuf),
+
+    #[error("The tags read back from '{0}' differ from the ones written")]
+    Mismatch(PathBuf),
+}
+
+/// The tags of a song file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tags {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    /// Front cover, as JPEG
+    pub cover: Option<Vec<u8>>,
+    pub replay_gain: Option<ReplayGain>,
+}
+
+/// ReplayGain of the track: the gain bringing it to the reference loudness and its peak
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayGain {
+    /// In dB, rounded to the hundredth as written in the tags
+    pub gain: f64,
+    /// Linear, 1.0 is full scale
+    pub peak: f64,
+}
+
+/// Names of the ReplayGain tags, in every format
+pub const REPLAYGAIN_TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+pub const REPLAYGAIN_TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+
+impl ReplayGain {
+    pub fn new(gain: f64, peak: f64) -> Self {
+        Self {
+            gain: (gain * 100.0).round() / 100.0,
+            peak: (peak * 1_000_000.0).round() / 1_000_000.0,
+        }
+    }
+
+    /// The gain tag, such as "-3.21 dB"
+    pub fn gain_text(&self) -> String {
+        format!("{:.2} dB", self.gain)
+    }
+
+    pub fn peak_text(&self) -> String {
+        format!("{:.6}", self.peak)
+    }
+
+    /// Read back the gain and peak tags
+    pub fn parse(gain: &str, peak: &str) -> Option<Self> {
+        let gain = gain.trim().trim_end_matches("dB").trim().parse().ok()?;
+        Some(Self::new(gain, peak.trim().parse().ok()?))
+    }
+}
+
+impl Tags {
+    /// The tags set on the song, an empty artist or title is no tag
+    pub fn from_song(song: &Song) -> Self {
+        let non_empty = |value: &str| Some(value.trim().to_string()).filter(|value| !value.is_empty());
+        Self {
+            artist: non_empty(&song.artist),
+            title: non_empty(&song.name),
+            album: song.album.as_deref().and_then(non_empty),
+            track: song.track,
+            year: song.year,
+            genre: song.genre.as_deref().and_then(non_empty),
+            cover: None,
+            replay_gain: None,
+        }
+    }
+}
+
+/// The tag formats, from the file extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Container {
+    Mp3,
+    Mp4,
+    Ogg,
+    Flac,
+}
+
+impl Container {
+    fn of(path: &Path) -> Result<Self, TaggingError> {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+        match extension.to_ascii_lowercase().as_str() {
+            "mp3" => Ok(Container::Mp3),
+            "m4a" | "mp4" | "m4b" => Ok(Container::Mp4),
+            "opus" | "ogg" | "oga" => Ok(Container::Ogg),
+            "flac" => Ok(Container::Flac),
+            _ => Err(TaggingError::Unsupported(path.to_path_buf())),
+        }
+    }
+}
+
+/// Whether [`write`] knows the format of the file
+pub fn is_supported<P: AsRef<Path>>(path: P) -> bool {
+    Container::of(path.as_ref()).is_ok()
+}
+
+/// Write the tags in the file, replacing the ones it had for the same fields
+pub fn write<P: AsRef<Path>>(path: P, tags: &Tags) -> Result<(), TaggingError> {
+    let path = path.as_ref();
+    match Container::of(path)? {
+        Container::Mp3 => mp3::write(path, tags),
+        Container::Mp4 => mp4::write(path, tags),
+        Container::Ogg => vorbis::write(path, tags),
+        Container::Flac => flac::write(path, tags),
+    }
+}
+
+/// Read the tags of the file
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Tags, TaggingError> {
+    let path = path.as_ref();
+    match Container::of(path)? {
+        Container::Mp3 => mp3::read(path),
+        Container::Mp4 => mp4::read(path),
+        Container::Ogg => vorbis::read(path),
+        Container::Flac => flac::read(path),
+    }
+}
+
+/// Write the song tags and the cover in the downloaded files, then check them back.
+/// The files of other formats (thumbnails, subtitles...) are left as is.
+pub fn tag_download(
+    song: &Song,
+    download: &Download,
+    cover: Option<Vec<u8>>,
+    replay_gain: Option<ReplayGain>,
+) -> Result<(), TaggingError> {
+    let tags = Tags {
+        cover,
+        replay_gain,
+        ..Tags::from_song(song)
+    };
+    for file in download.files.iter().filter(|file| is_supported(file)) {
+        write(file, &tags)?;
+
+        let written = read(file)?;
+        let matches = |expected: &Option<String>, found: &Option<String>| expected.is_none() || expected == found;
+        let same = matches(&tags.artist, &written.artist)
+            && matches(&tags.title, &written.title)
+            && matches(&tags.album, &written.album)
+            && matches(&tags.genre, &written.genre)
+            && (tags.track.is_none() || tags.track == written.track)
+            && (tags.year.is_none() || tags.year == written.year)
+            && (tags.cover.is_none() || tags.cover == written.cover)
+            && (tags.replay_gain.is_none() || tags.replay_gain == written.replay_gain);
+        if !same {
+            return Err(TaggingError::Mismatch(file.clone()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of a JPEG file, the writers do not decode the cover
+    const COVER: &[u8] = b"\xFF\xD8\xFF\xE0\0\x10JFIF";
+
+    #[test]
+    fn tags_from_song_skip_empty_fields() {
+        let song = Song {
+            album: Some(" ".into()),
+            track: Some(2),
+            ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "".into())
+        };
+
+        assert_eq!(
+            Tags::from_song(&song),
+            Tags {
+                artist: Some("Rust".into()),
+                track: Some(2),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn tag_download_tags_audio_files_only() {
+        let dir = std::env::temp_dir();
+        let audio = dir.join("monsieur_dlp_tag_download.mp3");
+        let cover = dir.join("monsieur_dlp_tag_download.webp");
+        std::fs::write(&audio, [0xFF, 0xFB, 0x90, 0x64]).unwrap();
+        std::fs::write(&cover, b"RIFF").unwrap();
+        let song = Song {
+            year: Some(2015),
+            ..Song::new("https://youtu.be/abc".into(), "Rust".into(), "Lang".into())
+        };
+        let download = Download {
+            files: vec![audio.clone(), cover.clone()],
+            ..Default::default()
+        };
+
+        let replay_gain = ReplayGain::new(-18.0 + 14.37, 10f64.powf(-0.5 / 20.0));
+        tag_download(&song, &download, Some(COVER.to_vec()), Some(replay_gain)).unwrap();
+
+        let expected = Tags {
+            cover: Some(COVER.to_vec()),
+            replay_gain: Some(replay_gain),
+            ..Tags::from_song(&song)
+        };
+        assert_eq!(read(&audio).unwrap(), expected);
+        assert_eq!(std::fs::read(&cover).unwrap(), b"RIFF");
+        std::fs::remove_file(audio).unwrap();
+        std::fs::remove_file(cover).unwrap();
+    }
+
+    #[test]
+    fn replay_gain_is_rounded_as_written() {
+        let replay_gain = ReplayGain::new(-18.0 + 14.37, 10f64.powf(-0.5 / 20.0));
+
+        assert_eq!(replay_gain.gain_text(), "-3.63 dB");
+        assert_eq!(replay_gain.peak_text(), "0.944061");
+        assert_eq!(ReplayGain::parse("-3.63 dB", "0.944061"), Some(replay_gain));
+        assert_eq!(ReplayGain::parse("+1.5", "1"), Some(ReplayGain::new(1.5, 1.0)));
+        assert_eq!(ReplayGain::parse("loud", "1"), None);
+    }
+
+    #[test]
+    fn write_rejects_unknown_formats() {
+        assert!(is_supported("song.M4A"));
+        assert!(matches!(
+            write("song.wav", &Tags::default()),
+            Err(TaggingError::Unsupported(path)) if path == Path::new("song.wav")
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use id3::frame::{ExtendedText, Picture, PictureType};
+use id3::{Tag, TagLike, Timestamp, Version};
+
+use super::{REPLAYGAIN_TRACK_GAIN, REPLAYGAIN_TRACK_PEAK, ReplayGain, Tags, TaggingError};
+
+/// Write an ID3v2.4 tag, keeping the frames of the existing tag that are not ours
+pub fn write(path: &Path, tags: &Tags) -> Result<(), TaggingError> {
+    let mut tag = id3::no_tag_ok(id3::partial_tag_ok(Tag::read_from_path(path)))?.unwrap_or_default();
+
+    if let Some(artist) = &tags.artist {
+        tag.set_artist(artist);
+    }
+    if let Some(title) = &tags.title {
+        tag.set_title(title);
+    }
+    if let Some(album) = &tags.album {
+        tag.set_album(album);
+    }
+    if let Some(track) = tags.track {
+        tag.set_track(track);
+    }
+    if let Some(year) = tags.year {
+        // TDRC, TYER only exists up to ID3v2.3
+        tag.remove_year();
+        tag.set_date_recorded(Timestamp {
+            year,
+            month: None,
+            day: None,
+            hour: None,
+            minute: None,
+            second: None,
+        });
+    }
+    if let Some(genre) = &tags.genre {
+        tag.set_genre(genre);
+    }
+    if let Some(cover) = &tags.cover {
+        tag.remove_picture_by_type(PictureType::CoverFront);
+        tag.add_frame(Picture {
+            mime_type: "image/jpeg".to_string(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.clone(),
+        });
+    }
+
+    if let Some(replay_gain) = &tags.replay_gain {
+        // TXXX frames, as foobar2000 and VLC read them
+        for (description, value) in [
+            (REPLAYGAIN_TRACK_GAIN, replay_gain.gain_text()),
+            (REPLAYGAIN_TRACK_PEAK, replay_gain.peak_text()),
+        ] {
+            tag.remove_extended_text(Some(description), None);
+            tag.add_frame(ExtendedText {
+                description: description.to_string(),
+                value,
+            });
+        }
+    }
+
+    tag.write_to_path(path, Version::Id3v24)?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Tags, TaggingError> {
+    let Some(tag) = id3::no_tag_ok(Tag::read_from_path(path))? else {
+        return Ok(Tags::default());
+    };
+
+    let extended_text = |description: &str| {
+        tag.extended_texts()
+            .find(|text| text.description.eq_ignore_ascii_case(description))
+            .map(|text| text.value.as_str())
+    };
+
+    Ok(Tags {
+        artist: tag.artist().map(String::from),
+        title: tag.title().map(String::from),
+        album: tag.album().map(String::from),
+        track: tag.track(),
+        year: tag.date_recorded().map(|date| date.year).or(tag.year()),
+        genre: tag.genre().map(String::from),
+        cover: tag
+            .pictures()
+            .find(|picture| picture.picture_type == PictureType::CoverFront)
+            .map(|picture| picture.data.clone()),
+        replay_gain: extended_text(REPLAYGAIN_TRACK_GAIN)
+            .zip(extended_text(REPLAYGAIN_TRACK_PEAK))
+            .and_then(|(gain, peak)| ReplayGain::parse(gain, peak)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// An MPEG audio frame header followed by silence, enough for a file without tag
+    const FRAME: [u8; 8] = [0xFF, 0xFB, 0x90, 0x64, 0, 0, 0, 0];
+
+    #[test]
+    fn write_then_read_round_trip() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_round_trip.mp3");
+        fs::write(&path, FRAME).unwrap();
+        let tags = Tags {
+            artist: Some("Guns N' Roses".into()),
+            title: Some("Don't Cry".into()),
+            album: Some("Use Your Illusion I".into()),
+            track: Some(13),
+            year: Some(1991),
+            genre: Some("Rock".into()),
+            cover: Some(b"\xFF\xD8\xFF\xE0cover".to_vec()),
+            replay_gain: Some(ReplayGain::new(-3.21, 0.98)),
+        };
+
+        write(&path, &tags).unwrap();
+        // Writing again replaces the frames rather than adding new ones
+        write(&path, &tags).unwrap();
+
+        assert_eq!(read(&path).unwrap(), tags);
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.version(), Version::Id3v24);
+        assert_eq!(tag.frames().filter(|frame| frame.id() == "TPE1").count(), 1);
+        assert_eq!(tag.pictures().count(), 1);
+        assert_eq!(tag.extended_texts().count(), 2);
+        assert!(fs::read(&path).unwrap().ends_with(&FRAME));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_file_without_tag() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_no_tag.mp3");
+        fs::write(&path, FRAME).unwrap();
+
+        assert_eq!(read(&path).unwrap(), Tags::default());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+//! iTunes style tags in the `moov/udta/meta/ilst` atom of MP4 files. The chunk offsets of
+//! the tracks are moved when the `moov` atom grows in front of the audio data.
+
+use std::fs;
+use std::path::Path;
+
+use super::{REPLAYGAIN_TRACK_GAIN, REPLAYGAIN_TRACK_PEAK, ReplayGain, Tags, TaggingError};
+
+const ARTIST: &[u8; 4] = b"\xa9ART";
+const TITLE: &[u8; 4] = b"\xa9nam";
+const ALBUM: &[u8; 4] = b"\xa9alb";
+const YEAR: &[u8; 4] = b"\xa9day";
+const GENRE: &[u8; 4] = b"\xa9gen";
+const TRACK: &[u8; 4] = b"trkn";
+const COVER: &[u8; 4] = b"covr";
+/// Item with a name of its own, in the `mean` namespace
+const FREEFORM: &[u8; 4] = b"----";
+
+/// Namespace of the freeform items players read
+const ITUNES: &str = "com.apple.iTunes";
+
+/// What an item replaces: its kind, and its name for the freeform items
+type ItemKey = ([u8; 4], Option<String>);
+
+/// Type of the `data` atom of text items
+const UTF8: u32 = 1;
+/// Type of the `data` atom of binary items such as `trkn`
+const IMPLICIT: u32 = 0;
+/// Type of the `data` atom of JPEG covers
+const JPEG: u32 = 13;
+
+/// An atom of the file, the payload is `data[start + header..end]`
+#[derive(Clone, Copy, Debug)]
+struct Atom {
+    kind: [u8; 4],
+    start: usize,
+    header: usize,
+    end: usize,
+}
+
+impl Atom {
+    fn payload<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.start + self.header..self.end]
+    }
+
+    fn bytes<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.start..self.end]
+    }
+}
+
+fn invalid(message: impl Into<String>) -> TaggingError {
+    TaggingError::Mp4(message.into())
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, TaggingError> {
+    let bytes = data.get(at..at + 4).ok_or_else(|| invalid("truncated atom"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap_or_default()))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Result<u64, TaggingError> {
+    let bytes = data.get(at..at + 8).ok_or_else(|| invalid("truncated atom"))?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap_or_default()))
+}
+
+/// The atoms one after the other in `data[start..end]`
+fn atoms(data: &[u8], start: usize, end: usize) -> Result<Vec<Atom>, TaggingError> {
+    let mut atoms = vec![];
+    let mut position = start;
+    while position + 8 <= end {
+        let size = read_u32(data, position)? as u64;
+        let kind: [u8; 4] = data[position + 4..position + 8].try_into().unwrap_or_default();
+        let (size, header) = match size {
+            // Up to the end of the file
+            0 => ((end - position) as u64, 8),
+            1 => (read_u64(data, position + 8)?, 16),
+            size => (size, 8),
+        };
+        let atom_end = usize::try_from(size).ok().and_then(|size| position.checked_add(size));
+        let Some(atom_end) = atom_end.filter(|atom_end| *atom_end <= end && size >= header as u64) else {
+            return Err(invalid(format!("atom '{}' overflows its parent", String::from_utf8_lossy(&kind))));
+        };
+        atoms.push(Atom { kind, start: position, header, end: atom_end });
+        position = atom_end;
+    }
+    Ok(atoms)
+}
+
+/// The children of the atom, `skip` bytes after its header (4 for the version and flags of a full atom)
+fn children(data: &[u8], atom: &Atom, skip: usize) -> Result<Vec<Atom>, TaggingError> {
+    atoms(data, (atom.start + atom.header + skip).min(atom.end), atom.end)
+}
+
+fn child(data: &[u8], atom: &Atom, kind: &[u8; 4], skip: usize) -> Result<Option<Atom>, TaggingError> {
+    Ok(children(data, atom, skip)?.into_iter().find(|child| &child.kind == kind))
+}
+
+fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 8);
+    bytes.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// An `ilst` item holding one `data` atom
+fn item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(value.len() + 8);
+    data.extend_from_slice(&data_type.to_be_bytes());
+    // Locale
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(value);
+    atom(kind, &atom(b"data", &data))
+}
+
+/// A freeform item of the iTunes namespace holding a text
+fn freeform(name: &str, value: &str) -> Vec<u8> {
+    let text = |text: &str| [&[0; 4], text.as_bytes()].concat();
+    let mut payload = atom(b"mean", &text(ITUNES));
+    payload.extend_from_slice(&atom(b"name", &text(name)));
+    let mut data = UTF8.to_be_bytes().to_vec();
+    data.extend_from_slice(&text(value));
+    payload.extend_from_slice(&atom(b"data", &data));
+    atom(FREEFORM, &payload)
+}
+
+/// The key of an existing item, the freeform names are compared case-insensitively
+fn item_key(data: &[u8], item: &Atom) -> Result<ItemKey, TaggingError> {
+    if &item.kind != FREEFORM {
+        return Ok((item.kind, None));
+    }
+    let name = child(data, item, b"name", 0)?
+        .map(|name| String::from_utf8_lossy(name.payload(data).get(4..).unwrap_or_default()).to_ascii_uppercase());
+    Ok((item.kind, name))
+}
+
+/// The items of the tags, with what they replace
+fn tag_items(tags: &Tags) -> Vec<(ItemKey, Vec<u8>)> {
+    let mut items: Vec<([u8; 4], Vec<u8>)> = vec![];
+    for (kind, value) in [
+        (ARTIST, &tags.artist),
+        (TITLE, &tags.title),
+        (ALBUM, &tags.album),
+        (GENRE, &tags.genre),
+    ] {
+        if let Some(value) = value {
+            items.push((*kind, item(kind, UTF8, value.as_bytes())));
+        }
+    }
+    if let Some(year) = tags.year {
+        items.push((*YEAR, item(YEAR, UTF8, year.to_string().as_bytes())));
+    }
+    if let Some(track) = tags.track {
+        // Reserved, track number, total tracks (unknown), reserved
+        let mut value = vec![0, 0];
+        value.extend_from_slice(&(track.min(u16::MAX as u32) as u16).to_be_bytes());
+        value.extend_from_slice(&[0, 0, 0, 0]);
+        items.push((*TRACK, item(TRACK, IMPLICIT, &value)));
+    }
+    if let Some(cover) = &tags.cover {
+        items.push((*COVER, item(COVER, JPEG, cover)));
+    }
+
+    let mut items: Vec<(ItemKey, Vec<u8>)> = items.into_iter().map(|(kind, item)| ((kind, None), item)).collect();
+    if let Some(replay_gain) = &tags.replay_gain {
+        for (name, value) in [
+            (REPLAYGAIN_TRACK_GAIN, replay_gain.gain_text()),
+            (REPLAYGAIN_TRACK_PEAK, replay_gain.peak_text()),
+        ] {
+            items.push(((*FREEFORM, Some(name.to_string())), freeform(&name.to_ascii_lowercase(), &value)));
+        }
+    }
+    items
+}
+
+/// The `meta` atom with the new items, keeping the other items of the existing one
+fn build_meta(data: &[u8], meta: Option<&Atom>, tags: &Tags) -> Result<Vec<u8>, TaggingError> {
+    let items = tag_items(tags);
+    let mut hdlr = None;
+    let mut ilst = vec![];
+    let mut others = vec![];
+
+    if let Some(meta) = meta {
+        for child in children(data, meta, 4)? {
+            match &child.kind {
+                b"hdlr" => hdlr = Some(child.bytes(data).to_vec()),
+                b"ilst" => {
+                    for existing in children(data, &child, 0)? {
+                        let key = item_key(data, &existing)?;
+                        if !items.iter().any(|(replaced, _)| *replaced == key) {
+                            ilst.extend_from_slice(existing.bytes(data));
+                        }
+                    }
+                }
+                // Padding, the file is rewritten anyway
+                b"free" => {}
+                _ => others.extend_from_slice(child.bytes(data)),
+            }
+        }
+    }
+    for (_, item) in &items {
+        ilst.extend_from_slice(item);
+    }
+
+    let hdlr = hdlr.unwrap_or_else(|| {
+        // Version and flags, predefined, handler type, reserved, empty name
+        let mut payload = vec![0; 8];
+        payload.extend_from_slice(b"mdirappl");
+        payload.extend_from_slice(&[0; 9]);
+        atom(b"hdlr", &payload)
+    });
+
+    let mut payload = vec![0; 4];
+    payload.extend_from_slice(&hdlr);
+    payload.extend_from_slice(&atom(b"ilst", &ilst));
+    payload.extend_from_slice(&others);
+    Ok(atom(b"meta", &payload))
+}
+
+/// The `moov` atom with the tags in its `udta/meta/ilst`, creating the missing atoms
+fn build_moov(data: &[u8], moov: &Atom, tags: &Tags) -> Result<Vec<u8>, TaggingError> {
+    let mut payload = vec![];
+    let mut has_udta = false;
+    for child in children(data, moov, 0)? {
+        if &child.kind != b"udta" {
+            payload.extend_from_slice(child.bytes(data));
+            continue;
+        }
+        has_udta = true;
+
+        let mut udta = vec![];
+        let mut has_meta = false;
+        for grandchild in children(data, &child, 0)? {
+            if &grandchild.kind == b"meta" {
+                has_meta = true;
+                udta.extend_from_slice(&build_meta(data, Some(&grandchild), tags)?);
+            } else {
+                udta.extend_from_slice(grandchild.bytes(data));
+            }
+        }
+        if !has_meta {
+            udta.extend_from_slice(&build_meta(data, None, tags)?);
+        }
+        payload.extend_from_slice(&atom(b"udta", &udta));
+    }
+    if !has_udta {
+        payload.extend_from_slice(&atom(b"udta", &build_meta(data, None, tags)?));
+    }
+    Ok(atom(b"moov", &payload))
+}
+
+/// Move the chunk offsets of every track pointing at or after `from` by `delta` bytes
+fn shift_chunk_offsets(moov: &mut [u8], from: u64, delta: i64) -> Result<(), TaggingError> {
+    let mut tables = vec![];
+    for moov_atom in atoms(moov, 0, moov.len())? {
+        for trak in children(moov, &moov_atom, 0)?.iter().filter(|atom| &atom.kind == b"trak") {
+            let Some(mdia) = child(moov, trak, b"mdia", 0)? else { continue };
+            let Some(minf) = child(moov, &mdia, b"minf", 0)? else { continue };
+            let Some(stbl) = child(moov, &minf, b"stbl", 0)? else { continue };
+            tables.extend(
+                children(moov, &stbl, 0)?
+                    .into_iter()
+                    .filter(|table| &table.kind == b"stco" || &table.kind == b"co64"),
+            );
+        }
+    }
+
+    for table in tables {
+        let start = table.start + table.header;
+        let count = read_u32(moov, start + 4)? as usize;
+        let width = if &table.kind == b"co64" { 8 } else { 4 };
+        if start + 8 + count * width > table.end {
+            return Err(invalid("truncated chunk offset table"));
+        }
+
+        for index in 0..count {
+            let at = start + 8 + index * width;
+            let offset = if width == 8 { read_u64(moov, at)? } else { read_u32(moov, at)? as u64 };
+            if offset < from {
+                continue;
+            }
+            let shifted = offset
+                .checked_add_signed(delta)
+                .ok_or_else(|| invalid("chunk offset out of range"))?;
+            if width == 8 {
+                moov[at..at + 8].copy_from_slice(&shifted.to_be_bytes());
+            } else {
+                let shifted = u32::try_from(shifted).map_err(|_| invalid("chunk offset out of range"))?;
+                moov[at..at + 4].copy_from_slice(&shifted.to_be_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn write(path: &Path, tags: &Tags) -> Result<(), TaggingError> {
+    let data = fs::read(path)?;
+    let moov = atoms(&data, 0, data.len())?
+        .into_iter()
+        .find(|atom| &atom.kind == b"moov")
+        .ok_or_else(|| invalid("no moov atom"))?;
+
+    let mut new_moov = build_moov(&data, &moov, tags)?;
+    let delta = new_moov.len() as i64 - (moov.end - moov.start) as i64;
+    if delta != 0 {
+        shift_chunk_offsets(&mut new_moov, moov.end as u64, delta)?;
+    }
+
+    let temp_path = path.with_extension("tagging.temp");
+    let mut tagged = Vec::with_capacity(data.len() + new_moov.len());
+    tagged.extend_from_slice(&data[..moov.start]);
+    tagged.extend_from_slice(&new_moov);
+    tagged.extend_from_slice(&data[moov.end..]);
+    fs::write(&temp_path, tagged)?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Tags, TaggingError> {
+    let data = fs::read(path)?;
+    let mut tags = Tags::default();
+
+    let Some(moov) = atoms(&data, 0, data.len())?.into_iter().find(|atom| &atom.kind == b"moov") else {
+        return Err(invalid("no moov atom"));
+    };
+    let Some(udta) = child(&data, &moov, b"udta", 0)? else { return Ok(tags) };
+    let Some(meta) = child(&data, &udta, b"meta", 0)? else { return Ok(tags) };
+    let Some(ilst) = child(&data, &meta, b"ilst", 4)? else { return Ok(tags) };
+
+    let (mut gain, mut peak) = (None, None);
+    for item in children(&data, &ilst, 0)? {
+        let Some(value) = child(&data, &item, b"data", 0)? else { continue };
+        // Type and locale
+        let value = value.payload(&data).get(8..).unwrap_or_default();
+        let text = || Some(String::from_utf8_lossy(value).into_owned());
+        match &item.kind {
+            ARTIST => tags.artist = text(),
+            TITLE => tags.title = text(),
+            ALBUM => tags.album = text(),
+            GENRE => tags.genre = text(),
+            YEAR => tags.year = String::from_utf8_lossy(value).get(..4).and_then(|year| year.parse().ok()),
+            TRACK => tags.track = value.get(2..4).map(|track| u16::from_be_bytes([track[0], track[1]]) as u32),
+            COVER => tags.cover = Some(value.to_vec()),
+            FREEFORM => match item_key(&data, &item)?.1.as_deref() {
+                Some(REPLAYGAIN_TRACK_GAIN) => gain = text(),
+                Some(REPLAYGAIN_TRACK_PEAK) => peak = text(),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    tags.replay_gain = gain.zip(peak).and_then(|(gain, peak)| ReplayGain::parse(&gain, &peak));
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO: &[u8] = b"not really aac";
+
+    /// ftyp, moov with one track whose only chunk is the mdat payload, and mdat
+    fn sample(moov_first: bool, extra_ilst: &[u8]) -> Vec<u8> {
+        let ftyp = atom(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        let moov = |offset: u32| {
+            let mut stco = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            stco.extend_from_slice(&offset.to_be_bytes());
+            let stbl = atom(b"stbl", &atom(b"stco", &stco));
+            let trak = atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)));
+            let mut payload = atom(b"mvhd", &[0; 100]);
+            payload.extend_from_slice(&trak);
+            if !extra_ilst.is_empty() {
+                let mut meta = vec![0; 4];
+                meta.extend_from_slice(&atom(b"ilst", extra_ilst));
+                meta.extend_from_slice(&atom(b"free", &[0; 16]));
+                payload.extend_from_slice(&atom(b"udta", &atom(b"meta", &meta)));
+            }
+            atom(b"moov", &payload)
+        };
+        let mdat = atom(b"mdat", AUDIO);
+
+        let mut file = ftyp.clone();
+        if moov_first {
+            let offset = (ftyp.len() + moov(0).len() + 8) as u32;
+            file.extend_from_slice(&moov(offset));
+            file.extend_from_slice(&mdat);
+        } else {
+            file.extend_from_slice(&mdat);
+            file.extend_from_slice(&moov(ftyp.len() as u32 + 8));
+        }
+        file
+    }
+
+    /// The bytes the first chunk offset of the file points at
+    fn first_chunk(path: &Path) -> Vec<u8> {
+        let data = fs::read(path).unwrap();
+        let moov = atoms(&data, 0, data.len()).unwrap().into_iter().find(|atom| &atom.kind == b"moov").unwrap();
+        let trak = child(&data, &moov, b"trak", 0).unwrap().unwrap();
+        let mdia = child(&data, &trak, b"mdia", 0).unwrap().unwrap();
+        let minf = child(&data, &mdia, b"minf", 0).unwrap().unwrap();
+        let stbl = child(&data, &minf, b"stbl", 0).unwrap().unwrap();
+        let stco = child(&data, &stbl, b"stco", 0).unwrap().unwrap();
+        let offset = read_u32(&data, stco.start + stco.header + 8).unwrap() as usize;
+        data[offset..offset + AUDIO.len()].to_vec()
+    }
+
+    fn tags() -> Tags {
+        Tags {
+            artist: Some("Guns N' Roses".into()),
+            title: Some("Don't Cry".into()),
+            album: Some("Use Your Illusion I".into()),
+            track: Some(13),
+            year: Some(1991),
+            genre: Some("Rock".into()),
+            cover: Some(b"\xFF\xD8\xFF\xE0cover".to_vec()),
+            replay_gain: Some(ReplayGain::new(-3.21, 0.98)),
+        }
+    }
+
+    #[test]
+    fn write_then_read_round_trip_moves_chunk_offsets() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_round_trip.m4a");
+        fs::write(&path, sample(true, &[])).unwrap();
+        assert_eq!(first_chunk(&path), AUDIO);
+
+        write(&path, &tags()).unwrap();
+
+        assert_eq!(read(&path).unwrap(), tags());
+        assert_eq!(first_chunk(&path), AUDIO);
+
+        // Tagging again replaces the items
+        let retagged = Tags {
+            title: Some("November Rain".into()),
+            track: Some(10),
+            ..Default::default()
+        };
+        write(&path, &retagged).unwrap();
+
+        assert_eq!(
+            read(&path).unwrap(),
+            Tags {
+                title: Some("November Rain".into()),
+                track: Some(10),
+                ..tags()
+            }
+        );
+        assert_eq!(first_chunk(&path), AUDIO);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_keeps_other_items_and_offsets_before_moov() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_mdat_first.m4a");
+        let mut items = item(b"\xa9too", UTF8, b"Lavf");
+        items.extend_from_slice(&freeform("iTunSMPB", "00000000 00000840"));
+        items.extend_from_slice(&freeform("replaygain_track_gain", "+9.00 dB"));
+        fs::write(&path, sample(false, &items)).unwrap();
+
+        write(&path, &tags()).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        for kept in [item(b"\xa9too", UTF8, b"Lavf"), freeform("iTunSMPB", "00000000 00000840")] {
+            assert!(data.windows(kept.len()).any(|window| window == kept));
+        }
+        assert!(!data.windows(8).any(|window| window == b"+9.00 dB"));
+        assert_eq!(read(&path).unwrap(), tags());
+        assert_eq!(first_chunk(&path), AUDIO);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_rejects_files_without_moov() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_no_moov.m4a");
+        fs::write(&path, atom(b"ftyp", b"M4A ")).unwrap();
+
+        assert!(matches!(write(&path, &tags()), Err(TaggingError::Mp4(_))));
+        fs::remove_file(path).unwrap();
+    }
+}
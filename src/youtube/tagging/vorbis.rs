@@ -0,0 +1,391 @@
+//! Vorbis comments of Ogg Opus and Ogg Vorbis files. The comment header is the second packet
+//! of the stream, the file is rewritten packet by packet to replace it. FLAC files hold the
+//! same comments in a metadata block, see `flac`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ogg::reading::PacketReader;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+use super::{REPLAYGAIN_TRACK_GAIN, REPLAYGAIN_TRACK_PEAK, ReplayGain, Tags, TaggingError};
+
+/// Comment holding the cover, a base64 FLAC picture block
+const PICTURE: &str = "METADATA_BLOCK_PICTURE";
+
+/// Picture type of the front cover in FLAC picture blocks
+const FRONT_COVER: u32 = 3;
+
+/// The codecs of an Ogg stream carrying Vorbis comments
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Codec {
+    Opus,
+    Vorbis,
+}
+
+impl Codec {
+    /// The codec of the stream, from its first packet
+    fn of(packet: &[u8]) -> Option<Self> {
+        if packet.starts_with(b"OpusHead") {
+            Some(Codec::Opus)
+        } else if packet.starts_with(b"\x01vorbis") {
+            Some(Codec::Vorbis)
+        } else {
+            None
+        }
+    }
+
+    /// Start of the comment header
+    fn magic(&self) -> &'static [u8] {
+        match self {
+            Codec::Opus => b"OpusTags",
+            Codec::Vorbis => b"\x03vorbis",
+        }
+    }
+}
+
+/// A comment header: the vendor string, the `KEY=value` comments and what follows them
+/// (the framing bit of Vorbis, padding or binary data of Opus)
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct Comments {
+    vendor: Vec<u8>,
+    comments: Vec<String>,
+    rest: Vec<u8>,
+}
+
+fn invalid(message: impl Into<String>) -> TaggingError {
+    TaggingError::Ogg(message.into())
+}
+
+impl Comments {
+    fn parse(codec: Codec, packet: &[u8]) -> Result<Self, TaggingError> {
+        let data = packet
+            .strip_prefix(codec.magic())
+            .ok_or_else(|| invalid("the second packet is not a comment header"))?;
+        Self::from_bytes(data)
+    }
+
+    /// The comments without the magic of the Ogg header, as in a FLAC block
+    pub(super) fn from_bytes(mut data: &[u8]) -> Result<Self, TaggingError> {
+        let vendor_length = take_u32(&mut data)? as usize;
+        let vendor = take(&mut data, vendor_length)?.to_vec();
+        let count = take_u32(&mut data)?;
+        let mut comments = vec![];
+        for _ in 0..count {
+            let length = take_u32(&mut data)? as usize;
+            comments.push(String::from_utf8_lossy(take(&mut data, length)?).into_owned());
+        }
+        Ok(Self { vendor, comments, rest: data.to_vec() })
+    }
+
+    fn to_packet(&self, codec: Codec) -> Vec<u8> {
+        let mut packet = codec.magic().to_vec();
+        packet.extend_from_slice(&self.to_bytes());
+        packet
+    }
+
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&self.vendor);
+        packet.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            packet.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            packet.extend_from_slice(comment.as_bytes());
+        }
+        packet.extend_from_slice(&self.rest);
+        packet
+    }
+
+    /// The value of the first comment with the key, compared case-insensitively
+    fn get(&self, key: &str) -> Option<&str> {
+        self.comments.iter().find_map(|comment| {
+            let (name, value) = comment.split_once('=')?;
+            name.eq_ignore_ascii_case(key).then_some(value)
+        })
+    }
+
+    /// Replace the comments with the key by one with the value
+    fn set(&mut self, key: &str, value: &str) {
+        self.comments.retain(|comment| {
+            comment
+                .split_once('=')
+                .is_none_or(|(name, _)| !name.eq_ignore_ascii_case(key))
+        });
+        self.comments.push(format!("{}={}", key, value));
+    }
+}
+
+/// The first `length` bytes of `data`, which moves past them
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Result<&'a [u8], TaggingError> {
+    if data.len() < length {
+        return Err(invalid("truncated comment header"));
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_u32(data: &mut &[u8]) -> Result<u32, TaggingError> {
+    let bytes = take(data, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A FLAC picture block holding the JPEG front cover
+pub(super) fn picture_block(jpeg: &[u8]) -> Vec<u8> {
+    // The dimensions are informative, players decode the image anyway
+    let (width, height) = image::ImageReader::with_format(Cursor::new(jpeg), image::ImageFormat::Jpeg)
+        .into_dimensions()
+        .unwrap_or_default();
+    let mime = b"image/jpeg";
+
+    let mut block = vec![];
+    for value in [FRONT_COVER, mime.len() as u32] {
+        block.extend_from_slice(&value.to_be_bytes());
+    }
+    block.extend_from_slice(mime);
+    // Empty description, width, height, color depth, palette size and the data length
+    for value in [0, width, height, 24, 0, jpeg.len() as u32] {
+        block.extend_from_slice(&value.to_be_bytes());
+    }
+    block.extend_from_slice(jpeg);
+    block
+}
+
+/// The image of a FLAC picture block when it is the front cover
+pub(super) fn front_cover(mut block: &[u8]) -> Option<Vec<u8>> {
+    // Big-endian unlike the comment header
+    let take_be_u32 = |data: &mut &[u8]| {
+        let bytes = take(data, 4).ok()?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    if take_be_u32(&mut block)? != FRONT_COVER {
+        return None;
+    }
+    for _ in 0..2 {
+        // Mime type then description
+        let length = take_be_u32(&mut block)? as usize;
+        take(&mut block, length).ok()?;
+    }
+    take(&mut block, 16).ok()?;
+    let length = take_be_u32(&mut block)? as usize;
+    Some(take(&mut block, length).ok()?.to_vec())
+}
+
+fn ogg_error(e: ogg::OggReadError) -> TaggingError {
+    invalid(e.to_string())
+}
+
+pub fn write(path: &Path, tags: &Tags) -> Result<(), TaggingError> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+    let mut writer = PacketWriter::new(vec![]);
+    let mut codecs: HashMap<u32, Option<Codec>> = HashMap::new();
+    let mut packets: HashMap<u32, usize> = HashMap::new();
+    let mut tagged = false;
+
+    // Same packets, pages and granule positions, only the comment header changes
+    while let Some(packet) = reader.read_packet().map_err(ogg_error)? {
+        let serial = packet.stream_serial();
+        let index = packets.entry(serial).or_default();
+        let info = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let granule = packet.absgp_page();
+        let mut data = packet.data;
+
+        if *index == 0 {
+            codecs.insert(serial, Codec::of(&data));
+        } else if *index == 1
+            && let Some(Some(codec)) = codecs.get(&serial)
+        {
+            let mut comments = Comments::parse(*codec, &data)?;
+            set_text_tags(&mut comments, tags);
+            if let Some(cover) = &tags.cover {
+                comments.set(PICTURE, &BASE64.encode(picture_block(cover)));
+            }
+            data = comments.to_packet(*codec);
+            tagged = true;
+        }
+        *index += 1;
+
+        writer.write_packet(data.into_boxed_slice(), serial, info, granule)?;
+    }
+    if !tagged {
+        return Err(invalid("no Opus or Vorbis stream"));
+    }
+
+    let temp_path = path.with_extension("tagging.temp");
+    fs::write(&temp_path, writer.into_inner())?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Set the text tags in the comments, the cover is up to the container
+pub(super) fn set_text_tags(comments: &mut Comments, tags: &Tags) {
+    for (key, value) in [
+        ("ARTIST", tags.artist.clone()),
+        ("TITLE", tags.title.clone()),
+        ("ALBUM", tags.album.clone()),
+        ("TRACKNUMBER", tags.track.map(|track| track.to_string())),
+        ("DATE", tags.year.map(|year| year.to_string())),
+        ("GENRE", tags.genre.clone()),
+        (REPLAYGAIN_TRACK_GAIN, tags.replay_gain.map(|replay_gain| replay_gain.gain_text())),
+        (REPLAYGAIN_TRACK_PEAK, tags.replay_gain.map(|replay_gain| replay_gain.peak_text())),
+    ] {
+        if let Some(value) = value {
+            comments.set(key, &value);
+        }
+    }
+}
+
+/// The text tags of the comments
+pub(super) fn text_tags(comments: &Comments) -> Tags {
+    let text = |key: &str| comments.get(key).map(String::from);
+    Tags {
+        artist: text("ARTIST"),
+        title: text("TITLE"),
+        album: text("ALBUM"),
+        // "3/12" is also a track number
+        track: comments
+            .get("TRACKNUMBER")
+            .and_then(|track| track.split('/').next()?.trim().parse().ok()),
+        year: comments.get("DATE").and_then(|date| date.get(..4)?.parse().ok()),
+        genre: text("GENRE"),
+        cover: None,
+        replay_gain: comments
+            .get(REPLAYGAIN_TRACK_GAIN)
+            .zip(comments.get(REPLAYGAIN_TRACK_PEAK))
+            .and_then(|(gain, peak)| ReplayGain::parse(gain, peak)),
+    }
+}
+
+pub fn read(path: &Path) -> Result<Tags, TaggingError> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+    let mut codecs: HashMap<u32, Option<Codec>> = HashMap::new();
+
+    while let Some(packet) = reader.read_packet().map_err(ogg_error)? {
+        let serial = packet.stream_serial();
+        let Some(codec) = codecs.get(&serial) else {
+            codecs.insert(serial, Codec::of(&packet.data));
+            continue;
+        };
+        let Some(codec) = *codec else { continue };
+
+        let comments = Comments::parse(codec, &packet.data)?;
+        return Ok(Tags {
+            cover: comments
+                .get(PICTURE)
+                .and_then(|picture| BASE64.decode(picture).ok())
+                .and_then(|block| front_cover(&block)),
+            ..text_tags(&comments)
+        });
+    }
+    Err(invalid("no Opus or Vorbis stream"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SERIAL: u32 = 0x1234;
+
+    fn comment_header(codec: Codec, comments: &[&str]) -> Vec<u8> {
+        let comments = Comments {
+            vendor: b"Lavf".to_vec(),
+            comments: comments.iter().map(|comment| comment.to_string()).collect(),
+            rest: if codec == Codec::Vorbis { vec![1] } else { vec![] },
+        };
+        comments.to_packet(codec)
+    }
+
+    /// Identification header, comment header, then audio packets with their granule position
+    fn sample(codec: Codec, comments: &[&str]) -> Vec<u8> {
+        let mut writer = PacketWriter::new(vec![]);
+        let head = match codec {
+            Codec::Opus => b"OpusHead\x01\x02\x38\x01\x80\xbb\0\0\0\0\0".to_vec(),
+            Codec::Vorbis => b"\x01vorbis\0\0\0\0\x02\x44\xac\0\0".to_vec(),
+        };
+        writer.write_packet(head.into_boxed_slice(), SERIAL, PacketWriteEndInfo::EndPage, 0).unwrap();
+        let tags = comment_header(codec, comments).into_boxed_slice();
+        writer.write_packet(tags, SERIAL, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(vec![1; 300].into_boxed_slice(), SERIAL, PacketWriteEndInfo::EndPage, 960).unwrap();
+        writer.write_packet(vec![2; 300].into_boxed_slice(), SERIAL, PacketWriteEndInfo::EndStream, 1920).unwrap();
+        writer.into_inner()
+    }
+
+    /// The audio packets of the file with their granule position
+    fn audio(path: &Path) -> Vec<(Vec<u8>, u64)> {
+        let mut reader = PacketReader::new(Cursor::new(fs::read(path).unwrap()));
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let granule = packet.absgp_page();
+            packets.push((packet.data, granule));
+        }
+        packets.split_off(2)
+    }
+
+    fn tags() -> Tags {
+        Tags {
+            artist: Some("Guns N' Roses".into()),
+            title: Some("Don't Cry".into()),
+            album: Some("Use Your Illusion I".into()),
+            track: Some(13),
+            year: Some(1991),
+            genre: Some("Rock".into()),
+            cover: Some(b"\xFF\xD8\xFF\xE0cover".to_vec()),
+            replay_gain: Some(ReplayGain::new(-3.21, 0.98)),
+        }
+    }
+
+    #[test]
+    fn write_then_read_opus_round_trip() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_round_trip.opus");
+        fs::write(&path, sample(Codec::Opus, &["encoder=Lavf", "title=Old"])).unwrap();
+        let before = audio(&path);
+
+        write(&path, &tags()).unwrap();
+
+        assert_eq!(read(&path).unwrap(), tags());
+        assert_eq!(audio(&path), before);
+        assert_eq!(before[1], (vec![2; 300], 1920));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_keeps_other_comments_and_vorbis_framing_bit() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_round_trip.ogg");
+        fs::write(&path, sample(Codec::Vorbis, &["ENCODER=Lavf", "TITLE=Old"])).unwrap();
+
+        write(&path, &tags()).unwrap();
+
+        let mut reader = PacketReader::new(Cursor::new(fs::read(&path).unwrap()));
+        reader.read_packet().unwrap();
+        let header = reader.read_packet().unwrap().unwrap().data;
+        let comments = Comments::parse(Codec::Vorbis, &header).unwrap();
+        assert_eq!(comments.rest, vec![1]);
+        assert_eq!(comments.get("encoder"), Some("Lavf"));
+        assert_eq!(comments.comments.iter().filter(|comment| comment.starts_with("TITLE=")).count(), 1);
+        assert_eq!(read(&path).unwrap(), tags());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_track_number_with_total() {
+        let path = std::env::temp_dir().join("monsieur_dlp_tagging_track_total.opus");
+        fs::write(&path, sample(Codec::Opus, &["TRACKNUMBER=3/12", "DATE=2001-05-14"])).unwrap();
+
+        let tags = read(&path).unwrap();
+
+        assert_eq!((tags.track, tags.year), (Some(3), Some(2001)));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+/// Length of a YouTube video id
+const VIDEO_ID_LEN: usize = 11;
+
+/// The id of the YouTube video behind any of its url variants
+/// (`youtu.be/<id>`, `m.youtube.com/watch?v=<id>&list=...&t=...`, `/shorts/<id>`...)
+pub fn video_id(url: &str) -> Option<String> {
+    let (host, path, query) = split_url(url);
+
+    let id = match host.as_str() {
+        "youtu.be" => path.split('/').next(),
+        "youtube.com" | "youtube-nocookie.com" => match path.split('/').collect::<Vec<_>>().as_slice() {
+            ["watch"] => query_param(query, "v"),
+            ["shorts" | "embed" | "live" | "v", id, ..] => Some(*id),
+            _ => None,
+        },
+        _ => None,
+    }?;
+
+    is_video_id(id).then(|| id.to_string())
+}
+
+/// The url identifying the song, the same for every variant of a YouTube url
+pub fn canonical_url(url: &str) -> String {
+    match video_id(url) {
+        Some(id) => format!("https://www.youtube.com/watch?v={}", id),
+        None => url.trim().to_string(),
+    }
+}
+
+/// Whether the url lists several songs: a playlist, a channel or an album, rather than one video
+pub fn is_collection(url: &str) -> bool {
+    let (host, path, query) = split_url(url);
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+    match host.as_str() {
+        "youtube.com" => match segments.as_slice() {
+            ["playlist", ..] => query_param(query, "list").is_some(),
+            ["browse", id, ..] => id.starts_with("MPREb") || id.starts_with("VL"),
+            [handle, ..] => handle.starts_with('@') || matches!(*handle, "channel" | "c" | "user"),
+            [] => false,
+        },
+        "soundcloud.com" => segments.get(1) == Some(&"sets"),
+        host => host.ends_with(".bandcamp.com") && segments.first() == Some(&"album"),
+    }
+}
+
+/// The url to enumerate for a collection: the uploads of a channel rather than its home tab
+pub fn collection_url(url: &str) -> String {
+    let url = url.trim();
+    let (host, path, query) = split_url(url);
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+    let channel_home = host == "youtube.com"
+        && query.is_empty()
+        && match segments.as_slice() {
+            [handle] => handle.starts_with('@'),
+            ["channel" | "c" | "user", _] => true,
+            _ => false,
+        };
+    if channel_home {
+        format!("{}/videos", url.trim_end_matches('/'))
+    } else {
+        url.to_string()
+    }
+}
+
+/// The lowercase host without its `www.`/`m.`/`music.` prefix, the path and the query of the url
+fn split_url(url: &str) -> (String, &str, &str) {
+    let url = url.trim();
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let host = host.to_ascii_lowercase();
+    let host = ["www.", "m.", "music."]
+        .iter()
+        .find_map(|prefix| host.strip_prefix(prefix))
+        .unwrap_or(&host)
+        .to_string();
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    (host, path.split('#').next().unwrap_or_default(), query)
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('#')
+        .next()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+fn is_video_id(id: &str) -> bool {
+    id.len() == VIDEO_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_id_of_url_variants() {
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&index=2",
+            "http://m.youtube.com/watch?feature=share&v=dQw4w9WgXcQ&t=42s",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?t=10",
+            "youtu.be/dQw4w9WgXcQ",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+            "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ",
+            "  https://WWW.YouTube.com/watch?v=dQw4w9WgXcQ#comments  ",
+        ] {
+            assert_eq!(video_id(url).as_deref(), Some("dQw4w9WgXcQ"), "{}", url);
+        }
+    }
+
+    #[test]
+    fn video_id_of_other_urls_is_none() {
+        for url in [
+            "https://www.youtube.com/playlist?list=PL123",
+            "https://www.youtube.com/watch?v=short",
+            "https://soundcloud.com/artist/song",
+            "file:///tmp/song.mp3",
+            "",
+        ] {
+            assert_eq!(video_id(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn canonical_url_of_youtube_and_other_urls() {
+        assert_eq!(
+            canonical_url("https://youtu.be/dQw4w9WgXcQ?t=10"),
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ"
+        );
+        assert_eq!(
+            canonical_url(" https://soundcloud.com/artist/song "),
+            "https://soundcloud.com/artist/song"
+        );
+    }
+
+    #[test]
+    fn is_collection_of_playlists_channels_and_albums() {
+        for url in [
+            "https://www.youtube.com/playlist?list=PL123",
+            "https://music.youtube.com/playlist?list=OLAK5uy_abc",
+            "https://music.youtube.com/browse/MPREb_abc",
+            "https://www.youtube.com/@rustlang",
+            "https://www.youtube.com/channel/UC123/videos",
+            "https://soundcloud.com/artist/sets/album",
+            "https://artist.bandcamp.com/album/name",
+        ] {
+            assert!(is_collection(url), "{}", url);
+        }
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123",
+            "https://youtu.be/dQw4w9WgXcQ",
+            "https://www.youtube.com/playlist",
+            "https://soundcloud.com/artist/song",
+            "/home/me/song.mp3",
+        ] {
+            assert!(!is_collection(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn collection_url_lists_channel_uploads() {
+        assert_eq!(
+            collection_url("https://www.youtube.com/@rustlang/"),
+            "https://www.youtube.com/@rustlang/videos"
+        );
+        assert_eq!(
+            collection_url("https://www.youtube.com/@rustlang/streams"),
+            "https://www.youtube.com/@rustlang/streams"
+        );
+        assert_eq!(
+            collection_url("https://www.youtube.com/playlist?list=PL123"),
+            "https://www.youtube.com/playlist?list=PL123"
+        );
+    }
+}